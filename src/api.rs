@@ -1,20 +1,202 @@
-use crate::db::Database;
-use crate::modem::ModemManager;
+use crate::auth::{self, AuthState};
+use crate::config::LongMessages;
+use crate::db::{
+    ApiKey, Database, MessageFilter, MessagePage, OutgoingMessage, OutgoingStatus, SmsMessage,
+    SortOrder, UssdSessionState,
+};
+use crate::encoding::{Encoding, Segments};
+use crate::export::{Batches, ExportFormat};
+use crate::idempotency::{self, IdempotencyState};
+use crate::metrics::{self, Metrics};
+use crate::modem::{ModemBackend, ModemInfo};
+use crate::negotiate::negotiate;
+use crate::outbox::OutboxSender;
+use crate::ussd::{SessionConflict, UssdService};
 use crate::utils::parse_rfc3339_timestamp;
 use axum::{
-    Router,
-    extract::{Path, Query, State},
-    http::StatusCode,
-    response::{IntoResponse, Json, Response},
+    Extension, Router,
+    body::{Body, Bytes},
+    extract::{
+        Path, Query, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware,
+    response::{
+        IntoResponse, Json, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
     routing::get,
 };
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::future::Future;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Mutex, broadcast, watch};
+use tracing::{debug, error, info, warn};
+
+/// Page size of message listings without a `limit`
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// Largest `limit` accepted for message listings
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Media types message listings can be returned in. JSON comes first so
+/// it is used for `*/*` and when there is no `Accept` header.
+const LISTING_TYPES: [&str; 3] = ["application/json", "application/x-ndjson", "text/csv"];
+
+/// Export formats in order of preference when picked by `Accept`
+const EXPORT_FORMATS: [ExportFormat; 3] =
+    [ExportFormat::Ndjson, ExportFormat::Csv, ExportFormat::Mbox];
+
+/// Carries the cursor of the next page of a line-oriented listing, which
+/// has no envelope to put it in
+const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+
+/// Time a readiness check may take before its component is reported failed
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[derive(Deserialize)]
 pub struct MessageQuery {
     after: Option<String>,
+    before: Option<String>,
+    /// Cursor from the previous page's `next_cursor`
+    after_id: Option<i64>,
+    limit: Option<usize>,
+    sender: Option<String>,
+    imei: Option<String>,
+    order: Option<SortOrder>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: Option<String>,
+    /// IMSI, ICCID or own number of the receiving SIM
+    sim: Option<String>,
+    after: Option<String>,
+    before: Option<String>,
+    after_id: Option<i64>,
+    limit: Option<usize>,
+    sender: Option<String>,
+    imei: Option<String>,
+    order: Option<SortOrder>,
+}
+
+/// Search results are listed newest first unless asked otherwise
+impl From<SearchQuery> for MessageQuery {
+    fn from(query: SearchQuery) -> Self {
+        Self {
+            after: query.after,
+            before: query.before,
+            after_id: query.after_id,
+            limit: query.limit,
+            sender: query.sender,
+            imei: query.imei,
+            order: Some(query.order.unwrap_or(SortOrder::Desc)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StreamQuery {
+    /// Alternative to the `Last-Event-ID` header for clients that cannot set headers
+    last_event_id: Option<i64>,
+}
+
+/// A message together with the SIM it was received on, for listings and
+/// streams that may span all SIMs
+#[derive(Serialize)]
+struct MessageWithSim<'a> {
+    #[serde(flatten)]
+    message: &'a SmsMessage,
+    imsi: &'a str,
+    iccid: &'a str,
+}
+
+impl<'a> From<&'a SmsMessage> for MessageWithSim<'a> {
+    fn from(message: &'a SmsMessage) -> Self {
+        Self {
+            message,
+            imsi: &message.imsi,
+            iccid: &message.iccid,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SearchHit<'a> {
+    #[serde(flatten)]
+    message: MessageWithSim<'a>,
+    snippet: &'a str,
+}
+
+#[derive(Deserialize)]
+pub struct SendMessageRequest {
+    number: String,
+    text: String,
+    /// RFC3339 time to send the message at instead of right away
+    send_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    format: Option<ExportFormat>,
+    imsi: Option<String>,
+    /// Only messages received after this time
+    from: Option<String>,
+    /// Only messages received before this time
+    to: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase", deny_unknown_fields)]
+pub enum UssdRequest {
+    /// Start a session with a code such as `*100#`
+    Initiate {
+        command: String,
+    },
+    /// Answer the network in the active session
+    Respond {
+        response: String,
+    },
+    Cancel,
+}
+
+#[derive(Deserialize)]
+pub struct UssdQuery {
+    after_id: Option<i64>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct SendMessageResponse {
+    /// The first message, if the text was split
+    id: i64,
+    status: OutgoingStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    send_at: Option<DateTime<Utc>>,
+    /// Encoding of the first message
+    encoding: Encoding,
+    /// SMS parts of all queued messages together
+    segments: u32,
+    /// Septets or UTF-16 code units each part of the first message holds
+    segment_length: usize,
+    /// Every message the text was split into, in sending order
+    #[serde(skip_serializing_if = "Option::is_none")]
+    split: Option<Vec<SplitMessage>>,
+}
+
+/// One of the messages a long text was split into. Each is encoded on its
+/// own, so a part without UCS-2 characters is sent as GSM-7.
+#[derive(Serialize)]
+pub struct SplitMessage {
+    id: i64,
+    encoding: Encoding,
+    segments: u32,
+    segment_length: usize,
 }
 
 #[derive(Serialize)]
@@ -24,6 +206,9 @@ pub struct ApiResponse<T> {
     data: Option<T>,
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
+    /// Cursor for the next page of a paginated listing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<i64>,
 }
 
 impl<T: Serialize> IntoResponse for ApiResponse<T> {
@@ -43,6 +228,14 @@ impl<T: Serialize> ApiResponse<T> {
             success: true,
             data: Some(data),
             error: None,
+            next_cursor: None,
+        }
+    }
+
+    pub fn page(data: T, next_cursor: Option<i64>) -> Self {
+        Self {
+            next_cursor,
+            ..Self::success(data)
         }
     }
 
@@ -51,6 +244,7 @@ impl<T: Serialize> ApiResponse<T> {
             success: false,
             data: None,
             error: Some(error),
+            next_cursor: None,
         }
     }
 
@@ -62,42 +256,232 @@ impl<T: Serialize> ApiResponse<T> {
     }
 }
 
+/// Result of one readiness check
+#[derive(Serialize)]
+pub struct ComponentHealth {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    database: ComponentHealth,
+    dbus: ComponentHealth,
+    poller: ComponentHealth,
+}
+
 #[derive(Clone)]
 pub struct AppState {
     db: Arc<Mutex<Database>>,
-    modem_manager: Arc<ModemManager>,
+    modem_manager: Arc<dyn ModemBackend>,
+    messages: broadcast::Sender<SmsMessage>,
+    ussd: Arc<UssdService>,
+    outbox: Arc<OutboxSender>,
 }
 
-pub fn create_router(db: Arc<Mutex<Database>>, modem_manager: Arc<ModemManager>) -> Router {
-    let state = AppState { db, modem_manager };
+pub fn create_router(
+    db: Arc<Mutex<Database>>,
+    modem_manager: Arc<dyn ModemBackend>,
+    messages: broadcast::Sender<SmsMessage>,
+    ussd: Arc<UssdService>,
+    outbox: Arc<OutboxSender>,
+    metrics: Arc<Metrics>,
+    idempotency_window: chrono::Duration,
+) -> Router {
+    let auth = AuthState {
+        db: db.clone(),
+        modem_manager: modem_manager.clone(),
+    };
+    let idempotency = IdempotencyState {
+        db: db.clone(),
+        window: idempotency_window,
+    };
+    let state = AppState {
+        db,
+        modem_manager,
+        messages,
+        ussd,
+        outbox,
+    };
 
     Router::new()
-        .route("/messages/:imsi", get(get_messages))
+        .route("/messages", get(get_all_messages))
+        .route("/messages/search", get(search_messages))
+        .route("/messages/export", get(export_messages))
+        .route("/messages/stream", get(stream_all_messages))
+        .route("/messages/ws", get(websocket_all_messages))
+        .route("/messages/:sim", get(get_messages).post(send_message))
+        .route("/messages/:sim/stream", get(stream_messages))
+        .route("/messages/:sim/ws", get(websocket_messages))
+        .route(
+            "/outbox/:id",
+            get(get_outgoing_message).delete(cancel_outgoing_message),
+        )
+        .route(
+            "/modems/:sim/ussd",
+            get(get_ussd_sessions).post(ussd_request),
+        )
+        // Runs after authentication, which provides the key it is scoped to
+        .route_layer(middleware::from_fn_with_state(
+            idempotency,
+            idempotency::replay_idempotent,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            auth,
+            auth::require_message_access,
+        ))
+        .layer(middleware::from_fn_with_state(
+            metrics,
+            metrics::track_http_requests,
+        ))
         .with_state(state)
 }
 
-pub fn create_metrics_router(modem_manager: Arc<ModemManager>) -> Router {
-    let state = AppState {
-        db: Arc::new(Mutex::new(Database::new(":memory:").unwrap())),
+/// State of the metrics router, which only serves `/metrics`, `/modems`
+/// and the health checks
+#[derive(Clone)]
+struct MetricsState {
+    db: Arc<Mutex<Database>>,
+    modem_manager: Arc<dyn ModemBackend>,
+    metrics: Arc<Metrics>,
+    poll_interval: watch::Receiver<Duration>,
+    /// Poll intervals the poller may go without polling while still ready
+    readiness_poll_intervals: u32,
+}
+
+/// `/health/ready` reports the poller as stuck once it has not polled for
+/// `readiness_poll_intervals` times the current `poll_interval`
+pub fn create_metrics_router(
+    db: Arc<Mutex<Database>>,
+    modem_manager: Arc<dyn ModemBackend>,
+    metrics: Arc<Metrics>,
+    poll_interval: watch::Receiver<Duration>,
+    readiness_poll_intervals: u32,
+) -> Router {
+    let auth = AuthState {
+        db: db.clone(),
+        modem_manager: modem_manager.clone(),
+    };
+    let state = MetricsState {
+        db,
         modem_manager,
+        metrics: metrics.clone(),
+        poll_interval,
+        readiness_poll_intervals,
     };
 
+    // Health checks stay open for load balancers and service managers
+    let health_routes = Router::new()
+        .route("/health", get(health_check))
+        .route("/health/live", get(health_check))
+        .route("/health/ready", get(readiness_check));
+
     Router::new()
         .route("/modems", get(get_modems))
         .route("/metrics", get(get_metrics))
-        .route("/health", get(health_check))
+        .route_layer(middleware::from_fn_with_state(
+            auth,
+            auth::require_metrics_access,
+        ))
+        .merge(health_routes)
         .with_state(state)
+        .layer(middleware::from_fn_with_state(
+            metrics,
+            metrics::track_http_requests,
+        ))
 }
 
+/// Liveness: answers as long as the HTTP server runs
 async fn health_check() -> Json<ApiResponse<String>> {
     Json(ApiResponse::success("OK".to_string()))
 }
 
-async fn get_modems(State(state): State<AppState>) -> Response {
+/// Readiness: checks the database, ModemManager and the poller, and fails
+/// with 503 if any of them is unhealthy
+async fn readiness_check(State(state): State<MetricsState>) -> Response {
+    let max_poll_age = *state.poll_interval.borrow() * state.readiness_poll_intervals;
+    let (database, dbus, poller) = tokio::join!(
+        check_component(async { state.db.lock().await.check_health() }),
+        check_component(state.modem_manager.ping()),
+        check_component(async { check_poller(&state.metrics, max_poll_age) }),
+    );
+    let readiness = Readiness {
+        database,
+        dbus,
+        poller,
+    };
+
+    let failed: Vec<&str> = [
+        ("database", &readiness.database),
+        ("dbus", &readiness.dbus),
+        ("poller", &readiness.poller),
+    ]
+    .into_iter()
+    .filter(|(_, component)| !component.ok)
+    .map(|(name, _)| name)
+    .collect();
+
+    if failed.is_empty() {
+        return Json(ApiResponse::success(readiness)).into_response();
+    }
+
+    debug!(components = ?failed, "Not ready");
+    let response = ApiResponse {
+        success: false,
+        data: Some(readiness),
+        error: Some(format!("Unhealthy components: {}", failed.join(", "))),
+        next_cursor: None,
+    };
+    (StatusCode::SERVICE_UNAVAILABLE, Json(response)).into_response()
+}
+
+async fn check_component(check: impl Future<Output = anyhow::Result<()>>) -> ComponentHealth {
+    let error = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, check).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(format!("{:#}", e)),
+        Err(_) => Some(format!(
+            "Check timed out after {}s",
+            HEALTH_CHECK_TIMEOUT.as_secs()
+        )),
+    };
+
+    ComponentHealth {
+        ok: error.is_none(),
+        error,
+    }
+}
+
+/// Fails if the poller has not started a poll within `max_age`, which means
+/// its task died or a poll is stuck
+fn check_poller(metrics: &Metrics, max_age: Duration) -> anyhow::Result<()> {
+    let heartbeat = metrics.poller_heartbeat.get();
+    if heartbeat == 0 {
+        anyhow::bail!("No poll has started yet");
+    }
+
+    let age = (Utc::now().timestamp() - heartbeat).max(0) as u64;
+    if age > max_age.as_secs() {
+        anyhow::bail!(
+            "Last poll started {}s ago, more than the allowed {}s",
+            age,
+            max_age.as_secs()
+        );
+    }
+    Ok(())
+}
+
+async fn get_modems(
+    State(state): State<MetricsState>,
+    Extension(key): Extension<ApiKey>,
+) -> Response {
     let modems = state.modem_manager.get_modems().await;
 
     match modems {
-        Ok(modems) => Json(ApiResponse::success(modems)).into_response(),
+        Ok(mut modems) => {
+            modems.retain(|modem| key.allows_imsi(&modem.imsi));
+            Json(ApiResponse::success(modems)).into_response()
+        }
         Err(e) => ApiResponse::<()>::error_with_status(
             format!("Failed to get modems: {}", e),
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -106,51 +490,471 @@ async fn get_modems(State(state): State<AppState>) -> Response {
     }
 }
 
-async fn get_metrics(State(state): State<AppState>) -> Response {
-    let modem_count = match state.modem_manager.get_modems().await {
-        Ok(modems) => modems.len(),
-        Err(_) => 0,
-    };
-
-    let response = format!(
-        "# HELP modem_count Total number of modems\n\
-         # TYPE modem_count gauge\n\
-         modem_count {}\n",
-        modem_count
-    );
+async fn get_metrics(State(state): State<MetricsState>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+        .into_response()
+}
 
-    response.into_response()
+async fn get_all_messages(
+    State(state): State<AppState>,
+    Extension(key): Extension<ApiKey>,
+    headers: HeaderMap,
+    Query(params): Query<MessageQuery>,
+) -> Response {
+    let response = match listing_format(&headers) {
+        Some(ListingFormat::Json) => match query_messages(&state, &key, None, params).await {
+            Ok(page) => {
+                let messages: Vec<MessageWithSim> =
+                    page.messages.iter().map(MessageWithSim::from).collect();
+                Json(ApiResponse::page(messages, page.next_cursor)).into_response()
+            }
+            Err(response) => response,
+        },
+        Some(ListingFormat::Lines(format)) => {
+            list_messages_as(&state, &key, None, params, format).await
+        }
+        None => not_acceptable(&LISTING_TYPES),
+    };
+    vary_on_accept(response)
 }
 
 async fn get_messages(
     State(state): State<AppState>,
-    Path(imsi): Path<String>,
+    Extension(key): Extension<ApiKey>,
+    Path(sim): Path<String>,
+    headers: HeaderMap,
     Query(params): Query<MessageQuery>,
 ) -> Response {
-    // Parse and validate 'after' timestamp parameter if provided
-    let after = if let Some(after_str) = params.after {
-        match parse_rfc3339_timestamp(&after_str) {
-            Ok(dt) => Some(dt),
-            Err(e) => {
-                return ApiResponse::<()>::error_with_status(
-                    format!("Invalid 'after' timestamp format. Expected RFC3339: {}", e),
-                    StatusCode::BAD_REQUEST,
-                )
+    let response = match listing_format(&headers) {
+        Some(ListingFormat::Json) => match query_messages(&state, &key, Some(sim), params).await {
+            Ok(page) => Json(ApiResponse::page(page.messages, page.next_cursor)).into_response(),
+            Err(response) => response,
+        },
+        Some(ListingFormat::Lines(format)) => {
+            list_messages_as(&state, &key, Some(sim), params, format).await
+        }
+        None => not_acceptable(&LISTING_TYPES),
+    };
+    vary_on_accept(response)
+}
+
+enum ListingFormat {
+    /// The paginated `ApiResponse` envelope
+    Json,
+    Lines(ExportFormat),
+}
+
+/// Picks the format of a message listing from the `Accept` header, or None
+/// if the client accepts none of them
+fn listing_format(headers: &HeaderMap) -> Option<ListingFormat> {
+    match negotiate(accept_header(headers), &LISTING_TYPES)? {
+        "application/json" => Some(ListingFormat::Json),
+        media_type => EXPORT_FORMATS
+            .into_iter()
+            .find(|format| format.media_type() == media_type)
+            .map(ListingFormat::Lines),
+    }
+}
+
+fn accept_header(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+}
+
+fn not_acceptable(supported: &[&str]) -> Response {
+    ApiResponse::<()>::error_with_status(
+        format!(
+            "None of the accepted media types can be produced, supported are: {}",
+            supported.join(", ")
+        ),
+        StatusCode::NOT_ACCEPTABLE,
+    )
+    .into_response()
+}
+
+/// Marks a negotiated response as depending on `Accept` for caches
+fn vary_on_accept(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .insert(header::VARY, HeaderValue::from_static("accept"));
+    response
+}
+
+/// Lists messages in a line-oriented format. Without a `limit` every
+/// matching message is streamed; with one, a single page is returned and
+/// the cursor for the next page is sent in the `X-Next-Cursor` header.
+async fn list_messages_as(
+    state: &AppState,
+    key: &ApiKey,
+    sim: Option<String>,
+    params: MessageQuery,
+    format: ExportFormat,
+) -> Response {
+    let content_type = [(header::CONTENT_TYPE, format.content_type())];
+
+    if params.limit.is_none() {
+        return match message_filter(key, sim, params) {
+            Ok(filter) => {
+                (content_type, message_body(state.db.clone(), filter, format)).into_response()
+            }
+            Err(error) => {
+                ApiResponse::<()>::error_with_status(error, StatusCode::BAD_REQUEST).into_response()
+            }
+        };
+    }
+
+    let page = match query_messages(state, key, sim, params).await {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+
+    // Writing to a Vec cannot fail
+    let mut body = Vec::new();
+    let _ = format.write_header(&mut body);
+    for msg in &page.messages {
+        let _ = format.write_message(&mut body, msg);
+    }
+
+    let mut response = (content_type, body).into_response();
+    if let Some(cursor) = page.next_cursor {
+        response
+            .headers_mut()
+            .insert(NEXT_CURSOR_HEADER, HeaderValue::from(cursor));
+    }
+    response
+}
+
+/// Runs a message listing for a SIM, or all SIMs if None, limited to the
+/// SIMs the API key is scoped to
+async fn query_messages(
+    state: &AppState,
+    key: &ApiKey,
+    sim: Option<String>,
+    params: MessageQuery,
+) -> Result<MessagePage, Response> {
+    let filter = message_filter(key, sim, params).map_err(|error| {
+        ApiResponse::<()>::error_with_status(error, StatusCode::BAD_REQUEST).into_response()
+    })?;
+
+    let page = {
+        let db = state.db.lock().await;
+        db.get_messages(&filter)
+    };
+
+    page.map_err(|e| {
+        ApiResponse::<()>::error_with_status(
+            format!("Database error: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response()
+    })
+}
+
+async fn search_messages(
+    State(state): State<AppState>,
+    Extension(key): Extension<ApiKey>,
+    Query(params): Query<SearchQuery>,
+) -> Response {
+    let Some(q) = params.q.clone().filter(|q| !q.trim().is_empty()) else {
+        return ApiResponse::<()>::error_with_status(
+            "Query parameter 'q' must not be empty".to_string(),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response();
+    };
+
+    let filter = match message_filter(&key, params.sim.clone(), params.into()) {
+        Ok(filter) => filter,
+        Err(error) => {
+            return ApiResponse::<()>::error_with_status(error, StatusCode::BAD_REQUEST)
                 .into_response();
+        }
+    };
+
+    let page = {
+        let db = state.db.lock().await;
+        db.search_messages(&q, &filter)
+    };
+
+    match page {
+        Ok(page) => {
+            let hits: Vec<SearchHit> = page
+                .results
+                .iter()
+                .map(|result| SearchHit {
+                    message: MessageWithSim::from(&result.message),
+                    snippet: &result.snippet,
+                })
+                .collect();
+            Json(ApiResponse::page(hits, page.next_cursor)).into_response()
+        }
+        Err(e) => ApiResponse::<()>::error_with_status(
+            format!("Database error: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response(),
+    }
+}
+
+/// Streams all matching messages, oldest first, in the `format` parameter
+/// or else the format picked by the `Accept` header
+async fn export_messages(
+    State(state): State<AppState>,
+    Extension(key): Extension<ApiKey>,
+    headers: HeaderMap,
+    Query(params): Query<ExportQuery>,
+) -> Response {
+    let format = match params.format {
+        Some(format) => format,
+        None => {
+            let supported = EXPORT_FORMATS.map(ExportFormat::media_type);
+            let Some(media_type) = negotiate(accept_header(&headers), &supported) else {
+                return not_acceptable(&supported);
+            };
+            EXPORT_FORMATS
+                .into_iter()
+                .find(|format| format.media_type() == media_type)
+                .unwrap_or(ExportFormat::Ndjson)
+        }
+    };
+
+    let filter = match export_filter(&key, params).await {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+
+    (
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"messages.{}\"", format.extension()),
+            ),
+        ],
+        message_body(state.db.clone(), filter, format),
+    )
+        .into_response()
+}
+
+/// A body with every message matching the filter in its order. The
+/// database is read one batch at a time and only locked while a batch is
+/// read, so bodies of any size neither buffer the whole result nor block
+/// ingestion.
+fn message_body(db: Arc<Mutex<Database>>, filter: MessageFilter, format: ExportFormat) -> Body {
+    let mut header = Vec::new();
+    // Writing to a Vec cannot fail
+    let _ = format.write_header(&mut header);
+
+    let batches = stream::unfold(Some(Batches::new(filter)), move |batches| {
+        let db = db.clone();
+        async move {
+            let mut batches = batches?;
+            let batch = {
+                let db = db.lock().await;
+                batches.next(&db)
+            };
+
+            match batch {
+                Ok(Some(messages)) => {
+                    let mut chunk = Vec::new();
+                    for msg in &messages {
+                        let _ = format.write_message(&mut chunk, msg);
+                    }
+                    Some((Ok(Bytes::from(chunk)), Some(batches)))
+                }
+                Ok(None) => None,
+                Err(e) => {
+                    // The status line is already sent, so abort the
+                    // response to keep the client from taking a truncated
+                    // body for a complete one
+                    error!(error = %e, "Failed to read messages");
+                    Some((Err(std::io::Error::other(e)), None))
+                }
             }
         }
-    } else {
-        None
+    });
+
+    Body::from_stream(stream::once(async move { Ok(Bytes::from(header)) }).chain(batches))
+}
+
+/// Builds the export filter, limited to the SIMs the API key is scoped to
+async fn export_filter(key: &ApiKey, params: ExportQuery) -> Result<MessageFilter, Response> {
+    let bad_request = |error: String| {
+        ApiResponse::<()>::error_with_status(error, StatusCode::BAD_REQUEST).into_response()
+    };
+
+    let after = parse_timestamp_param("from", params.from.as_deref()).map_err(bad_request)?;
+    let before = parse_timestamp_param("to", params.to.as_deref()).map_err(bad_request)?;
+
+    let imsis = match params.imsi.filter(|imsi| !imsi.is_empty()) {
+        Some(imsi) if !key.allows_imsi(&imsi) => {
+            return Err(ApiResponse::<()>::error_with_status(
+                format!("API key is not allowed to access SIM {}", imsi),
+                StatusCode::FORBIDDEN,
+            )
+            .into_response());
+        }
+        Some(imsi) => vec![imsi],
+        None => key.imsis.clone(),
+    };
+
+    Ok(MessageFilter {
+        imsis,
+        after,
+        before,
+        ..MessageFilter::default()
+    })
+}
+
+/// Validates listing parameters and builds the filter for them, limited to
+/// the SIMs the API key is scoped to
+fn message_filter(
+    key: &ApiKey,
+    sim: Option<String>,
+    params: MessageQuery,
+) -> Result<MessageFilter, String> {
+    let after = parse_timestamp_param("after", params.after.as_deref())?;
+    let before = parse_timestamp_param("before", params.before.as_deref())?;
+
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Err(format!("'limit' must be between 1 and {}", MAX_PAGE_SIZE));
+    }
+
+    Ok(MessageFilter {
+        sim,
+        imsis: key.imsis.clone(),
+        sender: params.sender.filter(|s| !s.is_empty()),
+        imei: params.imei.filter(|s| !s.is_empty()),
+        after,
+        before,
+        after_id: params.after_id,
+        order: params.order.unwrap_or_default(),
+        limit: Some(limit),
+    })
+}
+
+fn parse_timestamp_param(name: &str, value: Option<&str>) -> Result<Option<DateTime<Utc>>, String> {
+    value.map(parse_rfc3339_timestamp).transpose().map_err(|e| {
+        format!(
+            "Invalid '{}' timestamp format. Expected RFC3339: {}",
+            name, e
+        )
+    })
+}
+
+/// Finds the modem currently holding a SIM, given by IMSI, ICCID or own number
+async fn find_modem(state: &AppState, sim: &str) -> Result<ModemInfo, Response> {
+    let modems = state.modem_manager.get_modems().await.map_err(|e| {
+        ApiResponse::<()>::error_with_status(
+            format!("Failed to get modems: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response()
+    })?;
+
+    modems
+        .into_iter()
+        .find(|m| m.matches_sim(sim))
+        .ok_or_else(|| {
+            ApiResponse::<()>::error_with_status(
+                format!("No modem found for SIM {}", sim),
+                StatusCode::NOT_FOUND,
+            )
+            .into_response()
+        })
+}
+
+/// Starts, continues or cancels the USSD session of a SIM
+async fn ussd_request(
+    State(state): State<AppState>,
+    Path(sim): Path<String>,
+    Json(request): Json<UssdRequest>,
+) -> Response {
+    if let UssdRequest::Initiate { command: text } | UssdRequest::Respond { response: text } =
+        &request
+        && text.trim().is_empty()
+    {
+        return ApiResponse::<()>::error_with_status(
+            "The USSD code or response must be non-empty".to_string(),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response();
+    }
+
+    let modem = match find_modem(&state, &sim).await {
+        Ok(modem) => modem,
+        Err(response) => return response,
+    };
+
+    let result = match &request {
+        UssdRequest::Initiate { command } => state
+            .ussd
+            .initiate(&modem, command.trim(), false)
+            .await
+            .map(Some),
+        UssdRequest::Respond { response } => {
+            state.ussd.respond(&modem, response.trim()).await.map(Some)
+        }
+        UssdRequest::Cancel => state.ussd.cancel(&modem).await,
+    };
+
+    match result {
+        Ok(Some(session)) if session.state == UssdSessionState::Failed => {
+            ApiResponse::<()>::error_with_status(
+                format!("USSD request failed: {}", session.error.unwrap_or_default()),
+                StatusCode::BAD_GATEWAY,
+            )
+            .into_response()
+        }
+        Ok(session) => Json(ApiResponse::success(session)).into_response(),
+        Err(e) if e.is::<SessionConflict>() => {
+            ApiResponse::<()>::error_with_status(e.to_string(), StatusCode::CONFLICT)
+                .into_response()
+        }
+        Err(e) => ApiResponse::<()>::error_with_status(
+            format!("USSD error: {:#}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response(),
+    }
+}
+
+/// Lists the USSD sessions of a SIM with their transcripts, newest first
+async fn get_ussd_sessions(
+    State(state): State<AppState>,
+    Path(sim): Path<String>,
+    Query(params): Query<UssdQuery>,
+) -> Response {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return ApiResponse::<()>::error_with_status(
+            format!("'limit' must be between 1 and {}", MAX_PAGE_SIZE),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response();
+    }
+
+    // Sessions are stored by IMSI, which an own number has to be resolved to
+    let imsi = match state.modem_manager.get_modems().await {
+        Ok(modems) => modems
+            .into_iter()
+            .find(|m| m.matches_sim(&sim))
+            .map(|m| m.imsi),
+        Err(_) => None,
     };
 
-    // Query database
-    let messages = {
+    let page = {
         let db = state.db.lock().await;
-        db.get_messages(Some(&imsi), after)
+        db.get_ussd_sessions(imsi.as_deref().unwrap_or(&sim), params.after_id, limit)
     };
 
-    match messages {
-        Ok(messages) => Json(ApiResponse::success(messages)).into_response(),
+    match page {
+        Ok(page) => Json(ApiResponse::page(page.sessions, page.next_cursor)).into_response(),
         Err(e) => ApiResponse::<()>::error_with_status(
             format!("Database error: {}", e),
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -158,3 +962,1725 @@ async fn get_messages(
         .into_response(),
     }
 }
+
+/// Queues a message in the outbox of the SIM. The outbox sender sends it
+/// once it is due, retrying failed attempts.
+async fn send_message(
+    State(state): State<AppState>,
+    Path(sim): Path<String>,
+    Json(request): Json<SendMessageRequest>,
+) -> Response {
+    if request.number.trim().is_empty() || request.text.is_empty() {
+        return ApiResponse::<()>::error_with_status(
+            "Both 'number' and 'text' must be non-empty".to_string(),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response();
+    }
+
+    let send_at = match parse_timestamp_param("send_at", request.send_at.as_deref()) {
+        Ok(send_at) => send_at,
+        Err(e) => {
+            return ApiResponse::<()>::error_with_status(e, StatusCode::BAD_REQUEST)
+                .into_response();
+        }
+    };
+
+    let modem = match find_modem(&state, &sim).await {
+        Ok(modem) => modem,
+        Err(response) => return response,
+    };
+
+    let segments = Segments::of(&request.text);
+    let policy = state.outbox.policy();
+    let texts = if segments.parts.len() <= policy.max_parts {
+        vec![request.text.clone()]
+    } else if policy.long_messages == LongMessages::Split {
+        segments.split(policy.max_parts)
+    } else {
+        return ApiResponse::<()>::error_with_status(
+            format!(
+                "Text needs {} SMS parts of {} {} characters, more than the maximum of {}",
+                segments.parts.len(),
+                segments.part_length,
+                segments.encoding.as_str(),
+                policy.max_parts
+            ),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response();
+    };
+
+    let outgoing: Vec<_> = texts
+        .into_iter()
+        .map(|text| OutgoingMessage::queued(&modem, request.number.clone(), text, send_at))
+        .collect();
+    let ids = {
+        let db = state.db.lock().await;
+        db.insert_outgoing_messages(&outgoing)
+    };
+
+    match ids {
+        Ok(ids) => {
+            let messages: Vec<_> = ids
+                .iter()
+                .zip(&outgoing)
+                .map(|(&id, message)| {
+                    let segments = Segments::of(&message.text);
+                    SplitMessage {
+                        id,
+                        encoding: segments.encoding,
+                        segments: segments.parts.len() as u32,
+                        segment_length: segments.part_length,
+                    }
+                })
+                .collect();
+            let total = messages.iter().map(|m| m.segments).sum();
+            info!(
+                ids = ?ids,
+                imsi = %modem.imsi,
+                recipient = %request.number,
+                encoding = messages[0].encoding.as_str(),
+                segments = total,
+                "Queued SMS"
+            );
+            state.outbox.wake();
+            (
+                StatusCode::ACCEPTED,
+                Json(ApiResponse::success(SendMessageResponse {
+                    id: ids[0],
+                    status: OutgoingStatus::Queued,
+                    send_at,
+                    encoding: messages[0].encoding,
+                    segments: total,
+                    segment_length: messages[0].segment_length,
+                    split: (messages.len() > 1).then_some(messages),
+                })),
+            )
+                .into_response()
+        }
+        Err(e) => ApiResponse::<()>::error_with_status(
+            format!("Database error: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response(),
+    }
+}
+
+/// An outgoing message with its send and delivery status. Keys scoped to other SIMs get
+/// the same 404 as for a message that does not exist.
+async fn get_outgoing_message(
+    State(state): State<AppState>,
+    Extension(key): Extension<ApiKey>,
+    Path(id): Path<i64>,
+) -> Response {
+    let message = {
+        let db = state.db.lock().await;
+        db.get_outgoing_message(id)
+    };
+
+    match message {
+        Ok(Some(message)) if key.allows_imsi(&message.imsi) => {
+            Json(ApiResponse::success(message)).into_response()
+        }
+        Ok(_) => ApiResponse::<()>::error_with_status(
+            format!("No outgoing message with id {}", id),
+            StatusCode::NOT_FOUND,
+        )
+        .into_response(),
+        Err(e) => ApiResponse::<()>::error_with_status(
+            format!("Database error: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response(),
+    }
+}
+
+/// Cancels a message that is still queued. Messages being sent or already
+/// sent conflict.
+async fn cancel_outgoing_message(
+    State(state): State<AppState>,
+    Extension(key): Extension<ApiKey>,
+    Path(id): Path<i64>,
+) -> Response {
+    let result = {
+        let db = state.db.lock().await;
+        cancel_queued(&db, &key, id)
+    };
+
+    match result {
+        Ok(Ok(message)) => {
+            info!(id, imsi = %message.imsi, "Cancelled queued SMS");
+            Json(ApiResponse::success(message)).into_response()
+        }
+        Ok(Err(status)) => {
+            let error = if status == StatusCode::NOT_FOUND {
+                format!("No outgoing message with id {}", id)
+            } else {
+                format!("Message {} is no longer queued", id)
+            };
+            ApiResponse::<()>::error_with_status(error, status).into_response()
+        }
+        Err(e) => ApiResponse::<()>::error_with_status(
+            format!("Database error: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response(),
+    }
+}
+
+/// Cancels the message if it is queued and visible to the key
+fn cancel_queued(
+    db: &Database,
+    key: &ApiKey,
+    id: i64,
+) -> anyhow::Result<Result<OutgoingMessage, StatusCode>> {
+    match db.get_outgoing_message(id)? {
+        Some(message) if key.allows_imsi(&message.imsi) => {
+            if !db.cancel_outgoing_message(id)? {
+                return Ok(Err(StatusCode::CONFLICT));
+            }
+            Ok(db.get_outgoing_message(id)?.ok_or(StatusCode::NOT_FOUND))
+        }
+        _ => Ok(Err(StatusCode::NOT_FOUND)),
+    }
+}
+
+async fn stream_all_messages(
+    State(state): State<AppState>,
+    Extension(key): Extension<ApiKey>,
+    headers: HeaderMap,
+    Query(params): Query<StreamQuery>,
+) -> Response {
+    sse_response(state, key, None, &headers, params).await
+}
+
+async fn stream_messages(
+    State(state): State<AppState>,
+    Extension(key): Extension<ApiKey>,
+    Path(sim): Path<String>,
+    headers: HeaderMap,
+    Query(params): Query<StreamQuery>,
+) -> Response {
+    sse_response(state, key, Some(sim), &headers, params).await
+}
+
+async fn websocket_all_messages(
+    State(state): State<AppState>,
+    Extension(key): Extension<ApiKey>,
+    headers: HeaderMap,
+    Query(params): Query<StreamQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    websocket_response(state, key, None, &headers, params, ws).await
+}
+
+async fn websocket_messages(
+    State(state): State<AppState>,
+    Extension(key): Extension<ApiKey>,
+    Path(sim): Path<String>,
+    headers: HeaderMap,
+    Query(params): Query<StreamQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    websocket_response(state, key, Some(sim), &headers, params, ws).await
+}
+
+async fn sse_response(
+    state: AppState,
+    key: ApiKey,
+    sim: Option<String>,
+    headers: &HeaderMap,
+    params: StreamQuery,
+) -> Response {
+    let messages = match open_message_stream(&state, key, sim, headers, params).await {
+        Ok(messages) => messages,
+        Err(response) => return response,
+    };
+
+    let events = messages.map(|msg| {
+        Event::default()
+            .id(msg.id.unwrap_or_default().to_string())
+            .event("message")
+            .json_data(MessageWithSim::from(&msg))
+    });
+
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+async fn websocket_response(
+    state: AppState,
+    key: ApiKey,
+    sim: Option<String>,
+    headers: &HeaderMap,
+    params: StreamQuery,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let messages = match open_message_stream(&state, key, sim, headers, params).await {
+        Ok(messages) => messages,
+        Err(response) => return response,
+    };
+
+    ws.on_upgrade(|socket| forward_to_websocket(socket, messages))
+}
+
+async fn forward_to_websocket(
+    mut socket: WebSocket,
+    messages: impl Stream<Item = SmsMessage> + Send + 'static,
+) {
+    let mut messages = std::pin::pin!(messages);
+
+    loop {
+        tokio::select! {
+            msg = messages.next() => {
+                let Some(msg) = msg else {
+                    // The daemon is shutting down or the stream could not
+                    // catch up; the client can resume from its last message
+                    let _ = socket.send(Message::Close(None)).await;
+                    return;
+                };
+
+                let json = match serde_json::to_string(&MessageWithSim::from(&msg)) {
+                    Ok(json) => json,
+                    Err(e) => {
+                        warn!(error = %e, "Failed to serialize streamed message");
+                        continue;
+                    }
+                };
+
+                if socket.send(Message::Text(json)).await.is_err() {
+                    return;
+                }
+            }
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+}
+
+/// Messages for a stream: stored ones from the database, then live ones
+/// from the broadcast channel
+struct LiveMessages {
+    receiver: broadcast::Receiver<SmsMessage>,
+    db: Arc<Mutex<Database>>,
+    key: ApiKey,
+    sim: Option<String>,
+    /// Id of the last message sent to the client
+    last_id: i64,
+    /// Messages read from the database, sent before live ones
+    stored: VecDeque<SmsMessage>,
+    /// Whether the database may hold more messages after `last_id` than
+    /// `stored` does
+    behind: bool,
+}
+
+impl LiveMessages {
+    /// Reads the next batch of stored messages after `last_id`. Batches are
+    /// kept small so a long backlog never sits in memory at once.
+    async fn read_stored(&mut self) -> anyhow::Result<()> {
+        let filter = MessageFilter {
+            sim: self.sim.clone(),
+            imsis: self.key.imsis.clone(),
+            after_id: Some(self.last_id),
+            limit: Some(DEFAULT_PAGE_SIZE),
+            ..Default::default()
+        };
+        let page = self.db.lock().await.get_messages(&filter)?;
+        self.behind = page.next_cursor.is_some();
+        self.stored.extend(page.messages);
+        Ok(())
+    }
+
+    /// Returns the next message for the client, or None once the channel
+    /// closes. A subscriber that lags behind the channel catches up on the
+    /// messages it missed from the database, so the stream stays gapless.
+    async fn next(&mut self) -> Option<SmsMessage> {
+        loop {
+            if self.stored.is_empty() && self.behind {
+                if let Err(e) = self.read_stored().await {
+                    warn!(error = %e, "Failed to read stored messages for stream, closing it");
+                    return None;
+                }
+                continue;
+            }
+
+            let msg = match self.stored.pop_front() {
+                Some(msg) => msg,
+                None => match self.receiver.recv().await {
+                    Ok(msg) => msg,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        debug!(
+                            skipped,
+                            "Message stream subscriber lagged, catching up from the database"
+                        );
+                        self.behind = true;
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                },
+            };
+
+            let id = msg.id.unwrap_or_default();
+            // Skip anything already sent from the database
+            if id <= self.last_id
+                || self.sim.as_ref().is_some_and(|sim| !msg.matches_sim(sim))
+                || !self.key.allows_imsi(&msg.imsi)
+            {
+                continue;
+            }
+            self.last_id = id;
+            return Some(msg);
+        }
+    }
+}
+
+/// Builds a stream of stored messages for a SIM, or all SIMs if None,
+/// limited to the SIMs the API key is scoped to.
+/// When resuming from a last event id, messages stored after it are
+/// replayed from the database before switching to live delivery. Without
+/// one the stream starts with the next message stored.
+async fn open_message_stream(
+    state: &AppState,
+    key: ApiKey,
+    sim: Option<String>,
+    headers: &HeaderMap,
+    params: StreamQuery,
+) -> Result<impl Stream<Item = SmsMessage> + Send + 'static, Response> {
+    let last_event_id = match headers.get("last-event-id") {
+        Some(value) => match value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse::<i64>().ok())
+        {
+            Some(id) => Some(id),
+            None => {
+                return Err(ApiResponse::<()>::error_with_status(
+                    "Invalid 'Last-Event-ID' header. Expected a message id".to_string(),
+                    StatusCode::BAD_REQUEST,
+                )
+                .into_response());
+            }
+        },
+        None => params.last_event_id,
+    };
+    let database_error = |e: anyhow::Error| {
+        ApiResponse::<()>::error_with_status(
+            format!("Database error: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response()
+    };
+
+    // Subscribe before reading the database so nothing stored in between is missed
+    let receiver = state.messages.subscribe();
+
+    let last_id = match last_event_id {
+        Some(last_id) => last_id,
+        None => state
+            .db
+            .lock()
+            .await
+            .last_message_id()
+            .map_err(database_error)?,
+    };
+    let mut live = LiveMessages {
+        receiver,
+        db: state.db.clone(),
+        key,
+        sim,
+        last_id,
+        stored: VecDeque::new(),
+        behind: last_event_id.is_some(),
+    };
+    // Read the first batch of the backlog up front, so a database error is
+    // answered with a status instead of an empty stream
+    if live.behind {
+        live.read_stored().await.map_err(database_error)?;
+    }
+
+    Ok(stream::unfold(live, |mut live| async move {
+        let msg = live.next().await?;
+        Some((msg, live))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OutboxPolicy;
+    use crate::db::Permission;
+    use crate::modem::mock::{MockModemBackend, SentMessage};
+    use axum::body::Body;
+    use axum::http::Request;
+    use chrono::TimeZone;
+    use tower::ServiceExt;
+
+    const MODEM: &str = "/org/freedesktop/ModemManager1/Modem/0";
+    const IMSI: &str = "310260123456789";
+    const ICCID: &str = "8901260123456789012";
+    const OWN_NUMBER: &str = "+15551234567";
+
+    /// Key with every permission, sent by `send` unless a request has its own
+    const ADMIN_KEY: &str = "samson_admin";
+
+    /// Poll interval and readiness multiplier of the metrics router
+    const POLL_INTERVAL: Duration = Duration::from_secs(30);
+    const READINESS_POLL_INTERVALS: u32 = 3;
+
+    fn metrics_router(
+        db: Arc<Mutex<Database>>,
+        backend: Arc<MockModemBackend>,
+        metrics: Arc<Metrics>,
+    ) -> Router {
+        create_metrics_router(
+            db,
+            backend,
+            metrics,
+            watch::channel(POLL_INTERVAL).1,
+            READINESS_POLL_INTERVALS,
+        )
+    }
+
+    fn setup() -> (Arc<MockModemBackend>, Arc<Mutex<Database>>, Router) {
+        setup_with(OutboxPolicy::default())
+    }
+
+    fn setup_with(policy: OutboxPolicy) -> (Arc<MockModemBackend>, Arc<Mutex<Database>>, Router) {
+        let backend = Arc::new(MockModemBackend::new());
+        backend.add_modem(MODEM, "123456789012345", IMSI, ICCID);
+        backend.set_own_numbers(MODEM, &[OWN_NUMBER]);
+
+        let db = Arc::new(Mutex::new(Database::new(":memory:").unwrap()));
+        add_key(&db, ADMIN_KEY, &[], &[Permission::Admin]);
+        let router = router(&db, &backend, broadcast::channel(16).0, policy);
+
+        (backend, db, router)
+    }
+
+    fn router(
+        db: &Arc<Mutex<Database>>,
+        backend: &Arc<MockModemBackend>,
+        messages: broadcast::Sender<SmsMessage>,
+        policy: OutboxPolicy,
+    ) -> Router {
+        create_router(
+            db.clone(),
+            backend.clone(),
+            messages,
+            Arc::new(UssdService::new(db.clone(), backend.clone())),
+            Arc::new(OutboxSender::new(db.clone(), backend.clone(), policy)),
+            Arc::new(Metrics::new()),
+            chrono::Duration::days(1),
+        )
+    }
+
+    fn outbox(db: &Arc<Mutex<Database>>, backend: &Arc<MockModemBackend>) -> Arc<OutboxSender> {
+        Arc::new(OutboxSender::new(
+            db.clone(),
+            backend.clone(),
+            OutboxPolicy::default(),
+        ))
+    }
+
+    fn add_key(db: &Arc<Mutex<Database>>, key: &str, imsis: &[&str], permissions: &[Permission]) {
+        let imsis: Vec<String> = imsis.iter().map(|i| i.to_string()).collect();
+        db.try_lock()
+            .unwrap()
+            .insert_api_key("test", &auth::hash_key(key), &key[..7], &imsis, permissions)
+            .unwrap();
+    }
+
+    fn get_with_key(uri: &str, key: &str) -> Request<Body> {
+        Request::get(uri)
+            .header("authorization", format!("Bearer {}", key))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn insert_message(db: &Arc<Mutex<Database>>, text: &str, minute: u32) {
+        insert_message_from(db, IMSI, "+1234567890", text, minute).await;
+    }
+
+    async fn insert_message_from(
+        db: &Arc<Mutex<Database>>,
+        imsi: &str,
+        sender: &str,
+        text: &str,
+        minute: u32,
+    ) {
+        let msg = SmsMessage {
+            id: None,
+            imei: "123456789012345".to_string(),
+            imsi: imsi.to_string(),
+            iccid: ICCID.to_string(),
+            operator_id: "310260".to_string(),
+            operator_name: "Mock Mobile".to_string(),
+            own_numbers: vec![OWN_NUMBER.to_string()],
+            sender: sender.to_string(),
+            text: text.to_string(),
+            timestamp: Utc.with_ymd_and_hms(2026, 1, 9, 8, minute, 0).unwrap(),
+            partial: false,
+        };
+        db.lock().await.insert_message(&msg).unwrap();
+    }
+
+    async fn send(router: Router, mut request: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let headers = request.headers_mut();
+        if !headers.contains_key("authorization") && !headers.contains_key(auth::API_KEY_HEADER) {
+            headers.insert(
+                "authorization",
+                format!("Bearer {}", ADMIN_KEY).parse().unwrap(),
+            );
+        }
+
+        let response = router.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn post_json(uri: &str, body: serde_json::Value) -> Request<Body> {
+        Request::post(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn lists_messages_for_imsi() {
+        let (_, db, router) = setup();
+        insert_message(&db, "first", 0).await;
+        insert_message(&db, "second", 5).await;
+
+        let request = Request::get(format!("/messages/{}", IMSI))
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(router, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["success"], true);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0]["text"], "first");
+        assert_eq!(data[1]["text"], "second");
+        assert!(data[0].get("imsi").is_none());
+    }
+
+    #[tokio::test]
+    async fn pages_messages_in_storage_order_by_default() {
+        let (_, db, router) = setup();
+        // Stored out of timestamp order, as a modem delivering a backlog does
+        insert_message(&db, "late", 30).await;
+        insert_message(&db, "early", 10).await;
+        for _ in 0..DEFAULT_PAGE_SIZE {
+            insert_message(&db, "filler", 50).await;
+        }
+
+        let (_, body) = send(
+            router,
+            Request::get(format!("/messages/{}", IMSI))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), DEFAULT_PAGE_SIZE);
+        assert_eq!(data[0]["text"], "late");
+        assert_eq!(data[1]["text"], "early");
+        assert_eq!(body["next_cursor"], data[DEFAULT_PAGE_SIZE - 1]["id"]);
+    }
+
+    #[tokio::test]
+    async fn lists_messages_by_any_sim_identifier() {
+        let (_, db, router) = setup();
+        insert_message(&db, "first", 0).await;
+
+        for id in [ICCID, "%2B15551234567"] {
+            let request = Request::get(format!("/messages/{}", id))
+                .body(Body::empty())
+                .unwrap();
+            let (status, body) = send(router.clone(), request).await;
+
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(
+                body["data"].as_array().unwrap().len(),
+                1,
+                "lookup by {}",
+                id
+            );
+        }
+
+        let request = Request::get("/messages/5551234567")
+            .body(Body::empty())
+            .unwrap();
+        let (_, body) = send(router, request).await;
+        assert!(body["data"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn filters_messages_by_after_timestamp() {
+        let (_, db, router) = setup();
+        insert_message(&db, "first", 0).await;
+        insert_message(&db, "second", 5).await;
+
+        let request = Request::get(format!("/messages/{}?after=2026-01-09T08:01:00Z", IMSI))
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(router, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0]["text"], "second");
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_after_timestamp() {
+        let (_, _, router) = setup();
+
+        let request = Request::get(format!("/messages/{}?after=yesterday", IMSI))
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(router, request).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["success"], false);
+    }
+
+    #[tokio::test]
+    async fn paginates_messages_with_cursor() {
+        let (_, db, router) = setup();
+        for (i, text) in ["first", "second", "third"].into_iter().enumerate() {
+            insert_message(&db, text, i as u32).await;
+        }
+
+        let uri = format!("/messages/{}?limit=2", IMSI);
+        let (status, body) = send(
+            router.clone(),
+            Request::get(&uri).body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"].as_array().unwrap().len(), 2);
+        assert_eq!(body["next_cursor"], 2);
+
+        let uri = format!("/messages/{}?limit=2&after_id=2", IMSI);
+        let (_, body) = send(
+            router.clone(),
+            Request::get(&uri).body(Body::empty()).unwrap(),
+        )
+        .await;
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0]["text"], "third");
+        assert!(body.get("next_cursor").is_none());
+
+        let uri = format!("/messages/{}?limit=2&order=desc", IMSI);
+        let (_, body) = send(router, Request::get(&uri).body(Body::empty()).unwrap()).await;
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data[0]["text"], "third");
+        assert_eq!(data[1]["text"], "second");
+        assert_eq!(body["next_cursor"], 2);
+    }
+
+    #[tokio::test]
+    async fn filters_messages_by_sender_and_before() {
+        let (_, db, router) = setup();
+        insert_message_from(&db, IMSI, "+1111111111", "first", 0).await;
+        insert_message_from(&db, IMSI, "+2222222222", "second", 5).await;
+        insert_message_from(&db, IMSI, "+1111111111", "third", 10).await;
+
+        let uri = format!(
+            "/messages/{}?sender=%2B1111111111&before=2026-01-09T08:06:00Z",
+            IMSI
+        );
+        let (status, body) = send(router, Request::get(&uri).body(Body::empty()).unwrap()).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0]["text"], "first");
+    }
+
+    #[tokio::test]
+    async fn rejects_out_of_range_limit() {
+        let (_, _, router) = setup();
+
+        for limit in ["0", "1001"] {
+            let uri = format!("/messages/{}?limit={}", IMSI, limit);
+            let (status, body) = send(
+                router.clone(),
+                Request::get(&uri).body(Body::empty()).unwrap(),
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["success"], false);
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_all_messages_within_key_scope() {
+        let (_, db, router) = setup();
+        add_key(&db, "samson_scoped", &[IMSI], &[Permission::Read]);
+        insert_message(&db, "mine", 0).await;
+        insert_message_from(&db, "310260999999999", "+1234567890", "other", 5).await;
+
+        let (status, body) = send(
+            router.clone(),
+            Request::get("/messages").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[1]["imsi"], "310260999999999");
+
+        let (_, body) = send(router, get_with_key("/messages", "samson_scoped")).await;
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0]["text"], "mine");
+        assert_eq!(data[0]["imsi"], IMSI);
+    }
+
+    async fn export(router: Router, uri: &str, key: &str) -> (StatusCode, HeaderMap, String) {
+        let response = router.oneshot(get_with_key(uri, key)).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn exports_csv_for_imsi_and_date_range() {
+        let (_, db, router) = setup();
+        add_key(&db, "samson_scoped", &[IMSI], &[Permission::Read]);
+        insert_message(&db, "too early", 0).await;
+        insert_message(&db, "Hello, \"world\"", 5).await;
+        insert_message_from(&db, "310260999999999", "+1234567890", "other SIM", 5).await;
+        insert_message(&db, "too late", 10).await;
+
+        let (status, headers, body) = export(
+            router.clone(),
+            &format!(
+                "/messages/export?format=csv&imsi={}&from=2026-01-09T08:01:00Z&to=2026-01-09T08:09:00Z",
+                IMSI
+            ),
+            ADMIN_KEY,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/csv; charset=utf-8");
+        assert_eq!(
+            headers[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"messages.csv\""
+        );
+        let rows: Vec<&str> = body.lines().collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].starts_with("id,timestamp,imsi,"));
+        assert!(
+            rows[1].ends_with(",\"Hello, \"\"world\"\"\",false"),
+            "{}",
+            rows[1]
+        );
+
+        let (status, _, _) = export(
+            router,
+            "/messages/export?imsi=310260999999999",
+            "samson_scoped",
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn exports_ndjson_across_batches() {
+        let (_, db, router) = setup();
+        let count = crate::export::EXPORT_BATCH_SIZE + 1;
+        for i in 0..count {
+            insert_message(&db, &format!("message {}", i), 0).await;
+        }
+
+        let (status, headers, body) =
+            export(router, "/messages/export?format=ndjson", ADMIN_KEY).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/x-ndjson");
+
+        let texts: Vec<String> = body
+            .lines()
+            .map(|line| {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                record["text"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(texts.len(), count);
+        assert_eq!(texts[count - 1], format!("message {}", count - 1));
+    }
+
+    async fn get_as(router: Router, uri: &str, accept: &str) -> (StatusCode, HeaderMap, String) {
+        let request = Request::get(uri)
+            .header("authorization", format!("Bearer {}", ADMIN_KEY))
+            .header(header::ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn negotiates_message_listing_formats() {
+        let (_, db, router) = setup();
+        for (i, text) in ["one", "two", "three"].into_iter().enumerate() {
+            insert_message(&db, text, i as u32).await;
+        }
+        let uri = format!("/messages/{}", IMSI);
+
+        let (status, headers, body) = get_as(router.clone(), &uri, "text/csv").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/csv; charset=utf-8");
+        assert_eq!(headers[header::VARY], "accept");
+        assert_eq!(body.lines().count(), 4);
+
+        let ndjson = "application/json;q=0.5, application/x-ndjson";
+        let (status, headers, body) =
+            get_as(router.clone(), &format!("{}?limit=2", uri), ndjson).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/x-ndjson");
+        assert_eq!(body.lines().count(), 2);
+        let cursor = headers[NEXT_CURSOR_HEADER].to_str().unwrap().to_string();
+
+        let (_, headers, body) = get_as(
+            router.clone(),
+            &format!("/messages?limit=2&after_id={}", cursor),
+            ndjson,
+        )
+        .await;
+        let record: serde_json::Value = serde_json::from_str(body.trim()).unwrap();
+        assert_eq!(record["text"], "three");
+        assert!(!headers.contains_key(NEXT_CURSOR_HEADER));
+
+        let (status, headers, body) = get_as(router.clone(), &uri, "*/*").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["data"].as_array().unwrap().len(), 3);
+
+        let (status, _, _) = get_as(router.clone(), &uri, "text/html").await;
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+
+        let (status, headers, body) = get_as(router, "/messages/export", "application/mbox").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/mbox");
+        assert_eq!(body.matches("\nX-Samson-IMSI: ").count(), 3);
+    }
+
+    /// Sets up a router whose live streams buffer `capacity` messages
+    fn setup_streaming(
+        capacity: usize,
+    ) -> (Arc<Mutex<Database>>, broadcast::Sender<SmsMessage>, Router) {
+        let (backend, db, _) = setup();
+        let messages = broadcast::channel(capacity).0;
+        let router = router(&db, &backend, messages.clone(), OutboxPolicy::default());
+        (db, messages, router)
+    }
+
+    /// Stores a message and publishes it like the poller does
+    async fn publish(
+        db: &Arc<Mutex<Database>>,
+        messages: &broadcast::Sender<SmsMessage>,
+        text: &str,
+    ) {
+        insert_message(db, text, 0).await;
+        let msg = db
+            .lock()
+            .await
+            .get_messages(&MessageFilter {
+                order: SortOrder::Desc,
+                limit: Some(1),
+                ..Default::default()
+            })
+            .unwrap()
+            .messages
+            .remove(0);
+        assert_eq!(msg.text, text);
+        messages.send(msg).unwrap();
+    }
+
+    /// Reads server-sent events until `count` of them have arrived and
+    /// returns their ids
+    async fn event_ids(body: &mut axum::body::BodyDataStream, count: usize) -> Vec<i64> {
+        let mut ids = Vec::new();
+        let mut buffer = String::new();
+        while ids.len() < count {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.next())
+                .await
+                .expect("stream should deliver the next event")
+                .unwrap()
+                .unwrap();
+            buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+            while let Some(end) = buffer.find("\n\n") {
+                let event: String = buffer.drain(..end + 2).collect();
+                ids.extend(
+                    event
+                        .lines()
+                        .filter_map(|line| line.strip_prefix("id: "))
+                        .map(|id| id.parse::<i64>().unwrap()),
+                );
+            }
+        }
+        ids
+    }
+
+    async fn open_stream(router: Router, request: Request<Body>) -> axum::body::BodyDataStream {
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        response.into_body().into_data_stream()
+    }
+
+    #[tokio::test]
+    async fn replays_backlog_after_last_event_id() {
+        let (db, messages, router) = setup_streaming(16);
+        for text in ["first", "second", "third"] {
+            insert_message(&db, text, 0).await;
+        }
+
+        let mut request = get_with_key(&format!("/messages/{}/stream", IMSI), ADMIN_KEY);
+        request
+            .headers_mut()
+            .insert("last-event-id", "1".parse().unwrap());
+        let mut body = open_stream(router.clone(), request).await;
+        assert_eq!(event_ids(&mut body, 2).await, vec![2, 3]);
+
+        // Live messages follow the backlog without repeating it
+        publish(&db, &messages, "fourth").await;
+        assert_eq!(event_ids(&mut body, 1).await, vec![4]);
+
+        let request = get_with_key("/messages/stream?last_event_id=3", ADMIN_KEY);
+        let mut body = open_stream(router.clone(), request).await;
+        assert_eq!(event_ids(&mut body, 1).await, vec![4]);
+
+        let mut request = get_with_key("/messages/stream", ADMIN_KEY);
+        request
+            .headers_mut()
+            .insert("last-event-id", "latest".parse().unwrap());
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn lagging_stream_catches_up_from_database() {
+        let (db, messages, router) = setup_streaming(2);
+        // Stored before the stream opens, so it is not part of the stream
+        insert_message(&db, "earlier", 0).await;
+        let mut body = open_stream(router, get_with_key("/messages/stream", ADMIN_KEY)).await;
+
+        // Five messages overflow the channel before the client reads any
+        for i in 1..=5 {
+            publish(&db, &messages, &format!("message {}", i)).await;
+        }
+        assert_eq!(event_ids(&mut body, 5).await, vec![2, 3, 4, 5, 6]);
+
+        publish(&db, &messages, "message 6").await;
+        assert_eq!(event_ids(&mut body, 1).await, vec![7]);
+    }
+
+    #[tokio::test]
+    async fn replays_long_backlog_in_batches() {
+        let (db, messages, router) = setup_streaming(16);
+        let count = DEFAULT_PAGE_SIZE * 2 + 5;
+        for i in 0..count {
+            insert_message(&db, &format!("message {}", i), 0).await;
+        }
+
+        let request = get_with_key("/messages/stream?last_event_id=0", ADMIN_KEY);
+        let mut body = open_stream(router, request).await;
+        let expected: Vec<i64> = (1..=count as i64).collect();
+        assert_eq!(event_ids(&mut body, count).await, expected);
+
+        publish(&db, &messages, "live").await;
+        assert_eq!(event_ids(&mut body, 1).await, vec![count as i64 + 1]);
+    }
+
+    #[tokio::test]
+    async fn searches_messages_with_phrases_and_prefixes() {
+        let (_, db, router) = setup();
+        insert_message(&db, "Your code is 4821", 0).await;
+        insert_message(&db, "Code 1234 expires soon", 5).await;
+        insert_message(&db, "Meeting at <5pm>", 10).await;
+
+        let search = |q: &str| {
+            let router = router.clone();
+            let uri = format!("/messages/search?q={}", q);
+            async move {
+                let (status, body) =
+                    send(router, Request::get(&uri).body(Body::empty()).unwrap()).await;
+                assert_eq!(status, StatusCode::OK, "search for {}", uri);
+                body["data"].as_array().unwrap().clone()
+            }
+        };
+
+        let hits = search("4821").await;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0]["snippet"], "Your code is <mark>4821</mark>");
+        assert_eq!(hits[0]["imsi"], IMSI);
+
+        // Newest first
+        let hits = search("code").await;
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0]["text"], "Code 1234 expires soon");
+
+        assert_eq!(search("%22code%20is%22").await.len(), 1);
+        assert_eq!(search("%22is%20code%22").await.len(), 0);
+        assert_eq!(search("exp*").await.len(), 1);
+
+        let hits = search("5pm").await;
+        assert_eq!(hits[0]["snippet"], "Meeting at &lt;<mark>5pm</mark>&gt;");
+
+        // Punctuation and unbalanced quotes are not FTS5 syntax errors
+        assert!(search("%2B%20-%20%22").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_requires_query_and_respects_key_scope() {
+        let (_, db, router) = setup();
+        add_key(&db, "samson_scoped", &[IMSI], &[Permission::Read]);
+        insert_message(&db, "Your code is 4821", 0).await;
+        insert_message_from(&db, "310260999999999", "+1234567890", "Other code 4821", 5).await;
+
+        let (status, body) = send(
+            router.clone(),
+            Request::get("/messages/search?q=%20")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["success"], false);
+
+        let (_, body) = send(
+            router.clone(),
+            get_with_key("/messages/search?q=4821", "samson_scoped"),
+        )
+        .await;
+        let hits = body["data"].as_array().unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0]["text"], "Your code is 4821");
+
+        let request = Request::get("/messages/search?q=4821&sim=310260999999999&limit=1")
+            .body(Body::empty())
+            .unwrap();
+        let (_, body) = send(router, request).await;
+        assert_eq!(body["data"][0]["text"], "Other code 4821");
+        assert!(body.get("next_cursor").is_none());
+    }
+
+    #[tokio::test]
+    async fn sends_message_through_modem_holding_the_sim() {
+        let (backend, db, router) = setup();
+
+        let request = post_json(
+            &format!("/messages/{}", IMSI),
+            serde_json::json!({"number": "+1234567890", "text": "Hello"}),
+        );
+        let (status, body) = send(router, request).await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["data"]["id"], 1);
+        assert_eq!(body["data"]["status"], "queued");
+        assert!(backend.sent().is_empty());
+
+        outbox(&db, &backend).send_due_and_wait().await;
+        assert_eq!(
+            backend.sent(),
+            vec![SentMessage {
+                modem_path: MODEM.to_string(),
+                number: "+1234567890".to_string(),
+                text: "Hello".to_string(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_delivery_status_of_sent_messages() {
+        let (backend, db, router) = setup();
+        backend.add_modem(
+            "/org/freedesktop/ModemManager1/Modem/1",
+            "123456789012399",
+            "310260999999999",
+            "8901260999999999999",
+        );
+        add_key(
+            &db,
+            "samson_other",
+            &["310260999999999"],
+            &[Permission::Read],
+        );
+
+        let request = post_json(
+            &format!("/messages/{}", IMSI),
+            serde_json::json!({"number": "+1234567890", "text": "Hello"}),
+        );
+        let (_, body) = send(router.clone(), request).await;
+        let id = body["data"]["id"].as_i64().unwrap();
+        outbox(&db, &backend).send_due_and_wait().await;
+
+        let (status, body) = send(
+            router.clone(),
+            get_with_key(&format!("/outbox/{}", id), ADMIN_KEY),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["recipient"], "+1234567890");
+        assert_eq!(body["data"]["status"], "sent");
+        assert_eq!(body["data"]["attempts"], 1);
+        assert_eq!(body["data"]["delivery_status"], "pending");
+        assert_eq!(body["data"]["message_reference"], 0);
+        assert!(body["data"].get("sms_path").is_none());
+        // The SMS stays on the modem until its delivery is final
+        assert!(backend.deleted().is_empty());
+
+        for (uri, key) in [
+            (format!("/outbox/{}", id), "samson_other"),
+            ("/outbox/99".to_string(), ADMIN_KEY),
+        ] {
+            let (status, _) = send(router.clone(), get_with_key(&uri, key)).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_ussd_menu_sessions() {
+        let (backend, _, router) = setup();
+        backend.set_ussd_reply("*123#", "1. Data bundles\n2. Balance", true);
+        backend.set_ussd_reply("2", "Your balance is 4.10 EUR", false);
+        let uri = format!("/modems/{}/ussd", OWN_NUMBER.replace('+', "%2B"));
+        let ussd = |body: serde_json::Value| send(router.clone(), post_json(&uri, body));
+
+        let (status, _) = ussd(serde_json::json!({"action": "respond", "response": "1"})).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, body) =
+            ussd(serde_json::json!({"action": "initiate", "command": "*123#"})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["state"], "active");
+        assert_eq!(
+            body["data"]["transcript"][1]["text"],
+            "1. Data bundles\n2. Balance"
+        );
+
+        let (status, _) = ussd(serde_json::json!({"action": "initiate", "command": "*100#"})).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, body) = ussd(serde_json::json!({"action": "respond", "response": "2"})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["state"], "completed");
+        assert_eq!(body["data"]["transcript"].as_array().unwrap().len(), 4);
+
+        // No reply configured, so the network fails the request
+        let (status, _) = ussd(serde_json::json!({"action": "initiate", "command": "*100#"})).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(backend.ussd_sent(), vec!["*123#", "2", "*100#"]);
+
+        let request = Request::get(&uri).body(Body::empty()).unwrap();
+        let (status, body) = send(router.clone(), request).await;
+        assert_eq!(status, StatusCode::OK);
+        let sessions = body["data"].as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0]["state"], "failed");
+        assert_eq!(sessions[1]["command"], "*123#");
+        assert_eq!(sessions[1]["imsi"], IMSI);
+    }
+
+    #[tokio::test]
+    async fn sends_message_by_iccid() {
+        let (backend, db, router) = setup();
+
+        let request = post_json(
+            &format!("/messages/{}", ICCID),
+            serde_json::json!({"number": "+1234567890", "text": "Hello"}),
+        );
+        let (status, _) = send(router, request).await;
+        outbox(&db, &backend).send_due_and_wait().await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(backend.sent().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn send_to_unknown_imsi_returns_not_found() {
+        let (backend, _, router) = setup();
+
+        let request = post_json(
+            "/messages/999999999999999",
+            serde_json::json!({"number": "+1234567890", "text": "Hello"}),
+        );
+        let (status, _) = send(router, request).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(backend.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reports_segments_and_limits_long_messages() {
+        let (_, _, router) = setup();
+        let request = post_json(
+            &format!("/messages/{}", IMSI),
+            serde_json::json!({"number": "+1234567890", "text": "ж".repeat(100)}),
+        );
+        let (_, body) = send(router, request).await;
+        assert_eq!(body["data"]["encoding"], "ucs2");
+        assert_eq!(body["data"]["segments"], 2);
+        assert_eq!(body["data"]["segment_length"], 67);
+        assert!(body["data"].get("split").is_none());
+
+        // 4 parts of GSM-7 text
+        let text = "a".repeat(153 * 3 + 1);
+        let (_, _, router) = setup_with(OutboxPolicy {
+            max_parts: 3,
+            ..Default::default()
+        });
+        let request = post_json(
+            &format!("/messages/{}", IMSI),
+            serde_json::json!({"number": "+1234567890", "text": text}),
+        );
+        let (status, body) = send(router, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["error"],
+            "Text needs 4 SMS parts of 153 gsm7 characters, more than the maximum of 3"
+        );
+
+        let (_, db, router) = setup_with(OutboxPolicy {
+            max_parts: 3,
+            long_messages: LongMessages::Split,
+            ..Default::default()
+        });
+        let request = post_json(
+            &format!("/messages/{}", IMSI),
+            serde_json::json!({"number": "+1234567890", "text": text}),
+        );
+        let (status, body) = send(router, request).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["data"]["segments"], 4);
+        assert_eq!(body["data"]["split"][0]["id"], 1);
+        assert_eq!(body["data"]["split"][1]["id"], 2);
+        let db = db.lock().await;
+        let first = db.get_outgoing_message(1).unwrap().unwrap();
+        let second = db.get_outgoing_message(2).unwrap().unwrap();
+        assert_eq!((first.segments, second.segments), (Some(3), Some(1)));
+        assert_eq!(first.text + &second.text, text);
+    }
+
+    #[tokio::test]
+    async fn reports_encoding_of_each_split_message() {
+        let (_, db, router) = setup_with(OutboxPolicy {
+            max_parts: 2,
+            long_messages: LongMessages::Split,
+            ..Default::default()
+        });
+        // 4 UCS-2 parts as a whole, but the second half is plain GSM-7
+        let text = format!("{}{}", "ж".repeat(67 * 2), "a".repeat(67 * 2));
+        let request = post_json(
+            &format!("/messages/{}", IMSI),
+            serde_json::json!({"number": "+1234567890", "text": text}),
+        );
+        let (status, body) = send(router, request).await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["data"]["segments"], 3);
+        assert_eq!(body["data"]["encoding"], "ucs2");
+        assert_eq!(body["data"]["segment_length"], 67);
+        assert_eq!(
+            body["data"]["split"],
+            serde_json::json!([
+                {"id": 1, "encoding": "ucs2", "segments": 2, "segment_length": 67},
+                {"id": 2, "encoding": "gsm7", "segments": 1, "segment_length": 160},
+            ])
+        );
+        let db = db.lock().await;
+        let second = db.get_outgoing_message(2).unwrap().unwrap();
+        assert_eq!(
+            (second.encoding, second.segments),
+            (Some(Encoding::Gsm7), Some(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_send_is_queued_for_retry() {
+        let (backend, db, router) = setup();
+        backend.fail_sends(true);
+
+        let request = post_json(
+            &format!("/messages/{}", IMSI),
+            serde_json::json!({"number": "+1234567890", "text": "Hello"}),
+        );
+        let (status, body) = send(router.clone(), request).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let id = body["data"]["id"].as_i64().unwrap();
+        outbox(&db, &backend).send_due_and_wait().await;
+
+        let (_, body) = send(router, get_with_key(&format!("/outbox/{}", id), ADMIN_KEY)).await;
+        assert_eq!(body["data"]["status"], "queued");
+        assert_eq!(body["data"]["attempts"], 1);
+        assert!(
+            body["data"]["error"]
+                .as_str()
+                .unwrap()
+                .contains("Mock failure")
+        );
+        assert!(body["data"]["next_attempt_at"].is_string());
+    }
+
+    #[tokio::test]
+    async fn schedules_and_cancels_queued_messages() {
+        let (backend, db, router) = setup();
+        add_key(
+            &db,
+            "samson_other",
+            &["310260999999999"],
+            &[Permission::Send],
+        );
+
+        let request = post_json(
+            &format!("/messages/{}", IMSI),
+            serde_json::json!({"number": "+1234567890", "text": "Hi", "send_at": "yesterday"}),
+        );
+        let (status, _) = send(router.clone(), request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let request = post_json(
+            &format!("/messages/{}", IMSI),
+            serde_json::json!({
+                "number": "+1234567890",
+                "text": "Happy new year",
+                "send_at": "2099-01-01T00:00:00Z"
+            }),
+        );
+        let (status, body) = send(router.clone(), request).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["data"]["send_at"], "2099-01-01T00:00:00Z");
+        let uri = format!("/outbox/{}", body["data"]["id"]);
+
+        outbox(&db, &backend).send_due_and_wait().await;
+        assert!(backend.sent().is_empty());
+
+        let delete = |key: &str| {
+            Request::delete(&uri)
+                .header(auth::API_KEY_HEADER, key)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let (status, _) = send(router.clone(), delete("samson_other")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = send(router.clone(), delete(ADMIN_KEY)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["status"], "cancelled");
+
+        let (status, _) = send(router, delete(ADMIN_KEY)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn replays_requests_with_the_same_idempotency_key() {
+        let (backend, db, router) = setup();
+        add_key(&db, "samson_other", &[], &[Permission::Send]);
+        let request = |key: &str, idempotency_key: &str, text: &str| {
+            Request::post(format!("/messages/{}", IMSI))
+                .header(auth::API_KEY_HEADER, key)
+                .header(idempotency::IDEMPOTENCY_KEY_HEADER, idempotency_key)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({"number": "+1234567890", "text": text}).to_string(),
+                ))
+                .unwrap()
+        };
+
+        let first = router
+            .clone()
+            .oneshot(request(ADMIN_KEY, "order-17", "Hello"))
+            .await
+            .unwrap();
+        assert_eq!(first.status(), StatusCode::ACCEPTED);
+        assert!(first.headers().get(idempotency::REPLAYED_HEADER).is_none());
+
+        let retry = router
+            .clone()
+            .oneshot(request(ADMIN_KEY, "order-17", "Hello"))
+            .await
+            .unwrap();
+        assert_eq!(retry.status(), StatusCode::ACCEPTED);
+        assert_eq!(retry.headers()[idempotency::REPLAYED_HEADER], "true");
+        let body = axum::body::to_bytes(retry.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["data"]["id"], 1);
+
+        let (status, body) = send(router.clone(), request(ADMIN_KEY, "order-17", "Bye")).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            body["error"],
+            "Idempotency-Key was already used for a different request"
+        );
+
+        // Keys are scoped to the API key
+        let (status, body) = send(router, request("samson_other", "order-17", "Hello")).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["data"]["id"], 2);
+
+        // One message at a time from the SIM
+        let outbox = outbox(&db, &backend);
+        outbox.send_due_and_wait().await;
+        outbox.send_due_and_wait().await;
+        assert_eq!(backend.sent().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_and_invalid_keys() {
+        let (_, _, router) = setup();
+
+        let request = Request::get(format!("/messages/{}", IMSI))
+            .header(auth::API_KEY_HEADER, "samson_wrong")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(router.clone(), request).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["success"], false);
+        assert_eq!(body["error"], "Invalid API key");
+
+        let response = router
+            .oneshot(
+                Request::get(format!("/messages/{}", IMSI))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn read_key_cannot_send() {
+        let (backend, db, router) = setup();
+        add_key(&db, "samson_reader", &[], &[Permission::Read]);
+
+        let (status, _) = send(
+            router.clone(),
+            get_with_key(&format!("/messages/{}", IMSI), "samson_reader"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let mut request = post_json(
+            &format!("/messages/{}", IMSI),
+            serde_json::json!({"number": "+1234567890", "text": "Hello"}),
+        );
+        request
+            .headers_mut()
+            .insert("authorization", "Bearer samson_reader".parse().unwrap());
+        let (status, body) = send(router, request).await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"], "API key lacks the 'send' permission");
+        assert!(backend.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn scoped_key_only_reaches_its_sims() {
+        let (_, db, router) = setup();
+        add_key(&db, "samson_scoped", &[IMSI], &[Permission::Read]);
+        add_key(
+            &db,
+            "samson_other",
+            &["310260999999999"],
+            &[Permission::Read],
+        );
+        insert_message(&db, "first", 0).await;
+
+        // The ICCID resolves to the scoped IMSI through the connected modem
+        for sim in [IMSI, ICCID] {
+            let (status, body) = send(
+                router.clone(),
+                get_with_key(&format!("/messages/{}", sim), "samson_scoped"),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["data"].as_array().unwrap().len(), 1);
+        }
+
+        let (status, _) = send(
+            router,
+            get_with_key(&format!("/messages/{}", IMSI), "samson_other"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn revoked_key_is_rejected() {
+        let (_, db, router) = setup();
+        add_key(&db, "samson_revoked", &[], &[Permission::Read]);
+        let id = db.lock().await.list_api_keys().unwrap().last().unwrap().id;
+        assert!(db.lock().await.revoke_api_key(id).unwrap());
+
+        let (status, _) = send(
+            router,
+            get_with_key(&format!("/messages/{}", IMSI), "samson_revoked"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn metrics_router_requires_metrics_permission_except_health() {
+        let (backend, db, _) = setup();
+        add_key(&db, "samson_reader", &[], &[Permission::Read]);
+        add_key(&db, "samson_scraper", &[], &[Permission::Metrics]);
+        add_key(
+            &db,
+            "samson_other",
+            &["999990000000000"],
+            &[Permission::Metrics],
+        );
+        let router = metrics_router(db, backend, Arc::new(Metrics::new()));
+
+        let response = router
+            .clone()
+            .oneshot(Request::get("/health").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let (status, _) = send(router.clone(), get_with_key("/modems", "samson_reader")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let response = router
+            .clone()
+            .oneshot(get_with_key("/metrics", "samson_scraper"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let (_, body) = send(router.clone(), get_with_key("/modems", "samson_scraper")).await;
+        assert_eq!(body["data"][0]["imsi"], IMSI);
+        // Keys scoped to other SIMs do not see this modem
+        let (_, body) = send(router.clone(), get_with_key("/modems", "samson_other")).await;
+        assert_eq!(body["data"], serde_json::json!([]));
+
+        let (status, body) =
+            send(router, Request::get("/modems").body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"][0]["imsi"], IMSI);
+    }
+
+    #[tokio::test]
+    async fn readiness_reports_each_component() {
+        let (backend, db, _) = setup();
+        let metrics = Arc::new(Metrics::new());
+        let router = metrics_router(db, backend.clone(), metrics.clone());
+        let ready = || Request::get("/health/ready").body(Body::empty()).unwrap();
+
+        let (status, body) = send(router.clone(), ready()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["success"], false);
+        assert_eq!(body["data"]["database"]["ok"], true);
+        assert_eq!(body["data"]["dbus"]["ok"], true);
+        assert_eq!(body["data"]["poller"]["error"], "No poll has started yet");
+
+        metrics.poller_heartbeat.set(Utc::now().timestamp());
+        let (status, body) = send(router.clone(), ready()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["poller"]["ok"], true);
+
+        backend.set_unreachable(true);
+        let (status, body) = send(router.clone(), ready()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["error"], "Unhealthy components: dbus");
+        assert_eq!(body["data"]["dbus"]["ok"], false);
+
+        // Liveness does not depend on the components
+        let (status, _) = send(
+            router,
+            Request::get("/health/live").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn readiness_fails_when_poller_is_stale() {
+        let (backend, db, _) = setup();
+        let metrics = Arc::new(Metrics::new());
+        let router = metrics_router(db, backend, metrics.clone());
+
+        metrics
+            .poller_heartbeat
+            .set(Utc::now().timestamp() - 3 * POLL_INTERVAL.as_secs() as i64 - 10);
+        let (status, body) = send(
+            router,
+            Request::get("/health/ready").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["data"]["poller"]["ok"], false);
+        assert_eq!(body["error"], "Unhealthy components: poller");
+    }
+
+    #[tokio::test]
+    async fn metrics_report_requests_by_route() {
+        let (backend, db, _) = setup();
+        let metrics = Arc::new(Metrics::new());
+        let router = create_router(
+            db.clone(),
+            backend.clone(),
+            broadcast::channel(16).0,
+            Arc::new(UssdService::new(db.clone(), backend.clone())),
+            outbox(&db, &backend),
+            metrics.clone(),
+            chrono::Duration::days(1),
+        );
+        let metrics_router = metrics_router(db, backend, metrics);
+
+        let request = Request::get(format!("/messages/{}", IMSI))
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(router, request).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let response = metrics_router
+            .oneshot(
+                Request::get("/metrics")
+                    .header("authorization", format!("Bearer {}", ADMIN_KEY))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(
+            body.contains(
+                r#"http_requests_total{method="GET",route="/messages/:sim",status="200"} 1"#
+            ),
+            "{}",
+            body
+        );
+        assert!(body.contains("# TYPE modem_count gauge"));
+    }
+}
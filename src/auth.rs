@@ -0,0 +1,199 @@
+use crate::api::ApiResponse;
+use crate::db::{ApiKey, Database, Permission};
+use crate::modem::ModemBackend;
+use anyhow::{Context, Result};
+use axum::{
+    extract::{RawPathParams, Request, State},
+    http::{HeaderMap, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{debug, error};
+
+/// Prefix of every generated key, to make leaked keys easy to recognise
+const KEY_PREFIX: &str = "samson_";
+
+/// Number of leading key characters stored in clear to tell keys apart
+const DISPLAY_PREFIX_LEN: usize = 12;
+
+/// Header accepted as an alternative to `Authorization: Bearer <key>`
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+/// A newly generated key; `key` is shown once and never stored
+pub struct GeneratedKey {
+    pub key: String,
+    pub hash: String,
+    pub prefix: String,
+}
+
+pub fn generate_key() -> Result<GeneratedKey> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).context("Failed to generate random API key")?;
+
+    let key = format!("{}{}", KEY_PREFIX, hex::encode(bytes));
+    Ok(GeneratedKey {
+        hash: hash_key(&key),
+        prefix: key[..DISPLAY_PREFIX_LEN].to_string(),
+        key,
+    })
+}
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+#[derive(Clone)]
+pub struct AuthState {
+    pub db: Arc<Mutex<Database>>,
+    pub modem_manager: Arc<dyn ModemBackend>,
+}
+
+/// Guards the message endpoints: reading needs the `read` permission,
+/// anything else `send`. A `sim` path parameter must resolve to an IMSI the
+/// key is scoped to. The key is passed on as a request extension.
+pub async fn require_message_access(
+    State(auth): State<AuthState>,
+    params: RawPathParams,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let permission = match *request.method() {
+        Method::GET | Method::HEAD => Permission::Read,
+        _ => Permission::Send,
+    };
+
+    let key = match authenticate(&auth, request.headers(), permission).await {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+
+    if let Some((_, sim)) = params.iter().find(|(name, _)| *name == "sim")
+        && !sim_in_scope(&auth, &key, sim).await
+    {
+        return forbidden(format!("API key is not allowed to access SIM {}", sim));
+    }
+
+    request.extensions_mut().insert(key);
+    next.run(request).await
+}
+
+/// Guards the monitoring endpoints, modem listing and metrics, which need
+/// the `metrics` permission
+pub async fn require_metrics_access(
+    State(auth): State<AuthState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    match authenticate(&auth, request.headers(), Permission::Metrics).await {
+        Ok(key) => {
+            request.extensions_mut().insert(key);
+            next.run(request).await
+        }
+        Err(response) => response,
+    }
+}
+
+async fn authenticate(
+    auth: &AuthState,
+    headers: &HeaderMap,
+    permission: Permission,
+) -> Result<ApiKey, Response> {
+    let Some(presented) = presented_key(headers) else {
+        return Err(unauthorized("Missing API key"));
+    };
+
+    let key = {
+        let db = auth.db.lock().await;
+        db.authenticate_api_key(&hash_key(presented))
+    };
+
+    let key = match key {
+        Ok(Some(key)) => key,
+        Ok(None) => return Err(unauthorized("Invalid API key")),
+        Err(e) => {
+            error!(error = %e, "Failed to look up API key");
+            return Err(ApiResponse::<()>::error_with_status(
+                "Failed to authenticate API key".to_string(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response());
+        }
+    };
+
+    if !key.has_permission(permission) {
+        debug!(
+            key = key.id,
+            permission = permission.as_str(),
+            "API key lacks permission"
+        );
+        return Err(forbidden(format!(
+            "API key lacks the '{}' permission",
+            permission.as_str()
+        )));
+    }
+
+    Ok(key)
+}
+
+/// Reads the key from `Authorization: Bearer <key>` or `X-API-Key`
+fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(value) = headers.get(header::AUTHORIZATION) {
+        return value
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")
+            .map(str::trim)
+            .filter(|k| !k.is_empty());
+    }
+
+    headers
+        .get(API_KEY_HEADER)?
+        .to_str()
+        .ok()
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+}
+
+/// Whether a SIM given by IMSI, ICCID or own number belongs to one of the
+/// IMSIs the key is scoped to, judged by the connected modems and the
+/// messages stored for it
+async fn sim_in_scope(auth: &AuthState, key: &ApiKey, sim: &str) -> bool {
+    if key.allows_imsi(sim) {
+        return true;
+    }
+
+    if let Ok(modems) = auth.modem_manager.get_modems().await
+        && modems
+            .iter()
+            .any(|m| m.matches_sim(sim) && key.allows_imsi(&m.imsi))
+    {
+        return true;
+    }
+
+    let db = auth.db.lock().await;
+    match db.get_sim_imsis(sim) {
+        Ok(imsis) => imsis.iter().any(|imsi| key.allows_imsi(imsi)),
+        Err(e) => {
+            error!(error = %e, "Failed to resolve SIM for API key scope");
+            false
+        }
+    }
+}
+
+fn unauthorized(error: &str) -> Response {
+    let mut response =
+        ApiResponse::<()>::error_with_status(error.to_string(), StatusCode::UNAUTHORIZED)
+            .into_response();
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        header::HeaderValue::from_static("Bearer"),
+    );
+    response
+}
+
+fn forbidden(error: String) -> Response {
+    ApiResponse::<()>::error_with_status(error, StatusCode::FORBIDDEN).into_response()
+}
@@ -0,0 +1,810 @@
+//! A fake ModemManager for running samson end to end without modem hardware.
+//!
+//! Serves `org.freedesktop.ModemManager1` with the ObjectManager, Modem,
+//! Modem.Messaging, Modem.Modem3gpp.Ussd, Sms and Sim interfaces on a D-Bus
+//! bus, and exposes a small HTTP control API to add modems, inject SMS and
+//! report the delivery of sent SMS.
+
+use anyhow::{Context, Result};
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tracing::info;
+use zbus::object_server::SignalContext;
+use zbus::zvariant::{ObjectPath, OwnedObjectPath, OwnedValue};
+use zbus::{Connection, ObjectServer, fdo, interface};
+
+const SERVICE: &str = "org.freedesktop.ModemManager1";
+const ROOT_PATH: &str = "/org/freedesktop/ModemManager1";
+
+const SMS_STATE_STORED: u32 = 1;
+const SMS_STATE_RECEIVING: u32 = 2;
+const SMS_STATE_RECEIVED: u32 = 3;
+const SMS_STATE_SENT: u32 = 5;
+
+const USSD_STATE_IDLE: u32 = 1;
+const USSD_STATE_USER_RESPONSE: u32 = 3;
+
+/// Menu shown for `*123#` and after an invalid choice
+const USSD_MENU: &str = "1. Balance\n2. Data bundles\n0. Exit";
+
+const SMS_PDU_TYPE_DELIVER: u32 = 1;
+const SMS_PDU_TYPE_SUBMIT: u32 = 2;
+const SMS_PDU_TYPE_STATUS_REPORT: u32 = 3;
+
+const SMS_DELIVERY_STATE_UNKNOWN: u32 = 0x100;
+
+#[derive(Clone, Serialize)]
+struct SentSms {
+    modem: String,
+    path: String,
+    number: String,
+    text: String,
+    message_reference: u32,
+    delivery_report_request: bool,
+}
+
+struct FakeModem {
+    sim_path: OwnedObjectPath,
+    messages: Vec<OwnedObjectPath>,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_modem: u32,
+    next_sms: u32,
+    modems: BTreeMap<u32, FakeModem>,
+    sent: Vec<SentSms>,
+    next_reference: u32,
+}
+
+impl Registry {
+    fn next_sms_path(&mut self) -> OwnedObjectPath {
+        let path = format!("{}/SMS/{}", ROOT_PATH, self.next_sms);
+        self.next_sms += 1;
+        OwnedObjectPath::try_from(path).expect("valid object path")
+    }
+}
+
+type SharedRegistry = Arc<Mutex<Registry>>;
+
+struct Modem {
+    equipment_identifier: String,
+    sim: OwnedObjectPath,
+    own_numbers: Vec<String>,
+}
+
+#[interface(name = "org.freedesktop.ModemManager1.Modem")]
+impl Modem {
+    #[zbus(property)]
+    fn equipment_identifier(&self) -> String {
+        self.equipment_identifier.clone()
+    }
+
+    #[zbus(property)]
+    fn sim(&self) -> OwnedObjectPath {
+        self.sim.clone()
+    }
+
+    #[zbus(property)]
+    fn own_numbers(&self) -> Vec<String> {
+        self.own_numbers.clone()
+    }
+}
+
+struct Sim {
+    imsi: String,
+    sim_identifier: String,
+    operator_identifier: String,
+    operator_name: String,
+}
+
+#[interface(name = "org.freedesktop.ModemManager1.Sim")]
+impl Sim {
+    #[zbus(property)]
+    fn imsi(&self) -> String {
+        self.imsi.clone()
+    }
+
+    #[zbus(property)]
+    fn sim_identifier(&self) -> String {
+        self.sim_identifier.clone()
+    }
+
+    #[zbus(property)]
+    fn operator_identifier(&self) -> String {
+        self.operator_identifier.clone()
+    }
+
+    #[zbus(property)]
+    fn operator_name(&self) -> String {
+        self.operator_name.clone()
+    }
+}
+
+/// Answers USSD like a carrier: `*100#` with the balance, and `*123#` with
+/// a menu that waits for a choice
+struct Ussd {
+    state: u32,
+}
+
+impl Ussd {
+    async fn reply(
+        &mut self,
+        ctxt: &SignalContext<'_>,
+        text: &str,
+        state: u32,
+    ) -> fdo::Result<String> {
+        self.state = state;
+        self.state_changed(ctxt).await?;
+        Ok(text.to_string())
+    }
+}
+
+#[interface(name = "org.freedesktop.ModemManager1.Modem.Modem3gpp.Ussd")]
+impl Ussd {
+    async fn initiate(
+        &mut self,
+        command: String,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<String> {
+        if self.state == USSD_STATE_USER_RESPONSE {
+            return Err(fdo::Error::Failed(
+                "A USSD session is already active".to_string(),
+            ));
+        }
+
+        match command.as_str() {
+            "*100#" => {
+                self.reply(&ctxt, "Your balance is 12.50 EUR", USSD_STATE_IDLE)
+                    .await
+            }
+            "*123#" => self.reply(&ctxt, USSD_MENU, USSD_STATE_USER_RESPONSE).await,
+            _ => Err(fdo::Error::Failed(format!("Unknown USSD code {}", command))),
+        }
+    }
+
+    async fn respond(
+        &mut self,
+        response: String,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<String> {
+        if self.state != USSD_STATE_USER_RESPONSE {
+            return Err(fdo::Error::Failed(
+                "No USSD session is waiting for a response".to_string(),
+            ));
+        }
+
+        match response.as_str() {
+            "1" => {
+                self.reply(&ctxt, "Your balance is 12.50 EUR", USSD_STATE_IDLE)
+                    .await
+            }
+            "2" => {
+                self.reply(&ctxt, "You have 1.5 GB of data left", USSD_STATE_IDLE)
+                    .await
+            }
+            "0" => self.reply(&ctxt, "Goodbye", USSD_STATE_IDLE).await,
+            _ => {
+                let menu = format!("Invalid choice\n{}", USSD_MENU);
+                self.reply(&ctxt, &menu, USSD_STATE_USER_RESPONSE).await
+            }
+        }
+    }
+
+    async fn cancel(&mut self, #[zbus(signal_context)] ctxt: SignalContext<'_>) -> fdo::Result<()> {
+        self.state = USSD_STATE_IDLE;
+        self.state_changed(&ctxt).await?;
+        Ok(())
+    }
+
+    #[zbus(property)]
+    fn state(&self) -> u32 {
+        self.state
+    }
+}
+
+struct Messaging {
+    index: u32,
+    registry: SharedRegistry,
+}
+
+#[interface(name = "org.freedesktop.ModemManager1.Modem.Messaging")]
+impl Messaging {
+    fn list(&self) -> Vec<OwnedObjectPath> {
+        let registry = self.registry.lock().unwrap();
+        registry
+            .modems
+            .get(&self.index)
+            .map(|modem| modem.messages.clone())
+            .unwrap_or_default()
+    }
+
+    async fn delete(
+        &self,
+        path: ObjectPath<'_>,
+        #[zbus(object_server)] server: &ObjectServer,
+    ) -> fdo::Result<()> {
+        {
+            let mut registry = self.registry.lock().unwrap();
+            let modem = registry
+                .modems
+                .get_mut(&self.index)
+                .ok_or_else(|| fdo::Error::UnknownObject("Modem is gone".to_string()))?;
+            let before = modem.messages.len();
+            modem.messages.retain(|p| p.as_str() != path.as_str());
+            if modem.messages.len() == before {
+                return Err(fdo::Error::InvalidArgs(format!("No SMS at {}", path)));
+            }
+        }
+
+        server.remove::<Sms, _>(&path).await?;
+        Ok(())
+    }
+
+    async fn create(
+        &self,
+        properties: HashMap<String, OwnedValue>,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<OwnedObjectPath> {
+        let number = string_property(&properties, "number")?;
+        let text = string_property(&properties, "text")?;
+        let delivery_report_request = match properties.get("delivery-report-request") {
+            Some(value) => value.downcast_ref().map_err(|_| {
+                fdo::Error::InvalidArgs("'delivery-report-request' must be a boolean".to_string())
+            })?,
+            None => false,
+        };
+
+        let sms = Sms {
+            delivery_report_request,
+            ..Sms::new(
+                number,
+                text,
+                SMS_PDU_TYPE_SUBMIT,
+                SMS_STATE_STORED,
+                self.index,
+                &self.registry,
+            )
+        };
+
+        let path = add_sms(&self.registry, server, self.index, sms).await?;
+        Self::added(&ctxt, path.as_ref(), false).await?;
+        Ok(path)
+    }
+
+    #[zbus(signal)]
+    async fn added(
+        ctxt: &SignalContext<'_>,
+        path: ObjectPath<'_>,
+        received: bool,
+    ) -> zbus::Result<()>;
+}
+
+struct Sms {
+    number: String,
+    text: String,
+    timestamp: String,
+    pdu_type: u32,
+    state: u32,
+    message_reference: u32,
+    delivery_report_request: bool,
+    delivery_state: u32,
+    discharge_timestamp: String,
+    modem_index: u32,
+    registry: SharedRegistry,
+}
+
+impl Sms {
+    fn new(
+        number: String,
+        text: String,
+        pdu_type: u32,
+        state: u32,
+        modem_index: u32,
+        registry: &SharedRegistry,
+    ) -> Self {
+        Self {
+            number,
+            text,
+            timestamp: String::new(),
+            pdu_type,
+            state,
+            message_reference: 0,
+            delivery_report_request: false,
+            delivery_state: SMS_DELIVERY_STATE_UNKNOWN,
+            discharge_timestamp: String::new(),
+            modem_index,
+            registry: registry.clone(),
+        }
+    }
+}
+
+#[interface(name = "org.freedesktop.ModemManager1.Sms")]
+impl Sms {
+    async fn send(
+        &mut self,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        if self.pdu_type != SMS_PDU_TYPE_SUBMIT {
+            return Err(fdo::Error::Failed(
+                "Only locally created SMS can be sent".to_string(),
+            ));
+        }
+
+        {
+            let mut registry = self.registry.lock().unwrap();
+            self.message_reference = registry.next_reference;
+            registry.next_reference = (registry.next_reference + 1) % 256;
+            registry.sent.push(SentSms {
+                modem: format!("{}/Modem/{}", ROOT_PATH, self.modem_index),
+                path: header.path().map(|p| p.to_string()).unwrap_or_default(),
+                number: self.number.clone(),
+                text: self.text.clone(),
+                message_reference: self.message_reference,
+                delivery_report_request: self.delivery_report_request,
+            });
+        }
+
+        self.state = SMS_STATE_SENT;
+        self.state_changed(&ctxt).await?;
+        self.message_reference_changed(&ctxt).await?;
+        Ok(())
+    }
+
+    #[zbus(property)]
+    fn number(&self) -> String {
+        self.number.clone()
+    }
+
+    #[zbus(property)]
+    fn text(&self) -> String {
+        self.text.clone()
+    }
+
+    #[zbus(property)]
+    fn timestamp(&self) -> String {
+        self.timestamp.clone()
+    }
+
+    #[zbus(property)]
+    fn pdu_type(&self) -> u32 {
+        self.pdu_type
+    }
+
+    #[zbus(property)]
+    fn state(&self) -> u32 {
+        self.state
+    }
+
+    #[zbus(property)]
+    fn message_reference(&self) -> u32 {
+        self.message_reference
+    }
+
+    #[zbus(property)]
+    fn delivery_report_request(&self) -> bool {
+        self.delivery_report_request
+    }
+
+    #[zbus(property)]
+    fn delivery_state(&self) -> u32 {
+        self.delivery_state
+    }
+
+    #[zbus(property)]
+    fn discharge_timestamp(&self) -> String {
+        self.discharge_timestamp.clone()
+    }
+}
+
+fn string_property(properties: &HashMap<String, OwnedValue>, key: &str) -> fdo::Result<String> {
+    let value = properties
+        .get(key)
+        .ok_or_else(|| fdo::Error::InvalidArgs(format!("Missing '{}' property", key)))?;
+    let value: &str = value
+        .downcast_ref()
+        .map_err(|_| fdo::Error::InvalidArgs(format!("'{}' must be a string", key)))?;
+    Ok(value.to_string())
+}
+
+/// Exports an SMS object and adds it to a modem's message list
+async fn add_sms(
+    registry: &SharedRegistry,
+    server: &ObjectServer,
+    modem_index: u32,
+    sms: Sms,
+) -> zbus::Result<OwnedObjectPath> {
+    let path = {
+        let mut registry = registry.lock().unwrap();
+        if !registry.modems.contains_key(&modem_index) {
+            return Err(fdo::Error::UnknownObject("Modem is gone".to_string()).into());
+        }
+        registry.next_sms_path()
+    };
+
+    server.at(&path, sms).await?;
+
+    if let Some(modem) = registry.lock().unwrap().modems.get_mut(&modem_index) {
+        modem.messages.push(path.clone());
+    }
+
+    Ok(path)
+}
+
+#[derive(Clone)]
+struct ControlState {
+    conn: Connection,
+    registry: SharedRegistry,
+}
+
+#[derive(Deserialize)]
+struct AddModemRequest {
+    imei: String,
+    imsi: String,
+    iccid: String,
+    /// Defaults to the MCC and MNC at the start of the IMSI
+    operator_id: Option<String>,
+    #[serde(default)]
+    operator_name: String,
+    #[serde(default)]
+    own_numbers: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct AddModemResponse {
+    index: u32,
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct InjectSmsRequest {
+    number: String,
+    text: String,
+    /// Defaults to the current time
+    timestamp: Option<String>,
+    /// Simulates a multipart SMS with parts still missing
+    #[serde(default)]
+    receiving: bool,
+}
+
+#[derive(Deserialize)]
+struct CompleteSmsRequest {
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct DeliveryReportRequest {
+    /// TP-Status, 0 for delivered
+    #[serde(default)]
+    delivery_state: u32,
+    #[serde(default)]
+    separate: bool,
+}
+
+#[derive(Serialize)]
+struct InjectSmsResponse {
+    path: String,
+}
+
+fn error(status: StatusCode, e: impl std::fmt::Display) -> Response {
+    (status, e.to_string()).into_response()
+}
+
+async fn add_modem(
+    State(state): State<ControlState>,
+    Json(request): Json<AddModemRequest>,
+) -> Response {
+    let index = {
+        let mut registry = state.registry.lock().unwrap();
+        let index = registry.next_modem;
+        registry.next_modem += 1;
+        index
+    };
+
+    let modem_path = format!("{}/Modem/{}", ROOT_PATH, index);
+    let sim_path = OwnedObjectPath::try_from(format!("{}/SIM/{}", ROOT_PATH, index))
+        .expect("valid object path");
+
+    state.registry.lock().unwrap().modems.insert(
+        index,
+        FakeModem {
+            sim_path: sim_path.clone(),
+            messages: Vec::new(),
+        },
+    );
+
+    let server = state.conn.object_server();
+    let result = async {
+        server
+            .at(
+                &sim_path,
+                Sim {
+                    imsi: request.imsi.clone(),
+                    sim_identifier: request.iccid.clone(),
+                    operator_identifier: request
+                        .operator_id
+                        .clone()
+                        .unwrap_or_else(|| request.imsi.chars().take(6).collect()),
+                    operator_name: request.operator_name.clone(),
+                },
+            )
+            .await?;
+        server
+            .at(
+                modem_path.as_str(),
+                Messaging {
+                    index,
+                    registry: state.registry.clone(),
+                },
+            )
+            .await?;
+        server
+            .at(
+                modem_path.as_str(),
+                Ussd {
+                    state: USSD_STATE_IDLE,
+                },
+            )
+            .await?;
+        // Added last, since samson reacts to the Modem interface appearing
+        server
+            .at(
+                modem_path.as_str(),
+                Modem {
+                    equipment_identifier: request.imei.clone(),
+                    sim: sim_path.clone(),
+                    own_numbers: request.own_numbers.clone(),
+                },
+            )
+            .await
+    }
+    .await;
+
+    if let Err(e) = result {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, e);
+    }
+
+    info!(path = %modem_path, imei = %request.imei, imsi = %request.imsi, iccid = %request.iccid, "Added modem");
+    (
+        StatusCode::CREATED,
+        Json(AddModemResponse {
+            index,
+            path: modem_path,
+        }),
+    )
+        .into_response()
+}
+
+async fn remove_modem(State(state): State<ControlState>, Path(index): Path<u32>) -> Response {
+    let Some(modem) = state.registry.lock().unwrap().modems.remove(&index) else {
+        return error(StatusCode::NOT_FOUND, "No such modem");
+    };
+
+    let server = state.conn.object_server();
+    let modem_path = format!("{}/Modem/{}", ROOT_PATH, index);
+    let result = async {
+        server.remove::<Modem, _>(modem_path.as_str()).await?;
+        server.remove::<Messaging, _>(modem_path.as_str()).await?;
+        server.remove::<Ussd, _>(modem_path.as_str()).await?;
+        server.remove::<Sim, _>(&modem.sim_path).await?;
+        for path in &modem.messages {
+            server.remove::<Sms, _>(path).await?;
+        }
+        zbus::Result::Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => {
+            info!(path = %modem_path, "Removed modem");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+async fn inject_sms(
+    State(state): State<ControlState>,
+    Path(index): Path<u32>,
+    Json(request): Json<InjectSmsRequest>,
+) -> Response {
+    let state_value = if request.receiving {
+        SMS_STATE_RECEIVING
+    } else {
+        SMS_STATE_RECEIVED
+    };
+    let sms = Sms {
+        timestamp: request
+            .timestamp
+            .unwrap_or_else(|| chrono::Utc::now().to_rfc3339()),
+        ..Sms::new(
+            request.number,
+            request.text,
+            SMS_PDU_TYPE_DELIVER,
+            state_value,
+            index,
+            &state.registry,
+        )
+    };
+
+    receive_sms(&state, index, sms).await
+}
+
+/// Exports a received SMS and announces it like ModemManager does
+async fn receive_sms(state: &ControlState, index: u32, sms: Sms) -> Response {
+    let server = state.conn.object_server();
+    let path = match add_sms(&state.registry, &server, index, sms).await {
+        Ok(path) => path,
+        Err(e) => return error(StatusCode::NOT_FOUND, e),
+    };
+
+    let modem_path = format!("{}/Modem/{}", ROOT_PATH, index);
+    let result = async {
+        let ctxt = SignalContext::new(&state.conn, modem_path.as_str())?;
+        Messaging::added(&ctxt, path.as_ref(), true).await
+    }
+    .await;
+
+    if let Err(e) = result {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, e);
+    }
+
+    info!(path = %path, "Injected SMS");
+    (
+        StatusCode::CREATED,
+        Json(InjectSmsResponse {
+            path: path.to_string(),
+        }),
+    )
+        .into_response()
+}
+
+/// Reports the delivery of a sent SMS. By default the report is applied to
+/// the sent SMS object, as ModemManager does; with `separate` it arrives as
+/// a status report SMS of its own.
+async fn report_delivery(
+    State(state): State<ControlState>,
+    Path(index): Path<u32>,
+    Json(request): Json<DeliveryReportRequest>,
+) -> Response {
+    let path = format!("{}/SMS/{}", ROOT_PATH, index);
+    let iface = match state
+        .conn
+        .object_server()
+        .interface::<_, Sms>(path.as_str())
+        .await
+    {
+        Ok(iface) => iface,
+        Err(e) => return error(StatusCode::NOT_FOUND, e),
+    };
+
+    let now = chrono::Utc::now().to_rfc3339();
+    let mut sms = iface.get_mut().await;
+    if sms.pdu_type != SMS_PDU_TYPE_SUBMIT || sms.state != SMS_STATE_SENT {
+        return error(StatusCode::CONFLICT, "Only sent SMS get delivery reports");
+    }
+
+    if request.separate {
+        let report = Sms {
+            timestamp: now.clone(),
+            message_reference: sms.message_reference,
+            delivery_state: request.delivery_state,
+            discharge_timestamp: now,
+            ..Sms::new(
+                sms.number.clone(),
+                String::new(),
+                SMS_PDU_TYPE_STATUS_REPORT,
+                SMS_STATE_RECEIVED,
+                sms.modem_index,
+                &state.registry,
+            )
+        };
+        let modem_index = sms.modem_index;
+        drop(sms);
+        return receive_sms(&state, modem_index, report).await;
+    }
+
+    sms.delivery_state = request.delivery_state;
+    sms.discharge_timestamp = now;
+    let ctxt = iface.signal_context();
+    let result = async {
+        sms.delivery_state_changed(ctxt).await?;
+        sms.discharge_timestamp_changed(ctxt).await
+    }
+    .await;
+
+    match result {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Finishes a multipart SMS injected with `receiving: true`
+async fn complete_sms(
+    State(state): State<ControlState>,
+    Path(index): Path<u32>,
+    Json(request): Json<CompleteSmsRequest>,
+) -> Response {
+    let path = format!("{}/SMS/{}", ROOT_PATH, index);
+    let iface = match state
+        .conn
+        .object_server()
+        .interface::<_, Sms>(path.as_str())
+        .await
+    {
+        Ok(iface) => iface,
+        Err(e) => return error(StatusCode::NOT_FOUND, e),
+    };
+
+    let mut sms = iface.get_mut().await;
+    sms.text = request.text;
+    sms.state = SMS_STATE_RECEIVED;
+
+    let ctxt = iface.signal_context();
+    let result = async {
+        sms.text_changed(ctxt).await?;
+        sms.state_changed(ctxt).await
+    }
+    .await;
+
+    match result {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+async fn list_sent(State(state): State<ControlState>) -> Json<Vec<SentSms>> {
+    Json(state.registry.lock().unwrap().sent.clone())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let registry = SharedRegistry::default();
+
+    let builder = match std::env::var("DBUS_ADDRESS") {
+        Ok(address) => zbus::connection::Builder::address(address.as_str())?,
+        Err(_) => zbus::connection::Builder::session()?,
+    };
+    let conn = builder
+        .name(SERVICE)?
+        .serve_at(ROOT_PATH, fdo::ObjectManager)?
+        .build()
+        .await
+        .context("Failed to connect to D-Bus and acquire the ModemManager name")?;
+    info!("Serving {} on D-Bus", SERVICE);
+
+    let control_port = std::env::var("CONTROL_PORT")
+        .unwrap_or_else(|_| "3031".to_string())
+        .parse::<u16>()
+        .context("CONTROL_PORT must be a valid port number (0-65535)")?;
+
+    let app = Router::new()
+        .route("/modems", post(add_modem))
+        .route("/modems/:index", delete(remove_modem))
+        .route("/modems/:index/sms", post(inject_sms))
+        .route("/sms/:index/complete", post(complete_sms))
+        .route("/sms/:index/report", post(report_delivery))
+        .route("/sent", get(list_sent))
+        .with_state(ControlState { conn, registry });
+
+    let bind_addr = format!("127.0.0.1:{}", control_port);
+    let listener = tokio::net::TcpListener::bind(&bind_addr)
+        .await
+        .context(format!("Failed to bind to {}", bind_addr))?;
+    info!("Control API listening on {}", bind_addr);
+
+    axum::serve(listener, app).await?;
+    Ok(())
+}
@@ -0,0 +1,471 @@
+use crate::auth;
+use crate::config::{Config, VacuumMode};
+use crate::db::{Database, MessageFilter, Permission, SortOrder};
+use crate::export::{Batches, ExportFormat, MessageRecord};
+use crate::modem::{ModemBackend, ModemManager};
+use crate::tls::ReloadableTls;
+use crate::utils::parse_rfc3339_timestamp;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// SMS daemon for ModemManager modems. Runs the daemon when no command is given.
+#[derive(Parser)]
+#[command(name = "samson", version)]
+pub struct Cli {
+    /// TOML config file; environment variables override its settings
+    #[arg(long, global = true, env = "SAMSON_CONFIG", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the daemon (the default when no command is given)
+    Serve,
+    /// Query stored messages
+    Messages {
+        #[command(subcommand)]
+        command: MessagesCommand,
+    },
+    /// Export stored messages, oldest first
+    Export {
+        #[command(flatten)]
+        filter: FilterArgs,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Ndjson)]
+        format: ExportFormat,
+        /// File to write to instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// List the modems ModemManager currently reports
+    Modems,
+    /// Maintain the database
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// Manage API keys
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum MessagesCommand {
+    /// List messages, newest first
+    List {
+        #[command(flatten)]
+        filter: FilterArgs,
+        /// Maximum number of messages to show
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+        /// Print one JSON object per line instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// Criteria shared by the commands that read messages
+#[derive(Args)]
+pub struct FilterArgs {
+    /// Only messages received by this IMSI
+    #[arg(long)]
+    imsi: Option<String>,
+    /// Only messages from this sender
+    #[arg(long)]
+    sender: Option<String>,
+    /// Only messages received after this time: an RFC 3339 timestamp or an
+    /// age such as 30m, 12h or 7d
+    #[arg(long, value_parser = parse_time)]
+    since: Option<DateTime<Utc>>,
+    /// Only messages received before this time, in the same formats as --since
+    #[arg(long, value_parser = parse_time)]
+    until: Option<DateTime<Utc>>,
+}
+
+impl FilterArgs {
+    fn to_filter(&self, order: SortOrder) -> MessageFilter {
+        MessageFilter {
+            imsis: self.imsi.iter().cloned().collect(),
+            sender: self.sender.clone(),
+            after: self.since,
+            before: self.until,
+            order,
+            ..MessageFilter::default()
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Apply pending schema migrations and print the schema version
+    Migrate,
+    /// Return the space of deleted messages to the file system
+    Vacuum {
+        /// Reclaim only free pages; the first run switches the database to
+        /// incremental auto-vacuum, which takes a full VACUUM. The default
+        /// is a full VACUUM.
+        #[arg(long, value_enum, default_value_t = VacuumArg::Full)]
+        mode: VacuumArg,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum VacuumArg {
+    Full,
+    Incremental,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Validate the config file and environment, including TLS certificates
+    Check,
+}
+
+#[derive(Subcommand)]
+pub enum KeysCommand {
+    /// Create a key and print it. The key cannot be shown again later.
+    Create {
+        /// Label to recognise the key by
+        #[arg(long)]
+        name: String,
+        /// Comma-separated permissions: read, send, metrics, admin
+        #[arg(long, value_delimiter = ',', required = true)]
+        permissions: Vec<Permission>,
+        /// Comma-separated IMSIs the key may access; all SIMs if omitted
+        #[arg(long, value_delimiter = ',')]
+        imsis: Vec<String>,
+    },
+    /// List all keys
+    List,
+    /// Revoke a key by id
+    Revoke { id: i64 },
+}
+
+pub async fn run(command: Command, config_path: Option<&Path>) -> Result<()> {
+    let config = Config::load(config_path)?;
+
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::Messages {
+            command:
+                MessagesCommand::List {
+                    filter,
+                    limit,
+                    json,
+                },
+        } => list_messages(&Database::open(&config.db_path)?, &filter, limit, json),
+        Command::Export {
+            filter,
+            format,
+            output,
+        } => export_messages(
+            &Database::open(&config.db_path)?,
+            &filter,
+            format,
+            output.as_deref(),
+        ),
+        Command::Modems => list_modems(&config).await,
+        Command::Db { command } => run_db(&config.db_path, command),
+        Command::Keys { command } => run_keys(&Database::open(&config.db_path)?, command),
+        Command::Config {
+            command: ConfigCommand::Check,
+        } => check_config(&config, config_path),
+    }
+}
+
+fn list_messages(db: &Database, filter: &FilterArgs, limit: usize, json: bool) -> Result<()> {
+    let filter = MessageFilter {
+        limit: Some(limit),
+        ..filter.to_filter(SortOrder::Desc)
+    };
+    let page = db.get_messages(&filter)?;
+    let mut out = std::io::stdout().lock();
+
+    if json {
+        for msg in &page.messages {
+            serde_json::to_writer(&mut out, &MessageRecord::from(msg))?;
+            writeln!(out)?;
+        }
+        return Ok(());
+    }
+
+    writeln!(
+        out,
+        "{:<8} {:<20} {:<16} {:<16} TEXT",
+        "ID", "TIMESTAMP", "IMSI", "SENDER"
+    )?;
+    for msg in &page.messages {
+        // Keep one message per line
+        let text = msg.text.replace(['\r', '\n'], " ");
+        writeln!(
+            out,
+            "{:<8} {:<20} {:<16} {:<16} {}{}",
+            msg.id.unwrap_or_default(),
+            msg.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true),
+            msg.imsi,
+            msg.sender,
+            if msg.partial { "[partial] " } else { "" },
+            text
+        )?;
+    }
+
+    if page.next_cursor.is_some() {
+        eprintln!(
+            "Showing the newest {} messages, raise --limit for more",
+            limit
+        );
+    }
+    Ok(())
+}
+
+fn export_messages(
+    db: &Database,
+    filter: &FilterArgs,
+    format: ExportFormat,
+    output: Option<&Path>,
+) -> Result<()> {
+    let out: Box<dyn Write> = match output {
+        Some(path) => Box::new(
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?,
+        ),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let mut out = BufWriter::new(out);
+
+    let mut batches = Batches::new(filter.to_filter(SortOrder::Asc));
+    let mut exported = 0;
+
+    format.write_header(&mut out)?;
+    while let Some(messages) = batches.next(db)? {
+        for msg in &messages {
+            format.write_message(&mut out, msg)?;
+        }
+        exported += messages.len();
+    }
+
+    out.flush()?;
+    eprintln!("Exported {} messages", exported);
+    Ok(())
+}
+
+async fn list_modems(config: &Config) -> Result<()> {
+    let modem_manager = ModemManager::new(config.dbus_address.as_deref()).await?;
+    let modems = modem_manager.get_modems().await?;
+
+    if modems.is_empty() {
+        eprintln!("No modems found");
+        return Ok(());
+    }
+
+    println!(
+        "{:<45} {:<16} {:<16} {:<20} {:<20} NUMBERS",
+        "PATH", "IMEI", "IMSI", "ICCID", "OPERATOR"
+    );
+    for modem in modems {
+        println!(
+            "{:<45} {:<16} {:<16} {:<20} {:<20} {}",
+            modem.path,
+            modem.imei,
+            modem.imsi,
+            modem.iccid,
+            modem.operator_name,
+            modem.own_numbers.join(",")
+        );
+    }
+    Ok(())
+}
+
+fn run_db(db_path: &str, command: DbCommand) -> Result<()> {
+    match command {
+        DbCommand::Migrate => {
+            // Opening the database this way applies pending migrations
+            let db = Database::new(db_path)?;
+            println!(
+                "Database {} is at schema version {}",
+                db_path,
+                db.schema_version()?
+            );
+        }
+        DbCommand::Vacuum { mode } => {
+            let db = Database::open(db_path)?;
+            let size_before = file_size(db_path);
+            let mode = match mode {
+                VacuumArg::Full => VacuumMode::Full,
+                VacuumArg::Incremental => {
+                    if db.enable_incremental_vacuum()? {
+                        eprintln!("Switched {} to incremental auto-vacuum", db_path);
+                    }
+                    VacuumMode::Incremental
+                }
+            };
+            db.vacuum(mode)?;
+            println!(
+                "Vacuumed {}: {} -> {} bytes",
+                db_path,
+                size_before,
+                file_size(db_path)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn file_size(path: &str) -> u64 {
+    std::fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Parses an RFC 3339 timestamp, or an age such as `30m`, `12h` or `7d`
+/// counted back from now
+fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(timestamp) = parse_rfc3339_timestamp(value) {
+        return Ok(timestamp);
+    }
+
+    let invalid = || {
+        format!(
+            "'{}' is neither an RFC 3339 timestamp nor an age such as 30m, 12h or 7d",
+            value
+        )
+    };
+    let unit = value.chars().last().ok_or_else(invalid)?;
+    let amount: u32 = value[..value.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| invalid())?;
+    let amount = i64::from(amount);
+    let age = match unit {
+        's' => Duration::try_seconds(amount),
+        'm' => Duration::try_minutes(amount),
+        'h' => Duration::try_hours(amount),
+        'd' => Duration::try_days(amount),
+        'w' => Duration::try_weeks(amount),
+        _ => None,
+    }
+    .ok_or_else(invalid)?;
+
+    Ok(Utc::now() - age)
+}
+
+/// Loading the configuration already validated it; this also loads the TLS
+/// certificates, which are otherwise only read when the daemon starts
+fn check_config(config: &Config, config_path: Option<&Path>) -> Result<()> {
+    if let Some(settings) = &config.api_tls {
+        ReloadableTls::load("API", settings)?;
+    }
+    if let Some(settings) = &config.metrics_tls {
+        ReloadableTls::load("metrics", settings)?;
+    }
+
+    match config_path {
+        Some(path) => println!("Configuration in {} is valid", path.display()),
+        None => println!("Configuration is valid (no config file, environment only)"),
+    }
+    Ok(())
+}
+
+fn run_keys(db: &Database, command: KeysCommand) -> Result<()> {
+    match command {
+        KeysCommand::Create {
+            name,
+            permissions,
+            imsis,
+        } => {
+            if let Some(imsi) = imsis
+                .iter()
+                .find(|imsi| imsi.is_empty() || !imsi.chars().all(|c| c.is_ascii_digit()))
+            {
+                anyhow::bail!("Invalid IMSI '{}', expected digits only", imsi);
+            }
+
+            let generated = auth::generate_key()?;
+            let id = db.insert_api_key(
+                &name,
+                &generated.hash,
+                &generated.prefix,
+                &imsis,
+                &permissions,
+            )?;
+
+            eprintln!(
+                "Created API key {} ({}). Store it now, it is not shown again:",
+                id, name
+            );
+            println!("{}", generated.key);
+        }
+        KeysCommand::List => {
+            println!(
+                "{:<4} {:<20} {:<13} {:<16} {:<20} {:<26} STATUS",
+                "ID", "NAME", "PREFIX", "PERMISSIONS", "IMSIS", "LAST USED"
+            );
+            for key in db.list_api_keys()? {
+                let permissions: Vec<&str> =
+                    key.permissions.iter().map(Permission::as_str).collect();
+                let imsis = if key.imsis.is_empty() {
+                    "all".to_string()
+                } else {
+                    key.imsis.join(",")
+                };
+                let last_used = key
+                    .last_used_at
+                    .map(|t| t.to_rfc3339())
+                    .unwrap_or_else(|| "never".to_string());
+                let status = match key.revoked_at {
+                    Some(t) => format!("revoked {}", t.to_rfc3339()),
+                    None => "active".to_string(),
+                };
+
+                println!(
+                    "{:<4} {:<20} {:<13} {:<16} {:<20} {:<26} {}",
+                    key.id,
+                    key.name,
+                    format!("{}…", key.prefix),
+                    permissions.join(","),
+                    imsis,
+                    last_used,
+                    status
+                );
+            }
+        }
+        KeysCommand::Revoke { id } => {
+            if !db.revoke_api_key(id)? {
+                anyhow::bail!("No active API key with id {}", id);
+            }
+            println!("Revoked API key {}", id);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps_and_ages() {
+        let timestamp = parse_time("2024-01-15T10:30:00+01:00").unwrap();
+        assert_eq!(timestamp.to_rfc3339(), "2024-01-15T09:30:00+00:00");
+
+        let age = Utc::now() - parse_time("12h").unwrap();
+        assert!((age - Duration::hours(12)).num_seconds().abs() < 5);
+        let week_ago = parse_time("7d").unwrap();
+        assert!(Utc::now() - week_ago >= Duration::days(7));
+
+        for invalid in ["", "d", "12", "-3d", "5y", "yesterday"] {
+            assert!(parse_time(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -1,49 +1,970 @@
 use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookTarget {
+    pub url: String,
+    /// Only messages for the SIM with this IMSI, ICCID or own number are
+    /// delivered; None means all SIMs
+    pub sim: Option<String>,
+}
+
+/// A USSD code sent on a schedule, such as a balance check
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UssdJob {
+    /// The SIM to run the job on
+    pub imsi: String,
+    /// USSD code to send, such as `*100#`
+    pub command: String,
+    /// Seconds between runs
+    #[serde(default = "default_ussd_job_interval")]
+    pub interval: u64,
+    /// Regular expression applied to the reply. Its first capture group, or
+    /// the whole match, is recorded as the result; None records the reply.
+    pub pattern: Option<String>,
+}
+
+fn default_ussd_job_interval() -> u64 {
+    86400
+}
+
+/// Certificate and key for a TLS listener, as PEM files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    /// CA bundle that client certificates must chain to; None accepts
+    /// clients without a certificate
+    pub client_ca_path: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VacuumMode {
+    Off,
+    /// `PRAGMA incremental_vacuum` after each pruning run
+    Incremental,
+    /// A full `VACUUM` after each pruning run that deleted messages
+    Full,
+}
+
+/// Limits on how long and how many received messages are kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Delete messages older than this many days; 0 keeps them forever
+    pub max_age_days: u64,
+    /// Per-IMSI replacements for `max_age_days`
+    pub imsi_max_age_days: HashMap<String, u64>,
+    /// Delete the oldest messages beyond this count; 0 means no limit
+    pub max_messages: u64,
+    /// Seconds between pruning runs
+    pub interval: u64,
+    /// Messages deleted per statement, so the database is not locked for long
+    pub batch_size: usize,
+    pub vacuum: VacuumMode,
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.max_age_days > 0
+            || self.imsi_max_age_days.values().any(|days| *days > 0)
+            || self.max_messages > 0
+    }
+}
+
+/// Messages a SIM may send within rolling windows; 0 means no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Messages per 60 minutes
+    #[serde(default)]
+    pub hourly: u32,
+    /// Messages per 24 hours
+    #[serde(default)]
+    pub daily: u32,
+}
+
+/// What happens to a message that needs more than the maximum number of
+/// SMS parts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LongMessages {
+    /// Refuse to queue it
+    Reject,
+    /// Queue it as several messages that each fit
+    Split,
+}
+
+/// How queued outgoing messages are sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboxPolicy {
+    /// Messages sent at the same time from one SIM
+    pub concurrency: usize,
+    /// Send attempts before a message is marked failed
+    pub max_attempts: u32,
+    /// Seconds before the first retry, doubled after every failed attempt
+    pub retry_delay: u64,
+    /// Upper bound of the retry delay in seconds
+    pub max_retry_delay: u64,
+    /// Limit of every SIM without an entry in `imsi_rate_limits`
+    pub rate_limit: RateLimit,
+    pub imsi_rate_limits: HashMap<String, RateLimit>,
+    /// SMS parts a single message may be sent as
+    pub max_parts: usize,
+    pub long_messages: LongMessages,
+}
+
+/// Parts of a multipart SMS are numbered with a single octet
+const MAX_SMS_PARTS: usize = 255;
+
+impl Default for OutboxPolicy {
+    fn default() -> Self {
+        Self {
+            concurrency: 1,
+            max_attempts: 5,
+            retry_delay: 30,
+            max_retry_delay: 3600,
+            rate_limit: RateLimit::default(),
+            imsi_rate_limits: HashMap::new(),
+            max_parts: MAX_SMS_PARTS,
+            long_messages: LongMessages::Reject,
+        }
+    }
+}
+
+impl OutboxPolicy {
+    pub fn rate_limit(&self, imsi: &str) -> RateLimit {
+        self.imsi_rate_limits
+            .get(imsi)
+            .copied()
+            .unwrap_or(self.rate_limit)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Config {
     pub db_path: String,
+    /// D-Bus address to find ModemManager on; None means the system bus
+    pub dbus_address: Option<String>,
     pub poll_interval: u64,
+    pub multipart_timeout: u64,
+    /// Poll intervals without a poll before the daemon is reported not ready
+    pub readiness_poll_intervals: u32,
+    /// `tracing` filter directives, such as `info` or `samson=debug,zbus=warn`
+    pub log_level: String,
     pub api_host: String,
     pub api_port: u16,
+    pub api_tls: Option<TlsConfig>,
+    /// Seconds an `Idempotency-Key` and its response are kept for replay
+    pub idempotency_window: u64,
     pub metrics_host: String,
     pub metrics_port: u16,
+    pub metrics_tls: Option<TlsConfig>,
+    pub webhooks: Vec<WebhookTarget>,
+    pub webhook_secret: Option<String>,
+    pub webhook_max_attempts: u32,
+    pub retention: RetentionPolicy,
+    pub outbox: OutboxPolicy,
+    pub ussd_jobs: Vec<UssdJob>,
+}
+
+/// The config file. Every setting is optional and overridden by its
+/// environment variable.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    database_path: Option<String>,
+    dbus_address: Option<String>,
+    poll_interval: Option<u64>,
+    multipart_timeout: Option<u64>,
+    readiness_poll_intervals: Option<u32>,
+    log_level: Option<String>,
+    idempotency_window: Option<u64>,
+    #[serde(default)]
+    api: FileListener,
+    #[serde(default)]
+    metrics: FileListener,
+    #[serde(default)]
+    webhooks: FileWebhooks,
+    #[serde(default)]
+    retention: FileRetention,
+    #[serde(default)]
+    outbox: FileOutbox,
+    #[serde(default)]
+    ussd: FileUssd,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileListener {
+    host: Option<String>,
+    port: Option<u16>,
+    #[serde(default)]
+    tls: FileTls,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTls {
+    cert: Option<String>,
+    key: Option<String>,
+    client_ca: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileWebhooks {
+    targets: Option<Vec<WebhookTarget>>,
+    secret: Option<String>,
+    max_attempts: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileRetention {
+    max_age_days: Option<u64>,
+    imsi_max_age_days: Option<HashMap<String, u64>>,
+    max_messages: Option<u64>,
+    interval: Option<u64>,
+    batch_size: Option<usize>,
+    vacuum: Option<VacuumMode>,
+}
+
+/// Per-SIM rate limits are only configured in the file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileOutbox {
+    concurrency: Option<usize>,
+    max_attempts: Option<u32>,
+    retry_delay: Option<u64>,
+    max_retry_delay: Option<u64>,
+    hourly_limit: Option<u32>,
+    daily_limit: Option<u32>,
+    imsi_limits: Option<HashMap<String, RateLimit>>,
+    max_parts: Option<usize>,
+    long_messages: Option<LongMessages>,
+}
+
+/// Jobs are only configured in the file, as they do not fit in an
+/// environment variable
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileUssd {
+    jobs: Option<Vec<UssdJob>>,
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self> {
-        let db_path = std::env::var("DATABASE_PATH").unwrap_or_else(|_| "samson.db".to_string());
+    /// Loads settings from the environment, falling back to the TOML file
+    /// at `path` and then to defaults
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (file, file_name) = match path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read config file {}", path.display()))?;
+                let file = toml::from_str(&contents)
+                    .with_context(|| format!("Invalid config file {}", path.display()))?;
+                (file, Some(path.display().to_string()))
+            }
+            None => (FileConfig::default(), None),
+        };
 
-        let poll_interval = std::env::var("POLL_INTERVAL")
-            .unwrap_or_else(|_| "1".to_string())
-            .parse::<u64>()
-            .context("POLL_INTERVAL must be a valid number")?;
+        let sources = Sources {
+            env: |name: &str| std::env::var(name).ok(),
+            file_name,
+        };
+        Self::resolve(file, &sources)
+    }
 
-        if poll_interval == 0 {
-            anyhow::bail!("POLL_INTERVAL must be greater than 0");
-        }
+    fn resolve(
+        file: FileConfig,
+        sources: &Sources<impl Fn(&str) -> Option<String>>,
+    ) -> Result<Self> {
+        let db_path = sources
+            .string("DATABASE_PATH", file.database_path)
+            .unwrap_or_else(|| "samson.db".to_string());
+
+        let dbus_address = sources.string("DBUS_ADDRESS", file.dbus_address);
+
+        let poll_interval = sources
+            .number("POLL_INTERVAL", "poll_interval", file.poll_interval, 30)?
+            .positive()?;
+
+        let multipart_timeout = sources
+            .number(
+                "MULTIPART_TIMEOUT",
+                "multipart_timeout",
+                file.multipart_timeout,
+                300,
+            )?
+            .value;
+
+        let readiness_poll_intervals = sources
+            .number(
+                "READINESS_POLL_INTERVALS",
+                "readiness_poll_intervals",
+                file.readiness_poll_intervals,
+                3,
+            )?
+            .positive()?;
+
+        let log_level = sources.setting("LOG_LEVEL", "log_level", file.log_level);
+        let log_level = match log_level.value {
+            None => "info".to_string(),
+            Some(filter) => {
+                tracing_subscriber::EnvFilter::try_new(&filter)
+                    .with_context(|| format!("{} must be a valid log filter", log_level.source))?;
+                filter
+            }
+        };
+
+        let api_host = sources
+            .string("API_HOST", file.api.host)
+            .unwrap_or_else(|| "0.0.0.0".to_string());
+
+        let api_port = sources.port("API_PORT", file.api.port, 3030)?;
+
+        let api_tls = sources.tls("API", "api", file.api.tls)?;
+
+        let idempotency_window = sources
+            .number(
+                "IDEMPOTENCY_WINDOW",
+                "idempotency_window",
+                file.idempotency_window,
+                86400,
+            )?
+            .positive()?;
 
-        let api_host = std::env::var("API_HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
+        let metrics_host = sources
+            .string("METRICS_HOST", file.metrics.host)
+            .unwrap_or_else(|| "0.0.0.0".to_string());
 
-        let api_port = std::env::var("API_PORT")
-            .unwrap_or_else(|_| "3030".to_string())
-            .parse::<u16>()
-            .context("API_PORT must be a valid port number (0-65535)")?;
+        let metrics_port = sources.port("METRICS_PORT", file.metrics.port, 9090)?;
 
-        let metrics_host = std::env::var("METRICS_HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
+        let metrics_tls = sources.tls("METRICS", "metrics", file.metrics.tls)?;
 
-        let metrics_port = std::env::var("METRICS_PORT")
-            .unwrap_or_else(|_| "9090".to_string())
-            .parse::<u16>()
-            .context("METRICS_PORT must be a valid port number (0-65535)")?;
+        let webhooks = match sources.var("WEBHOOK_URLS") {
+            Some(value) => parse_webhook_targets(&value)?,
+            None => {
+                let targets = file.webhooks.targets.unwrap_or_default();
+                for (i, target) in targets.iter().enumerate() {
+                    check_webhook_target(target)
+                        .map_err(|e| sources.file_error(&format!("webhooks.targets[{}]", i), e))?;
+                }
+                targets
+            }
+        };
+
+        let webhook_secret = sources.string("WEBHOOK_SECRET", file.webhooks.secret);
+
+        let webhook_max_attempts = sources
+            .number(
+                "WEBHOOK_MAX_ATTEMPTS",
+                "webhooks.max_attempts",
+                file.webhooks.max_attempts,
+                10,
+            )?
+            .positive()?;
+
+        let retention = retention(file.retention, sources)?;
+
+        let outbox = outbox(file.outbox, sources)?;
+
+        let ussd_jobs = file.ussd.jobs.unwrap_or_default();
+        for (i, job) in ussd_jobs.iter().enumerate() {
+            check_ussd_job(job, &ussd_jobs[..i])
+                .map_err(|e| sources.file_error(&format!("ussd.jobs[{}]", i), e))?;
+        }
 
         Ok(Self {
             db_path,
+            dbus_address,
             poll_interval,
+            multipart_timeout,
+            readiness_poll_intervals,
+            log_level,
             api_host,
             api_port,
+            api_tls,
+            idempotency_window,
             metrics_host,
             metrics_port,
+            metrics_tls,
+            webhooks,
+            webhook_secret,
+            webhook_max_attempts,
+            retention,
+            outbox,
+            ussd_jobs,
         })
     }
 }
+
+fn outbox(
+    file: FileOutbox,
+    sources: &Sources<impl Fn(&str) -> Option<String>>,
+) -> Result<OutboxPolicy> {
+    let defaults = OutboxPolicy::default();
+    let concurrency = sources
+        .number(
+            "OUTBOX_CONCURRENCY",
+            "outbox.concurrency",
+            file.concurrency,
+            defaults.concurrency,
+        )?
+        .positive()?;
+
+    let max_attempts = sources
+        .number(
+            "OUTBOX_MAX_ATTEMPTS",
+            "outbox.max_attempts",
+            file.max_attempts,
+            defaults.max_attempts,
+        )?
+        .positive()?;
+
+    let retry_delay = sources
+        .number(
+            "OUTBOX_RETRY_DELAY",
+            "outbox.retry_delay",
+            file.retry_delay,
+            defaults.retry_delay,
+        )?
+        .positive()?;
+
+    let max_retry_delay = sources.number(
+        "OUTBOX_MAX_RETRY_DELAY",
+        "outbox.max_retry_delay",
+        file.max_retry_delay,
+        defaults.max_retry_delay,
+    )?;
+    if max_retry_delay.value < retry_delay {
+        anyhow::bail!(
+            "{} must be at least the retry delay of {} seconds",
+            max_retry_delay.source,
+            retry_delay
+        );
+    }
+
+    let rate_limit = RateLimit {
+        hourly: sources
+            .number(
+                "OUTBOX_HOURLY_LIMIT",
+                "outbox.hourly_limit",
+                file.hourly_limit,
+                0,
+            )?
+            .value,
+        daily: sources
+            .number(
+                "OUTBOX_DAILY_LIMIT",
+                "outbox.daily_limit",
+                file.daily_limit,
+                0,
+            )?
+            .value,
+    };
+
+    let imsi_rate_limits = file.imsi_limits.unwrap_or_default();
+    if let Some(imsi) = imsi_rate_limits.keys().find(|imsi| !is_imsi(imsi)) {
+        return Err(sources.file_error(
+            "outbox.imsi_limits",
+            format!("has an invalid IMSI '{}'", imsi),
+        ));
+    }
+
+    let max_parts = sources.number(
+        "OUTBOX_MAX_PARTS",
+        "outbox.max_parts",
+        file.max_parts,
+        defaults.max_parts,
+    )?;
+    if max_parts.value > MAX_SMS_PARTS {
+        anyhow::bail!(
+            "{} must be at most {}, the most parts a multipart SMS can have",
+            max_parts.source,
+            MAX_SMS_PARTS
+        );
+    }
+
+    let long_messages = match sources.var("OUTBOX_LONG_MESSAGES").as_deref() {
+        None => file.long_messages.unwrap_or(defaults.long_messages),
+        Some("reject") => LongMessages::Reject,
+        Some("split") => LongMessages::Split,
+        Some(other) => anyhow::bail!(
+            "OUTBOX_LONG_MESSAGES must be reject or split, got '{}'",
+            other
+        ),
+    };
+
+    Ok(OutboxPolicy {
+        concurrency,
+        max_attempts,
+        retry_delay,
+        max_retry_delay: max_retry_delay.value,
+        rate_limit,
+        imsi_rate_limits,
+        max_parts: max_parts.positive()?,
+        long_messages,
+    })
+}
+
+fn retention(
+    file: FileRetention,
+    sources: &Sources<impl Fn(&str) -> Option<String>>,
+) -> Result<RetentionPolicy> {
+    let max_age_days = sources
+        .number(
+            "RETENTION_MAX_AGE_DAYS",
+            "retention.max_age_days",
+            file.max_age_days,
+            0,
+        )?
+        .value;
+
+    let imsi_max_age_days = match sources.var("RETENTION_IMSI_MAX_AGE_DAYS") {
+        Some(value) => parse_imsi_max_ages(&value)?,
+        None => {
+            let max_ages = file.imsi_max_age_days.unwrap_or_default();
+            if let Some(imsi) = max_ages.keys().find(|imsi| !is_imsi(imsi)) {
+                return Err(sources.file_error(
+                    "retention.imsi_max_age_days",
+                    format!("has an invalid IMSI '{}'", imsi),
+                ));
+            }
+            max_ages
+        }
+    };
+
+    let max_messages = sources
+        .number(
+            "RETENTION_MAX_MESSAGES",
+            "retention.max_messages",
+            file.max_messages,
+            0,
+        )?
+        .value;
+
+    let interval = sources
+        .number(
+            "RETENTION_INTERVAL",
+            "retention.interval",
+            file.interval,
+            3600,
+        )?
+        .positive()?;
+
+    let batch_size = sources
+        .number(
+            "RETENTION_BATCH_SIZE",
+            "retention.batch_size",
+            file.batch_size,
+            1000,
+        )?
+        .positive()?;
+
+    let vacuum = match sources.var("RETENTION_VACUUM").as_deref() {
+        None => file.vacuum.unwrap_or(VacuumMode::Off),
+        Some("off") => VacuumMode::Off,
+        Some("incremental") => VacuumMode::Incremental,
+        Some("full") => VacuumMode::Full,
+        Some(other) => anyhow::bail!(
+            "RETENTION_VACUUM must be off, incremental or full, got '{}'",
+            other
+        ),
+    };
+
+    Ok(RetentionPolicy {
+        max_age_days,
+        imsi_max_age_days,
+        max_messages,
+        interval,
+        batch_size,
+        vacuum,
+    })
+}
+
+/// Where settings are read from: environment variables first, then the
+/// config file
+struct Sources<E> {
+    env: E,
+    /// Path of the config file, for error messages
+    file_name: Option<String>,
+}
+
+/// A resolved setting, with where it came from for error messages
+struct Setting<T> {
+    value: T,
+    source: String,
+}
+
+impl<T: Default + PartialEq> Setting<T> {
+    fn positive(self) -> Result<T> {
+        if self.value == T::default() {
+            anyhow::bail!("{} must be greater than 0", self.source);
+        }
+        Ok(self.value)
+    }
+}
+
+impl<E: Fn(&str) -> Option<String>> Sources<E> {
+    /// An environment variable, treating an empty value as unset
+    fn var(&self, name: &str) -> Option<String> {
+        (self.env)(name).filter(|s| !s.is_empty())
+    }
+
+    fn string(&self, var: &str, file: Option<String>) -> Option<String> {
+        self.var(var).or(file)
+    }
+
+    fn setting(&self, var: &str, key: &str, file: Option<String>) -> Setting<Option<String>> {
+        match self.var(var) {
+            Some(value) => Setting {
+                value: Some(value),
+                source: var.to_string(),
+            },
+            None => Setting {
+                value: file,
+                source: self.file_key(key),
+            },
+        }
+    }
+
+    fn number<T>(&self, var: &str, key: &str, file: Option<T>, default: T) -> Result<Setting<T>>
+    where
+        T: FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        match self.var(var) {
+            Some(value) => Ok(Setting {
+                value: value
+                    .parse()
+                    .with_context(|| format!("{} must be a valid number", var))?,
+                source: var.to_string(),
+            }),
+            None => Ok(Setting {
+                value: file.unwrap_or(default),
+                source: self.file_key(key),
+            }),
+        }
+    }
+
+    fn port(&self, var: &str, file: Option<u16>, default: u16) -> Result<u16> {
+        match self.var(var) {
+            Some(value) => value
+                .parse()
+                .with_context(|| format!("{} must be a valid port number (0-65535)", var)),
+            None => Ok(file.unwrap_or(default)),
+        }
+    }
+
+    /// Combines `<PREFIX>_TLS_CERT`, `<PREFIX>_TLS_KEY` and
+    /// `<PREFIX>_TLS_CLIENT_CA` with the `[<section>.tls]` table. TLS is off
+    /// unless both certificate and key are set.
+    fn tls(&self, prefix: &str, section: &str, file: FileTls) -> Result<Option<TlsConfig>> {
+        let var = |name: &str| self.var(&format!("{}_TLS_{}", prefix, name));
+
+        let client_ca_path = var("CLIENT_CA").or(file.client_ca);
+        match (var("CERT").or(file.cert), var("KEY").or(file.key)) {
+            (Some(cert_path), Some(key_path)) => Ok(Some(TlsConfig {
+                cert_path,
+                key_path,
+                client_ca_path,
+            })),
+            (None, None) if client_ca_path.is_some() => anyhow::bail!(
+                "A client CA for the {1} listener requires a certificate and key \
+                 ({0}_TLS_CERT and {0}_TLS_KEY, or {1}.tls.cert and {1}.tls.key)",
+                prefix,
+                section
+            ),
+            (None, None) => Ok(None),
+            _ => anyhow::bail!(
+                "The certificate and key of the {1} listener must be set together \
+                 ({0}_TLS_CERT and {0}_TLS_KEY, or {1}.tls.cert and {1}.tls.key)",
+                prefix,
+                section
+            ),
+        }
+    }
+
+    /// Names a key of the config file for error messages
+    fn file_key(&self, key: &str) -> String {
+        match &self.file_name {
+            Some(file_name) => format!("`{}` in {}", key, file_name),
+            None => format!("`{}`", key),
+        }
+    }
+
+    fn file_error(&self, key: &str, error: impl Display) -> anyhow::Error {
+        anyhow::anyhow!("{} {}", self.file_key(key), error)
+    }
+}
+
+/// Parses a comma-separated list of `IMSI=DAYS` entries
+fn parse_imsi_max_ages(value: &str) -> Result<HashMap<String, u64>> {
+    let mut max_ages = HashMap::new();
+
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((imsi, days)) = entry.split_once('=') else {
+            anyhow::bail!(
+                "RETENTION_IMSI_MAX_AGE_DAYS entry '{}' must be IMSI=DAYS",
+                entry
+            );
+        };
+
+        if !is_imsi(imsi) {
+            anyhow::bail!(
+                "RETENTION_IMSI_MAX_AGE_DAYS entry '{}' has an invalid IMSI",
+                entry
+            );
+        }
+
+        let days = days.trim().parse::<u64>().with_context(|| {
+            format!(
+                "RETENTION_IMSI_MAX_AGE_DAYS entry '{}' must have a number of days",
+                entry
+            )
+        })?;
+
+        max_ages.insert(imsi.to_string(), days);
+    }
+
+    Ok(max_ages)
+}
+
+/// Parses a comma-separated list of webhook targets.
+/// Each entry is either `URL` (all SIMs) or `SIM=URL` (a single SIM, given
+/// by IMSI, ICCID or own number).
+fn parse_webhook_targets(value: &str) -> Result<Vec<WebhookTarget>> {
+    let mut targets = Vec::new();
+
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (sim, url) = match entry.split_once('=') {
+            Some((sim, url)) if is_sim_identifier(sim) => (Some(sim.to_string()), url),
+            _ => (None, entry),
+        };
+
+        let target = WebhookTarget {
+            url: url.to_string(),
+            sim,
+        };
+        check_webhook_target(&target)
+            .map_err(|e| anyhow::anyhow!("WEBHOOK_URLS entry '{}' {}", entry, e))?;
+        targets.push(target);
+    }
+
+    Ok(targets)
+}
+
+fn check_webhook_target(target: &WebhookTarget) -> Result<(), String> {
+    if !target.url.starts_with("http://") && !target.url.starts_with("https://") {
+        return Err("must be an http(s) URL".to_string());
+    }
+    if let Some(sim) = &target.sim
+        && !is_sim_identifier(sim)
+    {
+        return Err(format!(
+            "has an invalid SIM '{}', expected an IMSI, ICCID or phone number",
+            sim
+        ));
+    }
+    Ok(())
+}
+
+/// Checks a USSD job against the jobs listed before it
+fn check_ussd_job(job: &UssdJob, earlier: &[UssdJob]) -> Result<(), String> {
+    if !is_imsi(&job.imsi) {
+        return Err(format!("has an invalid IMSI '{}'", job.imsi));
+    }
+    if earlier.iter().any(|other| other.imsi == job.imsi) {
+        return Err(format!("is a second job for SIM {}", job.imsi));
+    }
+    if job.command.trim().is_empty() {
+        return Err("must have a command".to_string());
+    }
+    if job.interval == 0 {
+        return Err("must have an interval greater than 0".to_string());
+    }
+    if let Some(pattern) = &job.pattern {
+        regex::Regex::new(pattern).map_err(|e| format!("has an invalid pattern: {}", e))?;
+    }
+    Ok(())
+}
+
+fn is_imsi(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|c| c.is_ascii_digit())
+}
+
+/// IMSIs and ICCIDs are all digits, own numbers may have a leading `+`
+fn is_sim_identifier(value: &str) -> bool {
+    is_imsi(value.strip_prefix('+').unwrap_or(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(toml: &str, env: &[(&str, &str)]) -> Result<Config> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let sources = Sources {
+            env: |name: &str| env.get(name).cloned(),
+            file_name: Some("samson.toml".to_string()),
+        };
+        Config::resolve(toml::from_str(toml)?, &sources)
+    }
+
+    #[test]
+    fn reads_file_with_env_overrides() {
+        let config = resolve(
+            r#"
+            poll_interval = 10
+            log_level = "samson=debug"
+
+            [api]
+            port = 8080
+
+            [api.tls]
+            cert = "cert.pem"
+            key = "key.pem"
+
+            [[webhooks.targets]]
+            url = "https://example.com/sms"
+            sim = "310260123456789"
+
+            [retention]
+            max_age_days = 30
+            vacuum = "incremental"
+
+            [retention.imsi_max_age_days]
+            310260999999999 = 7
+
+            [outbox]
+            hourly_limit = 20
+            max_parts = 3
+            long_messages = "split"
+
+            [outbox.imsi_limits.310260999999999]
+            daily = 100
+
+            [[ussd.jobs]]
+            imsi = "310260123456789"
+            command = "*100#"
+            pattern = 'Balance: ([0-9.]+)'
+            "#,
+            &[
+                ("POLL_INTERVAL", "20"),
+                ("API_TLS_KEY", "other.pem"),
+                ("IDEMPOTENCY_WINDOW", "600"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.poll_interval, 20);
+        assert_eq!(config.log_level, "samson=debug");
+        assert_eq!(config.api_port, 8080);
+        assert_eq!(config.metrics_port, 9090);
+        assert_eq!(config.idempotency_window, 600);
+        let tls = config.api_tls.unwrap();
+        assert_eq!(
+            (tls.cert_path.as_str(), tls.key_path.as_str()),
+            ("cert.pem", "other.pem")
+        );
+        assert_eq!(config.webhooks[0].sim.as_deref(), Some("310260123456789"));
+        assert_eq!(config.retention.max_age_days, 30);
+        assert_eq!(config.retention.vacuum, VacuumMode::Incremental);
+        assert_eq!(config.retention.imsi_max_age_days["310260999999999"], 7);
+        assert_eq!(config.ussd_jobs[0].interval, 86400);
+        assert_eq!(config.outbox.concurrency, 1);
+        assert_eq!(config.outbox.max_parts, 3);
+        assert_eq!(config.outbox.long_messages, LongMessages::Split);
+        assert_eq!(
+            config.outbox.rate_limit("310260123456789"),
+            RateLimit {
+                hourly: 20,
+                daily: 0
+            }
+        );
+        assert_eq!(
+            config.outbox.rate_limit("310260999999999"),
+            RateLimit {
+                hourly: 0,
+                daily: 100
+            }
+        );
+    }
+
+    #[test]
+    fn defaults_to_safety_net_polling() {
+        let config = resolve("", &[]).unwrap();
+        assert_eq!(config.poll_interval, 30);
+        assert_eq!(config.api_port, 3030);
+    }
+
+    #[test]
+    fn rejects_unknown_and_mistyped_keys() {
+        let error = resolve("pol_interval = 10", &[]).unwrap_err().to_string();
+        assert!(error.contains("unknown field `pol_interval`"), "{}", error);
+
+        let error = resolve("[retention]\nvacuum = \"sometimes\"", &[])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("line 2"), "{}", error);
+    }
+
+    #[test]
+    fn validation_errors_name_the_setting() {
+        let error = resolve("[retention]\ninterval = 0", &[]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`retention.interval` in samson.toml must be greater than 0"
+        );
+
+        let error = resolve("", &[("POLL_INTERVAL", "0")]).unwrap_err();
+        assert_eq!(error.to_string(), "POLL_INTERVAL must be greater than 0");
+
+        let error = resolve("[[webhooks.targets]]\nurl = \"ftp://example.com\"", &[]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`webhooks.targets[0]` in samson.toml must be an http(s) URL"
+        );
+
+        let error = resolve(
+            "[[ussd.jobs]]\nimsi = \"310260123456789\"\ncommand = \"*100#\"\npattern = \"(\"",
+            &[],
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("`ussd.jobs[0]` in samson.toml has an invalid pattern"),
+            "{}",
+            error
+        );
+
+        let error = resolve("", &[("OUTBOX_MAX_RETRY_DELAY", "10")]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "OUTBOX_MAX_RETRY_DELAY must be at least the retry delay of 30 seconds"
+        );
+
+        let error = resolve("[outbox]\nmax_parts = 300", &[]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`outbox.max_parts` in samson.toml must be at most 255, the most parts a multipart SMS can have"
+        );
+
+        let error = resolve("log_level = \"samson=loud\"", &[]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`log_level` in samson.toml must be a valid log filter"
+        );
+    }
+}
@@ -1,8 +1,16 @@
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
-use rusqlite::{Connection, params};
+use prometheus::HistogramTimer;
+use rusqlite::{Connection, OptionalExtension, params};
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::config::VacuumMode;
+use crate::encoding::{Encoding, Segments};
+use crate::metrics::Metrics;
+use crate::migrations;
+use crate::modem::{ModemInfo, SentSms};
 use crate::utils::parse_rfc3339_timestamp;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -12,55 +20,591 @@ pub struct SmsMessage {
     pub imei: String,
     #[serde(skip_serializing)]
     pub imsi: String,
+    #[serde(skip_serializing)]
+    pub iccid: String,
+    #[serde(skip_serializing)]
+    pub operator_id: String,
+    #[serde(skip_serializing)]
+    pub operator_name: String,
+    #[serde(skip_serializing)]
+    pub own_numbers: Vec<String>,
     pub sender: String,
     pub text: String,
     pub timestamp: DateTime<Utc>,
+    /// Parts of a multipart message were still missing when it was stored
+    pub partial: bool,
+}
+
+impl SmsMessage {
+    /// Creates an unsaved message received by `modem`
+    pub fn received(
+        modem: &ModemInfo,
+        sender: String,
+        text: String,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            id: None,
+            imei: modem.imei.clone(),
+            imsi: modem.imsi.clone(),
+            iccid: modem.iccid.clone(),
+            operator_id: modem.operator_id.clone(),
+            operator_name: modem.operator_name.clone(),
+            own_numbers: modem.own_numbers.clone(),
+            sender,
+            text,
+            timestamp,
+            partial: false,
+        }
+    }
+
+    /// Whether `id` is the IMSI, ICCID or one of the own numbers of the receiving SIM
+    pub fn matches_sim(&self, id: &str) -> bool {
+        self.imsi == id || self.iccid == id || self.own_numbers.iter().any(|n| n == id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Criteria for listing stored messages. Results are ordered by id, which
+/// is the order messages were stored in.
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilter {
+    /// IMSI, ICCID or own number of the receiving SIM; None means all SIMs
+    pub sim: Option<String>,
+    /// Only messages received by these IMSIs; empty means all
+    pub imsis: Vec<String>,
+    pub sender: Option<String>,
+    pub imei: Option<String>,
+    pub after: Option<DateTime<Utc>>,
+    pub before: Option<DateTime<Utc>>,
+    /// Cursor: only messages that come after this id in `order`
+    pub after_id: Option<i64>,
+    pub order: SortOrder,
+    /// None returns every match
+    pub limit: Option<usize>,
+}
+
+/// One page of a message listing
+#[derive(Debug)]
+pub struct MessagePage {
+    pub messages: Vec<SmsMessage>,
+    /// Id to pass as `after_id` for the next page; None on the last page
+    pub next_cursor: Option<i64>,
+}
+
+/// A full-text search match
+#[derive(Debug)]
+pub struct SearchResult {
+    pub message: SmsMessage,
+    /// HTML-escaped excerpt around the match, with matched terms wrapped in
+    /// `<mark>` tags
+    pub snippet: String,
+}
+
+/// One page of search results, paginated like `MessagePage`
+#[derive(Debug)]
+pub struct SearchPage {
+    pub results: Vec<SearchResult>,
+    pub next_cursor: Option<i64>,
+}
+
+/// An incomplete multipart SMS that is still waiting for its remaining parts
+#[derive(Debug, Clone)]
+pub struct PendingMessage {
+    pub modem_path: String,
+    pub sms_path: String,
+    /// The message as it would be stored now
+    pub message: SmsMessage,
+    pub first_seen: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutgoingStatus {
+    /// Waiting for its send time, a free slot on the SIM or a retry
+    Queued,
+    /// Handed to the modem
+    Sending,
+    Sent,
+    /// Every attempt failed
+    Failed,
+    /// Cancelled through the API before it was sent
+    Cancelled,
+}
+
+impl OutgoingStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutgoingStatus::Queued => "queued",
+            OutgoingStatus::Sending => "sending",
+            OutgoingStatus::Sent => "sent",
+            OutgoingStatus::Failed => "failed",
+            OutgoingStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl std::str::FromStr for OutgoingStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "queued" => Ok(OutgoingStatus::Queued),
+            "sending" => Ok(OutgoingStatus::Sending),
+            "sent" => Ok(OutgoingStatus::Sent),
+            "failed" => Ok(OutgoingStatus::Failed),
+            "cancelled" => Ok(OutgoingStatus::Cancelled),
+            _ => anyhow::bail!("Unknown outgoing message status '{}'", s),
+        }
+    }
+}
+
+/// What is known about the delivery of a sent SMS to its recipient
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// No final status report yet; the network may still be trying
+    Pending,
+    Delivered,
+    /// The network gave up on delivering the SMS
+    Failed,
+    /// No final status report arrived within the tracking window
+    Unknown,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+            DeliveryStatus::Unknown => "unknown",
+        }
+    }
+
+    /// Interprets a TP-Status value from a status report (3GPP TS 23.040
+    /// section 9.2.3.15). Values outside TP-Status, such as CDMA causes,
+    /// leave the delivery pending.
+    pub fn from_delivery_state(state: u32) -> Self {
+        match state {
+            // Transaction completed, including forwarded and replaced SMS
+            0x00..=0x1f => DeliveryStatus::Delivered,
+            // Temporary error, the service centre keeps trying
+            0x20..=0x3f => DeliveryStatus::Pending,
+            // Permanent error, or a temporary one the service centre gave up on
+            0x40..=0x7f => DeliveryStatus::Failed,
+            _ => DeliveryStatus::Pending,
+        }
+    }
+}
+
+impl std::str::FromStr for DeliveryStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pending" => Ok(DeliveryStatus::Pending),
+            "delivered" => Ok(DeliveryStatus::Delivered),
+            "failed" => Ok(DeliveryStatus::Failed),
+            "unknown" => Ok(DeliveryStatus::Unknown),
+            _ => anyhow::bail!("Unknown delivery status '{}'", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutgoingMessage {
+    pub id: Option<i64>,
+    pub imei: String,
+    pub imsi: String,
+    pub iccid: String,
+    pub recipient: String,
+    pub text: String,
+    pub status: OutgoingStatus,
+    /// Error of the latest failed attempt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// When the message was submitted
+    pub timestamp: DateTime<Utc>,
+    /// Alphabet the text is sent in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<Encoding>,
+    /// SMS parts the text is sent as, each billed as one SMS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub segments: Option<u32>,
+    /// Requested send time of a scheduled message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub send_at: Option<DateTime<Utc>>,
+    pub attempts: u32,
+    /// When a queued message is due to be sent next
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<DateTime<Utc>>,
+    /// Modem and object the sent SMS is kept at until its delivery is final
+    #[serde(skip)]
+    pub modem_path: Option<String>,
+    #[serde(skip)]
+    pub sms_path: Option<String>,
+    /// Reference the network matches the status report with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_reference: Option<u32>,
+    /// None for messages that were not sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delivery_status: Option<DeliveryStatus>,
+    /// TP-Status of the latest status report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delivery_state: Option<u32>,
+    /// When the network delivered the SMS or gave up, from the status report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reported_at: Option<DateTime<Utc>>,
+}
+
+impl OutgoingMessage {
+    /// Creates an unsaved message queued on the SIM of `modem`, to be sent
+    /// at `send_at` or as soon as possible
+    pub fn queued(
+        modem: &ModemInfo,
+        recipient: String,
+        text: String,
+        send_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        let now = Utc::now();
+        let segments = Segments::of(&text);
+        Self {
+            id: None,
+            imei: modem.imei.clone(),
+            imsi: modem.imsi.clone(),
+            iccid: modem.iccid.clone(),
+            encoding: Some(segments.encoding),
+            segments: Some(segments.parts.len() as u32),
+            recipient,
+            text,
+            status: OutgoingStatus::Queued,
+            error: None,
+            timestamp: now,
+            send_at,
+            attempts: 0,
+            next_attempt_at: Some(send_at.unwrap_or(now).max(now)),
+            sent_at: None,
+            modem_path: None,
+            sms_path: None,
+            message_reference: None,
+            delivery_status: None,
+            delivery_state: None,
+            reported_at: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// List and stream messages
+    Read,
+    /// Send messages
+    Send,
+    /// Read metrics and the modem list, for monitoring
+    Metrics,
+    /// Everything
+    Admin,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Send => "send",
+            Permission::Metrics => "metrics",
+            Permission::Admin => "admin",
+        }
+    }
+}
+
+impl std::str::FromStr for Permission {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read" => Ok(Permission::Read),
+            "send" => Ok(Permission::Send),
+            "metrics" => Ok(Permission::Metrics),
+            "admin" => Ok(Permission::Admin),
+            _ => anyhow::bail!(
+                "Unknown permission '{}', expected read, send, metrics or admin",
+                s
+            ),
+        }
+    }
+}
+
+/// An API key as stored; the key itself is only kept as a SHA-256 hash
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    /// First characters of the key, to tell keys apart
+    pub prefix: String,
+    /// IMSIs the key may access; empty means all SIMs
+    pub imsis: Vec<String>,
+    pub permissions: Vec<Permission>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions
+            .iter()
+            .any(|p| *p == permission || *p == Permission::Admin)
+    }
+
+    pub fn allows_imsi(&self, imsi: &str) -> bool {
+        self.imsis.is_empty() || self.imsis.iter().any(|i| i == imsi)
+    }
+}
+
+/// A mutating API request made with an `Idempotency-Key`
+#[derive(Debug, Clone)]
+pub struct IdempotencyRecord {
+    pub method: String,
+    pub path: String,
+    /// SHA-256 of the request body
+    pub request_hash: String,
+    /// None while the first request is still processed
+    pub response: Option<StoredResponse>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub message_id: i64,
+    pub url: String,
+    pub payload: String,
+    pub attempts: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UssdSessionState {
+    /// The network waits for a response
+    Active,
+    /// The network ended the session with its last reply
+    Completed,
+    Cancelled,
+    /// The modem or network returned an error
+    Failed,
+}
+
+impl UssdSessionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UssdSessionState::Active => "active",
+            UssdSessionState::Completed => "completed",
+            UssdSessionState::Cancelled => "cancelled",
+            UssdSessionState::Failed => "failed",
+        }
+    }
+}
+
+impl std::str::FromStr for UssdSessionState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "active" => Ok(UssdSessionState::Active),
+            "completed" => Ok(UssdSessionState::Completed),
+            "cancelled" => Ok(UssdSessionState::Cancelled),
+            "failed" => Ok(UssdSessionState::Failed),
+            _ => anyhow::bail!("Unknown USSD session state '{}'", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UssdDirection {
+    /// A code or response sent to the network
+    Sent,
+    /// A reply from the network
+    Received,
+}
+
+impl UssdDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UssdDirection::Sent => "sent",
+            UssdDirection::Received => "received",
+        }
+    }
+}
+
+/// One line of a USSD session transcript
+#[derive(Debug, Clone, Serialize)]
+pub struct UssdMessage {
+    pub direction: UssdDirection,
+    pub text: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UssdSession {
+    pub id: i64,
+    pub imei: String,
+    pub imsi: String,
+    pub iccid: String,
+    /// The code the session was started with
+    pub command: String,
+    pub state: UssdSessionState,
+    /// Started by a scheduled job rather than through the API
+    pub scheduled: bool,
+    /// What a scheduled job parsed from the reply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub transcript: Vec<UssdMessage>,
+}
+
+/// One page of USSD sessions, newest first, paginated like `MessagePage`
+#[derive(Debug)]
+pub struct UssdSessionPage {
+    pub sessions: Vec<UssdSession>,
+    pub next_cursor: Option<i64>,
 }
 
+/// How long a statement waits for a lock held by another connection
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Database {
     conn: Connection,
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl Database {
+    /// Opens the database and applies pending migrations. Only the daemon
+    /// and `samson db migrate` do this; other commands use `open`.
     pub fn new(path: &str) -> Result<Self> {
-        let conn = Connection::open(path)?;
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS messages (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                imei TEXT NOT NULL,
-                imsi TEXT NOT NULL,
-                sender TEXT NOT NULL,
-                text TEXT NOT NULL,
-                timestamp TEXT NOT NULL
-            )",
-            [],
-        )?;
+        let mut conn = Self::connect(path)?;
+        migrations::migrate(&mut conn)?;
 
-        conn.execute("CREATE INDEX IF NOT EXISTS idx_imei ON messages(imei)", [])?;
-        conn.execute("CREATE INDEX IF NOT EXISTS idx_imsi ON messages(imsi)", [])?;
+        Ok(Self {
+            conn,
+            metrics: None,
+        })
+    }
 
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_timestamp ON messages(timestamp)",
-            [],
-        )?;
+    /// Opens a database that is already at the current schema version,
+    /// refusing older and newer ones
+    pub fn open(path: &str) -> Result<Self> {
+        let conn = Self::connect(path)?;
+        migrations::ensure_current(&conn)?;
 
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            metrics: None,
+        })
     }
 
-    pub fn insert_message(&self, msg: &SmsMessage) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO messages (imei, imsi, sender, text, timestamp) VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![msg.imei, msg.imsi, msg.sender, msg.text, msg.timestamp.to_rfc3339(),],
+    /// Opens a connection that waits for locks held by other processes, such
+    /// as the CLI next to a running daemon, instead of failing right away
+    fn connect(path: &str) -> Result<Connection> {
+        let conn =
+            Connection::open(path).with_context(|| format!("Failed to open database {}", path))?;
+        conn.busy_timeout(BUSY_TIMEOUT)
+            .context("Failed to set database busy timeout")?;
+        // WAL lets readers continue while another connection writes. In-memory
+        // databases stay in their own journal mode.
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))
+            .context("Failed to enable WAL journal mode")?;
+        Ok(conn)
+    }
+
+    pub fn schema_version(&self) -> Result<u32> {
+        migrations::schema_version(&self.conn)
+    }
+
+    /// Records the duration of the frequent queries in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Times a query until the returned guard is dropped
+    fn time_query(&self, query: &str) -> Option<HistogramTimer> {
+        self.metrics.as_ref().map(|metrics| {
+            metrics
+                .db_query_duration
+                .with_label_values(&[query])
+                .start_timer()
+        })
+    }
+
+    pub fn insert_message(&self, msg: &SmsMessage) -> Result<i64> {
+        let _timer = self.time_query("insert_message");
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO messages (imei, imsi, iccid, operator_id, operator_name, own_numbers, sender, text, timestamp, partial)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                msg.imei,
+                msg.imsi,
+                msg.iccid,
+                msg.operator_id,
+                msg.operator_name,
+                join_list(&msg.own_numbers),
+                msg.sender,
+                msg.text,
+                msg.timestamp.to_rfc3339(),
+                msg.partial,
+            ],
         )?;
-        Ok(())
+        let id = tx.last_insert_rowid();
+        self.record_sim_identifiers(&msg.imsi, &msg.iccid, &msg.own_numbers)?;
+        tx.commit()?;
+        Ok(id)
     }
 
-    pub fn get_messages(
+    /// Remembers that the ICCID and own numbers belong to `imsi`, for
+    /// `get_sim_imsis`
+    fn record_sim_identifiers(
         &self,
-        imsi: Option<&str>,
-        after: Option<DateTime<Utc>>,
-    ) -> Result<Vec<SmsMessage>> {
-        let (query, params) = self.build_query(imsi, after);
+        imsi: &str,
+        iccid: &str,
+        own_numbers: &[String],
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(
+            "INSERT OR IGNORE INTO sim_identifiers (identifier, imsi) VALUES (?1, ?2)",
+        )?;
+        for identifier in std::iter::once(iccid).chain(own_numbers.iter().map(String::as_str)) {
+            if !identifier.is_empty() {
+                stmt.execute(params![identifier, imsi])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the messages matching `filter`, up to its limit
+    pub fn get_messages(&self, filter: &MessageFilter) -> Result<MessagePage> {
+        let _timer = self.time_query("get_messages");
+        let (query, params) = self.build_query(filter, None)?;
 
         let mut stmt = self
             .conn
@@ -69,69 +613,1480 @@ impl Database {
 
         let param_refs: Vec<&dyn rusqlite::ToSql> = params.iter().map(|p| p.as_ref()).collect();
 
-        let messages = stmt
+        let mut messages = stmt
+            .query_map(param_refs.as_slice(), message_from_row)
+            .context("Failed to query messages")?
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to collect message results")?;
+
+        let next_cursor = paginate(&mut messages, filter.limit, |m| m.id);
+
+        Ok(MessagePage {
+            messages,
+            next_cursor,
+        })
+    }
+
+    /// Finds messages whose text matches `search` (see `fts_query` for the
+    /// syntax) among those matching `filter`. A search without any terms
+    /// matches nothing.
+    pub fn search_messages(&self, search: &str, filter: &MessageFilter) -> Result<SearchPage> {
+        let _timer = self.time_query("search_messages");
+        let Some(search) = fts_query(search) else {
+            return Ok(SearchPage {
+                results: Vec::new(),
+                next_cursor: None,
+            });
+        };
+        let (query, params) = self.build_query(filter, Some(&search))?;
+
+        let mut stmt = self
+            .conn
+            .prepare(&query)
+            .context("Failed to prepare search query")?;
+
+        let param_refs: Vec<&dyn rusqlite::ToSql> = params.iter().map(|p| p.as_ref()).collect();
+
+        let mut results = stmt
             .query_map(param_refs.as_slice(), |row| {
-                let timestamp_str: String = row.get(5)?;
-                let timestamp = parse_rfc3339_timestamp(&timestamp_str).map_err(|e| {
-                    rusqlite::Error::FromSqlConversionFailure(
-                        5,
-                        rusqlite::types::Type::Text,
-                        Box::new(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
-                    )
-                })?;
-
-                Ok(SmsMessage {
-                    id: Some(row.get(0)?),
-                    imei: row.get(1)?,
-                    imsi: row.get(2)?,
-                    sender: row.get(3)?,
-                    text: row.get(4)?,
-                    timestamp,
+                Ok(SearchResult {
+                    message: message_from_row(row)?,
+                    snippet: highlight_snippet(&row.get::<_, String>(MESSAGE_COLUMN_COUNT)?),
                 })
             })
-            .context("Failed to query messages")?
+            .context("Failed to search messages")?
             .collect::<Result<Vec<_>, _>>()
-            .context("Failed to collect message results")?;
+            .context("Failed to collect search results")?;
 
-        Ok(messages)
+        let next_cursor = paginate(&mut results, filter.limit, |r| r.message.id);
+
+        Ok(SearchPage {
+            results,
+            next_cursor,
+        })
     }
 
+    /// Builds the listing query for `filter`, restricted to full-text
+    /// matches of the FTS5 expression `search` if given
     fn build_query(
         &self,
-        imsi: Option<&str>,
-        after: Option<DateTime<Utc>>,
-    ) -> (String, Vec<Box<dyn rusqlite::ToSql>>) {
-        let mut query =
-            String::from("SELECT id, imei, imsi, sender, text, timestamp FROM messages WHERE 1=1");
+        filter: &MessageFilter,
+        search: Option<&str>,
+    ) -> Result<(String, Vec<Box<dyn rusqlite::ToSql>>)> {
         let mut params: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
         let mut param_num = 1;
 
-        if let Some(imsi) = imsi {
-            query.push_str(&format!(" AND imsi = ?{}", param_num));
-            params.push(Box::new(imsi.to_string()));
+        let mut query = match search {
+            Some(search) => {
+                params.push(Box::new(SNIPPET_MATCH_START.to_string()));
+                params.push(Box::new(SNIPPET_MATCH_END.to_string()));
+                params.push(Box::new(search.to_string()));
+                param_num += 3;
+                format!(
+                    "SELECT {}, snippet(messages_fts, 0, ?1, ?2, '…', {}) FROM messages \
+                     JOIN messages_fts ON messages_fts.rowid = messages.id \
+                     WHERE messages_fts MATCH ?3",
+                    MESSAGE_COLUMNS, SNIPPET_TOKENS
+                )
+            }
+            None => format!("SELECT {} FROM messages WHERE 1=1", MESSAGE_COLUMNS),
+        };
+
+        // Resolved up front so that only the indexed `imsi` column is
+        // filtered on
+        let sim_imsis = match &filter.sim {
+            Some(sim) => Some(self.get_sim_imsis(sim)?),
+            None => None,
+        };
+        let imsi_sets = sim_imsis
+            .iter()
+            .chain((!filter.imsis.is_empty()).then_some(&filter.imsis));
+        for imsis in imsi_sets {
+            let placeholders: Vec<String> = (0..imsis.len())
+                .map(|i| format!("?{}", param_num + i))
+                .collect();
+            query.push_str(&format!(" AND imsi IN ({})", placeholders.join(", ")));
+            for imsi in imsis {
+                params.push(Box::new(imsi.clone()));
+            }
+            param_num += imsis.len();
+        }
+
+        if let Some(sender) = &filter.sender {
+            query.push_str(&format!(" AND sender = ?{}", param_num));
+            params.push(Box::new(sender.clone()));
+            param_num += 1;
+        }
+
+        if let Some(imei) = &filter.imei {
+            query.push_str(&format!(" AND imei = ?{}", param_num));
+            params.push(Box::new(imei.clone()));
             param_num += 1;
         }
 
-        if let Some(after) = after {
+        if let Some(after) = filter.after {
             query.push_str(&format!(" AND timestamp > ?{}", param_num));
             params.push(Box::new(after.to_rfc3339()));
+            param_num += 1;
+        }
+
+        if let Some(before) = filter.before {
+            query.push_str(&format!(" AND timestamp < ?{}", param_num));
+            params.push(Box::new(before.to_rfc3339()));
+            param_num += 1;
+        }
+
+        if let Some(after_id) = filter.after_id {
+            let op = match filter.order {
+                SortOrder::Asc => ">",
+                SortOrder::Desc => "<",
+            };
+            query.push_str(&format!(" AND id {} ?{}", op, param_num));
+            params.push(Box::new(after_id));
+            param_num += 1;
+        }
+
+        query.push_str(match filter.order {
+            SortOrder::Asc => " ORDER BY id ASC",
+            SortOrder::Desc => " ORDER BY id DESC",
+        });
+
+        if let Some(limit) = filter.limit {
+            query.push_str(&format!(" LIMIT ?{}", param_num));
+            params.push(Box::new(limit as i64 + 1));
         }
 
-        query.push_str(" ORDER BY timestamp ASC");
+        Ok((query, params))
+    }
 
-        (query, params)
+    /// Id of the last stored message, or 0 if there is none
+    pub fn last_message_id(&self) -> Result<i64> {
+        let id = self
+            .conn
+            .query_row("SELECT COALESCE(MAX(id), 0) FROM messages", [], |row| {
+                row.get(0)
+            })?;
+        Ok(id)
     }
 
     pub fn message_exists(&self, msg: &SmsMessage) -> Result<bool> {
+        let _timer = self.time_query("message_exists");
         let mut stmt = self.conn.prepare(
-            "SELECT COUNT(*) FROM messages WHERE imsi = ?1 AND sender = ?2 AND text = ?3 AND timestamp = ?4"
+            "SELECT COUNT(*) FROM messages WHERE iccid = ?1 AND sender = ?2 AND text = ?3 AND timestamp = ?4"
         )?;
 
         let count: i64 = stmt.query_row(
-            params![msg.imsi, msg.sender, msg.text, msg.timestamp.to_rfc3339(),],
+            params![msg.iccid, msg.sender, msg.text, msg.timestamp.to_rfc3339(),],
             |row| row.get(0),
         )?;
 
         Ok(count > 0)
     }
+
+    pub fn insert_outgoing_message(&self, msg: &OutgoingMessage) -> Result<i64> {
+        let _timer = self.time_query("insert_outgoing_message");
+        self.conn.execute(
+            "INSERT INTO outgoing_messages (imei, imsi, iccid, recipient, text, status, error, timestamp,
+                 send_at, attempts, next_attempt_at, sent_at,
+                 modem_path, sms_path, message_reference, delivery_status, delivery_state, reported_at,
+                 encoding, segments)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18,
+                 ?19, ?20)",
+            params![
+                msg.imei,
+                msg.imsi,
+                msg.iccid,
+                msg.recipient,
+                msg.text,
+                msg.status.as_str(),
+                msg.error,
+                msg.timestamp.to_rfc3339(),
+                msg.send_at.map(|t| t.to_rfc3339()),
+                msg.attempts,
+                msg.next_attempt_at.map(|t| t.to_rfc3339()),
+                msg.sent_at.map(|t| t.to_rfc3339()),
+                msg.modem_path,
+                msg.sms_path,
+                msg.message_reference,
+                msg.delivery_status.map(|status| status.as_str()),
+                msg.delivery_state,
+                msg.reported_at.map(|t| t.to_rfc3339()),
+                msg.encoding.map(|e| e.as_str()),
+                msg.segments,
+            ],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Inserts messages that belong together, such as the parts of a split
+    /// text, all or none of them
+    pub fn insert_outgoing_messages(&self, msgs: &[OutgoingMessage]) -> Result<Vec<i64>> {
+        let tx = self.conn.unchecked_transaction()?;
+        let ids = msgs
+            .iter()
+            .map(|msg| self.insert_outgoing_message(msg))
+            .collect::<Result<Vec<_>>>()?;
+        tx.commit()?;
+        Ok(ids)
+    }
+
+    pub fn get_outgoing_message(&self, id: i64) -> Result<Option<OutgoingMessage>> {
+        self.conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM outgoing_messages WHERE id = ?1",
+                    OUTGOING_COLUMNS
+                ),
+                params![id],
+                outgoing_from_row,
+            )
+            .optional()
+            .context("Failed to query outgoing message")
+    }
+
+    /// IMSIs with queued messages due by `now`
+    pub fn get_due_outgoing_imsis(&self, now: DateTime<Utc>) -> Result<Vec<String>> {
+        let _timer = self.time_query("get_due_outgoing_imsis");
+        let mut stmt = self.conn.prepare(
+            "SELECT DISTINCT imsi FROM outgoing_messages
+             WHERE status = ?1 AND next_attempt_at <= ?2",
+        )?;
+
+        stmt.query_map(
+            params![OutgoingStatus::Queued.as_str(), now.to_rfc3339()],
+            |row| row.get(0),
+        )
+        .context("Failed to query outbox")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect outbox SIMs")
+    }
+
+    /// Queued messages of a SIM due by `now`, in the order they are due
+    pub fn get_due_outgoing_messages(
+        &self,
+        imsi: &str,
+        now: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<Vec<OutgoingMessage>> {
+        let _timer = self.time_query("get_due_outgoing_messages");
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM outgoing_messages
+             WHERE imsi = ?1 AND status = ?2 AND next_attempt_at <= ?3
+             ORDER BY next_attempt_at, id LIMIT ?4",
+            OUTGOING_COLUMNS
+        ))?;
+
+        stmt.query_map(
+            params![
+                imsi,
+                OutgoingStatus::Queued.as_str(),
+                now.to_rfc3339(),
+                limit as i64
+            ],
+            outgoing_from_row,
+        )
+        .context("Failed to query due outgoing messages")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect due outgoing messages")
+    }
+
+    /// Moves a queued message to `sending`. Returns false if it is no
+    /// longer queued, e.g. because it was cancelled.
+    pub fn claim_outgoing_message(&self, id: i64) -> Result<bool> {
+        let updated = self.conn.execute(
+            "UPDATE outgoing_messages SET status = ?2 WHERE id = ?1 AND status = ?3",
+            params![
+                id,
+                OutgoingStatus::Sending.as_str(),
+                OutgoingStatus::Queued.as_str()
+            ],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Records a successful send; the delivery is followed from now on
+    pub fn mark_outgoing_sent(
+        &self,
+        id: i64,
+        attempts: u32,
+        modem_path: &str,
+        sent: &SentSms,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE outgoing_messages SET status = ?2, attempts = ?3, error = NULL,
+                 next_attempt_at = NULL, sent_at = ?4, modem_path = ?5, sms_path = ?6,
+                 message_reference = ?7, delivery_status = ?8
+             WHERE id = ?1",
+            params![
+                id,
+                OutgoingStatus::Sent.as_str(),
+                attempts,
+                Utc::now().to_rfc3339(),
+                modem_path,
+                sent.sms_path,
+                sent.message_reference,
+                DeliveryStatus::Pending.as_str(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Queues a message again after a failed attempt
+    pub fn schedule_outgoing_retry(
+        &self,
+        id: i64,
+        attempts: u32,
+        next_attempt_at: DateTime<Utc>,
+        error: &str,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE outgoing_messages SET status = ?2, attempts = ?3, next_attempt_at = ?4, error = ?5
+             WHERE id = ?1",
+            params![
+                id,
+                OutgoingStatus::Queued.as_str(),
+                attempts,
+                next_attempt_at.to_rfc3339(),
+                error
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn mark_outgoing_failed(&self, id: i64, attempts: u32, error: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE outgoing_messages SET status = ?2, attempts = ?3, next_attempt_at = NULL, error = ?4
+             WHERE id = ?1",
+            params![id, OutgoingStatus::Failed.as_str(), attempts, error],
+        )?;
+        Ok(())
+    }
+
+    /// Cancels a queued message. Returns false if it is not queued.
+    pub fn cancel_outgoing_message(&self, id: i64) -> Result<bool> {
+        let updated = self.conn.execute(
+            "UPDATE outgoing_messages SET status = ?2, next_attempt_at = NULL
+             WHERE id = ?1 AND status = ?3",
+            params![
+                id,
+                OutgoingStatus::Cancelled.as_str(),
+                OutgoingStatus::Queued.as_str()
+            ],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Postpones the queued messages of a SIM that are due before `until`
+    pub fn defer_outgoing_messages(&self, imsi: &str, until: DateTime<Utc>) -> Result<usize> {
+        let updated = self.conn.execute(
+            "UPDATE outgoing_messages SET next_attempt_at = ?3
+             WHERE imsi = ?1 AND status = ?2 AND next_attempt_at < ?3",
+            params![imsi, OutgoingStatus::Queued.as_str(), until.to_rfc3339()],
+        )?;
+        Ok(updated)
+    }
+
+    /// Send times of the messages a SIM sent since `since`, oldest first
+    pub fn get_send_times(&self, imsi: &str, since: DateTime<Utc>) -> Result<Vec<DateTime<Utc>>> {
+        let _timer = self.time_query("get_send_times");
+        let mut stmt = self.conn.prepare(
+            "SELECT sent_at FROM outgoing_messages
+             WHERE imsi = ?1 AND status = ?2 AND sent_at >= ?3
+             ORDER BY sent_at",
+        )?;
+
+        stmt.query_map(
+            params![imsi, OutgoingStatus::Sent.as_str(), since.to_rfc3339()],
+            |row| timestamp_column(row, 0),
+        )
+        .context("Failed to query send times")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect send times")
+    }
+
+    /// Fails messages left in `sending` by a shutdown. They may or may not
+    /// have reached the network, and are not retried so that recipients do
+    /// not get them twice. Returns the number of messages.
+    pub fn fail_interrupted_outgoing_messages(&self) -> Result<usize> {
+        let updated = self.conn.execute(
+            "UPDATE outgoing_messages SET status = ?1, next_attempt_at = NULL,
+                 error = 'Interrupted while sending, the message may have been sent'
+             WHERE status = ?2",
+            params![
+                OutgoingStatus::Failed.as_str(),
+                OutgoingStatus::Sending.as_str()
+            ],
+        )?;
+        Ok(updated)
+    }
+
+    /// Sent messages still waiting for a final status report, oldest first
+    pub fn get_pending_deliveries(&self) -> Result<Vec<OutgoingMessage>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM outgoing_messages WHERE delivery_status = ?1 ORDER BY id",
+            OUTGOING_COLUMNS
+        ))?;
+
+        stmt.query_map(params![DeliveryStatus::Pending.as_str()], outgoing_from_row)
+            .context("Failed to query pending deliveries")?
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to collect pending deliveries")
+    }
+
+    /// Finds the pending message a status report from a SIM refers to.
+    /// Message references repeat after 256 messages, so the newest match
+    /// wins, preferring one sent to the reported recipient.
+    pub fn find_pending_delivery(
+        &self,
+        imsi: &str,
+        recipient: &str,
+        message_reference: u32,
+    ) -> Result<Option<OutgoingMessage>> {
+        self.conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM outgoing_messages
+                     WHERE imsi = ?1 AND message_reference = ?2 AND delivery_status = ?3
+                     ORDER BY recipient = ?4 DESC, id DESC LIMIT 1",
+                    OUTGOING_COLUMNS
+                ),
+                params![
+                    imsi,
+                    message_reference,
+                    DeliveryStatus::Pending.as_str(),
+                    recipient
+                ],
+                outgoing_from_row,
+            )
+            .optional()
+            .context("Failed to match status report")
+    }
+
+    pub fn update_delivery(
+        &self,
+        id: i64,
+        status: DeliveryStatus,
+        delivery_state: Option<u32>,
+        reported_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE outgoing_messages SET delivery_status = ?2,
+                 delivery_state = COALESCE(?3, delivery_state), reported_at = COALESCE(?4, reported_at)
+             WHERE id = ?1",
+            params![
+                id,
+                status.as_str(),
+                delivery_state,
+                reported_at.map(|t| t.to_rfc3339())
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Fills in the identifiers of a SIM on rows stored before they were
+    /// recorded separately, which hold the ICCID in the `imsi` column.
+    /// Returns the number of updated messages.
+    pub fn backfill_sim_identity(&self, modem: &ModemInfo) -> Result<usize> {
+        if modem.iccid.is_empty() || modem.imsi.is_empty() || modem.imsi == modem.iccid {
+            return Ok(0);
+        }
+
+        let updated = self.conn.execute(
+            "UPDATE messages SET imsi = ?2, operator_id = ?3, operator_name = ?4, own_numbers = ?5
+             WHERE iccid = ?1 AND imsi = ?1",
+            params![
+                modem.iccid,
+                modem.imsi,
+                modem.operator_id,
+                modem.operator_name,
+                join_list(&modem.own_numbers),
+            ],
+        )?;
+        self.conn.execute(
+            "UPDATE outgoing_messages SET imsi = ?2 WHERE iccid = ?1 AND imsi = ?1",
+            params![modem.iccid, modem.imsi],
+        )?;
+        if updated > 0 {
+            self.conn.execute(
+                "DELETE FROM sim_identifiers WHERE imsi = ?1",
+                params![modem.iccid],
+            )?;
+            self.record_sim_identifiers(&modem.imsi, &modem.iccid, &modem.own_numbers)?;
+        }
+
+        Ok(updated)
+    }
+
+    /// Deletes up to `limit` of the oldest messages with a timestamp before
+    /// `cutoff`, either for a single IMSI or for all IMSIs but `exclude_imsis`
+    pub fn delete_messages_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        imsi: Option<&str>,
+        exclude_imsis: &[String],
+        limit: usize,
+    ) -> Result<usize> {
+        let _timer = self.time_query("delete_messages_before");
+        let mut query = "SELECT id FROM messages WHERE timestamp < ?1".to_string();
+        let mut params: Vec<Box<dyn rusqlite::ToSql>> =
+            vec![Box::new(cutoff.to_rfc3339()), Box::new(limit as i64)];
+
+        if let Some(imsi) = imsi {
+            query.push_str(&format!(" AND imsi = ?{}", params.len() + 1));
+            params.push(Box::new(imsi.to_string()));
+        }
+
+        if !exclude_imsis.is_empty() {
+            let placeholders: Vec<String> = (0..exclude_imsis.len())
+                .map(|i| format!("?{}", params.len() + 1 + i))
+                .collect();
+            query.push_str(&format!(" AND imsi NOT IN ({})", placeholders.join(", ")));
+            for imsi in exclude_imsis {
+                params.push(Box::new(imsi.clone()));
+            }
+        }
+
+        let param_refs: Vec<&dyn rusqlite::ToSql> = params.iter().map(|p| p.as_ref()).collect();
+        let deleted = self
+            .conn
+            .execute(
+                &format!(
+                    "DELETE FROM messages WHERE id IN ({} ORDER BY id LIMIT ?2)",
+                    query
+                ),
+                param_refs.as_slice(),
+            )
+            .context("Failed to delete expired messages")?;
+
+        Ok(deleted)
+    }
+
+    /// Deletes up to `limit` of the oldest messages beyond the newest `keep`
+    pub fn delete_excess_messages(&self, keep: u64, limit: usize) -> Result<usize> {
+        let _timer = self.time_query("delete_excess_messages");
+        if keep == 0 {
+            anyhow::bail!("At least one message must be kept");
+        }
+
+        let deleted = self
+            .conn
+            .execute(
+                "DELETE FROM messages WHERE id IN (
+                    SELECT id FROM messages
+                    WHERE id < (SELECT id FROM messages ORDER BY id DESC LIMIT 1 OFFSET ?1)
+                    ORDER BY id LIMIT ?2
+                )",
+                params![keep as i64 - 1, limit as i64],
+            )
+            .context("Failed to delete excess messages")?;
+
+        Ok(deleted)
+    }
+
+    /// Removes finished webhook deliveries of deleted messages, as their
+    /// payload still holds the message text
+    pub fn delete_orphaned_webhook_deliveries(&self) -> Result<usize> {
+        let deleted = self.conn.execute(
+            "DELETE FROM webhook_deliveries
+             WHERE status != 'pending' AND message_id NOT IN (SELECT id FROM messages)",
+            [],
+        )?;
+        Ok(deleted)
+    }
+
+    /// Whether the database uses incremental auto-vacuum, without which
+    /// `PRAGMA incremental_vacuum` releases nothing
+    pub fn incremental_vacuum_enabled(&self) -> Result<bool> {
+        let mode: i64 = self
+            .conn
+            .pragma_query_value(None, "auto_vacuum", |row| row.get(0))?;
+
+        // 2 is INCREMENTAL
+        Ok(mode == 2)
+    }
+
+    /// Switches the database to incremental auto-vacuum. Changing the mode of
+    /// an existing database takes a full VACUUM, which blocks every other
+    /// user of the file, so this is only done by `samson db vacuum`.
+    /// Returns whether the mode was changed.
+    pub fn enable_incremental_vacuum(&self) -> Result<bool> {
+        if self.incremental_vacuum_enabled()? {
+            return Ok(false);
+        }
+
+        self.conn
+            .pragma_update(None, "auto_vacuum", "INCREMENTAL")
+            .context("Failed to enable incremental vacuum")?;
+        self.conn
+            .execute_batch("VACUUM")
+            .context("Failed to vacuum database")?;
+        Ok(true)
+    }
+
+    /// Returns the space of deleted rows to the file system
+    pub fn vacuum(&self, mode: VacuumMode) -> Result<()> {
+        let statement = match mode {
+            VacuumMode::Off => return Ok(()),
+            VacuumMode::Incremental => "PRAGMA incremental_vacuum",
+            VacuumMode::Full => "VACUUM",
+        };
+
+        self.conn
+            .execute_batch(statement)
+            .context("Failed to vacuum database")?;
+        // In WAL mode the file only shrinks once the log is checkpointed
+        self.conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+            .context("Failed to checkpoint database")
+    }
+
+    /// Checks that the database can be read and written. The write rewrites
+    /// the schema version in a transaction that is rolled back, so nothing
+    /// changes but the journal has to be created.
+    pub fn check_health(&self) -> Result<()> {
+        let _timer = self.time_query("check_health");
+        if self.conn.is_readonly(rusqlite::DatabaseName::Main)? {
+            anyhow::bail!("Database is read-only");
+        }
+
+        let version: i64 = self
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .context("Failed to read from database")?;
+        self.conn
+            .execute_batch(&format!(
+                "BEGIN IMMEDIATE; PRAGMA user_version = {}; ROLLBACK;",
+                version
+            ))
+            .inspect_err(|_| {
+                // Leave no transaction open if the write failed halfway
+                let _ = self.conn.execute_batch("ROLLBACK");
+            })
+            .context("Failed to write to database")
+    }
+
+    pub fn enqueue_webhook_delivery(
+        &self,
+        message_id: i64,
+        url: &str,
+        payload: &str,
+    ) -> Result<()> {
+        let _timer = self.time_query("enqueue_webhook_delivery");
+        let now = Utc::now().to_rfc3339();
+        self.conn.execute(
+            "INSERT INTO webhook_deliveries (message_id, url, payload, status, next_attempt_at, created_at) VALUES (?1, ?2, ?3, 'pending', ?4, ?4)",
+            params![message_id, url, payload, now],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_due_webhook_deliveries(
+        &self,
+        now: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<Vec<WebhookDelivery>> {
+        let _timer = self.time_query("get_due_webhook_deliveries");
+        let mut stmt = self.conn.prepare(
+            "SELECT id, message_id, url, payload, attempts FROM webhook_deliveries
+             WHERE status = 'pending' AND next_attempt_at <= ?1
+             ORDER BY next_attempt_at ASC LIMIT ?2",
+        )?;
+
+        let deliveries = stmt
+            .query_map(params![now.to_rfc3339(), limit as i64], |row| {
+                Ok(WebhookDelivery {
+                    id: row.get(0)?,
+                    message_id: row.get(1)?,
+                    url: row.get(2)?,
+                    payload: row.get(3)?,
+                    attempts: row.get(4)?,
+                })
+            })
+            .context("Failed to query webhook deliveries")?
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to collect webhook deliveries")?;
+
+        Ok(deliveries)
+    }
+
+    pub fn mark_webhook_delivered(&self, id: i64, attempts: u32) -> Result<()> {
+        let _timer = self.time_query("mark_webhook_delivered");
+        self.conn.execute(
+            "UPDATE webhook_deliveries SET status = 'delivered', attempts = ?2, last_error = NULL WHERE id = ?1",
+            params![id, attempts],
+        )?;
+        Ok(())
+    }
+
+    pub fn schedule_webhook_retry(
+        &self,
+        id: i64,
+        attempts: u32,
+        next_attempt_at: DateTime<Utc>,
+        error: &str,
+    ) -> Result<()> {
+        let _timer = self.time_query("schedule_webhook_retry");
+        self.conn.execute(
+            "UPDATE webhook_deliveries SET attempts = ?2, next_attempt_at = ?3, last_error = ?4 WHERE id = ?1",
+            params![id, attempts, next_attempt_at.to_rfc3339(), error],
+        )?;
+        Ok(())
+    }
+
+    /// Moves a delivery to the dead-letter state, where it is no longer retried
+    pub fn mark_webhook_dead(&self, id: i64, attempts: u32, error: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE webhook_deliveries SET status = 'dead', attempts = ?2, last_error = ?3 WHERE id = ?1",
+            params![id, attempts, error],
+        )?;
+        Ok(())
+    }
+
+    /// Status and attempts of a webhook delivery
+    #[cfg(test)]
+    pub fn get_webhook_delivery_status(&self, id: i64) -> Result<(String, u32)> {
+        self.conn
+            .query_row(
+                "SELECT status, attempts FROM webhook_deliveries WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .context("Failed to query webhook delivery")
+    }
+
+    /// IMSIs of stored messages received by the SIM with the given IMSI,
+    /// ICCID or own number
+    pub fn get_sim_imsis(&self, sim: &str) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT ?1 WHERE EXISTS (SELECT 1 FROM messages WHERE imsi = ?1)
+             UNION SELECT imsi FROM sim_identifiers WHERE identifier = ?1",
+        )?;
+
+        let imsis = stmt
+            .query_map(params![sim], |row| row.get(0))
+            .context("Failed to query SIM IMSIs")?
+            .collect::<Result<Vec<String>, _>>()
+            .context("Failed to collect SIM IMSIs")?;
+
+        Ok(imsis)
+    }
+
+    pub fn insert_api_key(
+        &self,
+        name: &str,
+        key_hash: &str,
+        prefix: &str,
+        imsis: &[String],
+        permissions: &[Permission],
+    ) -> Result<i64> {
+        let permissions: Vec<&str> = permissions.iter().map(Permission::as_str).collect();
+        self.conn.execute(
+            "INSERT INTO api_keys (name, key_hash, prefix, imsis, permissions, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                name,
+                key_hash,
+                prefix,
+                join_list(imsis),
+                permissions.join(","),
+                Utc::now().to_rfc3339(),
+            ],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn list_api_keys(&self) -> Result<Vec<ApiKey>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM api_keys ORDER BY id ASC",
+            API_KEY_COLUMNS
+        ))?;
+
+        let keys = stmt
+            .query_map([], api_key_from_row)
+            .context("Failed to query API keys")?
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to collect API keys")?;
+
+        Ok(keys)
+    }
+
+    /// Looks up a key that has not been revoked and records its use
+    pub fn authenticate_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>> {
+        let _timer = self.time_query("authenticate_api_key");
+        let key = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM api_keys WHERE key_hash = ?1 AND revoked_at IS NULL",
+                    API_KEY_COLUMNS
+                ),
+                params![key_hash],
+                api_key_from_row,
+            )
+            .optional()
+            .context("Failed to query API key")?;
+
+        if let Some(key) = &key {
+            self.conn.execute(
+                "UPDATE api_keys SET last_used_at = ?2 WHERE id = ?1",
+                params![key.id, Utc::now().to_rfc3339()],
+            )?;
+        }
+
+        Ok(key)
+    }
+
+    pub fn count_active_api_keys(&self) -> Result<i64> {
+        let count = self.conn.query_row(
+            "SELECT COUNT(*) FROM api_keys WHERE revoked_at IS NULL",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    /// Revokes a key, returning false if no active key has this id
+    pub fn revoke_api_key(&self, id: i64) -> Result<bool> {
+        let updated = self.conn.execute(
+            "UPDATE api_keys SET revoked_at = ?2 WHERE id = ?1 AND revoked_at IS NULL",
+            params![id, Utc::now().to_rfc3339()],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Claims an idempotency key for a request. Returns None if the key is
+    /// new, or the request that claimed it before.
+    pub fn claim_idempotency_key(
+        &self,
+        api_key_id: i64,
+        key: &str,
+        method: &str,
+        path: &str,
+        request_hash: &str,
+    ) -> Result<Option<IdempotencyRecord>> {
+        let _timer = self.time_query("claim_idempotency_key");
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO idempotency_keys (api_key_id, key, method, path, request_hash, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                api_key_id,
+                key,
+                method,
+                path,
+                request_hash,
+                Utc::now().to_rfc3339()
+            ],
+        )?;
+        if inserted > 0 {
+            return Ok(None);
+        }
+
+        let record = self.conn.query_row(
+            "SELECT method, path, request_hash, status, content_type, body FROM idempotency_keys
+             WHERE api_key_id = ?1 AND key = ?2",
+            params![api_key_id, key],
+            |row| {
+                let status: Option<u16> = row.get(3)?;
+                Ok(IdempotencyRecord {
+                    method: row.get(0)?,
+                    path: row.get(1)?,
+                    request_hash: row.get(2)?,
+                    response: match status {
+                        Some(status) => Some(StoredResponse {
+                            status,
+                            content_type: row.get(4)?,
+                            body: row.get(5)?,
+                        }),
+                        None => None,
+                    },
+                })
+            },
+        )?;
+        Ok(Some(record))
+    }
+
+    /// Stores the response to replay for a claimed idempotency key
+    pub fn store_idempotent_response(
+        &self,
+        api_key_id: i64,
+        key: &str,
+        response: &StoredResponse,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE idempotency_keys SET status = ?3, content_type = ?4, body = ?5
+             WHERE api_key_id = ?1 AND key = ?2",
+            params![
+                api_key_id,
+                key,
+                response.status,
+                response.content_type,
+                response.body
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Frees a claimed idempotency key so the request can be retried
+    pub fn release_idempotency_key(&self, api_key_id: i64, key: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM idempotency_keys WHERE api_key_id = ?1 AND key = ?2",
+            params![api_key_id, key],
+        )?;
+        Ok(())
+    }
+
+    /// Deletes idempotency keys claimed before `before`
+    pub fn delete_idempotency_keys_before(&self, before: DateTime<Utc>) -> Result<usize> {
+        let deleted = self.conn.execute(
+            "DELETE FROM idempotency_keys WHERE created_at < ?1",
+            params![before.to_rfc3339()],
+        )?;
+        Ok(deleted)
+    }
+
+    /// Deletes idempotency keys claimed before `before` that never got a
+    /// response, such as claims of requests interrupted by a restart
+    pub fn delete_unanswered_idempotency_keys_before(
+        &self,
+        before: DateTime<Utc>,
+    ) -> Result<usize> {
+        let deleted = self.conn.execute(
+            "DELETE FROM idempotency_keys WHERE status IS NULL AND created_at < ?1",
+            params![before.to_rfc3339()],
+        )?;
+        Ok(deleted)
+    }
+
+    #[cfg(test)]
+    pub fn set_idempotency_key_created_at(
+        &self,
+        api_key_id: i64,
+        key: &str,
+        created_at: DateTime<Utc>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE idempotency_keys SET created_at = ?3 WHERE api_key_id = ?1 AND key = ?2",
+            params![api_key_id, key, created_at.to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    /// Records or refreshes an incomplete multipart SMS and returns when it was first seen
+    pub fn upsert_pending_message(
+        &self,
+        modem_path: &str,
+        sms_path: &str,
+        msg: &SmsMessage,
+    ) -> Result<DateTime<Utc>> {
+        let _timer = self.time_query("upsert_pending_message");
+        self.conn.execute(
+            "INSERT INTO pending_messages (modem_path, sms_path, imei, imsi, iccid, operator_id, operator_name, own_numbers, sender, text, timestamp, first_seen)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+             ON CONFLICT(sms_path) DO UPDATE SET text = excluded.text, sender = excluded.sender, timestamp = excluded.timestamp",
+            params![
+                modem_path,
+                sms_path,
+                msg.imei,
+                msg.imsi,
+                msg.iccid,
+                msg.operator_id,
+                msg.operator_name,
+                join_list(&msg.own_numbers),
+                msg.sender,
+                msg.text,
+                msg.timestamp.to_rfc3339(),
+                Utc::now().to_rfc3339(),
+            ],
+        )?;
+
+        let first_seen: String = self.conn.query_row(
+            "SELECT first_seen FROM pending_messages WHERE sms_path = ?1",
+            params![sms_path],
+            |row| row.get(0),
+        )?;
+
+        parse_rfc3339_timestamp(&first_seen)
+    }
+
+    pub fn get_pending_messages(&self) -> Result<Vec<PendingMessage>> {
+        let _timer = self.time_query("get_pending_messages");
+        let mut stmt = self.conn.prepare(
+            "SELECT modem_path, sms_path, imei, imsi, iccid, operator_id, operator_name, own_numbers, sender, text, timestamp, first_seen
+             FROM pending_messages ORDER BY first_seen ASC",
+        )?;
+
+        let pending = stmt
+            .query_map([], |row| {
+                Ok(PendingMessage {
+                    modem_path: row.get(0)?,
+                    sms_path: row.get(1)?,
+                    message: SmsMessage {
+                        id: None,
+                        imei: row.get(2)?,
+                        imsi: row.get(3)?,
+                        iccid: row.get(4)?,
+                        operator_id: row.get(5)?,
+                        operator_name: row.get(6)?,
+                        own_numbers: split_list(&row.get::<_, String>(7)?),
+                        sender: row.get(8)?,
+                        text: row.get(9)?,
+                        timestamp: timestamp_column(row, 10)?,
+                        partial: false,
+                    },
+                    first_seen: timestamp_column(row, 11)?,
+                })
+            })
+            .context("Failed to query pending messages")?
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to collect pending messages")?;
+
+        Ok(pending)
+    }
+
+    pub fn delete_pending_message(&self, sms_path: &str) -> Result<()> {
+        let _timer = self.time_query("delete_pending_message");
+        self.conn.execute(
+            "DELETE FROM pending_messages WHERE sms_path = ?1",
+            params![sms_path],
+        )?;
+        Ok(())
+    }
+
+    /// Records a new active USSD session on the SIM of `modem`
+    pub fn insert_ussd_session(
+        &self,
+        modem: &ModemInfo,
+        command: &str,
+        scheduled: bool,
+    ) -> Result<i64> {
+        let now = Utc::now().to_rfc3339();
+        self.conn.execute(
+            "INSERT INTO ussd_sessions (imei, imsi, iccid, command, state, scheduled, started_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)",
+            params![
+                modem.imei,
+                modem.imsi,
+                modem.iccid,
+                command,
+                UssdSessionState::Active.as_str(),
+                scheduled,
+                now,
+            ],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Appends a line to the transcript of a USSD session
+    pub fn add_ussd_message(
+        &self,
+        session_id: i64,
+        direction: UssdDirection,
+        text: &str,
+    ) -> Result<()> {
+        let now = Utc::now().to_rfc3339();
+        self.conn.execute(
+            "INSERT INTO ussd_messages (session_id, direction, text, timestamp) VALUES (?1, ?2, ?3, ?4)",
+            params![session_id, direction.as_str(), text, now],
+        )?;
+        self.conn.execute(
+            "UPDATE ussd_sessions SET updated_at = ?2 WHERE id = ?1",
+            params![session_id, now],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_ussd_session(
+        &self,
+        id: i64,
+        state: UssdSessionState,
+        error: Option<&str>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE ussd_sessions SET state = ?2, error = ?3, updated_at = ?4 WHERE id = ?1",
+            params![id, state.as_str(), error, Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_ussd_session_result(&self, id: i64, result: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE ussd_sessions SET result = ?2 WHERE id = ?1",
+            params![id, result],
+        )?;
+        Ok(())
+    }
+
+    /// Marks sessions left active when the daemon last stopped as failed,
+    /// since whether the network still waits for them is unknown. Returns
+    /// the number of sessions ended.
+    pub fn end_interrupted_ussd_sessions(&self) -> Result<usize> {
+        let ended = self.conn.execute(
+            "UPDATE ussd_sessions SET state = ?1, error = 'Interrupted by a restart', updated_at = ?2
+             WHERE state = ?3",
+            params![
+                UssdSessionState::Failed.as_str(),
+                Utc::now().to_rfc3339(),
+                UssdSessionState::Active.as_str(),
+            ],
+        )?;
+        Ok(ended)
+    }
+
+    pub fn get_ussd_session(&self, id: i64) -> Result<Option<UssdSession>> {
+        let session = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM ussd_sessions WHERE id = ?1",
+                    USSD_SESSION_COLUMNS
+                ),
+                params![id],
+                ussd_session_from_row,
+            )
+            .optional()
+            .context("Failed to query USSD session")?;
+
+        session
+            .map(|session| self.with_transcript(session))
+            .transpose()
+    }
+
+    /// The session on a SIM that waits for a response, if any
+    pub fn get_active_ussd_session(&self, imsi: &str) -> Result<Option<UssdSession>> {
+        let session = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM ussd_sessions WHERE imsi = ?1 AND state = ?2
+                     ORDER BY id DESC LIMIT 1",
+                    USSD_SESSION_COLUMNS
+                ),
+                params![imsi, UssdSessionState::Active.as_str()],
+                ussd_session_from_row,
+            )
+            .optional()
+            .context("Failed to query active USSD session")?;
+
+        session
+            .map(|session| self.with_transcript(session))
+            .transpose()
+    }
+
+    /// Lists the USSD sessions of the SIM with the given IMSI or ICCID,
+    /// newest first
+    pub fn get_ussd_sessions(
+        &self,
+        sim: &str,
+        after_id: Option<i64>,
+        limit: usize,
+    ) -> Result<UssdSessionPage> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM ussd_sessions
+             WHERE (imsi = ?1 OR iccid = ?1) AND id < ?2
+             ORDER BY id DESC LIMIT ?3",
+            USSD_SESSION_COLUMNS
+        ))?;
+
+        let mut sessions = stmt
+            .query_map(
+                params![sim, after_id.unwrap_or(i64::MAX), limit as i64 + 1],
+                ussd_session_from_row,
+            )
+            .context("Failed to query USSD sessions")?
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to collect USSD sessions")?;
+
+        let next_cursor = paginate(&mut sessions, Some(limit), |session| Some(session.id));
+        let sessions = sessions
+            .into_iter()
+            .map(|session| self.with_transcript(session))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(UssdSessionPage {
+            sessions,
+            next_cursor,
+        })
+    }
+
+    /// When the last session of a scheduled job on a SIM started
+    pub fn last_scheduled_ussd_session(&self, imsi: &str) -> Result<Option<DateTime<Utc>>> {
+        self.conn
+            .query_row(
+                "SELECT started_at FROM ussd_sessions WHERE imsi = ?1 AND scheduled = 1
+                 ORDER BY id DESC LIMIT 1",
+                params![imsi],
+                |row| timestamp_column(row, 0),
+            )
+            .optional()
+            .context("Failed to query last scheduled USSD session")
+    }
+
+    fn with_transcript(&self, mut session: UssdSession) -> Result<UssdSession> {
+        let mut stmt = self.conn.prepare(
+            "SELECT direction, text, timestamp FROM ussd_messages WHERE session_id = ?1 ORDER BY id",
+        )?;
+
+        session.transcript = stmt
+            .query_map(params![session.id], |row| {
+                let direction = match row.get::<_, String>(0)?.as_str() {
+                    "sent" => UssdDirection::Sent,
+                    _ => UssdDirection::Received,
+                };
+                Ok(UssdMessage {
+                    direction,
+                    text: row.get(1)?,
+                    timestamp: timestamp_column(row, 2)?,
+                })
+            })
+            .context("Failed to query USSD transcript")?
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to collect USSD transcript")?;
+
+        Ok(session)
+    }
+}
+
+const MESSAGE_COLUMNS: &str = "messages.id, messages.imei, messages.imsi, messages.iccid, \
+     messages.operator_id, messages.operator_name, messages.own_numbers, messages.sender, \
+     messages.text, messages.timestamp, messages.partial";
+
+/// Number of columns in `MESSAGE_COLUMNS`
+const MESSAGE_COLUMN_COUNT: usize = 11;
+
+/// Maximum number of tokens in a search snippet
+const SNIPPET_TOKENS: usize = 12;
+
+/// Markers FTS5 puts around matched terms in snippets, replaced by
+/// `highlight_snippet` once the text is escaped
+const SNIPPET_MATCH_START: char = '\u{2}';
+const SNIPPET_MATCH_END: char = '\u{3}';
+
+/// Reads a row selected with `MESSAGE_COLUMNS`
+fn message_from_row(row: &rusqlite::Row) -> rusqlite::Result<SmsMessage> {
+    Ok(SmsMessage {
+        id: Some(row.get(0)?),
+        imei: row.get(1)?,
+        imsi: row.get(2)?,
+        iccid: row.get(3)?,
+        operator_id: row.get(4)?,
+        operator_name: row.get(5)?,
+        own_numbers: split_list(&row.get::<_, String>(6)?),
+        sender: row.get(7)?,
+        text: row.get(8)?,
+        timestamp: timestamp_column(row, 9)?,
+        partial: row.get(10)?,
+    })
+}
+
+/// Trims the extra row fetched beyond `limit` to tell whether another page
+/// follows, and returns the cursor for that page
+fn paginate<T>(
+    rows: &mut Vec<T>,
+    limit: Option<usize>,
+    id: impl Fn(&T) -> Option<i64>,
+) -> Option<i64> {
+    match limit {
+        Some(limit) if rows.len() > limit => {
+            rows.truncate(limit);
+            rows.last().and_then(id)
+        }
+        _ => None,
+    }
+}
+
+/// Turns a search string into an FTS5 expression. `"quoted phrases"` match
+/// consecutive words and a trailing `*` matches words by prefix; all terms
+/// must appear in the text. Anything else is matched literally, so user
+/// input cannot cause FTS5 syntax errors. None if there are no terms.
+fn fts_query(input: &str) -> Option<String> {
+    let quote = |term: &str| format!("\"{}\"", term.replace('"', "\"\""));
+    let mut terms = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let phrase: String = chars.by_ref().take_while(|&c| c != '"').collect();
+            if !phrase.trim().is_empty() {
+                terms.push(quote(&phrase));
+            }
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '"' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+
+            match word.strip_suffix('*') {
+                Some("") => {}
+                Some(prefix) => terms.push(format!("{}*", quote(prefix))),
+                None => terms.push(quote(&word)),
+            }
+        }
+    }
+
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// HTML-escapes an FTS5 snippet and turns its match markers into `<mark>` tags
+fn highlight_snippet(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            SNIPPET_MATCH_START => html.push_str("<mark>"),
+            SNIPPET_MATCH_END => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+/// Lists such as own numbers are stored comma-separated in a single TEXT column
+fn join_list(values: &[String]) -> String {
+    values.join(",")
+}
+
+fn split_list(values: &str) -> Vec<String> {
+    values
+        .split(',')
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+const USSD_SESSION_COLUMNS: &str =
+    "id, imei, imsi, iccid, command, state, scheduled, result, error, started_at, updated_at";
+
+/// Reads a row selected with `USSD_SESSION_COLUMNS`, without its transcript
+fn ussd_session_from_row(row: &rusqlite::Row) -> rusqlite::Result<UssdSession> {
+    let state = row
+        .get::<_, String>(5)?
+        .parse()
+        .map_err(|e: anyhow::Error| {
+            rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Text, e.into())
+        })?;
+
+    Ok(UssdSession {
+        id: row.get(0)?,
+        imei: row.get(1)?,
+        imsi: row.get(2)?,
+        iccid: row.get(3)?,
+        command: row.get(4)?,
+        state,
+        scheduled: row.get(6)?,
+        result: row.get(7)?,
+        error: row.get(8)?,
+        started_at: timestamp_column(row, 9)?,
+        updated_at: timestamp_column(row, 10)?,
+        transcript: Vec::new(),
+    })
+}
+
+const OUTGOING_COLUMNS: &str = "id, imei, imsi, iccid, recipient, text, status, error, timestamp, \
+     send_at, attempts, next_attempt_at, sent_at, \
+     modem_path, sms_path, message_reference, delivery_status, delivery_state, reported_at, \
+     encoding, segments";
+
+/// Reads a row selected with `OUTGOING_COLUMNS`
+fn outgoing_from_row(row: &rusqlite::Row) -> rusqlite::Result<OutgoingMessage> {
+    Ok(OutgoingMessage {
+        id: row.get(0)?,
+        imei: row.get(1)?,
+        imsi: row.get(2)?,
+        iccid: row.get(3)?,
+        recipient: row.get(4)?,
+        text: row.get(5)?,
+        status: parsed_column(row, 6)?,
+        error: row.get(7)?,
+        timestamp: timestamp_column(row, 8)?,
+        send_at: optional_timestamp_column(row, 9)?,
+        attempts: row.get(10)?,
+        next_attempt_at: optional_timestamp_column(row, 11)?,
+        sent_at: optional_timestamp_column(row, 12)?,
+        modem_path: row.get(13)?,
+        sms_path: row.get(14)?,
+        message_reference: row.get(15)?,
+        delivery_status: row
+            .get::<_, Option<String>>(16)?
+            .map(|_| parsed_column(row, 16))
+            .transpose()?,
+        delivery_state: row.get(17)?,
+        reported_at: optional_timestamp_column(row, 18)?,
+        encoding: row
+            .get::<_, Option<String>>(19)?
+            .map(|_| parsed_column(row, 19))
+            .transpose()?,
+        segments: row.get(20)?,
+    })
+}
+
+/// Reads a TEXT column holding one of the enums stored by `as_str`
+fn parsed_column<T>(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<T>
+where
+    T: std::str::FromStr<Err = anyhow::Error>,
+{
+    row.get::<_, String>(idx)?
+        .parse()
+        .map_err(|e: anyhow::Error| {
+            rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, e.into())
+        })
+}
+
+const API_KEY_COLUMNS: &str =
+    "id, name, prefix, imsis, permissions, created_at, last_used_at, revoked_at";
+
+/// Reads a row selected with `API_KEY_COLUMNS`
+fn api_key_from_row(row: &rusqlite::Row) -> rusqlite::Result<ApiKey> {
+    let permissions: String = row.get(4)?;
+    let permissions = permissions
+        .split(',')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            p.parse().map_err(|e: anyhow::Error| {
+                rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Text, e.into())
+            })
+        })
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(ApiKey {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        prefix: row.get(2)?,
+        imsis: split_list(&row.get::<_, String>(3)?),
+        permissions,
+        created_at: timestamp_column(row, 5)?,
+        last_used_at: optional_timestamp_column(row, 6)?,
+        revoked_at: optional_timestamp_column(row, 7)?,
+    })
+}
+
+fn optional_timestamp_column(
+    row: &rusqlite::Row,
+    idx: usize,
+) -> rusqlite::Result<Option<DateTime<Utc>>> {
+    match row.get_ref(idx)? {
+        rusqlite::types::ValueRef::Null => Ok(None),
+        _ => timestamp_column(row, idx).map(Some),
+    }
+}
+
+/// Reads an RFC3339 timestamp stored as TEXT
+fn timestamp_column(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<DateTime<Utc>> {
+    let timestamp_str: String = row.get(idx)?;
+    parse_rfc3339_timestamp(&timestamp_str).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(
+            idx,
+            rusqlite::types::Type::Text,
+            Box::new(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        )
+    })
 }
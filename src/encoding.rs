@@ -0,0 +1,233 @@
+use serde::{Deserialize, Serialize};
+
+/// GSM 03.38 default alphabet, without the escape to the extension table
+const GSM7_BASIC: &str = "@£$¥èéùìòÇ\nØø\rÅåΔ_ΦΓΛΩΠΨΣΘΞÆæßÉ !\"#¤%&'()*+,-./0123456789:;<=>?\
+    ¡ABCDEFGHIJKLMNOPQRSTUVWXYZÄÖÑÜ§¿abcdefghijklmnopqrstuvwxyzäöñüà";
+
+/// Characters of the GSM 03.38 extension table, which take an escape
+/// septet each
+const GSM7_EXTENSION: &str = "\u{0C}^{}\\[~]|€";
+
+/// Capacity of a single SMS, in septets for GSM-7 and UTF-16 code units
+/// for UCS-2
+const GSM7_SINGLE: usize = 160;
+const UCS2_SINGLE: usize = 70;
+
+/// Capacity of each part of a multipart SMS, which loses room to the
+/// concatenation header
+const GSM7_MULTIPART: usize = 153;
+const UCS2_MULTIPART: usize = 67;
+
+/// Alphabet ModemManager encodes a text in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Gsm7,
+    Ucs2,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gsm7 => "gsm7",
+            Encoding::Ucs2 => "ucs2",
+        }
+    }
+
+    /// Encoding of `text`: GSM-7 if every character is in the default
+    /// alphabet or its extension table, otherwise UCS-2
+    pub fn of(text: &str) -> Self {
+        if text.chars().all(|c| gsm7_septets(c).is_some()) {
+            Encoding::Gsm7
+        } else {
+            Encoding::Ucs2
+        }
+    }
+
+    /// Septets or UTF-16 code units `c` takes
+    fn units(&self, c: char) -> usize {
+        match self {
+            Encoding::Gsm7 => gsm7_septets(c).unwrap_or(1),
+            Encoding::Ucs2 => c.len_utf16(),
+        }
+    }
+
+    fn capacity(&self, multipart: bool) -> usize {
+        match (self, multipart) {
+            (Encoding::Gsm7, false) => GSM7_SINGLE,
+            (Encoding::Gsm7, true) => GSM7_MULTIPART,
+            (Encoding::Ucs2, false) => UCS2_SINGLE,
+            (Encoding::Ucs2, true) => UCS2_MULTIPART,
+        }
+    }
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gsm7" => Ok(Encoding::Gsm7),
+            "ucs2" => Ok(Encoding::Ucs2),
+            _ => anyhow::bail!("Unknown SMS encoding '{}'", s),
+        }
+    }
+}
+
+fn gsm7_septets(c: char) -> Option<usize> {
+    if GSM7_BASIC.contains(c) {
+        Some(1)
+    } else if GSM7_EXTENSION.contains(c) {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+/// How a text is sent as SMS parts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segments<'a> {
+    pub encoding: Encoding,
+    /// The text of each part. Characters are never split between parts,
+    /// so a part may hold a little less than `part_length`.
+    pub parts: Vec<&'a str>,
+    /// Septets or UTF-16 code units of the whole text
+    pub length: usize,
+    /// Septets or UTF-16 code units each part holds
+    pub part_length: usize,
+}
+
+impl<'a> Segments<'a> {
+    pub fn of(text: &'a str) -> Self {
+        let encoding = Encoding::of(text);
+        let length: usize = text.chars().map(|c| encoding.units(c)).sum();
+        let single = encoding.capacity(false);
+        if length <= single {
+            return Self {
+                encoding,
+                parts: vec![text],
+                length,
+                part_length: single,
+            };
+        }
+
+        let part_length = encoding.capacity(true);
+        let mut parts = Vec::new();
+        let (mut start, mut used) = (0, 0);
+        for (index, c) in text.char_indices() {
+            let units = encoding.units(c);
+            if used + units > part_length {
+                parts.push(&text[start..index]);
+                (start, used) = (index, 0);
+            }
+            used += units;
+        }
+        parts.push(&text[start..]);
+
+        Self {
+            encoding,
+            parts,
+            length,
+            part_length,
+        }
+    }
+
+    /// Splits the text into texts of at most `max_parts` parts each, at
+    /// part boundaries. A text without UCS-2 characters is sent as GSM-7
+    /// and may then take fewer parts than it did here, so each has to be
+    /// counted again.
+    pub fn split(&self, max_parts: usize) -> Vec<String> {
+        self.parts
+            .chunks(max_parts.max(1))
+            .map(|chunk| chunk.concat())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_encoding() {
+        assert_eq!(Encoding::of("Hello, world! @£$"), Encoding::Gsm7);
+        // The extension table is still GSM-7
+        assert_eq!(Encoding::of("Price: 5€ [approx] {ok}"), Encoding::Gsm7);
+        // Some accented letters are in the default alphabet
+        assert_eq!(Encoding::of("Grüße aus Köln"), Encoding::Gsm7);
+        assert_eq!(Encoding::of("Cześć"), Encoding::Ucs2);
+        assert_eq!(Encoding::of("Thanks 👍"), Encoding::Ucs2);
+    }
+
+    #[test]
+    fn counts_parts_and_lengths() {
+        let single = "a".repeat(160);
+        let segments = Segments::of(&single);
+        assert_eq!(
+            (segments.parts.len(), segments.length, segments.part_length),
+            (1, 160, 160)
+        );
+
+        let double = "a".repeat(161);
+        let segments = Segments::of(&double);
+        assert_eq!(segments.parts.len(), 2);
+        assert_eq!(segments.parts[0].len(), 153);
+        assert_eq!(segments.part_length, 153);
+
+        // Extension characters take two septets each
+        let euros = "€".repeat(80);
+        let segments = Segments::of(&euros);
+        assert_eq!(segments.length, 160);
+        assert_eq!(segments.parts.len(), 1);
+        let euros = "€".repeat(81);
+        assert_eq!(Segments::of(&euros).parts.len(), 2);
+
+        let ucs2 = "ж".repeat(71);
+        let segments = Segments::of(&ucs2);
+        assert_eq!(segments.encoding, Encoding::Ucs2);
+        assert_eq!((segments.parts.len(), segments.part_length), (2, 67));
+    }
+
+    #[test]
+    fn keeps_characters_whole_across_parts() {
+        // 152 septets, then an escape sequence that does not fit the first part
+        let text = format!("{}€{}", "a".repeat(152), "b".repeat(10));
+        let segments = Segments::of(&text);
+        assert_eq!(segments.parts[0], "a".repeat(152));
+        assert!(segments.parts[1].starts_with('€'));
+
+        // Surrogate pairs stay together too
+        let text = format!("{}👍{}", "ж".repeat(66), "x".repeat(10));
+        let segments = Segments::of(&text);
+        assert_eq!(segments.parts[0], "ж".repeat(66));
+        assert!(segments.parts[1].starts_with('👍'));
+    }
+
+    #[test]
+    fn splits_into_messages_of_at_most_max_parts() {
+        let text = "a".repeat(153 * 5);
+        let messages = Segments::of(&text).split(2);
+        assert_eq!(
+            messages.iter().map(String::len).collect::<Vec<_>>(),
+            vec![306, 306, 153]
+        );
+        assert_eq!(Segments::of(&messages[0]).parts.len(), 2);
+        assert_eq!(Segments::of(&messages[2]).parts.len(), 1);
+    }
+
+    #[test]
+    fn split_texts_are_encoded_on_their_own() {
+        let text = format!("{}{}", "ж".repeat(67 * 2), "a".repeat(67 * 2));
+        let segments = Segments::of(&text);
+        assert_eq!(
+            (segments.encoding, segments.parts.len()),
+            (Encoding::Ucs2, 4)
+        );
+
+        let messages = segments.split(2);
+        let first = Segments::of(&messages[0]);
+        let second = Segments::of(&messages[1]);
+        assert_eq!((first.encoding, first.parts.len()), (Encoding::Ucs2, 2));
+        assert_eq!((second.encoding, second.parts.len()), (Encoding::Gsm7, 1));
+    }
+}
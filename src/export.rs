@@ -0,0 +1,295 @@
+use crate::db::{Database, MessageFilter, SmsMessage};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+
+/// Messages read from the database per query while exporting
+pub const EXPORT_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// Comma-separated values with a header row (RFC 4180)
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+    /// One email per message, in the mboxrd variant
+    Mbox,
+}
+
+impl ExportFormat {
+    /// Media type without parameters, as matched against `Accept`
+    pub fn media_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Mbox => "application/mbox",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson | ExportFormat::Mbox => self.media_type(),
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Mbox => "mbox",
+        }
+    }
+
+    /// Writes what comes before the first message, if anything
+    pub fn write_header(self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            ExportFormat::Csv => write_csv_row(out, &CSV_COLUMNS),
+            ExportFormat::Ndjson | ExportFormat::Mbox => Ok(()),
+        }
+    }
+
+    pub fn write_message(self, out: &mut impl Write, msg: &SmsMessage) -> io::Result<()> {
+        match self {
+            ExportFormat::Csv => write_csv_row(
+                out,
+                &[
+                    &msg.id.unwrap_or_default().to_string(),
+                    &msg.timestamp.to_rfc3339(),
+                    &msg.imsi,
+                    &msg.iccid,
+                    &msg.imei,
+                    &msg.operator_id,
+                    &msg.operator_name,
+                    &msg.own_numbers.join(" "),
+                    &msg.sender,
+                    &msg.text,
+                    if msg.partial { "true" } else { "false" },
+                ],
+            ),
+            ExportFormat::Ndjson => {
+                serde_json::to_writer(&mut *out, &MessageRecord::from(msg))?;
+                out.write_all(b"\n")
+            }
+            ExportFormat::Mbox => write_mbox_message(out, msg),
+        }
+    }
+}
+
+/// A stored message with everything known about the receiving SIM
+#[derive(Serialize)]
+pub struct MessageRecord<'a> {
+    id: Option<i64>,
+    imei: &'a str,
+    imsi: &'a str,
+    iccid: &'a str,
+    operator_id: &'a str,
+    operator_name: &'a str,
+    own_numbers: &'a [String],
+    sender: &'a str,
+    text: &'a str,
+    timestamp: DateTime<Utc>,
+    partial: bool,
+}
+
+impl<'a> From<&'a SmsMessage> for MessageRecord<'a> {
+    fn from(msg: &'a SmsMessage) -> Self {
+        Self {
+            id: msg.id,
+            imei: &msg.imei,
+            imsi: &msg.imsi,
+            iccid: &msg.iccid,
+            operator_id: &msg.operator_id,
+            operator_name: &msg.operator_name,
+            own_numbers: &msg.own_numbers,
+            sender: &msg.sender,
+            text: &msg.text,
+            timestamp: msg.timestamp,
+            partial: msg.partial,
+        }
+    }
+}
+
+/// Reads every message matching a filter in the filter's order, one batch
+/// per call, so an export of any size never holds more than a batch in
+/// memory. Each batch continues after the last id of the previous one, so
+/// messages stored during the export do not shift the batches.
+pub struct Batches {
+    filter: MessageFilter,
+    done: bool,
+}
+
+impl Batches {
+    pub fn new(filter: MessageFilter) -> Self {
+        Self {
+            filter: MessageFilter {
+                limit: Some(EXPORT_BATCH_SIZE),
+                ..filter
+            },
+            done: false,
+        }
+    }
+
+    /// The next batch, or None after the last one
+    pub fn next(&mut self, db: &Database) -> Result<Option<Vec<SmsMessage>>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let page = db.get_messages(&self.filter)?;
+        match page.next_cursor {
+            Some(cursor) => self.filter.after_id = Some(cursor),
+            None => self.done = true,
+        }
+        Ok(Some(page.messages))
+    }
+}
+
+const CSV_COLUMNS: [&str; 11] = [
+    "id",
+    "timestamp",
+    "imsi",
+    "iccid",
+    "imei",
+    "operator_id",
+    "operator_name",
+    "own_numbers",
+    "sender",
+    "text",
+    "partial",
+];
+
+fn write_csv_row(out: &mut impl Write, fields: &[&str]) -> io::Result<()> {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        if field.contains([',', '"', '\r', '\n']) {
+            write!(out, "\"{}\"", field.replace('"', "\"\""))?;
+        } else {
+            out.write_all(field.as_bytes())?;
+        }
+    }
+    out.write_all(b"\r\n")
+}
+
+fn write_mbox_message(out: &mut impl Write, msg: &SmsMessage) -> io::Result<()> {
+    let sender = header_value(&msg.sender);
+    let recipient = msg.own_numbers.first().unwrap_or(&msg.imsi);
+
+    writeln!(
+        out,
+        "From {}@sms.invalid {}",
+        mailbox(&msg.sender),
+        msg.timestamp.format("%a %b %e %H:%M:%S %Y")
+    )?;
+    writeln!(
+        out,
+        "From: \"{}\" <{}@sms.invalid>",
+        sender.replace(['"', '\\'], ""),
+        mailbox(&msg.sender)
+    )?;
+    writeln!(out, "To: <{}@sms.invalid>", mailbox(recipient))?;
+    writeln!(out, "Date: {}", msg.timestamp.to_rfc2822())?;
+    writeln!(out, "Subject: SMS from {}", sender)?;
+    writeln!(
+        out,
+        "Message-ID: <{}.{}@samson>",
+        msg.id.unwrap_or_default(),
+        mailbox(&msg.imsi)
+    )?;
+    writeln!(out, "X-Samson-IMSI: {}", header_value(&msg.imsi))?;
+    writeln!(out, "X-Samson-ICCID: {}", header_value(&msg.iccid))?;
+    writeln!(out, "X-Samson-IMEI: {}", header_value(&msg.imei))?;
+    if msg.partial {
+        writeln!(out, "X-Samson-Partial: true")?;
+    }
+    writeln!(out, "MIME-Version: 1.0")?;
+    writeln!(out, "Content-Type: text/plain; charset=utf-8")?;
+    writeln!(out, "Content-Transfer-Encoding: 8bit")?;
+    writeln!(out)?;
+
+    for line in msg.text.replace("\r\n", "\n").replace('\r', "\n").lines() {
+        // mboxrd: quote lines that would read as the start of a message,
+        // including already quoted ones, so readers can unquote exactly
+        if line.trim_start_matches('>').starts_with("From ") {
+            out.write_all(b">")?;
+        }
+        writeln!(out, "{}", line)?;
+    }
+    writeln!(out)
+}
+
+/// Keeps a header value on one line
+fn header_value(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+/// A local part for a pseudo email address made from a phone number or
+/// alphanumeric sender name
+fn mailbox(value: &str) -> String {
+    let local: String = value
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '+')
+        .collect();
+    if local.is_empty() {
+        "unknown".to_string()
+    } else {
+        local
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn message(text: &str) -> SmsMessage {
+        SmsMessage {
+            id: Some(7),
+            imei: "123456789012345".to_string(),
+            imsi: "310260123456789".to_string(),
+            iccid: "8901260123456789012".to_string(),
+            operator_id: "310260".to_string(),
+            operator_name: "Mock, Mobile".to_string(),
+            own_numbers: vec!["+15551234567".to_string()],
+            sender: "Bank Alerts".to_string(),
+            text: text.to_string(),
+            timestamp: Utc.with_ymd_and_hms(2024, 1, 5, 9, 30, 0).unwrap(),
+            partial: false,
+        }
+    }
+
+    fn export(format: ExportFormat, msg: &SmsMessage) -> String {
+        let mut out = Vec::new();
+        format.write_header(&mut out).unwrap();
+        format.write_message(&mut out, msg).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn quotes_csv_fields() {
+        let csv = export(ExportFormat::Csv, &message("Say \"hi\"\nbye"));
+        let rows: Vec<&str> = csv.split("\r\n").collect();
+
+        assert_eq!(rows[0], CSV_COLUMNS.join(","));
+        assert_eq!(
+            rows[1],
+            "7,2024-01-05T09:30:00+00:00,310260123456789,8901260123456789012,123456789012345,\
+             310260,\"Mock, Mobile\",+15551234567,Bank Alerts,\"Say \"\"hi\"\"\nbye\",false"
+        );
+    }
+
+    #[test]
+    fn writes_mboxrd_messages() {
+        let mbox = export(ExportFormat::Mbox, &message("From here\n>From there\nok"));
+
+        assert!(mbox.starts_with("From BankAlerts@sms.invalid Fri Jan  5 09:30:00 2024\n"));
+        assert!(mbox.contains("\nFrom: \"Bank Alerts\" <BankAlerts@sms.invalid>\n"));
+        assert!(mbox.contains("\nTo: <+15551234567@sms.invalid>\n"));
+        assert!(mbox.contains("\nDate: Fri, 5 Jan 2024 09:30:00 +0000\n"));
+        assert!(mbox.ends_with("\n\n>From here\n>>From there\nok\n\n"));
+    }
+}
@@ -0,0 +1,479 @@
+use crate::api::ApiResponse;
+use crate::db::{ApiKey, Database, IdempotencyRecord, StoredResponse};
+use anyhow::Result;
+use axum::{
+    body::{Body, to_bytes},
+    extract::{Request, State},
+    http::{HeaderValue, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{debug, error};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Set on responses replayed for a repeated idempotency key
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+const MAX_KEY_LEN: usize = 255;
+
+/// Largest request body buffered to fingerprint a request, the same as
+/// axum's default limit for JSON bodies
+const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// How long a claimed key without a response blocks retries. Requests run
+/// to completion even when the client disconnects and take at most a minute
+/// (a USSD request), so an older claim belongs to a request the daemon did
+/// not get to finish because it stopped.
+const CLAIM_TIMEOUT: chrono::Duration = chrono::Duration::minutes(5);
+
+#[derive(Clone)]
+pub struct IdempotencyState {
+    pub db: Arc<Mutex<Database>>,
+    /// How long keys and their responses are kept
+    pub window: chrono::Duration,
+}
+
+/// Makes mutating requests with an `Idempotency-Key` header safe to retry.
+/// The first request with a key runs as usual and its response is stored;
+/// repeating it within the window replays that response instead of running
+/// the request again. Keys are scoped to the API key, which the auth
+/// middleware in front of this one has put in the request extensions.
+pub async fn replay_idempotent(
+    State(state): State<IdempotencyState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        return next.run(request).await;
+    }
+    let Some(value) = request.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return next.run(request).await;
+    };
+    let key = match value.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => key.to_string(),
+        _ => {
+            return error(
+                format!(
+                    "{} must be 1 to {} visible ASCII characters",
+                    IDEMPOTENCY_KEY_HEADER, MAX_KEY_LEN
+                ),
+                StatusCode::BAD_REQUEST,
+            );
+        }
+    };
+    let Some(api_key_id) = request.extensions().get::<ApiKey>().map(|k| k.id) else {
+        return next.run(request).await;
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = match to_bytes(body, MAX_BODY_SIZE).await {
+        Ok(body) => body,
+        Err(_) => {
+            return error(
+                "Request body is too large".to_string(),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            );
+        }
+    };
+    let method = parts.method.as_str();
+    let path = parts.uri.path();
+    let request_hash = hex::encode(Sha256::digest(&body));
+
+    let claimed = {
+        let db = state.db.lock().await;
+        claim(
+            &db,
+            state.window,
+            api_key_id,
+            &key,
+            method,
+            path,
+            &request_hash,
+        )
+    };
+    match claimed {
+        Ok(None) => {}
+        Ok(Some(previous)) => return replay(previous, method, path, &request_hash),
+        Err(e) => {
+            error!(error = %e, "Failed to look up idempotency key");
+            return error(
+                "Failed to look up idempotency key".to_string(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            );
+        }
+    }
+
+    // The request runs in a task of its own, so it finishes and its response
+    // is stored even if the client disconnects midway. Dropping it instead
+    // could leave a message queued under a key that a retry later reclaims.
+    let request = Request::from_parts(parts, Body::from(body));
+    let task = tokio::spawn(run_and_store(
+        state.clone(),
+        api_key_id,
+        key.clone(),
+        request,
+        next,
+    ));
+    match task.await {
+        Ok(response) => response,
+        Err(e) => {
+            error!(error = %e, "Request with idempotency key failed");
+            release(&state, api_key_id, &key).await;
+            error(
+                "Request failed".to_string(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+/// Runs a request with a claimed key and stores its response for replay
+async fn run_and_store(
+    state: IdempotencyState,
+    api_key_id: i64,
+    key: String,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            error!(error = %e, "Failed to read response for idempotency key");
+            release(&state, api_key_id, &key).await;
+            return error(
+                "Failed to read response".to_string(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            );
+        }
+    };
+    // Server errors leave the key free so the request can be retried
+    if parts.status.is_server_error() {
+        release(&state, api_key_id, &key).await;
+        return Response::from_parts(parts, Body::from(body));
+    }
+
+    let stored = StoredResponse {
+        status: parts.status.as_u16(),
+        content_type: parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        body: body.to_vec(),
+    };
+    let result = {
+        let db = state.db.lock().await;
+        db.store_idempotent_response(api_key_id, &key, &stored)
+    };
+    if let Err(e) = result {
+        error!(error = %e, "Failed to store response for idempotency key");
+        release(&state, api_key_id, &key).await;
+    }
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Drops expired keys and abandoned claims, then claims `key` or returns
+/// its earlier request
+fn claim(
+    db: &Database,
+    window: chrono::Duration,
+    api_key_id: i64,
+    key: &str,
+    method: &str,
+    path: &str,
+    request_hash: &str,
+) -> Result<Option<IdempotencyRecord>> {
+    let now = Utc::now();
+    db.delete_idempotency_keys_before(now - window)?;
+    db.delete_unanswered_idempotency_keys_before(now - CLAIM_TIMEOUT)?;
+    db.claim_idempotency_key(api_key_id, key, method, path, request_hash)
+}
+
+async fn release(state: &IdempotencyState, api_key_id: i64, key: &str) {
+    let result = state
+        .db
+        .lock()
+        .await
+        .release_idempotency_key(api_key_id, key);
+    if let Err(e) = result {
+        error!(error = %e, "Failed to release idempotency key");
+    }
+}
+
+/// Answers a repeated request with the response to the first one
+fn replay(previous: IdempotencyRecord, method: &str, path: &str, request_hash: &str) -> Response {
+    if previous.method != method || previous.path != path || previous.request_hash != request_hash {
+        return error(
+            format!(
+                "{} was already used for a different request",
+                IDEMPOTENCY_KEY_HEADER
+            ),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        );
+    }
+    let Some(stored) = previous.response else {
+        return error(
+            format!(
+                "A request with this {} is still being processed",
+                IDEMPOTENCY_KEY_HEADER
+            ),
+            StatusCode::CONFLICT,
+        );
+    };
+
+    debug!(method, path, "Replaying response for idempotency key");
+    let mut response = Response::new(Body::from(stored.body));
+    *response.status_mut() = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    let headers = response.headers_mut();
+    if let Some(content_type) = stored
+        .content_type
+        .and_then(|value| HeaderValue::from_str(&value).ok())
+    {
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+    headers.insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+    response
+}
+
+fn error(message: String, status: StatusCode) -> Response {
+    ApiResponse::<()>::error_with_status(message, status).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Extension, Router, routing::post};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::Notify;
+    use tower::ServiceExt;
+
+    const API_KEY_ID: i64 = 1;
+    const KEY: &str = "order-17";
+
+    struct Fixture {
+        db: Arc<Mutex<Database>>,
+        router: Router,
+        /// Requests that reached the handlers
+        calls: Arc<AtomicUsize>,
+        /// Lets a request to `/slow` finish
+        release: Arc<Notify>,
+    }
+
+    /// Routes behind the middleware: `/messages` and `/other` succeed, `/slow`
+    /// waits for `release` and `/flaky` fails with 503 on its first call
+    fn fixture() -> Fixture {
+        let db = Arc::new(Mutex::new(Database::new(":memory:").unwrap()));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(Notify::new());
+
+        let count = |calls: &Arc<AtomicUsize>| calls.fetch_add(1, Ordering::SeqCst) + 1;
+        let ok = {
+            let calls = calls.clone();
+            move || async move { (StatusCode::CREATED, format!("call {}", count(&calls))) }
+        };
+        let slow = {
+            let (calls, release) = (calls.clone(), release.clone());
+            move || async move {
+                let call = count(&calls);
+                release.notified().await;
+                (StatusCode::CREATED, format!("call {}", call))
+            }
+        };
+        let flaky = {
+            let calls = calls.clone();
+            move || async move {
+                match count(&calls) {
+                    1 => (StatusCode::SERVICE_UNAVAILABLE, "call 1".to_string()),
+                    call => (StatusCode::CREATED, format!("call {}", call)),
+                }
+            }
+        };
+
+        let state = IdempotencyState {
+            db: db.clone(),
+            window: chrono::Duration::days(1),
+        };
+        let api_key = ApiKey {
+            id: API_KEY_ID,
+            name: "test".to_string(),
+            prefix: "samson_".to_string(),
+            imsis: Vec::new(),
+            permissions: Vec::new(),
+            created_at: Utc::now(),
+            last_used_at: None,
+            revoked_at: None,
+        };
+        let router = Router::new()
+            .route("/messages", post(ok.clone()))
+            .route("/other", post(ok))
+            .route("/slow", post(slow))
+            .route("/flaky", post(flaky))
+            .route_layer(axum::middleware::from_fn_with_state(
+                state,
+                replay_idempotent,
+            ))
+            .layer(Extension(api_key));
+
+        Fixture {
+            db,
+            router,
+            calls,
+            release,
+        }
+    }
+
+    /// Sends a request with `KEY` and returns the status, body and whether
+    /// it was replayed
+    async fn post_with_key(router: &Router, path: &str, body: &str) -> (StatusCode, String, bool) {
+        let request = Request::post(path)
+            .header(IDEMPOTENCY_KEY_HEADER, KEY)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let replayed = response.headers().contains_key(REPLAYED_HEADER);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap(), replayed)
+    }
+
+    /// Starts a request to `/slow` and waits until its handler runs
+    async fn start_slow(f: &Fixture) -> tokio::task::JoinHandle<(StatusCode, String, bool)> {
+        let calls = f.calls.load(Ordering::SeqCst);
+        let router = f.router.clone();
+        let request = tokio::spawn(async move { post_with_key(&router, "/slow", "hello").await });
+        while f.calls.load(Ordering::SeqCst) == calls {
+            tokio::task::yield_now().await;
+        }
+        request
+    }
+
+    async fn backdate(f: &Fixture, age: chrono::Duration) {
+        f.db.lock()
+            .await
+            .set_idempotency_key_created_at(API_KEY_ID, KEY, Utc::now() - age)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_concurrent_request_with_the_same_key() {
+        let f = fixture();
+        let first = start_slow(&f).await;
+
+        let (status, _, _) = post_with_key(&f.router, "/slow", "hello").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        f.release.notify_one();
+        assert_eq!(
+            first.await.unwrap(),
+            (StatusCode::CREATED, "call 1".to_string(), false)
+        );
+        assert_eq!(
+            post_with_key(&f.router, "/slow", "hello").await,
+            (StatusCode::CREATED, "call 1".to_string(), true)
+        );
+        assert_eq!(f.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn server_errors_release_the_key() {
+        let f = fixture();
+
+        let (status, _, _) = post_with_key(&f.router, "/flaky", "hello").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            post_with_key(&f.router, "/flaky", "hello").await,
+            (StatusCode::CREATED, "call 2".to_string(), false)
+        );
+        assert_eq!(
+            post_with_key(&f.router, "/flaky", "hello").await,
+            (StatusCode::CREATED, "call 2".to_string(), true)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_key_reused_for_a_different_request() {
+        let f = fixture();
+        post_with_key(&f.router, "/messages", "hello").await;
+
+        for (path, body) in [("/messages", "bye"), ("/other", "hello")] {
+            let (status, _, _) = post_with_key(&f.router, path, body).await;
+            assert_eq!(
+                status,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "{} {}",
+                path,
+                body
+            );
+        }
+        assert_eq!(f.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn forgets_keys_after_the_window() {
+        let f = fixture();
+        post_with_key(&f.router, "/messages", "hello").await;
+
+        backdate(&f, chrono::Duration::hours(23)).await;
+        let (_, body, replayed) = post_with_key(&f.router, "/messages", "hello").await;
+        assert_eq!((body.as_str(), replayed), ("call 1", true));
+
+        backdate(&f, chrono::Duration::hours(25)).await;
+        let (_, body, replayed) = post_with_key(&f.router, "/messages", "hello").await;
+        assert_eq!((body.as_str(), replayed), ("call 2", false));
+    }
+
+    #[tokio::test]
+    async fn finishes_requests_whose_client_disconnected() {
+        let f = fixture();
+        // Drops the request while its handler runs
+        start_slow(&f).await.abort();
+        f.release.notify_one();
+
+        let response = loop {
+            let response = post_with_key(&f.router, "/slow", "hello").await;
+            if response.0 != StatusCode::CONFLICT {
+                break response;
+            }
+            tokio::task::yield_now().await;
+        };
+        assert_eq!(response, (StatusCode::CREATED, "call 1".to_string(), true));
+        assert_eq!(f.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn reclaims_keys_of_requests_that_never_finished() {
+        let f = fixture();
+        // A claim without a response, as left behind when the daemon stops
+        // while a request runs
+        let request_hash = hex::encode(Sha256::digest(b"hello"));
+        f.db.lock()
+            .await
+            .claim_idempotency_key(API_KEY_ID, KEY, "POST", "/slow", &request_hash)
+            .unwrap();
+
+        let (status, _, _) = post_with_key(&f.router, "/slow", "hello").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        backdate(&f, CLAIM_TIMEOUT + chrono::Duration::seconds(1)).await;
+        f.release.notify_one();
+        assert_eq!(
+            post_with_key(&f.router, "/slow", "hello").await,
+            (StatusCode::CREATED, "call 1".to_string(), false)
+        );
+
+        // Answered keys are kept for the whole window
+        backdate(&f, CLAIM_TIMEOUT + chrono::Duration::seconds(1)).await;
+        let (_, body, replayed) = post_with_key(&f.router, "/slow", "hello").await;
+        assert_eq!((body.as_str(), replayed), ("call 1", true));
+    }
+}
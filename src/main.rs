@@ -1,71 +1,239 @@
 mod api;
+mod auth;
+mod cli;
 mod config;
 mod db;
+mod encoding;
+mod export;
+mod idempotency;
+mod metrics;
+mod migrations;
 mod modem;
+mod negotiate;
+mod outbox;
 mod poller;
+mod reload;
+mod retention;
+mod tls;
+mod ussd;
 mod utils;
+mod webhook;
 
 use anyhow::{Context, Result};
+use clap::Parser;
 use config::Config;
+use modem::ModemBackend;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::info;
-use tracing_subscriber;
+use tokio::sync::{Mutex, broadcast};
+use tracing::{info, warn};
+use tracing_subscriber::{EnvFilter, prelude::*};
+
+/// Stored messages buffered per live stream subscriber before it lags behind
+const MESSAGE_CHANNEL_CAPACITY: usize = 1024;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
+    let cli = cli::Cli::parse();
 
-    info!("Starting Samson SMS Daemon");
+    match cli.command {
+        None | Some(cli::Command::Serve) => serve(cli.config).await,
+        Some(command) => {
+            // Keep stdout for the command's own output
+            tracing_subscriber::fmt()
+                .with_writer(std::io::stderr)
+                .init();
+            cli::run(command, cli.config.as_deref()).await
+        }
+    }
+}
 
+/// Runs the daemon until it is interrupted
+async fn serve(config_path: Option<PathBuf>) -> Result<()> {
     // Load and validate configuration
-    let config = Config::from_env()?;
-    info!("Configuration loaded successfully");
+    let config = Config::load(config_path.as_deref())?;
+
+    // Initialize tracing, with a filter that can be changed on reload
+    let (log_filter, log_filter_handle) =
+        tracing_subscriber::reload::Layer::new(EnvFilter::new(&config.log_level));
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    info!("Starting Samson SMS Daemon");
+    match &config_path {
+        Some(path) => info!("Configuration loaded from {}", path.display()),
+        None => info!("Configuration loaded from the environment"),
+    }
+
+    let metrics = Arc::new(metrics::Metrics::new());
 
     // Initialize database
-    let db = Arc::new(Mutex::new(db::Database::new(&config.db_path)?));
+    let db = Arc::new(Mutex::new(
+        db::Database::new(&config.db_path)?.with_metrics(metrics.clone()),
+    ));
     info!("Database initialized at {}", config.db_path);
 
+    if db.lock().await.count_active_api_keys()? == 0 {
+        warn!(
+            "No API keys exist, all API requests will be rejected. Create one with `samson keys create`"
+        );
+    }
+
     // Initialize ModemManager connection
-    let modem_manager = Arc::new(modem::ModemManager::new().await?);
+    let modem_manager: Arc<dyn ModemBackend> = Arc::new(metrics::InstrumentedBackend::new(
+        Arc::new(modem::ModemManager::new(config.dbus_address.as_deref()).await?),
+        metrics.clone(),
+    ));
     info!("Connected to ModemManager");
 
+    // Start webhook dispatcher
+    let webhooks = Arc::new(webhook::WebhookDispatcher::new(
+        db.clone(),
+        config.webhooks.clone(),
+        config.webhook_secret.clone(),
+        config.webhook_max_attempts,
+    )?);
+
+    let webhook_handle = tokio::spawn({
+        let webhooks = webhooks.clone();
+        async move {
+            webhooks.start().await;
+        }
+    });
+
+    // Channel for pushing stored messages to live API streams
+    let (messages_tx, _) = broadcast::channel(MESSAGE_CHANNEL_CAPACITY);
+
     // Start polling service
     let poller = Arc::new(poller::SmsPoller::new(
         modem_manager.clone(),
         db.clone(),
+        webhooks.clone(),
+        messages_tx.clone(),
         config.poll_interval,
+        config.multipart_timeout,
+        metrics.clone(),
     ));
 
-    let poller_handle = tokio::spawn(async move {
-        poller.start().await;
+    let poller_handle = tokio::spawn({
+        let poller = poller.clone();
+        async move {
+            poller.start().await;
+        }
+    });
+
+    // Start outbox sender. Messages interrupted while being sent may have
+    // reached the network, so they are not sent again.
+    let interrupted = db.lock().await.fail_interrupted_outgoing_messages()?;
+    if interrupted > 0 {
+        warn!(
+            interrupted,
+            "Marked outgoing messages interrupted by the last shutdown as failed"
+        );
+    }
+    let outbox = Arc::new(outbox::OutboxSender::new(
+        db.clone(),
+        modem_manager.clone(),
+        config.outbox.clone(),
+    ));
+    let outbox_handle = tokio::spawn({
+        let outbox = outbox.clone();
+        async move {
+            outbox.start().await;
+        }
     });
 
+    // Start retention task
+    if config.retention.is_enabled() {
+        let task =
+            retention::RetentionTask::new(db.clone(), config.retention.clone(), metrics.clone());
+        tokio::spawn(task.start());
+    } else {
+        info!("No retention policy configured, messages are kept forever");
+    }
+
+    // USSD sessions, and the scheduled jobs that run them
+    let ended = db.lock().await.end_interrupted_ussd_sessions()?;
+    if ended > 0 {
+        warn!(
+            ended,
+            "Marked USSD sessions interrupted by the last shutdown as failed"
+        );
+    }
+    let ussd = Arc::new(ussd::UssdService::new(db.clone(), modem_manager.clone()));
+    ussd::start_jobs(ussd.clone(), config.ussd_jobs.clone());
+
+    // Load TLS certificates before binding so misconfiguration fails fast
+    let api_tls = config
+        .api_tls
+        .as_ref()
+        .map(|settings| tls::ReloadableTls::load("API", settings))
+        .transpose()?;
+    let metrics_tls = config
+        .metrics_tls
+        .as_ref()
+        .map(|settings| tls::ReloadableTls::load("metrics", settings))
+        .transpose()?;
+
+    // Reload certificates and the safe settings on SIGHUP
+    let reloader = reload::Reloader::new(
+        config_path,
+        config.clone(),
+        api_tls.iter().chain(&metrics_tls).cloned().collect(),
+        log_filter_handle,
+        poller.clone(),
+        webhooks.clone(),
+    );
+    tokio::spawn(reloader.start());
+
     // Start HTTP API server
-    let app = api::create_router(db.clone(), modem_manager.clone());
+    let app = api::create_router(
+        db.clone(),
+        modem_manager.clone(),
+        messages_tx,
+        ussd,
+        outbox,
+        metrics.clone(),
+        chrono::Duration::seconds(config.idempotency_window as i64),
+    );
     let bind_addr = format!("{}:{}", config.api_host, config.api_port);
     let listener = tokio::net::TcpListener::bind(&bind_addr)
         .await
         .context(format!("Failed to bind to {}", bind_addr))?;
-    info!("HTTP API listening on {}", bind_addr);
+    info!(
+        "HTTP API listening on {} ({})",
+        bind_addr,
+        scheme(&config.api_tls)
+    );
 
     let api_handle = tokio::spawn(async move {
-        if let Err(e) = axum::serve(listener, app).await {
+        if let Err(e) = tls::serve(listener, app, api_tls).await {
             tracing::error!("API server error: {}", e);
         }
     });
 
     // Start metrics/health server
-    let metrics_app = api::create_metrics_router(modem_manager.clone());
+    let metrics_app = api::create_metrics_router(
+        db.clone(),
+        modem_manager.clone(),
+        metrics,
+        poller.poll_interval(),
+        config.readiness_poll_intervals,
+    );
     let metrics_bind_addr = format!("{}:{}", config.metrics_host, config.metrics_port);
     let metrics_listener = tokio::net::TcpListener::bind(&metrics_bind_addr)
         .await
         .context(format!("Failed to bind to {}", metrics_bind_addr))?;
-    info!("Metrics API listening on {}", metrics_bind_addr);
+    info!(
+        "Metrics API listening on {} ({})",
+        metrics_bind_addr,
+        scheme(&config.metrics_tls)
+    );
 
     let metrics_handle = tokio::spawn(async move {
-        if let Err(e) = axum::serve(metrics_listener, metrics_app).await {
+        if let Err(e) = tls::serve(metrics_listener, metrics_app, metrics_tls).await {
             tracing::error!("Metrics server error: {}", e);
         }
     });
@@ -86,6 +254,12 @@ async fn main() -> Result<()> {
         _ = poller_handle => {
             info!("Poller task ended unexpectedly");
         }
+        _ = webhook_handle => {
+            info!("Webhook task ended unexpectedly");
+        }
+        _ = outbox_handle => {
+            info!("Outbox task ended unexpectedly");
+        }
         _ = api_handle => {
             info!("API task ended unexpectedly");
         }
@@ -97,3 +271,13 @@ async fn main() -> Result<()> {
     info!("Samson SMS Daemon stopped");
     Ok(())
 }
+
+fn scheme(tls: &Option<config::TlsConfig>) -> &'static str {
+    match tls {
+        Some(settings) if settings.client_ca_path.is_some() => {
+            "https, client certificates required"
+        }
+        Some(_) => "https",
+        None => "http",
+    }
+}
@@ -0,0 +1,291 @@
+use crate::modem::{
+    ModemBackend, ModemEvent, ModemInfo, SentSms, SentSmsState, SmsInfo, UssdReply,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use futures_util::stream::BoxStream;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Buckets for database queries, which mostly take well under a millisecond
+const DB_QUERY_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// All metrics exported on `/metrics`
+pub struct Metrics {
+    registry: Registry,
+    /// Messages stored, by SIM and modem
+    pub messages_ingested: IntCounterVec,
+    /// Messages read from a modem that were already stored
+    pub duplicate_messages: IntCounterVec,
+    /// Stored messages that could not be deleted from the modem
+    pub delete_failures: IntCounterVec,
+    /// Duration of full polls of all modems
+    pub poll_duration: Histogram,
+    /// Unix time of the last poll that read a modem's messages successfully
+    pub last_successful_poll: IntGaugeVec,
+    /// Modems seen by the last poll
+    pub modems: IntGauge,
+    /// Unix time the poller last started a full poll; readiness checks
+    /// use it to tell whether the poller is still running
+    pub poller_heartbeat: IntGauge,
+    /// Failed ModemManager calls, by operation
+    pub dbus_errors: IntCounterVec,
+    /// Database query duration, by query
+    pub db_query_duration: HistogramVec,
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+    /// Messages deleted by the retention policy, by reason
+    pub messages_pruned: IntCounterVec,
+    /// Unix time of the last retention run
+    pub retention_last_run: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let metrics = Self {
+            messages_ingested: IntCounterVec::new(
+                Opts::new("messages_ingested_total", "Messages stored in the database"),
+                &["imsi", "imei"],
+            )
+            .unwrap(),
+            duplicate_messages: IntCounterVec::new(
+                Opts::new(
+                    "messages_duplicate_total",
+                    "Messages read from a modem that were already stored",
+                ),
+                &["imsi", "imei"],
+            )
+            .unwrap(),
+            delete_failures: IntCounterVec::new(
+                Opts::new(
+                    "message_delete_failures_total",
+                    "Stored messages that could not be deleted from the modem",
+                ),
+                &["imsi", "imei"],
+            )
+            .unwrap(),
+            poll_duration: Histogram::with_opts(HistogramOpts::new(
+                "poll_duration_seconds",
+                "Duration of a full poll of all modems",
+            ))
+            .unwrap(),
+            last_successful_poll: IntGaugeVec::new(
+                Opts::new(
+                    "modem_last_successful_poll_timestamp_seconds",
+                    "Unix time of the last poll that read the modem's messages",
+                ),
+                &["imsi", "imei"],
+            )
+            .unwrap(),
+            modems: IntGauge::new("modem_count", "Modems seen by the last poll").unwrap(),
+            poller_heartbeat: IntGauge::new(
+                "poller_heartbeat_timestamp_seconds",
+                "Unix time the poller last started a full poll",
+            )
+            .unwrap(),
+            dbus_errors: IntCounterVec::new(
+                Opts::new("dbus_errors_total", "Failed ModemManager calls"),
+                &["operation"],
+            )
+            .unwrap(),
+            db_query_duration: HistogramVec::new(
+                HistogramOpts::new("db_query_duration_seconds", "Database query duration")
+                    .buckets(DB_QUERY_BUCKETS.to_vec()),
+                &["query"],
+            )
+            .unwrap(),
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests handled"),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Time until the response headers were sent",
+                ),
+                &["method", "route"],
+            )
+            .unwrap(),
+            messages_pruned: IntCounterVec::new(
+                Opts::new(
+                    "messages_pruned_total",
+                    "Messages deleted by the retention policy",
+                ),
+                &["reason"],
+            )
+            .unwrap(),
+            retention_last_run: IntGauge::new(
+                "retention_last_run_timestamp_seconds",
+                "Unix time of the last retention run",
+            )
+            .unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 13] = [
+            Box::new(metrics.messages_ingested.clone()),
+            Box::new(metrics.duplicate_messages.clone()),
+            Box::new(metrics.delete_failures.clone()),
+            Box::new(metrics.poll_duration.clone()),
+            Box::new(metrics.last_successful_poll.clone()),
+            Box::new(metrics.modems.clone()),
+            Box::new(metrics.poller_heartbeat.clone()),
+            Box::new(metrics.dbus_errors.clone()),
+            Box::new(metrics.db_query_duration.clone()),
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.http_request_duration.clone()),
+            Box::new(metrics.messages_pruned.clone()),
+            Box::new(metrics.retention_last_run.clone()),
+        ];
+        for collector in collectors {
+            // Names are unique, so registration cannot fail
+            metrics.registry.register(collector).unwrap();
+        }
+
+        metrics
+    }
+
+    /// Encodes all metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        // Encoding into a Vec only fails on invalid metric families, which
+        // the registry does not produce
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Records the count and latency of HTTP requests by matched route
+pub async fn track_http_requests(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    // Label by route template so SIM identifiers do not create new series
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+
+    metrics
+        .http_request_duration
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    metrics
+        .http_requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+
+    response
+}
+
+/// Counts failed calls of the wrapped backend in `dbus_errors_total`
+pub struct InstrumentedBackend {
+    inner: Arc<dyn ModemBackend>,
+    metrics: Arc<Metrics>,
+}
+
+impl InstrumentedBackend {
+    pub fn new(inner: Arc<dyn ModemBackend>, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+
+    fn record<T>(&self, operation: &str, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.metrics
+                .dbus_errors
+                .with_label_values(&[operation])
+                .inc();
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl ModemBackend for InstrumentedBackend {
+    async fn get_modems(&self) -> Result<Vec<ModemInfo>> {
+        self.record("get_modems", self.inner.get_modems().await)
+    }
+
+    async fn get_messages(&self, modem_path: &str) -> Result<Vec<SmsInfo>> {
+        self.record("get_messages", self.inner.get_messages(modem_path).await)
+    }
+
+    async fn get_message(&self, sms_path: &str) -> Result<Option<SmsInfo>> {
+        self.record("get_message", self.inner.get_message(sms_path).await)
+    }
+
+    async fn subscribe(&self) -> Result<BoxStream<'static, ModemEvent>> {
+        self.record("subscribe", self.inner.subscribe().await)
+    }
+
+    async fn delete_message(&self, modem_path: &str, sms_path: &str) -> Result<()> {
+        self.record(
+            "delete_message",
+            self.inner.delete_message(modem_path, sms_path).await,
+        )
+    }
+
+    async fn send_message(&self, modem_path: &str, number: &str, text: &str) -> Result<SentSms> {
+        self.record(
+            "send_message",
+            self.inner.send_message(modem_path, number, text).await,
+        )
+    }
+
+    async fn get_sent_message(&self, sms_path: &str) -> Result<SentSmsState> {
+        self.record(
+            "get_sent_message",
+            self.inner.get_sent_message(sms_path).await,
+        )
+    }
+
+    async fn ussd_initiate(&self, modem_path: &str, command: &str) -> Result<UssdReply> {
+        self.record(
+            "ussd_initiate",
+            self.inner.ussd_initiate(modem_path, command).await,
+        )
+    }
+
+    async fn ussd_respond(&self, modem_path: &str, response: &str) -> Result<UssdReply> {
+        self.record(
+            "ussd_respond",
+            self.inner.ussd_respond(modem_path, response).await,
+        )
+    }
+
+    async fn ussd_cancel(&self, modem_path: &str) -> Result<()> {
+        self.record("ussd_cancel", self.inner.ussd_cancel(modem_path).await)
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.record("ping", self.inner.ping().await)
+    }
+}
@@ -0,0 +1,642 @@
+use anyhow::{Context, Result};
+use rusqlite::{Connection, Transaction};
+use tracing::info;
+
+/// A forward-only schema change. The schema version of a database is the
+/// number of migrations applied to it, stored in SQLite's `user_version`.
+struct Migration {
+    description: &'static str,
+    apply: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+/// All migrations in the order they are applied. Never edit or reorder an
+/// existing entry; append a new one instead.
+///
+/// Databases created before versioning was introduced report version 0
+/// but may already contain any of the early tables, so those migrations
+/// must tolerate existing objects.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "create messages table",
+        apply: create_messages,
+    },
+    Migration {
+        description: "create outgoing_messages table",
+        apply: create_outgoing_messages,
+    },
+    Migration {
+        description: "create webhook_deliveries table",
+        apply: create_webhook_deliveries,
+    },
+    Migration {
+        description: "track partial multipart messages",
+        apply: add_partial_messages,
+    },
+    Migration {
+        description: "record SIM identifiers separately",
+        apply: add_sim_identifiers,
+    },
+    Migration {
+        description: "create api_keys table",
+        apply: create_api_keys,
+    },
+    Migration {
+        description: "index message text for full-text search",
+        apply: create_message_search,
+    },
+    Migration {
+        description: "create ussd_sessions and ussd_messages tables",
+        apply: create_ussd_sessions,
+    },
+    Migration {
+        description: "track delivery of outgoing messages",
+        apply: add_delivery_tracking,
+    },
+    Migration {
+        description: "queue outgoing messages",
+        apply: add_outbox_queue,
+    },
+    Migration {
+        description: "create idempotency_keys table",
+        apply: create_idempotency_keys,
+    },
+    Migration {
+        description: "record encoding and parts of outgoing messages",
+        apply: add_outgoing_segments,
+    },
+    Migration {
+        description: "index ICCIDs and own numbers of SIMs",
+        apply: create_sim_identifiers,
+    },
+];
+
+/// Schema version produced by this binary
+pub const LATEST_VERSION: u32 = MIGRATIONS.len() as u32;
+
+pub fn schema_version(conn: &Connection) -> Result<u32> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+        .context("Failed to read database schema version")
+}
+
+/// Brings the database up to `LATEST_VERSION`, refusing to touch databases
+/// written by a newer binary
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    migrate_to(conn, LATEST_VERSION)
+}
+
+/// Fails unless the database is at exactly `LATEST_VERSION`, for users of
+/// the database that must not migrate it themselves
+pub fn ensure_current(conn: &Connection) -> Result<()> {
+    let current = schema_version(conn)?;
+
+    if current > LATEST_VERSION {
+        anyhow::bail!(
+            "Database schema version {} is newer than the latest version {} supported by this binary",
+            current,
+            LATEST_VERSION
+        );
+    }
+
+    if current < LATEST_VERSION {
+        anyhow::bail!(
+            "Database schema version {} is out of date, this binary needs version {}; \
+             run `samson db migrate` or start the daemon to upgrade it",
+            current,
+            LATEST_VERSION
+        );
+    }
+
+    Ok(())
+}
+
+/// Applies all pending migrations up to `target` in a single transaction
+fn migrate_to(conn: &mut Connection, target: u32) -> Result<()> {
+    let current = schema_version(conn)?;
+
+    if current > LATEST_VERSION {
+        anyhow::bail!(
+            "Database schema version {} is newer than the latest version {} supported by this binary",
+            current,
+            LATEST_VERSION
+        );
+    }
+
+    if current >= target {
+        return Ok(());
+    }
+
+    let tx = conn
+        .transaction()
+        .context("Failed to start migration transaction")?;
+
+    for (index, migration) in MIGRATIONS
+        .iter()
+        .enumerate()
+        .take(target as usize)
+        .skip(current as usize)
+    {
+        let version = index as u32 + 1;
+        (migration.apply)(&tx).with_context(|| {
+            format!(
+                "Failed to apply migration {} ({})",
+                version, migration.description
+            )
+        })?;
+        info!(
+            version,
+            "Applied database migration: {}", migration.description
+        );
+    }
+
+    tx.pragma_update(None, "user_version", target)
+        .context("Failed to update database schema version")?;
+    tx.commit().context("Failed to commit migrations")?;
+
+    Ok(())
+}
+
+fn create_messages(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            imei TEXT NOT NULL,
+            imsi TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            text TEXT NOT NULL,
+            timestamp TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_imei ON messages(imei);
+        CREATE INDEX IF NOT EXISTS idx_imsi ON messages(imsi);
+        CREATE INDEX IF NOT EXISTS idx_timestamp ON messages(timestamp);",
+    )
+}
+
+fn create_outgoing_messages(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS outgoing_messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            imei TEXT NOT NULL,
+            imsi TEXT NOT NULL,
+            recipient TEXT NOT NULL,
+            text TEXT NOT NULL,
+            status TEXT NOT NULL,
+            error TEXT,
+            timestamp TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_outgoing_imsi ON outgoing_messages(imsi);",
+    )
+}
+
+fn create_webhook_deliveries(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS webhook_deliveries (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            message_id INTEGER NOT NULL,
+            url TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            status TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            next_attempt_at TEXT NOT NULL,
+            last_error TEXT,
+            created_at TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_webhook_due ON webhook_deliveries(status, next_attempt_at);",
+    )
+}
+
+fn add_partial_messages(tx: &Transaction) -> rusqlite::Result<()> {
+    if !column_exists(tx, "messages", "partial")? {
+        tx.execute(
+            "ALTER TABLE messages ADD COLUMN partial INTEGER NOT NULL DEFAULT 0",
+            [],
+        )?;
+    }
+
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS pending_messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            modem_path TEXT NOT NULL,
+            sms_path TEXT NOT NULL UNIQUE,
+            imei TEXT NOT NULL,
+            imsi TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            text TEXT NOT NULL,
+            timestamp TEXT NOT NULL,
+            first_seen TEXT NOT NULL
+        );",
+    )
+}
+
+/// Earlier releases stored the ICCID in the `imsi` column. It is copied to
+/// the new `iccid` column here; the real IMSI is filled in by the poller once
+/// the SIM is seen again (see `Database::backfill_sim_identity`).
+fn add_sim_identifiers(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE messages ADD COLUMN iccid TEXT NOT NULL DEFAULT '';
+        ALTER TABLE messages ADD COLUMN operator_id TEXT NOT NULL DEFAULT '';
+        ALTER TABLE messages ADD COLUMN operator_name TEXT NOT NULL DEFAULT '';
+        ALTER TABLE messages ADD COLUMN own_numbers TEXT NOT NULL DEFAULT '';
+        UPDATE messages SET iccid = imsi;
+        CREATE INDEX idx_iccid ON messages(iccid);
+
+        ALTER TABLE outgoing_messages ADD COLUMN iccid TEXT NOT NULL DEFAULT '';
+        UPDATE outgoing_messages SET iccid = imsi;
+
+        ALTER TABLE pending_messages ADD COLUMN iccid TEXT NOT NULL DEFAULT '';
+        ALTER TABLE pending_messages ADD COLUMN operator_id TEXT NOT NULL DEFAULT '';
+        ALTER TABLE pending_messages ADD COLUMN operator_name TEXT NOT NULL DEFAULT '';
+        ALTER TABLE pending_messages ADD COLUMN own_numbers TEXT NOT NULL DEFAULT '';
+        UPDATE pending_messages SET iccid = imsi;",
+    )
+}
+
+fn create_api_keys(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE api_keys (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            key_hash TEXT NOT NULL UNIQUE,
+            prefix TEXT NOT NULL,
+            imsis TEXT NOT NULL,
+            permissions TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            last_used_at TEXT,
+            revoked_at TEXT
+        );",
+    )
+}
+
+/// External-content FTS5 index over `messages.text`, kept in sync by
+/// triggers and filled from the existing rows
+fn create_message_search(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE VIRTUAL TABLE messages_fts USING fts5(
+            text,
+            content = 'messages',
+            content_rowid = 'id',
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+
+        CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+            INSERT INTO messages_fts (rowid, text) VALUES (new.id, new.text);
+        END;
+        CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+            INSERT INTO messages_fts (messages_fts, rowid, text) VALUES ('delete', old.id, old.text);
+        END;
+        CREATE TRIGGER messages_fts_update AFTER UPDATE OF text ON messages BEGIN
+            INSERT INTO messages_fts (messages_fts, rowid, text) VALUES ('delete', old.id, old.text);
+            INSERT INTO messages_fts (rowid, text) VALUES (new.id, new.text);
+        END;
+
+        INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');",
+    )
+}
+
+/// USSD sessions with their transcript, one row per code, response or
+/// network reply
+fn create_ussd_sessions(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE ussd_sessions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            imei TEXT NOT NULL,
+            imsi TEXT NOT NULL,
+            iccid TEXT NOT NULL,
+            command TEXT NOT NULL,
+            state TEXT NOT NULL,
+            scheduled INTEGER NOT NULL DEFAULT 0,
+            result TEXT,
+            error TEXT,
+            started_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        );
+        CREATE INDEX idx_ussd_sessions_imsi ON ussd_sessions(imsi, state);
+
+        CREATE TABLE ussd_messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            session_id INTEGER NOT NULL,
+            direction TEXT NOT NULL,
+            text TEXT NOT NULL,
+            timestamp TEXT NOT NULL
+        );
+        CREATE INDEX idx_ussd_messages_session ON ussd_messages(session_id);",
+    )
+}
+
+/// Where a sent SMS is kept on the modem while its delivery is followed,
+/// and what the network reported about it
+fn add_delivery_tracking(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE outgoing_messages ADD COLUMN modem_path TEXT;
+        ALTER TABLE outgoing_messages ADD COLUMN sms_path TEXT;
+        ALTER TABLE outgoing_messages ADD COLUMN message_reference INTEGER;
+        ALTER TABLE outgoing_messages ADD COLUMN delivery_status TEXT;
+        ALTER TABLE outgoing_messages ADD COLUMN delivery_state INTEGER;
+        ALTER TABLE outgoing_messages ADD COLUMN reported_at TEXT;
+        CREATE INDEX idx_outgoing_delivery ON outgoing_messages(delivery_status);",
+    )
+}
+
+/// Outgoing messages are queued and sent by a worker, possibly after
+/// several attempts. Messages sent before were sent on their first attempt.
+fn add_outbox_queue(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE outgoing_messages ADD COLUMN send_at TEXT;
+        ALTER TABLE outgoing_messages ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE outgoing_messages ADD COLUMN next_attempt_at TEXT;
+        ALTER TABLE outgoing_messages ADD COLUMN sent_at TEXT;
+        UPDATE outgoing_messages SET attempts = 1;
+        UPDATE outgoing_messages SET sent_at = timestamp WHERE status = 'sent';
+        CREATE INDEX idx_outgoing_queue ON outgoing_messages(status, next_attempt_at);
+        CREATE INDEX idx_outgoing_sent ON outgoing_messages(imsi, sent_at);",
+    )
+}
+
+/// Responses of mutating API requests by the key the client sent, kept
+/// for replay. `status` is NULL while the first request is processed.
+fn create_idempotency_keys(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE idempotency_keys (
+            api_key_id INTEGER NOT NULL,
+            key TEXT NOT NULL,
+            method TEXT NOT NULL,
+            path TEXT NOT NULL,
+            request_hash TEXT NOT NULL,
+            status INTEGER,
+            content_type TEXT,
+            body BLOB,
+            created_at TEXT NOT NULL,
+            PRIMARY KEY (api_key_id, key)
+        );
+        CREATE INDEX idx_idempotency_keys_created ON idempotency_keys(created_at);",
+    )
+}
+
+/// Left empty for messages queued before, which were not analysed
+fn add_outgoing_segments(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE outgoing_messages ADD COLUMN encoding TEXT;
+        ALTER TABLE outgoing_messages ADD COLUMN segments INTEGER;",
+    )
+}
+
+/// ICCIDs and own numbers seen with each IMSI, so that a SIM can be resolved
+/// to its IMSIs without scanning the comma-separated `own_numbers` of every
+/// message. Filled from the existing rows.
+fn create_sim_identifiers(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE sim_identifiers (
+            identifier TEXT NOT NULL,
+            imsi TEXT NOT NULL,
+            PRIMARY KEY (identifier, imsi)
+        ) WITHOUT ROWID;
+
+        INSERT OR IGNORE INTO sim_identifiers (identifier, imsi)
+            SELECT DISTINCT iccid, imsi FROM messages WHERE iccid != '';",
+    )?;
+
+    let mut stmt =
+        tx.prepare("SELECT DISTINCT imsi, own_numbers FROM messages WHERE own_numbers != ''")?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (imsi, own_numbers) in rows {
+        for number in own_numbers.split(',').filter(|n| !n.is_empty()) {
+            tx.execute(
+                "INSERT OR IGNORE INTO sim_identifiers (identifier, imsi) VALUES (?1, ?2)",
+                [number, &imsi],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn column_exists(tx: &Transaction, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let count: i64 = tx.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        [table, column],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Schema of databases created by releases without migrations
+    const UNVERSIONED_SCHEMA: &str = "
+        CREATE TABLE messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            imei TEXT NOT NULL,
+            imsi TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            text TEXT NOT NULL,
+            timestamp TEXT NOT NULL
+        );
+        CREATE INDEX idx_imei ON messages(imei);
+        CREATE INDEX idx_imsi ON messages(imsi);
+        CREATE INDEX idx_timestamp ON messages(timestamp);";
+
+    fn insert_fixture_message(conn: &Connection) {
+        conn.execute(
+            "INSERT INTO messages (imei, imsi, sender, text, timestamp)
+             VALUES ('123456789012345', '310260123456789', '+1234567890', 'Your code is 4821', '2026-01-09T08:20:13+00:00')",
+            [],
+        )
+        .unwrap();
+    }
+
+    fn assert_fixture_message(conn: &Connection) {
+        let (text, partial): (String, bool) = conn
+            .query_row(
+                "SELECT text, partial FROM messages WHERE imsi = '310260123456789'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(text, "Your code is 4821");
+        assert!(!partial);
+    }
+
+    #[test]
+    fn copies_legacy_sim_identifier_to_iccid() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_to(&mut conn, 4).unwrap();
+        // Before version 5 the `imsi` column held the ICCID
+        conn.execute(
+            "INSERT INTO messages (imei, imsi, sender, text, timestamp)
+             VALUES ('123456789012345', '8901260123456789012', '+1234567890', 'Hi', '2026-01-09T08:20:13+00:00')",
+            [],
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        let (imsi, iccid, own_numbers): (String, String, String) = conn
+            .query_row("SELECT imsi, iccid, own_numbers FROM messages", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        assert_eq!(imsi, "8901260123456789012");
+        assert_eq!(iccid, "8901260123456789012");
+        assert_eq!(own_numbers, "");
+    }
+
+    #[test]
+    fn indexes_existing_and_new_messages_for_search() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_to(&mut conn, 6).unwrap();
+        insert_fixture_message(&conn);
+
+        migrate(&mut conn).unwrap();
+
+        let matches = |conn: &Connection, query: &str| -> i64 {
+            conn.query_row(
+                "SELECT COUNT(*) FROM messages_fts WHERE messages_fts MATCH ?1",
+                [query],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(matches(&conn, "4821"), 1);
+
+        conn.execute(
+            "UPDATE messages SET text = 'Your code is 9917' WHERE id = 1",
+            [],
+        )
+        .unwrap();
+        assert_eq!(matches(&conn, "4821"), 0);
+        assert_eq!(matches(&conn, "9917"), 1);
+
+        conn.execute("DELETE FROM messages", []).unwrap();
+        assert_eq!(matches(&conn, "code"), 0);
+    }
+
+    #[test]
+    fn indexes_identifiers_of_existing_messages() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_to(&mut conn, 12).unwrap();
+        conn.execute(
+            "INSERT INTO messages (imei, imsi, iccid, own_numbers, sender, text, timestamp)
+             VALUES ('123456789012345', '310260123456789', '8901260123456789012', '+15551234567,+15557654321',
+                     '+1234567890', 'Hi', '2026-01-09T08:20:13+00:00')",
+            [],
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        let mut stmt = conn
+            .prepare("SELECT identifier, imsi FROM sim_identifiers ORDER BY identifier")
+            .unwrap();
+        let rows: Vec<(String, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            [
+                ("+15551234567".to_string(), "310260123456789".to_string()),
+                ("+15557654321".to_string(), "310260123456789".to_string()),
+                (
+                    "8901260123456789012".to_string(),
+                    "310260123456789".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn migrates_empty_database_to_latest() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), LATEST_VERSION);
+    }
+
+    #[test]
+    fn migrating_twice_is_a_no_op() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        insert_fixture_message(&conn);
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), LATEST_VERSION);
+        assert_fixture_message(&conn);
+    }
+
+    #[test]
+    fn upgrades_unversioned_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(UNVERSIONED_SCHEMA).unwrap();
+        insert_fixture_message(&conn);
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(schema_version(&conn).unwrap(), LATEST_VERSION);
+        assert_fixture_message(&conn);
+    }
+
+    #[test]
+    fn upgrades_from_every_past_version() {
+        for version in 1..LATEST_VERSION {
+            let mut conn = Connection::open_in_memory().unwrap();
+            migrate_to(&mut conn, version).unwrap();
+            assert_eq!(schema_version(&conn).unwrap(), version);
+            insert_fixture_message(&conn);
+
+            migrate(&mut conn)
+                .unwrap_or_else(|e| panic!("Upgrade from version {} failed: {:#}", version, e));
+
+            assert_eq!(schema_version(&conn).unwrap(), LATEST_VERSION);
+            assert_fixture_message(&conn);
+        }
+    }
+
+    #[test]
+    fn only_accepts_the_latest_version_as_current() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_to(&mut conn, LATEST_VERSION - 1).unwrap();
+        let error = ensure_current(&conn).unwrap_err();
+        assert!(error.to_string().contains("samson db migrate"), "{}", error);
+
+        migrate(&mut conn).unwrap();
+        ensure_current(&conn).unwrap();
+
+        conn.pragma_update(None, "user_version", LATEST_VERSION + 1)
+            .unwrap();
+        assert!(ensure_current(&conn).is_err());
+    }
+
+    #[test]
+    fn refuses_newer_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", LATEST_VERSION + 1)
+            .unwrap();
+
+        assert!(migrate(&mut conn).is_err());
+        assert_eq!(schema_version(&conn).unwrap(), LATEST_VERSION + 1);
+    }
+
+    #[test]
+    fn failed_migration_rolls_back() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        // Views cannot be indexed, so the outgoing_messages migration fails
+        conn.execute_batch("CREATE VIEW outgoing_messages AS SELECT 1 AS imsi")
+            .unwrap();
+
+        assert!(migrate(&mut conn).is_err());
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+
+        // The messages table from the first migration was rolled back too
+        let tables: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'messages'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(tables, 0);
+    }
+}
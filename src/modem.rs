@@ -1,10 +1,17 @@
 use anyhow::{Context, Result};
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use tracing::warn;
-use zbus::{Connection, proxy};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use std::collections::HashMap;
+use tracing::{debug, warn};
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+use zbus::{Connection, MatchRule, MessageStream, proxy};
 
 use crate::utils::parse_rfc3339_timestamp;
 
+#[cfg(test)]
+pub mod mock;
+
 #[proxy(
     interface = "org.freedesktop.ModemManager1.Modem",
     default_service = "org.freedesktop.ModemManager1"
@@ -15,6 +22,9 @@ trait Modem {
 
     #[zbus(property)]
     fn sim(&self) -> zbus::Result<zbus::zvariant::OwnedObjectPath>;
+
+    #[zbus(property)]
+    fn own_numbers(&self) -> zbus::Result<Vec<String>>;
 }
 
 #[proxy(
@@ -24,6 +34,10 @@ trait Modem {
 trait ModemMessaging {
     fn list(&self) -> zbus::Result<Vec<zbus::zvariant::OwnedObjectPath>>;
     fn delete(&self, path: &zbus::zvariant::ObjectPath<'_>) -> zbus::Result<()>;
+    fn create(&self, properties: HashMap<&str, Value<'_>>) -> zbus::Result<OwnedObjectPath>;
+
+    #[zbus(signal)]
+    fn added(&self, path: OwnedObjectPath, received: bool) -> zbus::Result<()>;
 }
 
 #[proxy(
@@ -39,6 +53,37 @@ trait Sms {
 
     #[zbus(property)]
     fn timestamp(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn pdu_type(&self) -> zbus::Result<u32>;
+
+    #[zbus(property)]
+    fn state(&self) -> zbus::Result<u32>;
+
+    #[zbus(property)]
+    fn message_reference(&self) -> zbus::Result<u32>;
+
+    #[zbus(property)]
+    fn delivery_state(&self) -> zbus::Result<u32>;
+
+    /// When the network delivered the SMS or gave up, from its status report
+    #[zbus(property)]
+    fn discharge_timestamp(&self) -> zbus::Result<String>;
+
+    fn send(&self) -> zbus::Result<()>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.ModemManager1.Modem.Modem3gpp.Ussd",
+    default_service = "org.freedesktop.ModemManager1"
+)]
+trait Ussd {
+    fn initiate(&self, command: &str) -> zbus::Result<String>;
+    fn respond(&self, response: &str) -> zbus::Result<String>;
+    fn cancel(&self) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn state(&self) -> zbus::Result<u32>;
 }
 
 #[proxy(
@@ -46,8 +91,18 @@ trait Sms {
     default_service = "org.freedesktop.ModemManager1"
 )]
 trait Sim {
+    #[zbus(property)]
+    fn imsi(&self) -> zbus::Result<String>;
+
+    /// The ICCID printed on the SIM card
     #[zbus(property)]
     fn sim_identifier(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn operator_identifier(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn operator_name(&self) -> zbus::Result<String>;
 }
 
 #[proxy(
@@ -56,31 +111,196 @@ trait Sim {
     default_path = "/org/freedesktop/ModemManager1"
 )]
 trait ObjectManager {
-    fn get_managed_objects(
+    fn get_managed_objects(&self) -> zbus::Result<ManagedObjects>;
+
+    #[zbus(signal)]
+    fn interfaces_added(
         &self,
-    ) -> zbus::Result<
-        std::collections::HashMap<
-            zbus::zvariant::OwnedObjectPath,
-            std::collections::HashMap<
-                String,
-                std::collections::HashMap<String, zbus::zvariant::OwnedValue>,
-            >,
-        >,
-    >;
-}
-
-#[derive(serde::Serialize)]
+        object_path: OwnedObjectPath,
+        interfaces_and_properties: HashMap<String, HashMap<String, OwnedValue>>,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn interfaces_removed(
+        &self,
+        object_path: OwnedObjectPath,
+        interfaces: Vec<String>,
+    ) -> zbus::Result<()>;
+}
+
+const MODEM_MANAGER_SERVICE: &str = "org.freedesktop.ModemManager1";
+const MODEM_INTERFACE: &str = "org.freedesktop.ModemManager1.Modem";
+const MESSAGING_INTERFACE: &str = "org.freedesktop.ModemManager1.Modem.Messaging";
+
+type ManagedObjects = HashMap<OwnedObjectPath, HashMap<String, HashMap<String, OwnedValue>>>;
+
+/// MM_SMS_STATE_RECEIVING: a multipart SMS with parts still missing
+const SMS_STATE_RECEIVING: u32 = 2;
+
+/// MM_SMS_STATE_SENT: the SMS was handed to the network
+const SMS_STATE_SENT: u32 = 5;
+
+/// Time the modem gets to confirm that it handed an SMS to the network
+const SEND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// MM_SMS_PDU_TYPE_SUBMIT: an SMS created locally for sending
+const SMS_PDU_TYPE_SUBMIT: u32 = 2;
+
+/// MM_SMS_PDU_TYPE_STATUS_REPORT: the network's delivery report for an SMS
+/// sent earlier
+const SMS_PDU_TYPE_STATUS_REPORT: u32 = 3;
+
+/// MM_SMS_PDU_TYPE_CDMA_SUBMIT: the CDMA equivalent of SMS_PDU_TYPE_SUBMIT
+const SMS_PDU_TYPE_CDMA_SUBMIT: u32 = 5;
+
+/// MM_MODEM_3GPP_USSD_SESSION_STATE_USER_RESPONSE: the network waits for
+/// an answer to its last USSD message
+const USSD_STATE_USER_RESPONSE: u32 = 3;
+
+/// MM_SMS_DELIVERY_STATE_UNKNOWN: no status report has arrived yet
+pub const DELIVERY_STATE_UNKNOWN: u32 = 0x100;
+
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct ModemInfo {
     pub path: String,
     pub imei: String,
+    /// Subscriber identity of the SIM
     pub imsi: String,
+    /// Serial number of the SIM card
+    pub iccid: String,
+    /// MCC and MNC of the SIM's home network
+    pub operator_id: String,
+    pub operator_name: String,
+    /// Phone numbers of the SIM, if the network or SIM provides them
+    pub own_numbers: Vec<String>,
+}
+
+impl ModemInfo {
+    /// Whether `id` is the IMSI, ICCID or one of the own numbers of this modem's SIM
+    pub fn matches_sim(&self, id: &str) -> bool {
+        self.imsi == id || self.iccid == id || self.own_numbers.iter().any(|n| n == id)
+    }
 }
 
+#[derive(Debug, Clone)]
 pub struct SmsInfo {
     pub sender: String,
     pub text: String,
     pub timestamp: DateTime<Utc>,
     pub sms_path: String,
+    /// ModemManager is still waiting for further parts of a multipart SMS;
+    /// `text` only contains the parts received so far
+    pub receiving: bool,
+    /// Set if this is a status report rather than a message, in which case
+    /// `sender` is the recipient of the reported SMS and `text` is empty
+    pub status_report: Option<DeliveryReport>,
+}
+
+/// Delivery state of a sent SMS as reported by the network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryReport {
+    /// TP-Message-Reference the network assigned to the sent SMS
+    pub message_reference: u32,
+    /// TP-Status of 3GPP TS 23.040, or `DELIVERY_STATE_UNKNOWN` before a
+    /// report arrived
+    pub delivery_state: u32,
+    /// When the network delivered the SMS or gave up
+    pub discharge_time: Option<DateTime<Utc>>,
+}
+
+/// An SMS handed to the network by [`ModemBackend::send_message`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentSms {
+    pub sms_path: String,
+    /// Identifies the delivery report the network sends for this SMS
+    pub message_reference: u32,
+}
+
+/// The modem was asked to send an SMS but did not confirm it. The SMS may
+/// have reached the network anyway, so it must not be sent again.
+#[derive(Debug)]
+pub struct SendFailed {
+    /// The SMS object created for the send, still stored on the modem for
+    /// the caller to delete
+    pub sms_path: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for SendFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to send SMS {}: {}", self.sms_path, self.reason)
+    }
+}
+
+impl std::error::Error for SendFailed {}
+
+/// The progress of a sent SMS, read from its object on the modem
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentSmsState {
+    /// The modem finished handing the SMS to the network
+    pub sent: bool,
+    pub report: DeliveryReport,
+}
+
+/// What the network answered to a USSD command or response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UssdReply {
+    pub text: String,
+    /// The network asked a question, typically a menu, and keeps the
+    /// session open for an answer
+    pub awaiting_response: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum ModemEvent {
+    /// A new SMS was received on the modem at `modem_path`
+    MessageAdded {
+        modem_path: String,
+        sms_path: String,
+    },
+    /// A modem appeared or disappeared
+    ModemsChanged,
+}
+
+/// Access to modems and their SMS storage. Implemented by [`ModemManager`]
+/// over D-Bus, and by an in-memory mock in tests.
+#[async_trait]
+pub trait ModemBackend: Send + Sync {
+    async fn get_modems(&self) -> Result<Vec<ModemInfo>>;
+
+    async fn get_messages(&self, modem_path: &str) -> Result<Vec<SmsInfo>>;
+
+    /// Reads a received SMS. Returns None for outgoing SMS and for SMS
+    /// that no longer exist, e.g. because they were deleted or their modem
+    /// was removed.
+    async fn get_message(&self, sms_path: &str) -> Result<Option<SmsInfo>>;
+
+    /// Subscribes to notifications about new SMS and modem hotplug.
+    /// The stream ends if the underlying connection goes away.
+    async fn subscribe(&self) -> Result<BoxStream<'static, ModemEvent>>;
+
+    async fn delete_message(&self, modem_path: &str, sms_path: &str) -> Result<()>;
+
+    /// Sends an SMS with a delivery report requested. The SMS object stays
+    /// on the modem so its delivery can be followed with
+    /// [`ModemBackend::get_sent_message`]. Fails with [`SendFailed`] once
+    /// the modem was asked to send, and with other errors before that.
+    async fn send_message(&self, modem_path: &str, number: &str, text: &str) -> Result<SentSms>;
+
+    /// Reads the state and delivery state of an SMS sent earlier
+    async fn get_sent_message(&self, sms_path: &str) -> Result<SentSmsState>;
+
+    /// Starts a USSD session with a code such as `*100#`
+    async fn ussd_initiate(&self, modem_path: &str, command: &str) -> Result<UssdReply>;
+
+    /// Answers the network in the USSD session waiting for a response
+    async fn ussd_respond(&self, modem_path: &str, response: &str) -> Result<UssdReply>;
+
+    /// Ends the USSD session of a modem
+    async fn ussd_cancel(&self, modem_path: &str) -> Result<()>;
+
+    /// Checks that ModemManager is running and reachable
+    async fn ping(&self) -> Result<()>;
 }
 
 pub struct ModemManager {
@@ -88,10 +308,18 @@ pub struct ModemManager {
 }
 
 impl ModemManager {
-    pub async fn new() -> Result<Self> {
-        let conn = Connection::system()
-            .await
-            .context("Failed to connect to system D-Bus")?;
+    /// Connects to the system bus, or to `address` if given
+    pub async fn new(address: Option<&str>) -> Result<Self> {
+        let conn = match address {
+            Some(address) => zbus::connection::Builder::address(address)
+                .with_context(|| format!("Invalid D-Bus address: {}", address))?
+                .build()
+                .await
+                .with_context(|| format!("Failed to connect to D-Bus at {}", address))?,
+            None => Connection::system()
+                .await
+                .context("Failed to connect to system D-Bus")?,
+        };
         Ok(Self { conn })
     }
 
@@ -128,6 +356,51 @@ impl ModemManager {
             .context("Failed to create SMS proxy")
     }
 
+    async fn create_ussd_proxy<'a>(&'a self, path: &'a str) -> Result<UssdProxy<'a>> {
+        // The session state is read right after each call, before a
+        // PropertiesChanged signal could update a cached value
+        UssdProxy::builder(&self.conn)
+            .path(path)?
+            .cache_properties(zbus::proxy::CacheProperties::No)
+            .build()
+            .await
+            .context("Failed to create USSD proxy")
+    }
+
+    /// Reads the session state following a USSD reply
+    async fn ussd_reply(&self, proxy: &UssdProxy<'_>, text: String) -> Result<UssdReply> {
+        let state = proxy
+            .state()
+            .await
+            .context("Failed to get USSD session state")?;
+        Ok(UssdReply {
+            text,
+            awaiting_response: state == USSD_STATE_USER_RESPONSE,
+        })
+    }
+
+    /// Reads the delivery properties of a sent SMS or a status report
+    async fn delivery_report(&self, proxy: &SmsProxy<'_>) -> Result<DeliveryReport> {
+        let message_reference = proxy
+            .message_reference()
+            .await
+            .context("Failed to get SMS message reference")?;
+        let delivery_state = proxy
+            .delivery_state()
+            .await
+            .context("Failed to get SMS delivery state")?;
+        let discharge_timestamp = proxy
+            .discharge_timestamp()
+            .await
+            .context("Failed to get SMS discharge timestamp")?;
+
+        Ok(DeliveryReport {
+            message_reference,
+            delivery_state,
+            discharge_time: parse_rfc3339_timestamp(&discharge_timestamp).ok(),
+        })
+    }
+
     async fn create_sim_proxy<'a>(
         &'a self,
         path: zbus::zvariant::OwnedObjectPath,
@@ -138,8 +411,11 @@ impl ModemManager {
             .await
             .context("Failed to create SIM proxy")
     }
+}
 
-    pub async fn get_modems(&self) -> Result<Vec<ModemInfo>> {
+#[async_trait]
+impl ModemBackend for ModemManager {
+    async fn get_modems(&self) -> Result<Vec<ModemInfo>> {
         let proxy = ObjectManagerProxy::new(&self.conn)
             .await
             .context("Failed to create ObjectManager proxy")?;
@@ -151,7 +427,7 @@ impl ModemManager {
         let mut modems = Vec::new();
 
         for (path, interfaces) in objects {
-            if interfaces.contains_key("org.freedesktop.ModemManager1.Modem") {
+            if interfaces.contains_key(MODEM_INTERFACE) {
                 let modem_proxy = self.create_modem_proxy(path.clone()).await?;
 
                 let imei = modem_proxy
@@ -159,18 +435,36 @@ impl ModemManager {
                     .await
                     .context("Failed to get modem IMEI")?;
 
+                let own_numbers = modem_proxy
+                    .own_numbers()
+                    .await
+                    .context("Failed to get own numbers")?;
+
                 let sim_path = modem_proxy.sim().await.context("Failed to get SIM path")?;
 
                 let sim_proxy = self.create_sim_proxy(sim_path).await?;
-                let imsi = sim_proxy
+                let imsi = sim_proxy.imsi().await.context("Failed to get SIM IMSI")?;
+                let iccid = sim_proxy
                     .sim_identifier()
                     .await
-                    .context("Failed to get SIM IMSI")?;
+                    .context("Failed to get SIM ICCID")?;
+                let operator_id = sim_proxy
+                    .operator_identifier()
+                    .await
+                    .context("Failed to get SIM operator identifier")?;
+                let operator_name = sim_proxy
+                    .operator_name()
+                    .await
+                    .context("Failed to get SIM operator name")?;
 
                 modems.push(ModemInfo {
                     path: path.to_string(),
                     imei,
                     imsi,
+                    iccid,
+                    operator_id,
+                    operator_name,
+                    own_numbers,
                 });
             }
         }
@@ -180,7 +474,7 @@ impl ModemManager {
         Ok(modems)
     }
 
-    pub async fn get_messages(&self, modem_path: &str) -> Result<Vec<SmsInfo>> {
+    async fn get_messages(&self, modem_path: &str) -> Result<Vec<SmsInfo>> {
         let messaging_proxy = self.create_messaging_proxy(modem_path).await?;
 
         let sms_paths = messaging_proxy
@@ -190,42 +484,133 @@ impl ModemManager {
         let mut messages = Vec::new();
 
         for sms_path in sms_paths {
-            let sms_proxy = self.create_sms_proxy(sms_path.clone()).await?;
+            if let Some(sms) = self.get_message(sms_path.as_str()).await? {
+                messages.push(sms);
+            }
+        }
 
-            let sender = sms_proxy
-                .number()
-                .await
-                .context("Failed to get SMS sender")?;
-            let text = sms_proxy.text().await.context("Failed to get SMS text")?;
-            let timestamp_str = sms_proxy
-                .timestamp()
-                .await
-                .context("Failed to get SMS timestamp")?;
-
-            // Parse timestamp with warning on failure
-            let timestamp = match parse_rfc3339_timestamp(&timestamp_str) {
-                Ok(dt) => dt,
-                Err(e) => {
-                    warn!(
-                        "Failed to parse SMS timestamp '{}': {}. Using current time.",
-                        timestamp_str, e
-                    );
-                    Utc::now()
+        Ok(messages)
+    }
+
+    async fn get_message(&self, sms_path: &str) -> Result<Option<SmsInfo>> {
+        let sms_obj_path = OwnedObjectPath::try_from(sms_path)
+            .context(format!("Invalid SMS path: {}", sms_path))?;
+        let sms_proxy = self.create_sms_proxy(sms_obj_path).await?;
+
+        let pdu_type = match sms_proxy.pdu_type().await {
+            Ok(pdu_type) => pdu_type,
+            Err(e) if is_unknown_object(&e) => {
+                debug!(path = %sms_path, "SMS no longer exists");
+                return Ok(None);
+            }
+            Err(e) => return Err(e).context("Failed to get SMS PDU type"),
+        };
+        // Skip outgoing messages created through send_message
+        if pdu_type == SMS_PDU_TYPE_SUBMIT || pdu_type == SMS_PDU_TYPE_CDMA_SUBMIT {
+            debug!(path = %sms_path, "Skipping outgoing SMS");
+            return Ok(None);
+        }
+
+        let sender = sms_proxy
+            .number()
+            .await
+            .context("Failed to get SMS sender")?;
+        let text = sms_proxy.text().await.context("Failed to get SMS text")?;
+        let timestamp_str = sms_proxy
+            .timestamp()
+            .await
+            .context("Failed to get SMS timestamp")?;
+        let state = sms_proxy.state().await.context("Failed to get SMS state")?;
+
+        let status_report = if pdu_type == SMS_PDU_TYPE_STATUS_REPORT {
+            Some(self.delivery_report(&sms_proxy).await?)
+        } else {
+            None
+        };
+
+        // Parse timestamp with warning on failure
+        let timestamp = match parse_rfc3339_timestamp(&timestamp_str) {
+            Ok(dt) => dt,
+            Err(e) => {
+                warn!(
+                    "Failed to parse SMS timestamp '{}': {}. Using current time.",
+                    timestamp_str, e
+                );
+                Utc::now()
+            }
+        };
+
+        Ok(Some(SmsInfo {
+            sender,
+            text,
+            timestamp,
+            sms_path: sms_path.to_string(),
+            receiving: state == SMS_STATE_RECEIVING,
+            status_report,
+        }))
+    }
+
+    async fn subscribe(&self) -> Result<BoxStream<'static, ModemEvent>> {
+        let rule = MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .sender(MODEM_MANAGER_SERVICE)?
+            .interface(MESSAGING_INTERFACE)?
+            .member("Added")?
+            .build();
+        let added = MessageStream::for_match_rule(rule, &self.conn, None)
+            .await
+            .context("Failed to subscribe to Messaging.Added signals")?
+            .filter_map(|msg| async move {
+                let msg = msg.ok()?;
+                let modem_path = msg.header().path()?.to_string();
+                let signal = Added::from_message(msg)?;
+                let args = signal.args().ok()?;
+
+                // Messages created locally for sending are reported with received=false
+                if !args.received {
+                    return None;
                 }
-            };
 
-            messages.push(SmsInfo {
-                sender,
-                text,
-                timestamp,
-                sms_path: sms_path.to_string(),
+                Some(ModemEvent::MessageAdded {
+                    modem_path,
+                    sms_path: args.path.to_string(),
+                })
             });
-        }
 
-        Ok(messages)
+        let proxy = ObjectManagerProxy::new(&self.conn)
+            .await
+            .context("Failed to create ObjectManager proxy")?;
+        let interfaces_added = proxy
+            .receive_interfaces_added()
+            .await
+            .context("Failed to subscribe to InterfacesAdded signals")?
+            .filter_map(|signal| async move {
+                let args = signal.args().ok()?;
+                args.interfaces_and_properties
+                    .contains_key(MODEM_INTERFACE)
+                    .then_some(ModemEvent::ModemsChanged)
+            });
+        let interfaces_removed = proxy
+            .receive_interfaces_removed()
+            .await
+            .context("Failed to subscribe to InterfacesRemoved signals")?
+            .filter_map(|signal| async move {
+                let args = signal.args().ok()?;
+                args.interfaces
+                    .iter()
+                    .any(|i| i == MODEM_INTERFACE)
+                    .then_some(ModemEvent::ModemsChanged)
+            });
+
+        Ok(stream::select_all([
+            added.boxed(),
+            interfaces_added.boxed(),
+            interfaces_removed.boxed(),
+        ])
+        .boxed())
     }
 
-    pub async fn delete_message(&self, modem_path: &str, sms_path: &str) -> Result<()> {
+    async fn delete_message(&self, modem_path: &str, sms_path: &str) -> Result<()> {
         let messaging_proxy = self.create_messaging_proxy(modem_path).await?;
 
         let sms_obj_path = zbus::zvariant::ObjectPath::try_from(sms_path)
@@ -237,4 +622,114 @@ impl ModemManager {
             .context("Failed to delete SMS from modem")?;
         Ok(())
     }
+
+    async fn send_message(&self, modem_path: &str, number: &str, text: &str) -> Result<SentSms> {
+        let messaging_proxy = self.create_messaging_proxy(modem_path).await?;
+
+        let mut properties = HashMap::new();
+        properties.insert("number", Value::from(number));
+        properties.insert("text", Value::from(text));
+        properties.insert("delivery-report-request", Value::from(true));
+
+        let sms_path = messaging_proxy
+            .create(properties)
+            .await
+            .context("Failed to create SMS on modem")?;
+
+        let sms_proxy = self.create_sms_proxy(sms_path.clone()).await?;
+        let sent = tokio::time::timeout(SEND_TIMEOUT, async {
+            sms_proxy.send().await?;
+            sms_proxy.message_reference().await
+        })
+        .await;
+
+        match sent {
+            Ok(Ok(message_reference)) => Ok(SentSms {
+                sms_path: sms_path.to_string(),
+                message_reference,
+            }),
+            Ok(Err(e)) => Err(SendFailed {
+                sms_path: sms_path.to_string(),
+                reason: e.to_string(),
+            }
+            .into()),
+            Err(_) => Err(SendFailed {
+                sms_path: sms_path.to_string(),
+                reason: format!("not confirmed within {}s", SEND_TIMEOUT.as_secs()),
+            }
+            .into()),
+        }
+    }
+
+    async fn get_sent_message(&self, sms_path: &str) -> Result<SentSmsState> {
+        let sms_obj_path = OwnedObjectPath::try_from(sms_path)
+            .context(format!("Invalid SMS path: {}", sms_path))?;
+        let sms_proxy = self.create_sms_proxy(sms_obj_path).await?;
+
+        let state = sms_proxy.state().await.context("Failed to get SMS state")?;
+        Ok(SentSmsState {
+            sent: state == SMS_STATE_SENT,
+            report: self.delivery_report(&sms_proxy).await?,
+        })
+    }
+
+    async fn ussd_initiate(&self, modem_path: &str, command: &str) -> Result<UssdReply> {
+        let proxy = self.create_ussd_proxy(modem_path).await?;
+        let text = proxy
+            .initiate(command)
+            .await
+            .context("Failed to initiate USSD session")?;
+        self.ussd_reply(&proxy, text).await
+    }
+
+    async fn ussd_respond(&self, modem_path: &str, response: &str) -> Result<UssdReply> {
+        let proxy = self.create_ussd_proxy(modem_path).await?;
+        let text = proxy
+            .respond(response)
+            .await
+            .context("Failed to send USSD response")?;
+        self.ussd_reply(&proxy, text).await
+    }
+
+    async fn ussd_cancel(&self, modem_path: &str) -> Result<()> {
+        let proxy = self.create_ussd_proxy(modem_path).await?;
+        proxy
+            .cancel()
+            .await
+            .context("Failed to cancel USSD session")
+    }
+
+    async fn ping(&self) -> Result<()> {
+        let proxy = zbus::fdo::DBusProxy::new(&self.conn)
+            .await
+            .context("Failed to create D-Bus proxy")?;
+        let running = proxy
+            .name_has_owner(MODEM_MANAGER_SERVICE.try_into()?)
+            .await
+            .context("Failed to query the D-Bus daemon")?;
+
+        if !running {
+            anyhow::bail!("{} is not running", MODEM_MANAGER_SERVICE);
+        }
+        Ok(())
+    }
+}
+
+/// Whether a call failed because the object it was made on does not exist.
+/// GDBus reports unknown object paths as unknown methods or interfaces.
+fn is_unknown_object(e: &zbus::Error) -> bool {
+    use zbus::fdo::Error as Fdo;
+    match e {
+        zbus::Error::FDO(e) => matches!(
+            **e,
+            Fdo::UnknownObject(_) | Fdo::UnknownMethod(_) | Fdo::UnknownInterface(_)
+        ),
+        zbus::Error::MethodError(name, _, _) => matches!(
+            name.as_str(),
+            "org.freedesktop.DBus.Error.UnknownObject"
+                | "org.freedesktop.DBus.Error.UnknownMethod"
+                | "org.freedesktop.DBus.Error.UnknownInterface"
+        ),
+        _ => false,
+    }
 }
@@ -0,0 +1,423 @@
+//! Scriptable in-memory [`ModemBackend`] for tests that need no D-Bus.
+
+use super::{
+    DELIVERY_STATE_UNKNOWN, DeliveryReport, ModemBackend, ModemEvent, ModemInfo, SendFailed,
+    SentSms, SentSmsState, SmsInfo, UssdReply,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+/// An SMS handed to [`ModemBackend::send_message`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentMessage {
+    pub modem_path: String,
+    pub number: String,
+    pub text: String,
+}
+
+#[derive(Default)]
+struct State {
+    modems: BTreeMap<String, ModemInfo>,
+    /// SMS stored on each modem, keyed by modem path
+    messages: BTreeMap<String, Vec<SmsInfo>>,
+    deleted: Vec<String>,
+    sent: Vec<SentMessage>,
+    /// Sent SMS still stored on a modem, keyed by SMS path
+    outgoing: BTreeMap<String, SentSmsState>,
+    next_reference: u32,
+    /// Replies to USSD codes and responses, by what was sent
+    ussd_replies: HashMap<String, UssdReply>,
+    /// Modems with a USSD session waiting for a response
+    ussd_sessions: BTreeSet<String>,
+    /// Everything sent over USSD, in order
+    ussd_sent: Vec<String>,
+    next_sms: u32,
+    fail_get_modems: bool,
+    fail_get_message: bool,
+    fail_deletes: bool,
+    fail_sends: bool,
+    reject_sends: bool,
+    unreachable: bool,
+    subscribers: Vec<mpsc::UnboundedSender<ModemEvent>>,
+}
+
+impl State {
+    fn ussd_reply(&mut self, modem_path: &str, sent: &str) -> Result<UssdReply> {
+        self.ussd_sent.push(sent.to_string());
+        let Some(reply) = self.ussd_replies.get(sent).cloned() else {
+            anyhow::bail!("Mock network has no reply to {}", sent);
+        };
+        if reply.awaiting_response {
+            self.ussd_sessions.insert(modem_path.to_string());
+        }
+        Ok(reply)
+    }
+}
+
+#[derive(Default)]
+pub struct MockModemBackend {
+    state: Mutex<State>,
+}
+
+impl MockModemBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_modem(&self, path: &str, imei: &str, imsi: &str, iccid: &str) -> ModemInfo {
+        let modem = ModemInfo {
+            path: path.to_string(),
+            imei: imei.to_string(),
+            imsi: imsi.to_string(),
+            iccid: iccid.to_string(),
+            operator_id: imsi.chars().take(6).collect(),
+            operator_name: "Mock Mobile".to_string(),
+            own_numbers: Vec::new(),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.modems.insert(path.to_string(), modem.clone());
+        state.messages.entry(path.to_string()).or_default();
+        modem
+    }
+
+    pub fn set_own_numbers(&self, modem_path: &str, numbers: &[&str]) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(modem) = state.modems.get_mut(modem_path) {
+            modem.own_numbers = numbers.iter().map(|n| n.to_string()).collect();
+        }
+    }
+
+    /// Stores a received SMS on a modem and returns its object path
+    pub fn add_message(
+        &self,
+        modem_path: &str,
+        sender: &str,
+        text: &str,
+        timestamp: DateTime<Utc>,
+    ) -> String {
+        self.insert_message(modem_path, sender, text, timestamp, false)
+    }
+
+    /// Stores a multipart SMS that is still waiting for parts
+    pub fn add_receiving_message(
+        &self,
+        modem_path: &str,
+        sender: &str,
+        text: &str,
+        timestamp: DateTime<Utc>,
+    ) -> String {
+        self.insert_message(modem_path, sender, text, timestamp, true)
+    }
+
+    /// Marks a multipart SMS as complete with its full text
+    pub fn complete_message(&self, sms_path: &str, text: &str) {
+        let mut state = self.state.lock().unwrap();
+        for sms in state.messages.values_mut().flatten() {
+            if sms.sms_path == sms_path {
+                sms.text = text.to_string();
+                sms.receiving = false;
+            }
+        }
+    }
+
+    fn insert_message(
+        &self,
+        modem_path: &str,
+        sender: &str,
+        text: &str,
+        timestamp: DateTime<Utc>,
+        receiving: bool,
+    ) -> String {
+        let mut state = self.state.lock().unwrap();
+        let sms_path = format!("/org/freedesktop/ModemManager1/SMS/{}", state.next_sms);
+        state.next_sms += 1;
+
+        state
+            .messages
+            .entry(modem_path.to_string())
+            .or_default()
+            .push(SmsInfo {
+                sender: sender.to_string(),
+                text: text.to_string(),
+                timestamp,
+                sms_path: sms_path.clone(),
+                receiving,
+                status_report: None,
+            });
+
+        sms_path
+    }
+
+    /// Stores a status report for the SMS sent to `recipient` with
+    /// `message_reference`, as a separate message on the modem
+    pub fn add_status_report(
+        &self,
+        modem_path: &str,
+        recipient: &str,
+        message_reference: u32,
+        delivery_state: u32,
+    ) -> String {
+        let sms_path = self.insert_message(modem_path, recipient, "", Utc::now(), false);
+        let mut state = self.state.lock().unwrap();
+        for sms in state.messages.values_mut().flatten() {
+            if sms.sms_path == sms_path {
+                sms.status_report = Some(DeliveryReport {
+                    message_reference,
+                    delivery_state,
+                    discharge_time: Some(sms.timestamp),
+                });
+            }
+        }
+        sms_path
+    }
+
+    /// Applies a status report to the sent SMS object, as ModemManager does
+    /// when it still has the SMS
+    pub fn set_delivery_state(&self, sms_path: &str, delivery_state: u32) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(sent) = state.outgoing.get_mut(sms_path) {
+            sent.report.delivery_state = delivery_state;
+            sent.report.discharge_time = Some(Utc::now());
+        }
+    }
+
+    /// Paths of the sent SMS still stored on the modems
+    pub fn outgoing(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .outgoing
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// SMS currently stored on a modem
+    pub fn messages(&self, modem_path: &str) -> Vec<SmsInfo> {
+        let state = self.state.lock().unwrap();
+        state.messages.get(modem_path).cloned().unwrap_or_default()
+    }
+
+    /// Paths of all SMS deleted so far
+    pub fn deleted(&self) -> Vec<String> {
+        self.state.lock().unwrap().deleted.clone()
+    }
+
+    pub fn sent(&self) -> Vec<SentMessage> {
+        self.state.lock().unwrap().sent.clone()
+    }
+
+    /// Makes the network answer `sent`, a USSD code or response, with `text`
+    pub fn set_ussd_reply(&self, sent: &str, text: &str, awaiting_response: bool) {
+        self.state.lock().unwrap().ussd_replies.insert(
+            sent.to_string(),
+            UssdReply {
+                text: text.to_string(),
+                awaiting_response,
+            },
+        );
+    }
+
+    /// USSD codes and responses sent so far
+    pub fn ussd_sent(&self) -> Vec<String> {
+        self.state.lock().unwrap().ussd_sent.clone()
+    }
+
+    pub fn fail_get_modems(&self, fail: bool) {
+        self.state.lock().unwrap().fail_get_modems = fail;
+    }
+
+    pub fn fail_get_message(&self, fail: bool) {
+        self.state.lock().unwrap().fail_get_message = fail;
+    }
+
+    pub fn fail_deletes(&self, fail: bool) {
+        self.state.lock().unwrap().fail_deletes = fail;
+    }
+
+    pub fn fail_sends(&self, fail: bool) {
+        self.state.lock().unwrap().fail_sends = fail;
+    }
+
+    /// Makes sends fail after the SMS was created on the modem, like a
+    /// `Send` call that ModemManager answers with an error
+    pub fn reject_sends(&self, reject: bool) {
+        self.state.lock().unwrap().reject_sends = reject;
+    }
+
+    /// Makes `ping` fail, as if ModemManager had stopped
+    pub fn set_unreachable(&self, unreachable: bool) {
+        self.state.lock().unwrap().unreachable = unreachable;
+    }
+
+    /// Delivers an event to all current subscribers
+    pub fn emit(&self, event: ModemEvent) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Ends all subscriptions, like a lost D-Bus connection would
+    pub fn disconnect(&self) {
+        self.state.lock().unwrap().subscribers.clear();
+    }
+}
+
+#[async_trait]
+impl ModemBackend for MockModemBackend {
+    async fn get_modems(&self) -> Result<Vec<ModemInfo>> {
+        let state = self.state.lock().unwrap();
+        if state.fail_get_modems {
+            anyhow::bail!("Mock failure listing modems");
+        }
+        Ok(state.modems.values().cloned().collect())
+    }
+
+    async fn get_messages(&self, modem_path: &str) -> Result<Vec<SmsInfo>> {
+        let state = self.state.lock().unwrap();
+        match state.messages.get(modem_path) {
+            Some(messages) => Ok(messages.clone()),
+            None => anyhow::bail!("Unknown modem: {}", modem_path),
+        }
+    }
+
+    async fn get_message(&self, sms_path: &str) -> Result<Option<SmsInfo>> {
+        let state = self.state.lock().unwrap();
+        if state.fail_get_message {
+            anyhow::bail!("Mock failure reading SMS {}", sms_path);
+        }
+        Ok(state
+            .messages
+            .values()
+            .flatten()
+            .find(|sms| sms.sms_path == sms_path)
+            .cloned())
+    }
+
+    async fn subscribe(&self) -> Result<BoxStream<'static, ModemEvent>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.state.lock().unwrap().subscribers.push(tx);
+
+        Ok(stream::unfold(rx, |mut rx| async move {
+            let event = rx.recv().await?;
+            Some((event, rx))
+        })
+        .boxed())
+    }
+
+    async fn delete_message(&self, modem_path: &str, sms_path: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.fail_deletes {
+            anyhow::bail!("Mock failure deleting {}", sms_path);
+        }
+
+        let Some(messages) = state.messages.get_mut(modem_path) else {
+            anyhow::bail!("Unknown modem: {}", modem_path);
+        };
+        messages.retain(|sms| sms.sms_path != sms_path);
+        state.outgoing.remove(sms_path);
+        state.deleted.push(sms_path.to_string());
+        Ok(())
+    }
+
+    async fn send_message(&self, modem_path: &str, number: &str, text: &str) -> Result<SentSms> {
+        let mut state = self.state.lock().unwrap();
+        if state.fail_sends {
+            anyhow::bail!("Mock failure sending to {}", number);
+        }
+        if !state.modems.contains_key(modem_path) {
+            anyhow::bail!("Unknown modem: {}", modem_path);
+        }
+
+        state.sent.push(SentMessage {
+            modem_path: modem_path.to_string(),
+            number: number.to_string(),
+            text: text.to_string(),
+        });
+
+        let sms_path = format!("/org/freedesktop/ModemManager1/SMS/{}", state.next_sms);
+        state.next_sms += 1;
+        if state.reject_sends {
+            state.outgoing.insert(
+                sms_path.clone(),
+                SentSmsState {
+                    sent: false,
+                    report: DeliveryReport {
+                        message_reference: 0,
+                        delivery_state: DELIVERY_STATE_UNKNOWN,
+                        discharge_time: None,
+                    },
+                },
+            );
+            return Err(SendFailed {
+                sms_path,
+                reason: "Mock network rejected the SMS".to_string(),
+            }
+            .into());
+        }
+        let message_reference = state.next_reference;
+        state.next_reference = (state.next_reference + 1) % 256;
+
+        state.outgoing.insert(
+            sms_path.clone(),
+            SentSmsState {
+                sent: true,
+                report: DeliveryReport {
+                    message_reference,
+                    delivery_state: DELIVERY_STATE_UNKNOWN,
+                    discharge_time: None,
+                },
+            },
+        );
+
+        Ok(SentSms {
+            sms_path,
+            message_reference,
+        })
+    }
+
+    async fn get_sent_message(&self, sms_path: &str) -> Result<SentSmsState> {
+        match self.state.lock().unwrap().outgoing.get(sms_path) {
+            Some(sent) => Ok(sent.clone()),
+            None => anyhow::bail!("Unknown SMS: {}", sms_path),
+        }
+    }
+
+    async fn ussd_initiate(&self, modem_path: &str, command: &str) -> Result<UssdReply> {
+        let mut state = self.state.lock().unwrap();
+        if !state.modems.contains_key(modem_path) {
+            anyhow::bail!("Unknown modem: {}", modem_path);
+        }
+        if state.ussd_sessions.contains(modem_path) {
+            anyhow::bail!("A USSD session is already active");
+        }
+        state.ussd_reply(modem_path, command)
+    }
+
+    async fn ussd_respond(&self, modem_path: &str, response: &str) -> Result<UssdReply> {
+        let mut state = self.state.lock().unwrap();
+        if !state.ussd_sessions.remove(modem_path) {
+            anyhow::bail!("No USSD session is waiting for a response");
+        }
+        state.ussd_reply(modem_path, response)
+    }
+
+    async fn ussd_cancel(&self, modem_path: &str) -> Result<()> {
+        self.state.lock().unwrap().ussd_sessions.remove(modem_path);
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        if self.state.lock().unwrap().unreachable {
+            anyhow::bail!("Mock ModemManager is not running");
+        }
+        Ok(())
+    }
+}
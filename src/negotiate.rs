@@ -0,0 +1,112 @@
+/// Picks the media type to respond with from an `Accept` header (RFC 9110
+/// section 12.5.1). `supported` is in the server's order of preference,
+/// which decides between types the client rates equally, e.g. for `*/*`.
+/// Without an `Accept` header the first supported type is used. Returns
+/// None if the client accepts none of the supported types.
+pub fn negotiate<'a>(accept: Option<&str>, supported: &[&'a str]) -> Option<&'a str> {
+    let Some(accept) = accept.filter(|accept| !accept.trim().is_empty()) else {
+        return supported.first().copied();
+    };
+
+    let ranges: Vec<(String, f32)> = accept.split(',').filter_map(parse_range).collect();
+
+    let mut best = None;
+    let mut best_quality = 0.0;
+    for &media_type in supported {
+        let quality = quality(&ranges, media_type);
+        if quality > best_quality {
+            best = Some(media_type);
+            best_quality = quality;
+        }
+    }
+    best
+}
+
+/// Parses one media range such as `text/csv;q=0.5` into the lowercased
+/// range and its quality. Malformed ranges are skipped.
+fn parse_range(range: &str) -> Option<(String, f32)> {
+    let mut parts = range.split(';').map(str::trim);
+    let media_range = parts.next()?.to_ascii_lowercase();
+    if !media_range.contains('/') {
+        return None;
+    }
+
+    let mut quality = 1.0;
+    for param in parts {
+        if let Some((name, value)) = param.split_once('=')
+            && name.trim().eq_ignore_ascii_case("q")
+        {
+            quality = value
+                .trim()
+                .parse()
+                .ok()
+                .filter(|q| (0.0..=1.0).contains(q))?;
+        }
+    }
+
+    Some((media_range, quality))
+}
+
+/// The quality the client gives `media_type`, taken from the most specific
+/// range that matches it
+fn quality(ranges: &[(String, f32)], media_type: &str) -> f32 {
+    let type_wildcard = match media_type.split_once('/') {
+        Some((kind, _)) => format!("{}/*", kind),
+        None => return 0.0,
+    };
+
+    ranges
+        .iter()
+        .filter_map(|(range, quality)| {
+            let specificity = if range == media_type {
+                2
+            } else if *range == type_wildcard {
+                1
+            } else if range == "*/*" {
+                0
+            } else {
+                return None;
+            };
+            Some((specificity, *quality))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map_or(0.0, |(_, quality)| quality)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUPPORTED: &[&str] = &["application/json", "application/x-ndjson", "text/csv"];
+
+    #[test]
+    fn honours_preferences_and_wildcards() {
+        let cases = [
+            (None, Some("application/json")),
+            (Some("*/*"), Some("application/json")),
+            (Some("text/csv"), Some("text/csv")),
+            (Some("TEXT/CSV; charset=utf-8"), Some("text/csv")),
+            (Some("text/*"), Some("text/csv")),
+            (
+                Some("application/json;q=0.5, application/x-ndjson"),
+                Some("application/x-ndjson"),
+            ),
+            (Some("text/csv;q=0.2, */*;q=0.1"), Some("text/csv")),
+            (
+                Some("*/*, application/json;q=0"),
+                Some("application/x-ndjson"),
+            ),
+            (
+                Some("text/html,application/xhtml+xml,*/*;q=0.8"),
+                Some("application/json"),
+            ),
+            (Some("text/html"), None),
+            (Some("text/csv;q=0"), None),
+            (Some("text/csv;q=abc"), None),
+        ];
+
+        for (accept, expected) in cases {
+            assert_eq!(negotiate(accept, SUPPORTED), expected, "{:?}", accept);
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use crate::config::{OutboxPolicy, RateLimit};
+use crate::db::{Database, OutgoingMessage};
+use crate::modem::{ModemBackend, ModemInfo, SendFailed};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify, Semaphore};
+use tracing::{debug, error, info, warn};
+
+/// How often the outbox is checked for due messages when nothing wakes
+/// the sender earlier
+const SEND_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time a whole send may take. ModemManager gives the `Send` call a minute
+/// of its own, so this only ends sends that hang elsewhere.
+const SEND_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Sends the messages queued in the outbox. Every SIM sends up to the
+/// policy's concurrency at once, within its rate limits. Sends that fail
+/// before the modem is asked to send are retried with exponential backoff;
+/// later failures may have reached the network and are not.
+pub struct OutboxSender {
+    db: Arc<Mutex<Database>>,
+    modem_manager: Arc<dyn ModemBackend>,
+    policy: OutboxPolicy,
+    /// Signalled when a message is queued or a send finishes
+    wake: Notify,
+    /// Free send slots per IMSI
+    slots: std::sync::Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+/// How many messages a SIM may send right now
+enum Budget {
+    Remaining(usize),
+    /// The rate limit is used up until the given time
+    Exhausted(DateTime<Utc>),
+}
+
+impl OutboxSender {
+    pub fn new(
+        db: Arc<Mutex<Database>>,
+        modem_manager: Arc<dyn ModemBackend>,
+        policy: OutboxPolicy,
+    ) -> Self {
+        Self {
+            db,
+            modem_manager,
+            policy,
+            wake: Notify::new(),
+            slots: Default::default(),
+        }
+    }
+
+    pub fn policy(&self) -> &OutboxPolicy {
+        &self.policy
+    }
+
+    /// Makes the sender check the outbox now, e.g. after queueing a message
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    pub async fn start(self: Arc<Self>) {
+        info!(
+            concurrency = self.policy.concurrency,
+            max_attempts = self.policy.max_attempts,
+            "Starting outbox sender"
+        );
+
+        loop {
+            if let Err(e) = self.send_due().await {
+                error!("Error sending queued messages: {:#}", e);
+            }
+
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep(SEND_INTERVAL) => {}
+            }
+        }
+    }
+
+    fn slots_for(&self, imsi: &str) -> Arc<Semaphore> {
+        self.slots
+            .lock()
+            .unwrap()
+            .entry(imsi.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.policy.concurrency)))
+            .clone()
+    }
+
+    /// Starts sending the due messages of every SIM that has a free slot
+    /// and is within its rate limits
+    async fn send_due(self: &Arc<Self>) -> Result<()> {
+        let now = Utc::now();
+        let imsis = self.db.lock().await.get_due_outgoing_imsis(now)?;
+        if imsis.is_empty() {
+            return Ok(());
+        }
+
+        let modems = self.modem_manager.get_modems().await?;
+        for imsi in imsis {
+            // Messages wait in the queue while their SIM is not in a modem
+            let Some(modem) = modems.iter().find(|m| m.imsi == imsi) else {
+                debug!(imsi = %imsi, "SIM of queued messages is not in any modem");
+                continue;
+            };
+
+            let slots = self.slots_for(&imsi);
+            let free = slots.available_permits();
+            if free == 0 {
+                continue;
+            }
+            let in_flight = self.policy.concurrency - free;
+
+            let db = self.db.lock().await;
+            let limit = match send_budget(
+                &db,
+                self.policy.rate_limit(&imsi),
+                &imsi,
+                in_flight,
+                now,
+            )? {
+                Budget::Remaining(remaining) => remaining.min(free),
+                Budget::Exhausted(until) => {
+                    let deferred = db.defer_outgoing_messages(&imsi, until)?;
+                    info!(imsi = %imsi, deferred, "SIM reached its rate limit, deferring queued messages until {}", until);
+                    continue;
+                }
+            };
+            if limit == 0 {
+                continue;
+            }
+
+            for message in db.get_due_outgoing_messages(&imsi, now, limit)? {
+                let id = message.id.context("Queued message without id")?;
+                let Ok(permit) = slots.clone().try_acquire_owned() else {
+                    break;
+                };
+                if !db.claim_outgoing_message(id)? {
+                    continue;
+                }
+
+                let sender = self.clone();
+                let modem = modem.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = sender.send(id, message, &modem).await {
+                        error!(id, "Failed to record outbox send: {:#}", e);
+                    }
+                    drop(permit);
+                    sender.wake();
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs a send round and waits until its sends are recorded
+    #[cfg(test)]
+    pub async fn send_due_and_wait(self: &Arc<Self>) {
+        self.send_due().await.unwrap();
+        let slots: Vec<_> = self.slots.lock().unwrap().values().cloned().collect();
+        for slots in slots {
+            while slots.available_permits() < self.policy.concurrency {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+    }
+
+    /// Makes one attempt at sending a claimed message and records the outcome
+    async fn send(&self, id: i64, message: OutgoingMessage, modem: &ModemInfo) -> Result<()> {
+        let attempts = message.attempts + 1;
+        let result = tokio::time::timeout(
+            SEND_TIMEOUT,
+            self.modem_manager
+                .send_message(&modem.path, &message.recipient, &message.text),
+        )
+        .await;
+        // Once the modem was asked to send, the SMS may have reached the
+        // network even if the send failed, so it is not sent again. A send
+        // cut off by the timeout may have got that far too.
+        let (result, retriable) = match result {
+            Ok(Err(e)) => {
+                let retriable = !e.is::<SendFailed>();
+                (Err(e), retriable)
+            }
+            Ok(sent) => (sent, false),
+            Err(_) => (
+                Err(anyhow::anyhow!(
+                    "ModemManager did not finish sending within {}s",
+                    SEND_TIMEOUT.as_secs()
+                )),
+                false,
+            ),
+        };
+
+        // The poller skips sent SMS, so the object of a failed send would
+        // stay in the modem's storage for good
+        if let Err(e) = &result
+            && let Some(failed) = e.downcast_ref::<SendFailed>()
+            && let Err(e) = self
+                .modem_manager
+                .delete_message(&modem.path, &failed.sms_path)
+                .await
+        {
+            warn!(id, sms_path = %failed.sms_path, error = %e, "Failed to delete SMS of failed send");
+        }
+
+        let db = self.db.lock().await;
+        match result {
+            // The poller follows the delivery and removes the SMS from the
+            // modem once it is final
+            Ok(sent) => {
+                info!(id, imsi = %modem.imsi, recipient = %message.recipient, attempts, "Sent SMS");
+                db.mark_outgoing_sent(id, attempts, &modem.path, &sent)
+            }
+            Err(e) if !retriable || attempts >= self.policy.max_attempts => {
+                error!(
+                    id,
+                    imsi = %modem.imsi,
+                    attempts,
+                    retriable,
+                    error = %e,
+                    "Sending SMS failed permanently"
+                );
+                db.mark_outgoing_failed(id, attempts, &e.to_string())
+            }
+            Err(e) => {
+                let next_attempt_at = Utc::now() + self.retry_delay(attempts);
+                warn!(
+                    id,
+                    imsi = %modem.imsi,
+                    attempts,
+                    error = %e,
+                    "Sending SMS failed, retrying at {}",
+                    next_attempt_at
+                );
+                db.schedule_outgoing_retry(id, attempts, next_attempt_at, &e.to_string())
+            }
+        }
+    }
+
+    fn retry_delay(&self, attempts: u32) -> chrono::Duration {
+        let exponent = attempts.saturating_sub(1).min(20);
+        let secs = self
+            .policy
+            .retry_delay
+            .saturating_mul(1 << exponent)
+            .min(self.policy.max_retry_delay);
+        chrono::Duration::seconds(secs as i64)
+    }
+}
+
+/// Works out how many more messages a SIM may send under its rate limit.
+/// Messages still being sent count as sent now.
+fn send_budget(
+    db: &Database,
+    limit: RateLimit,
+    imsi: &str,
+    in_flight: usize,
+    now: DateTime<Utc>,
+) -> Result<Budget> {
+    let windows = [
+        (limit.hourly, chrono::Duration::hours(1)),
+        (limit.daily, chrono::Duration::days(1)),
+    ];
+    if windows.iter().all(|(limit, _)| *limit == 0) {
+        return Ok(Budget::Remaining(usize::MAX));
+    }
+
+    let sent = db.get_send_times(imsi, now - chrono::Duration::days(1))?;
+    let mut remaining = usize::MAX;
+    let mut exhausted_until: Option<DateTime<Utc>> = None;
+    for (limit, window) in windows {
+        let limit = limit as usize;
+        if limit == 0 {
+            continue;
+        }
+
+        let since = now - window;
+        let times: Vec<_> = sent.iter().filter(|t| **t >= since).collect();
+        if times.len() >= limit {
+            // Sending is possible again once enough of these leave the window
+            let until = *times[times.len() - limit] + window;
+            exhausted_until = Some(exhausted_until.map_or(until, |u| u.max(until)));
+        } else {
+            remaining = remaining.min(limit - times.len());
+        }
+    }
+
+    Ok(match exhausted_until {
+        Some(until) => Budget::Exhausted(until),
+        None => Budget::Remaining(remaining.saturating_sub(in_flight)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::OutgoingStatus;
+    use crate::modem::mock::MockModemBackend;
+
+    const MODEM: &str = "/org/freedesktop/ModemManager1/Modem/0";
+    const IMSI: &str = "310260123456789";
+
+    fn setup(
+        policy: OutboxPolicy,
+    ) -> (
+        Arc<MockModemBackend>,
+        Arc<Mutex<Database>>,
+        Arc<OutboxSender>,
+    ) {
+        let backend = Arc::new(MockModemBackend::new());
+        backend.add_modem(MODEM, "123456789012345", IMSI, "8901260123456789012");
+        let db = Arc::new(Mutex::new(Database::new(":memory:").unwrap()));
+        let sender = Arc::new(OutboxSender::new(db.clone(), backend.clone(), policy));
+        (backend, db, sender)
+    }
+
+    async fn queue(
+        backend: &MockModemBackend,
+        db: &Mutex<Database>,
+        text: &str,
+        send_at: Option<DateTime<Utc>>,
+    ) -> i64 {
+        let modem = backend.get_modems().await.unwrap().remove(0);
+        let message =
+            OutgoingMessage::queued(&modem, "+1234567890".to_string(), text.to_string(), send_at);
+        db.lock().await.insert_outgoing_message(&message).unwrap()
+    }
+
+    async fn message(db: &Mutex<Database>, id: i64) -> OutgoingMessage {
+        db.lock().await.get_outgoing_message(id).unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn sends_queued_messages_up_to_the_concurrency() {
+        let (backend, db, sender) = setup(OutboxPolicy {
+            concurrency: 2,
+            ..Default::default()
+        });
+        for text in ["one", "two", "three"] {
+            queue(&backend, &db, text, None).await;
+        }
+
+        sender.send_due().await.unwrap();
+        // Nothing yielded to the spawned sends yet
+        assert_eq!(message(&db, 2).await.status, OutgoingStatus::Sending);
+        assert_eq!(message(&db, 3).await.status, OutgoingStatus::Queued);
+
+        sender.send_due_and_wait().await;
+        sender.send_due_and_wait().await;
+        let sent: Vec<_> = backend.sent().into_iter().map(|s| s.text).collect();
+        assert_eq!(sent, vec!["one", "two", "three"]);
+        let message = message(&db, 3).await;
+        assert_eq!(message.status, OutgoingStatus::Sent);
+        assert_eq!(message.attempts, 1);
+        assert!(message.sent_at.is_some());
+        assert!(message.next_attempt_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn retries_failed_sends_with_backoff() {
+        let (backend, db, sender) = setup(OutboxPolicy {
+            max_attempts: 2,
+            retry_delay: 10,
+            ..Default::default()
+        });
+        let id = queue(&backend, &db, "Hello", None).await;
+        backend.fail_sends(true);
+
+        sender.send_due_and_wait().await;
+        let queued = message(&db, id).await;
+        assert_eq!(queued.status, OutgoingStatus::Queued);
+        assert_eq!(queued.attempts, 1);
+        assert!(queued.error.unwrap().contains("Mock failure"));
+        let delay = queued.next_attempt_at.unwrap() - Utc::now();
+        assert!(delay > chrono::Duration::seconds(8), "{}", delay);
+
+        // Not due yet
+        sender.send_due_and_wait().await;
+        assert_eq!(message(&db, id).await.attempts, 1);
+
+        let due = Utc::now() - chrono::Duration::seconds(1);
+        db.lock()
+            .await
+            .schedule_outgoing_retry(id, 1, due, "Mock failure")
+            .unwrap();
+        sender.send_due_and_wait().await;
+        let failed = message(&db, id).await;
+        assert_eq!(failed.status, OutgoingStatus::Failed);
+        assert_eq!(failed.attempts, 2);
+        assert!(backend.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_sends_the_modem_was_asked_to_make() {
+        let (backend, db, sender) = setup(OutboxPolicy {
+            max_attempts: 3,
+            ..Default::default()
+        });
+        let id = queue(&backend, &db, "Hello", None).await;
+        backend.reject_sends(true);
+
+        sender.send_due_and_wait().await;
+        let failed = message(&db, id).await;
+        assert_eq!(failed.status, OutgoingStatus::Failed);
+        assert_eq!(failed.attempts, 1);
+        assert!(failed.error.unwrap().contains("Mock network rejected"));
+        assert!(failed.next_attempt_at.is_none());
+        // The SMS created for the send does not stay in the modem's storage
+        assert!(backend.outgoing().is_empty());
+        assert_eq!(backend.deleted().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn waits_for_scheduled_send_time() {
+        let (backend, db, sender) = setup(OutboxPolicy::default());
+        let later = Utc::now() + chrono::Duration::minutes(5);
+        let id = queue(&backend, &db, "Later", Some(later)).await;
+
+        sender.send_due_and_wait().await;
+        assert!(backend.sent().is_empty());
+        assert_eq!(message(&db, id).await.next_attempt_at, Some(later));
+    }
+
+    #[tokio::test]
+    async fn defers_messages_beyond_the_rate_limit() {
+        let (backend, db, sender) = setup(OutboxPolicy {
+            concurrency: 5,
+            rate_limit: RateLimit {
+                hourly: 2,
+                daily: 0,
+            },
+            ..Default::default()
+        });
+        for text in ["one", "two", "three"] {
+            queue(&backend, &db, text, None).await;
+        }
+
+        sender.send_due_and_wait().await;
+        assert_eq!(backend.sent().len(), 2);
+
+        sender.send_due_and_wait().await;
+        assert_eq!(backend.sent().len(), 2);
+        let deferred = message(&db, 3).await;
+        assert_eq!(deferred.status, OutgoingStatus::Queued);
+        let first_sent = message(&db, 1).await.sent_at.unwrap();
+        assert_eq!(
+            deferred.next_attempt_at,
+            Some(first_sent + chrono::Duration::hours(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_cancelled_messages() {
+        let (backend, db, sender) = setup(OutboxPolicy::default());
+        let id = queue(&backend, &db, "Hello", None).await;
+
+        assert!(db.lock().await.cancel_outgoing_message(id).unwrap());
+        sender.send_due_and_wait().await;
+
+        assert!(backend.sent().is_empty());
+        assert_eq!(message(&db, id).await.status, OutgoingStatus::Cancelled);
+        assert!(!db.lock().await.cancel_outgoing_message(id).unwrap());
+    }
+}
@@ -1,44 +1,236 @@
-use crate::db::{Database, SmsMessage};
-use crate::modem::ModemManager;
+use crate::db::{Database, DeliveryStatus, OutgoingMessage, SmsMessage};
+use crate::metrics::Metrics;
+use crate::modem::{DELIVERY_STATE_UNKNOWN, DeliveryReport, ModemBackend, ModemEvent, ModemInfo};
+use crate::webhook::WebhookDispatcher;
 use anyhow::Result;
+use chrono::Utc;
+use futures_util::StreamExt;
+use futures_util::stream::BoxStream;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
-use tracing::{debug, error, info};
+use tokio::sync::{Mutex, broadcast, watch};
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{debug, error, info, warn};
+
+/// How often incomplete multipart messages are re-read. ModemManager does
+/// not signal when the last part arrives, so this bounds the added latency.
+const PENDING_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long the delivery of a sent SMS is followed before it is recorded as
+/// unknown. Networks typically retry undelivered SMS for a few days.
+const DELIVERY_TRACKING_WINDOW: chrono::Duration = chrono::Duration::days(3);
 
 pub struct SmsPoller {
-    modem_manager: Arc<ModemManager>,
+    modem_manager: Arc<dyn ModemBackend>,
     db: Arc<Mutex<Database>>,
-    poll_interval: Duration,
+    webhooks: Arc<WebhookDispatcher>,
+    /// Publishes every stored message to live API streams
+    messages: broadcast::Sender<SmsMessage>,
+    /// Changed on configuration reload
+    poll_interval: watch::Sender<Duration>,
+    multipart_timeout: chrono::Duration,
+    /// Modems seen during the last poll, keyed by D-Bus path
+    modems: Mutex<HashMap<String, ModemInfo>>,
+    metrics: Arc<Metrics>,
 }
 
 impl SmsPoller {
     pub fn new(
-        modem_manager: Arc<ModemManager>,
+        modem_manager: Arc<dyn ModemBackend>,
         db: Arc<Mutex<Database>>,
+        webhooks: Arc<WebhookDispatcher>,
+        messages: broadcast::Sender<SmsMessage>,
         poll_interval_secs: u64,
+        multipart_timeout_secs: u64,
+        metrics: Arc<Metrics>,
     ) -> Self {
         Self {
             modem_manager,
             db,
-            poll_interval: Duration::from_secs(poll_interval_secs),
+            webhooks,
+            messages,
+            poll_interval: watch::Sender::new(Duration::from_secs(poll_interval_secs)),
+            multipart_timeout: chrono::Duration::seconds(multipart_timeout_secs as i64),
+            modems: Mutex::new(HashMap::new()),
+            metrics,
         }
     }
 
+    /// Changes the interval of full polls, starting a new interval now
+    pub fn set_poll_interval(&self, poll_interval_secs: u64) {
+        self.poll_interval
+            .send_replace(Duration::from_secs(poll_interval_secs));
+    }
+
+    /// Follows the interval of full polls as it is changed
+    pub fn poll_interval(&self) -> watch::Receiver<Duration> {
+        self.poll_interval.subscribe()
+    }
+
+    /// Ingests messages as ModemManager announces them, with a full poll
+    /// every `poll_interval` as a safety net for missed signals.
     pub async fn start(self: Arc<Self>) {
-        info!("Starting SMS polling service");
+        info!("Starting SMS ingestion service");
 
         loop {
-            if let Err(e) = self.poll_modems().await {
-                error!("Error polling modems: {}", e);
+            match self.modem_manager.subscribe().await {
+                Ok(events) => self.run(events).await,
+                Err(e) => {
+                    error!("Failed to subscribe to ModemManager signals: {}", e);
+                    if let Err(e) = self.poll_modems().await {
+                        error!("Error polling modems: {}", e);
+                    }
+                    if let Err(e) = self.check_pending().await {
+                        error!("Error checking pending multipart messages: {}", e);
+                    }
+                    let poll_interval = *self.poll_interval.borrow();
+                    tokio::time::sleep(poll_interval).await;
+                }
             }
+        }
+    }
+
+    /// Handles events until the signal stream ends
+    async fn run(&self, mut events: BoxStream<'static, ModemEvent>) {
+        info!("Subscribed to ModemManager signals");
+
+        // The first tick fires immediately to catch up on messages that
+        // arrived before the subscription was established
+        let mut poll_interval = self.poll_interval.subscribe();
+        let mut interval = tokio::time::interval(*poll_interval.borrow_and_update());
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
-            tokio::time::sleep(self.poll_interval).await;
+        let mut pending_interval = tokio::time::interval(PENDING_CHECK_INTERVAL);
+        pending_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(event) => self.handle_event(event).await,
+                    None => {
+                        warn!("ModemManager signal stream ended, resubscribing");
+                        return;
+                    }
+                },
+                _ = interval.tick() => {
+                    if let Err(e) = self.poll_modems().await {
+                        error!("Error polling modems: {}", e);
+                    }
+                }
+                _ = pending_interval.tick() => {
+                    if let Err(e) = self.check_pending().await {
+                        error!("Error checking pending multipart messages: {}", e);
+                    }
+                }
+                Ok(()) = poll_interval.changed() => {
+                    let period = *poll_interval.borrow_and_update();
+                    interval = tokio::time::interval_at(Instant::now() + period, period);
+                    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                    info!(seconds = period.as_secs(), "Poll interval changed");
+                }
+            }
+        }
+    }
+
+    async fn handle_event(&self, event: ModemEvent) {
+        match event {
+            ModemEvent::ModemsChanged => {
+                info!("Modems changed, refreshing");
+                if let Err(e) = self.poll_modems().await {
+                    error!("Error polling modems: {}", e);
+                }
+            }
+            ModemEvent::MessageAdded {
+                modem_path,
+                sms_path,
+            } => {
+                debug!(modem = %modem_path, sms = %sms_path, "SMS added");
+
+                let Some(modem) = self.find_modem(&modem_path).await else {
+                    warn!(modem = %modem_path, "SMS added on unknown modem, ignoring");
+                    return;
+                };
+
+                match self.modem_manager.get_message(&sms_path).await {
+                    Ok(Some(sms)) => {
+                        if let Err(e) = self.process_message(&modem, sms).await {
+                            error!(error = %e, "Failed to process message");
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!(imei = %modem.imei, imsi = %modem.imsi, error = %e, "Failed to read added message");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Looks up a modem by path, refreshing the cache if it is not known yet
+    async fn find_modem(&self, modem_path: &str) -> Option<ModemInfo> {
+        if let Some(modem) = self.modems.lock().await.get(modem_path) {
+            return Some(modem.clone());
+        }
+
+        self.refresh_modems().await.ok()?;
+        self.modems.lock().await.get(modem_path).cloned()
+    }
+
+    async fn refresh_modems(&self) -> Result<Vec<ModemInfo>> {
+        let modems = self.modem_manager.get_modems().await?;
+
+        let previous = std::mem::replace(
+            &mut *self.modems.lock().await,
+            modems
+                .iter()
+                .map(|modem| (modem.path.clone(), modem.clone()))
+                .collect(),
+        );
+
+        self.metrics.modems.set(modems.len() as i64);
+        for removed in previous
+            .values()
+            .filter(|m| !modems.iter().any(|modem| modem.path == m.path))
+        {
+            let _ = self
+                .metrics
+                .last_successful_poll
+                .remove_label_values(&[&removed.imsi, &removed.imei]);
+        }
+
+        for modem in &modems {
+            let known = previous
+                .get(&modem.path)
+                .is_some_and(|m| m.iccid == modem.iccid);
+            if !known {
+                self.backfill_sim_identity(modem).await;
+            }
+        }
+
+        Ok(modems)
+    }
+
+    /// Updates messages stored before the SIM's identifiers were recorded
+    /// separately, once the SIM shows up again
+    async fn backfill_sim_identity(&self, modem: &ModemInfo) {
+        let db = self.db.lock().await;
+        match db.backfill_sim_identity(modem) {
+            Ok(0) => {}
+            Ok(updated) => {
+                info!(iccid = %modem.iccid, imsi = %modem.imsi, updated, "Backfilled SIM identifiers on stored messages")
+            }
+            Err(e) => {
+                error!(iccid = %modem.iccid, error = %e, "Failed to backfill SIM identifiers")
+            }
         }
     }
 
     async fn poll_modems(&self) -> Result<()> {
-        let modems = match self.modem_manager.get_modems().await {
+        self.metrics.poller_heartbeat.set(Utc::now().timestamp());
+        let _timer = self.metrics.poll_duration.start_timer();
+
+        let modems = match self.refresh_modems().await {
             Ok(modems) => modems,
             Err(e) => {
                 error!("Failed to get modems list: {}", e);
@@ -53,6 +245,11 @@ impl SmsPoller {
 
             match self.modem_manager.get_messages(&modem.path).await {
                 Ok(messages) => {
+                    self.metrics
+                        .last_successful_poll
+                        .with_label_values(&[&modem.imsi, &modem.imei])
+                        .set(Utc::now().timestamp());
+
                     if messages.is_empty() {
                         continue;
                     }
@@ -76,54 +273,249 @@ impl SmsPoller {
             }
         }
 
+        if let Err(e) = self.check_deliveries().await {
+            error!("Error checking delivery of sent messages: {}", e);
+        }
+
+        Ok(())
+    }
+
+    /// Reads the delivery state of sent SMS until it is final. ModemManager
+    /// applies status reports to the sent SMS it still has; reports it
+    /// stores separately are handled by `process_status_report`.
+    async fn check_deliveries(&self) -> Result<()> {
+        let pending = {
+            let db = self.db.lock().await;
+            db.get_pending_deliveries()?
+        };
+
+        for msg in pending {
+            let Some(id) = msg.id else { continue };
+
+            // The SMS object is gone if the modem was removed, but a
+            // separate status report may still arrive
+            let report = match &msg.sms_path {
+                Some(sms_path) => match self.modem_manager.get_sent_message(sms_path).await {
+                    Ok(state) if state.sent => Some(state.report),
+                    Ok(_) => None,
+                    Err(e) => {
+                        debug!(id, sms = %sms_path, error = %e, "Failed to read sent SMS");
+                        None
+                    }
+                },
+                None => None,
+            };
+
+            let mut status = report.as_ref().map_or(DeliveryStatus::Pending, |report| {
+                DeliveryStatus::from_delivery_state(report.delivery_state)
+            });
+            let delivery_state = report
+                .as_ref()
+                .map(|report| report.delivery_state)
+                .filter(|state| *state != DELIVERY_STATE_UNKNOWN);
+
+            if status == DeliveryStatus::Pending {
+                if Utc::now() - msg.timestamp < DELIVERY_TRACKING_WINDOW {
+                    // Record temporary errors while the network keeps trying
+                    if delivery_state.is_some() && delivery_state != msg.delivery_state {
+                        let db = self.db.lock().await;
+                        db.update_delivery(id, status, delivery_state, None)?;
+                    }
+                    continue;
+                }
+                warn!(id, imsi = %msg.imsi, "No final delivery report for sent SMS, giving up");
+                status = DeliveryStatus::Unknown;
+            }
+
+            {
+                let db = self.db.lock().await;
+                db.update_delivery(
+                    id,
+                    status,
+                    delivery_state,
+                    report.and_then(|report| report.discharge_time),
+                )?;
+            }
+            info!(id, imsi = %msg.imsi, status = status.as_str(), "Delivery of sent SMS is final");
+            self.remove_sent_message(&msg).await;
+        }
+
         Ok(())
     }
 
-    async fn process_message(
+    /// Applies a status report to the sent message it refers to. Reports are
+    /// removed from the modem and never stored as received messages.
+    async fn process_status_report(
         &self,
-        modem: &crate::modem::ModemInfo,
-        sms: crate::modem::SmsInfo,
+        modem: &ModemInfo,
+        sms: &crate::modem::SmsInfo,
+        report: &DeliveryReport,
     ) -> Result<()> {
-        let msg = SmsMessage {
-            id: None,
-            imei: modem.imei.clone(),
-            imsi: modem.imsi.clone(),
-            sender: sms.sender.clone(),
-            text: sms.text.clone(),
-            timestamp: sms.timestamp,
+        let status = DeliveryStatus::from_delivery_state(report.delivery_state);
+        let sent = {
+            let db = self.db.lock().await;
+            let sent =
+                db.find_pending_delivery(&modem.imsi, &sms.sender, report.message_reference)?;
+            if let Some(id) = sent.as_ref().and_then(|sent| sent.id) {
+                db.update_delivery(
+                    id,
+                    status,
+                    Some(report.delivery_state),
+                    report.discharge_time,
+                )?;
+            }
+            sent
         };
 
-        // Check if message already exists (without holding lock during network operations)
-        let message_exists = {
+        match &sent {
+            Some(sent) => {
+                info!(id = sent.id, imsi = %modem.imsi, status = status.as_str(), "Received delivery report");
+                if status != DeliveryStatus::Pending {
+                    self.remove_sent_message(sent).await;
+                }
+            }
+            None => {
+                warn!(
+                    imsi = %modem.imsi,
+                    message_reference = report.message_reference,
+                    "Delivery report matches no sent SMS, discarding"
+                );
+            }
+        }
+
+        if let Err(e) = self
+            .modem_manager
+            .delete_message(&modem.path, &sms.sms_path)
+            .await
+        {
+            error!(error = %e, "Failed to delete delivery report from modem");
+        }
+
+        Ok(())
+    }
+
+    /// Deletes a sent SMS from the modem once its delivery is final
+    async fn remove_sent_message(&self, msg: &OutgoingMessage) {
+        let (Some(modem_path), Some(sms_path)) = (&msg.modem_path, &msg.sms_path) else {
+            return;
+        };
+        if let Err(e) = self
+            .modem_manager
+            .delete_message(modem_path, sms_path)
+            .await
+        {
+            debug!(id = msg.id, sms = %sms_path, error = %e, "Failed to delete sent SMS from modem");
+        }
+    }
+
+    /// Re-reads incomplete multipart messages so they are stored as soon as
+    /// ModemManager has all parts, and stores whatever was received once
+    /// `multipart_timeout` has passed
+    async fn check_pending(&self) -> Result<()> {
+        let pending = {
             let db = self.db.lock().await;
-            db.message_exists(&msg)?
+            db.get_pending_messages()?
         };
 
-        if message_exists {
+        for pending in pending {
+            match self.modem_manager.get_message(&pending.sms_path).await {
+                Ok(Some(sms)) => {
+                    // Stores the parts and deletes the SMS from the modem
+                    // once it is complete or timed out
+                    let Some(modem) = self.find_modem(&pending.modem_path).await else {
+                        debug!(sms = %pending.sms_path, "Modem of pending multipart message not found, retrying");
+                        continue;
+                    };
+                    if let Err(e) = self.process_message(&modem, sms).await {
+                        error!(error = %e, "Failed to process pending message");
+                    }
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    // The SMS may well still be there; storing its parts now
+                    // would store the message twice once it is complete
+                    warn!(sms = %pending.sms_path, error = %e, "Failed to read pending multipart message, retrying");
+                    continue;
+                }
+            }
+
+            // The SMS object is gone, e.g. because the modem was removed
+            if Utc::now() - pending.first_seen < self.multipart_timeout {
+                continue;
+            }
+
+            warn!(
+                imsi = %pending.message.imsi,
+                sms = %pending.sms_path,
+                "Multipart message disappeared before all parts arrived, storing received parts"
+            );
+
+            let msg = SmsMessage {
+                partial: true,
+                ..pending.message
+            };
+
+            self.store_message(msg).await?;
+
+            let db = self.db.lock().await;
+            db.delete_pending_message(&pending.sms_path)?;
+        }
+
+        Ok(())
+    }
+
+    async fn process_message(&self, modem: &ModemInfo, sms: crate::modem::SmsInfo) -> Result<()> {
+        if let Some(report) = &sms.status_report {
+            return self.process_status_report(modem, &sms, report).await;
+        }
+
+        let mut msg =
+            SmsMessage::received(modem, sms.sender.clone(), sms.text.clone(), sms.timestamp);
+
+        // Leave incomplete multipart messages on the modem until ModemManager
+        // has assembled all parts or the timeout expires
+        if sms.receiving {
+            let first_seen = {
+                let db = self.db.lock().await;
+                db.upsert_pending_message(&modem.path, &sms.sms_path, &msg)?
+            };
+
+            if Utc::now() - first_seen < self.multipart_timeout {
+                debug!(sms = %sms.sms_path, "Multipart message incomplete, waiting for remaining parts");
+                return Ok(());
+            }
+
+            warn!(
+                imsi = %modem.imsi,
+                sms = %sms.sms_path,
+                "Multipart message still incomplete after timeout, storing received parts"
+            );
+            msg.partial = true;
+        }
+
+        if !self.store_message(msg).await? {
             info!(
                 "Message from {} already exists, deleting duplicate from modem",
                 sms.sender
             );
-            self.modem_manager
-                .delete_message(&modem.path, &sms.sms_path)
-                .await?;
-            return Ok(());
         }
 
-        // Save message to database
         {
             let db = self.db.lock().await;
-            db.insert_message(&msg)?;
+            db.delete_pending_message(&sms.sms_path)?;
         }
 
-        info!("Saved message from {} to database", msg.sender);
-
         // Only delete from modem after successful database insert
         if let Err(e) = self
             .modem_manager
             .delete_message(&modem.path, &sms.sms_path)
             .await
         {
+            self.metrics
+                .delete_failures
+                .with_label_values(&[&modem.imsi, &modem.imei])
+                .inc();
             error!(
                 "Failed to delete message from modem: {} - message will be reprocessed next poll",
                 e
@@ -133,4 +525,595 @@ impl SmsPoller {
 
         Ok(())
     }
+
+    /// Saves a message unless it is a duplicate, returning whether it was stored
+    async fn store_message(&self, mut msg: SmsMessage) -> Result<bool> {
+        // Check if message already exists (without holding lock during network operations)
+        let message_exists = {
+            let db = self.db.lock().await;
+            db.message_exists(&msg)?
+        };
+
+        if message_exists {
+            self.metrics
+                .duplicate_messages
+                .with_label_values(&[&msg.imsi, &msg.imei])
+                .inc();
+            return Ok(false);
+        }
+
+        // Save message to database and queue webhook deliveries
+        {
+            let db = self.db.lock().await;
+            let id = db.insert_message(&msg)?;
+
+            if let Err(e) = self.webhooks.enqueue(&db, id, &msg) {
+                error!(id, error = %e, "Failed to queue webhook deliveries");
+            }
+
+            msg.id = Some(id);
+        }
+
+        self.metrics
+            .messages_ingested
+            .with_label_values(&[&msg.imsi, &msg.imei])
+            .inc();
+        info!("Saved message from {} to database", msg.sender);
+
+        // Sending only fails when nobody is listening
+        let _ = self.messages.send(msg);
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MessageFilter;
+    use crate::metrics::InstrumentedBackend;
+    use crate::modem::mock::MockModemBackend;
+    use chrono::TimeZone;
+
+    const MODEM: &str = "/org/freedesktop/ModemManager1/Modem/0";
+    const IMEI: &str = "123456789012345";
+    const IMSI: &str = "310260123456789";
+    const ICCID: &str = "8901260123456789012";
+
+    struct Fixture {
+        backend: Arc<MockModemBackend>,
+        db: Arc<Mutex<Database>>,
+        poller: SmsPoller,
+        stored: broadcast::Receiver<SmsMessage>,
+        metrics: Arc<Metrics>,
+    }
+
+    fn fixture(multipart_timeout_secs: u64) -> Fixture {
+        let backend = Arc::new(MockModemBackend::new());
+        backend.add_modem(MODEM, IMEI, IMSI, ICCID);
+
+        let db = Arc::new(Mutex::new(Database::new(":memory:").unwrap()));
+        let webhooks = Arc::new(WebhookDispatcher::new(db.clone(), Vec::new(), None, 1).unwrap());
+        let (messages, stored) = broadcast::channel(16);
+        let metrics = Arc::new(Metrics::new());
+
+        let poller = SmsPoller::new(
+            Arc::new(InstrumentedBackend::new(backend.clone(), metrics.clone())),
+            db.clone(),
+            webhooks,
+            messages,
+            30,
+            multipart_timeout_secs,
+            metrics.clone(),
+        );
+
+        Fixture {
+            backend,
+            db,
+            poller,
+            stored,
+            metrics,
+        }
+    }
+
+    fn timestamp() -> chrono::DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 1, 9, 8, 20, 13).unwrap()
+    }
+
+    async fn stored_messages(db: &Arc<Mutex<Database>>) -> Vec<SmsMessage> {
+        db.lock()
+            .await
+            .get_messages(&MessageFilter {
+                sim: Some(IMSI.to_string()),
+                ..Default::default()
+            })
+            .unwrap()
+            .messages
+    }
+
+    #[tokio::test]
+    async fn stores_new_messages_and_deletes_them_from_modem() {
+        let f = fixture(300);
+        let sms_path =
+            f.backend
+                .add_message(MODEM, "+1234567890", "Your code is 4821", timestamp());
+
+        f.poller.poll_modems().await.unwrap();
+
+        let messages = stored_messages(&f.db).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].imei, IMEI);
+        assert_eq!(messages[0].sender, "+1234567890");
+        assert_eq!(messages[0].text, "Your code is 4821");
+        assert_eq!(messages[0].timestamp, timestamp());
+        assert!(!messages[0].partial);
+
+        assert!(f.backend.messages(MODEM).is_empty());
+        assert_eq!(f.backend.deleted(), vec![sms_path]);
+
+        let labels = [IMSI, IMEI];
+        assert_eq!(
+            f.metrics.messages_ingested.with_label_values(&labels).get(),
+            1
+        );
+        assert!(
+            f.metrics
+                .last_successful_poll
+                .with_label_values(&labels)
+                .get()
+                > 0
+        );
+        assert_eq!(f.metrics.modems.get(), 1);
+        assert_eq!(f.metrics.poll_duration.get_sample_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn deletes_duplicates_without_storing_them_again() {
+        let f = fixture(300);
+        f.backend
+            .add_message(MODEM, "+1234567890", "Your code is 4821", timestamp());
+        f.backend
+            .add_message(MODEM, "+1234567890", "Your code is 4821", timestamp());
+
+        f.poller.poll_modems().await.unwrap();
+
+        assert_eq!(stored_messages(&f.db).await.len(), 1);
+        assert!(f.backend.messages(MODEM).is_empty());
+        assert_eq!(f.backend.deleted().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn keeps_stored_message_on_modem_when_delete_fails() {
+        let f = fixture(300);
+        f.backend
+            .add_message(MODEM, "+1234567890", "Your code is 4821", timestamp());
+        f.backend.fail_deletes(true);
+
+        f.poller.poll_modems().await.unwrap();
+
+        assert_eq!(stored_messages(&f.db).await.len(), 1);
+        assert_eq!(f.backend.messages(MODEM).len(), 1);
+
+        // The next poll recognises the duplicate and retries the delete
+        f.backend.fail_deletes(false);
+        f.poller.poll_modems().await.unwrap();
+
+        assert_eq!(stored_messages(&f.db).await.len(), 1);
+        assert!(f.backend.messages(MODEM).is_empty());
+
+        let labels = [IMSI, IMEI];
+        let metrics = &f.metrics;
+        assert_eq!(metrics.delete_failures.with_label_values(&labels).get(), 1);
+        assert_eq!(
+            metrics.duplicate_messages.with_label_values(&labels).get(),
+            1
+        );
+        assert_eq!(
+            metrics
+                .dbus_errors
+                .with_label_values(&["delete_message"])
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn survives_modem_listing_failures() {
+        let f = fixture(300);
+        f.backend.fail_get_modems(true);
+
+        f.poller.poll_modems().await.unwrap();
+
+        assert!(stored_messages(&f.db).await.is_empty());
+        assert_eq!(
+            f.metrics
+                .dbus_errors
+                .with_label_values(&["get_modems"])
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn ingests_message_added_events() {
+        let f = fixture(300);
+        let sms_path =
+            f.backend
+                .add_message(MODEM, "+1234567890", "Your code is 4821", timestamp());
+
+        f.poller
+            .handle_event(ModemEvent::MessageAdded {
+                modem_path: MODEM.to_string(),
+                sms_path,
+            })
+            .await;
+
+        assert_eq!(stored_messages(&f.db).await.len(), 1);
+        assert!(f.backend.messages(MODEM).is_empty());
+    }
+
+    #[tokio::test]
+    async fn ignores_events_from_unknown_modems() {
+        let f = fixture(300);
+        let other = "/org/freedesktop/ModemManager1/Modem/9";
+        let sms_path =
+            f.backend
+                .add_message(other, "+1234567890", "Your code is 4821", timestamp());
+
+        f.poller
+            .handle_event(ModemEvent::MessageAdded {
+                modem_path: other.to_string(),
+                sms_path,
+            })
+            .await;
+
+        assert!(stored_messages(&f.db).await.is_empty());
+        assert!(f.backend.deleted().is_empty());
+    }
+
+    #[tokio::test]
+    async fn publishes_stored_messages() {
+        let mut f = fixture(300);
+        f.backend
+            .add_message(MODEM, "+1234567890", "Your code is 4821", timestamp());
+
+        f.poller.poll_modems().await.unwrap();
+
+        let published = f.stored.try_recv().unwrap();
+        assert_eq!(published.id, stored_messages(&f.db).await[0].id);
+        assert_eq!(published.imsi, IMSI);
+        assert_eq!(published.iccid, ICCID);
+    }
+
+    #[tokio::test]
+    async fn backfills_imsi_on_legacy_messages() {
+        let f = fixture(300);
+        {
+            // Stored by a release that put the ICCID into the imsi column
+            let db = f.db.lock().await;
+            let mut msg = SmsMessage::received(
+                &f.backend.get_modems().await.unwrap()[0],
+                "+1234567890".to_string(),
+                "Your code is 4821".to_string(),
+                timestamp(),
+            );
+            msg.imsi = ICCID.to_string();
+            msg.operator_id = String::new();
+            db.insert_message(&msg).unwrap();
+        }
+
+        f.poller.poll_modems().await.unwrap();
+
+        let messages = stored_messages(&f.db).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].imsi, IMSI);
+        assert_eq!(messages[0].iccid, ICCID);
+        assert_eq!(messages[0].operator_id, "310260");
+    }
+
+    #[tokio::test]
+    async fn waits_for_all_multipart_parts() {
+        let f = fixture(300);
+        let sms_path = f.backend.add_receiving_message(
+            MODEM,
+            "+1234567890",
+            "Your login link is https://exa",
+            timestamp(),
+        );
+
+        f.poller.poll_modems().await.unwrap();
+
+        assert!(stored_messages(&f.db).await.is_empty());
+        assert_eq!(f.backend.messages(MODEM).len(), 1);
+
+        f.backend.complete_message(
+            &sms_path,
+            "Your login link is https://example.com/login/4821",
+        );
+        f.poller.check_pending().await.unwrap();
+
+        let messages = stored_messages(&f.db).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].text,
+            "Your login link is https://example.com/login/4821"
+        );
+        assert!(!messages[0].partial);
+        assert!(f.backend.messages(MODEM).is_empty());
+        assert!(f.db.lock().await.get_pending_messages().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stores_incomplete_multipart_message_after_timeout() {
+        let f = fixture(0);
+        f.backend.add_receiving_message(
+            MODEM,
+            "+1234567890",
+            "Your login link is https://exa",
+            timestamp(),
+        );
+
+        f.poller.poll_modems().await.unwrap();
+
+        let messages = stored_messages(&f.db).await;
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].partial);
+        assert!(f.backend.messages(MODEM).is_empty());
+    }
+
+    #[tokio::test]
+    async fn stores_orphaned_multipart_parts_after_timeout() {
+        let f = fixture(0);
+        {
+            let modem = f.poller.find_modem(MODEM).await.unwrap();
+            let db = f.db.lock().await;
+            let msg = SmsMessage::received(
+                &modem,
+                "+1234567890".to_string(),
+                "Your login link is https://exa".to_string(),
+                timestamp(),
+            );
+            db.upsert_pending_message(MODEM, "/org/freedesktop/ModemManager1/SMS/9", &msg)
+                .unwrap();
+        }
+
+        f.poller.check_pending().await.unwrap();
+
+        let messages = stored_messages(&f.db).await;
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].partial);
+        assert!(f.db.lock().await.get_pending_messages().unwrap().is_empty());
+    }
+
+    /// Records a multipart SMS as pending, as the first poll that saw it did
+    async fn track_pending(f: &Fixture, sms_path: &str) {
+        let modem = f.backend.get_modems().await.unwrap().remove(0);
+        let msg = SmsMessage::received(
+            &modem,
+            "+1234567890".to_string(),
+            "Your login link is https://exa".to_string(),
+            timestamp(),
+        );
+        let db = f.db.lock().await;
+        db.upsert_pending_message(MODEM, sms_path, &msg).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_pending_multipart_parts_while_sms_cannot_be_read() {
+        let f = fixture(0);
+        let sms_path = f.backend.add_receiving_message(
+            MODEM,
+            "+1234567890",
+            "Your login link is https://exa",
+            timestamp(),
+        );
+        track_pending(&f, &sms_path).await;
+
+        // Neither a failed read nor a modem missing from the listing means
+        // the SMS is gone
+        f.backend.fail_get_message(true);
+        f.poller.check_pending().await.unwrap();
+        f.backend.fail_get_message(false);
+        f.backend.fail_get_modems(true);
+        f.poller.check_pending().await.unwrap();
+
+        assert!(stored_messages(&f.db).await.is_empty());
+        assert_eq!(f.db.lock().await.get_pending_messages().unwrap().len(), 1);
+
+        f.backend.fail_get_modems(false);
+        f.backend.complete_message(
+            &sms_path,
+            "Your login link is https://example.com/login/4821",
+        );
+        f.poller.check_pending().await.unwrap();
+
+        let messages = stored_messages(&f.db).await;
+        assert_eq!(messages.len(), 1);
+        assert!(!messages[0].partial);
+        assert!(f.backend.messages(MODEM).is_empty());
+    }
+
+    #[tokio::test]
+    async fn deletes_timed_out_multipart_parts_from_modem() {
+        let f = fixture(0);
+        let sms_path = f.backend.add_receiving_message(
+            MODEM,
+            "+1234567890",
+            "Your login link is https://exa",
+            timestamp(),
+        );
+        track_pending(&f, &sms_path).await;
+
+        f.poller.check_pending().await.unwrap();
+
+        let messages = stored_messages(&f.db).await;
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].partial);
+        assert_eq!(f.backend.deleted(), vec![sms_path]);
+        assert!(f.db.lock().await.get_pending_messages().unwrap().is_empty());
+    }
+
+    /// Sends an SMS through the mock and records it like the outbox does
+    async fn send_tracked(f: &Fixture, recipient: &str) -> OutgoingMessage {
+        let modem = f.backend.get_modems().await.unwrap().remove(0);
+        let sent = f
+            .backend
+            .send_message(MODEM, recipient, "Hello")
+            .await
+            .unwrap();
+        let db = f.db.lock().await;
+        let msg = OutgoingMessage::queued(&modem, recipient.to_string(), "Hello".to_string(), None);
+        let id = db.insert_outgoing_message(&msg).unwrap();
+        db.mark_outgoing_sent(id, 1, MODEM, &sent).unwrap();
+        db.get_outgoing_message(id).unwrap().unwrap()
+    }
+
+    async fn outgoing(f: &Fixture, msg: &OutgoingMessage) -> OutgoingMessage {
+        let db = f.db.lock().await;
+        db.get_outgoing_message(msg.id.unwrap()).unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn tracks_delivery_state_until_final() {
+        let f = fixture(300);
+        let msg = send_tracked(&f, "+1234567890").await;
+        let sms_path = msg.sms_path.clone().unwrap();
+
+        f.poller.poll_modems().await.unwrap();
+        let tracked = outgoing(&f, &msg).await;
+        assert_eq!(tracked.delivery_status, Some(DeliveryStatus::Pending));
+        assert_eq!(tracked.delivery_state, None);
+
+        // Recipient busy: the network keeps trying
+        f.backend.set_delivery_state(&sms_path, 0x21);
+        f.poller.poll_modems().await.unwrap();
+        let tracked = outgoing(&f, &msg).await;
+        assert_eq!(tracked.delivery_status, Some(DeliveryStatus::Pending));
+        assert_eq!(tracked.delivery_state, Some(0x21));
+        assert_eq!(f.backend.outgoing(), vec![sms_path.clone()]);
+
+        f.backend.set_delivery_state(&sms_path, 0x00);
+        f.poller.poll_modems().await.unwrap();
+        let tracked = outgoing(&f, &msg).await;
+        assert_eq!(tracked.delivery_status, Some(DeliveryStatus::Delivered));
+        assert_eq!(tracked.delivery_state, Some(0x00));
+        assert!(tracked.reported_at.is_some());
+        assert!(f.backend.outgoing().is_empty());
+    }
+
+    #[tokio::test]
+    async fn matches_status_reports_by_message_reference() {
+        let f = fixture(300);
+        let first = send_tracked(&f, "+1234567890").await;
+        let second = send_tracked(&f, "+1987654321").await;
+
+        let report = f.backend.add_status_report(
+            MODEM,
+            "+1987654321",
+            second.message_reference.unwrap(),
+            0x41,
+        );
+        let unmatched = f.backend.add_status_report(MODEM, "+1555000000", 200, 0x00);
+        f.poller.poll_modems().await.unwrap();
+
+        assert_eq!(
+            outgoing(&f, &first).await.delivery_status,
+            Some(DeliveryStatus::Pending)
+        );
+        let failed = outgoing(&f, &second).await;
+        assert_eq!(failed.delivery_status, Some(DeliveryStatus::Failed));
+        assert_eq!(failed.delivery_state, Some(0x41));
+
+        // Reports are consumed, never stored as received messages
+        assert!(stored_messages(&f.db).await.is_empty());
+        assert!(f.backend.messages(MODEM).is_empty());
+        let deleted = f.backend.deleted();
+        assert!(deleted.contains(&report) && deleted.contains(&unmatched));
+        assert!(deleted.contains(second.sms_path.as_ref().unwrap()));
+        assert_eq!(f.backend.outgoing(), vec![first.sms_path.unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn run_returns_when_signal_stream_ends() {
+        let f = fixture(300);
+        let events = f.backend.subscribe().await.unwrap();
+        f.backend.disconnect();
+
+        tokio::time::timeout(Duration::from_secs(5), f.poller.run(events))
+            .await
+            .expect("run should return once the stream ends");
+    }
+
+    #[tokio::test]
+    async fn ingests_messages_from_signal_stream() {
+        let f = fixture(300);
+        let events = f.backend.subscribe().await.unwrap();
+
+        let scenario = async {
+            // Let the initial catch-up poll finish before the message arrives
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let sms_path =
+                f.backend
+                    .add_message(MODEM, "+1234567890", "Your code is 4821", timestamp());
+            f.backend.emit(ModemEvent::MessageAdded {
+                modem_path: MODEM.to_string(),
+                sms_path,
+            });
+
+            while stored_messages(&f.db).await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                _ = f.poller.run(events) => panic!("run returned while subscribed"),
+                _ = scenario => {}
+            }
+        })
+        .await
+        .expect("message should be ingested from the added signal");
+
+        // Only the catch-up poll ran; the message came in through the signal
+        assert_eq!(f.metrics.poll_duration.get_sample_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn polls_immediately_when_a_modem_is_added() {
+        let f = fixture(300);
+        let events = f.backend.subscribe().await.unwrap();
+        let other = "/org/freedesktop/ModemManager1/Modem/1";
+
+        let scenario = async {
+            while f.metrics.poll_duration.get_sample_count() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            f.backend.add_modem(
+                other,
+                "123456789012346",
+                "310260987654321",
+                "8901260987654321098",
+            );
+            f.backend
+                .add_message(other, "+1234567890", "Your code is 4821", timestamp());
+            f.backend.emit(ModemEvent::ModemsChanged);
+
+            while !f.backend.messages(other).is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        // Well within the 30 second poll interval of the fixture
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                _ = f.poller.run(events) => panic!("run returned while subscribed"),
+                _ = scenario => {}
+            }
+        })
+        .await
+        .expect("an added modem should be polled right away");
+
+        assert_eq!(f.metrics.poll_duration.get_sample_count(), 2);
+        assert_eq!(f.metrics.modems.get(), 2);
+    }
 }
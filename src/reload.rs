@@ -0,0 +1,119 @@
+use crate::config::Config;
+use crate::poller::SmsPoller;
+use crate::tls::ReloadableTls;
+use crate::webhook::WebhookDispatcher;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::signal::unix::{SignalKind, signal};
+use tracing::{error, info, warn};
+use tracing_subscriber::{EnvFilter, Registry, reload};
+
+/// Swaps the log filter of the running subscriber
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Applies configuration changes on SIGHUP. TLS certificates are re-read,
+/// and so is the configuration, of which the poll interval, log level and
+/// webhook targets take effect immediately.
+pub struct Reloader {
+    config_path: Option<PathBuf>,
+    /// The configuration currently in effect
+    config: Config,
+    tls: Vec<ReloadableTls>,
+    log_filter: LogFilterHandle,
+    poller: Arc<SmsPoller>,
+    webhooks: Arc<WebhookDispatcher>,
+}
+
+impl Reloader {
+    pub fn new(
+        config_path: Option<PathBuf>,
+        config: Config,
+        tls: Vec<ReloadableTls>,
+        log_filter: LogFilterHandle,
+        poller: Arc<SmsPoller>,
+        webhooks: Arc<WebhookDispatcher>,
+    ) -> Self {
+        Self {
+            config_path,
+            config,
+            tls,
+            log_filter,
+            poller,
+            webhooks,
+        }
+    }
+
+    pub async fn start(mut self) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!(
+                    "Failed to install SIGHUP handler, configuration reload disabled: {}",
+                    e
+                );
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading configuration");
+            self.reload_tls();
+            self.reload_config();
+        }
+    }
+
+    fn reload_tls(&self) {
+        for listener in &self.tls {
+            match listener.reload() {
+                Ok(()) => info!(
+                    "Reloaded TLS certificates for the {} listener",
+                    listener.name()
+                ),
+                Err(e) => error!(
+                    "Failed to reload TLS certificates for the {} listener, keeping the previous ones: {:#}",
+                    listener.name(),
+                    e
+                ),
+            }
+        }
+    }
+
+    fn reload_config(&mut self) {
+        let config = match Config::load(self.config_path.as_deref()) {
+            Ok(config) => config,
+            Err(e) => {
+                error!(
+                    "Failed to reload configuration, keeping the current settings: {:#}",
+                    e
+                );
+                return;
+            }
+        };
+
+        if config.poll_interval != self.config.poll_interval {
+            self.poller.set_poll_interval(config.poll_interval);
+            self.config.poll_interval = config.poll_interval;
+        }
+
+        if config.log_level != self.config.log_level {
+            // The filter was validated when the configuration was loaded
+            match self.log_filter.reload(EnvFilter::new(&config.log_level)) {
+                Ok(()) => {
+                    info!(filter = %config.log_level, "Log level changed");
+                    self.config.log_level = config.log_level.clone();
+                }
+                Err(e) => error!("Failed to change log level: {}", e),
+            }
+        }
+
+        if config.webhooks != self.config.webhooks {
+            self.webhooks.set_targets(config.webhooks.clone());
+            info!(targets = config.webhooks.len(), "Webhook targets changed");
+            self.config.webhooks = config.webhooks.clone();
+        }
+
+        if config != self.config {
+            warn!("Some changed settings only take effect after a restart");
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use crate::config::{RetentionPolicy, VacuumMode};
+use crate::db::Database;
+use crate::metrics::Metrics;
+use anyhow::Result;
+use chrono::{Duration as ChronoDuration, Utc};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, warn};
+
+/// Deletes messages that fall outside the retention policy
+pub struct RetentionTask {
+    db: Arc<Mutex<Database>>,
+    policy: RetentionPolicy,
+    metrics: Arc<Metrics>,
+}
+
+impl RetentionTask {
+    pub fn new(db: Arc<Mutex<Database>>, policy: RetentionPolicy, metrics: Arc<Metrics>) -> Self {
+        Self {
+            db,
+            policy,
+            metrics,
+        }
+    }
+
+    pub async fn start(self) {
+        info!(
+            max_age_days = self.policy.max_age_days,
+            imsi_overrides = self.policy.imsi_max_age_days.len(),
+            max_messages = self.policy.max_messages,
+            "Starting retention task"
+        );
+
+        if self.policy.vacuum == VacuumMode::Incremental {
+            // Switching takes a full VACUUM that would block the daemon, so
+            // it is left to `samson db vacuum`
+            match self.db.lock().await.incremental_vacuum_enabled() {
+                Ok(true) => {}
+                Ok(false) => warn!(
+                    "Database is not in incremental auto-vacuum mode, so pruning releases no \
+                     disk space; run `samson db vacuum --mode incremental` to switch it"
+                ),
+                Err(e) => error!("Failed to read the auto-vacuum mode: {}", e),
+            }
+        }
+
+        let mut interval = tokio::time::interval(Duration::from_secs(self.policy.interval));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.run_once().await {
+                error!("Error enforcing retention policy: {}", e);
+            }
+        }
+    }
+
+    /// Applies the policy once, returning the number of deleted messages
+    async fn run_once(&self) -> Result<u64> {
+        let now = Utc::now();
+        let mut by_age = 0;
+
+        // SIMs with their own maximum age, 0 keeps their messages forever
+        for (imsi, days) in &self.policy.imsi_max_age_days {
+            if *days == 0 {
+                continue;
+            }
+            let cutoff = now - ChronoDuration::days(*days as i64);
+            by_age += self
+                .delete_in_batches(|db, batch| {
+                    db.delete_messages_before(cutoff, Some(imsi), &[], batch)
+                })
+                .await?;
+        }
+
+        if self.policy.max_age_days > 0 {
+            let cutoff = now - ChronoDuration::days(self.policy.max_age_days as i64);
+            let overridden: Vec<String> = self.policy.imsi_max_age_days.keys().cloned().collect();
+            by_age += self
+                .delete_in_batches(|db, batch| {
+                    db.delete_messages_before(cutoff, None, &overridden, batch)
+                })
+                .await?;
+        }
+
+        let by_count = if self.policy.max_messages > 0 {
+            self.delete_in_batches(|db, batch| {
+                db.delete_excess_messages(self.policy.max_messages, batch)
+            })
+            .await?
+        } else {
+            0
+        };
+
+        let total = by_age + by_count;
+        if total > 0 {
+            let db = self.db.lock().await;
+            let deliveries = db.delete_orphaned_webhook_deliveries()?;
+            db.vacuum(self.policy.vacuum)?;
+            info!(by_age, by_count, deliveries, "Pruned messages");
+        } else {
+            debug!("No messages to prune");
+        }
+
+        let pruned = &self.metrics.messages_pruned;
+        pruned.with_label_values(&["age"]).inc_by(by_age);
+        pruned.with_label_values(&["count"]).inc_by(by_count);
+        self.metrics.retention_last_run.set(now.timestamp());
+
+        Ok(total)
+    }
+
+    /// Repeats a delete of at most one batch until it deletes less than a
+    /// full batch, releasing the database between batches
+    async fn delete_in_batches(
+        &self,
+        delete: impl Fn(&Database, usize) -> Result<usize>,
+    ) -> Result<u64> {
+        let batch_size = self.policy.batch_size;
+        let mut total = 0;
+
+        loop {
+            let deleted = {
+                let db = self.db.lock().await;
+                delete(&db, batch_size)?
+            };
+            total += deleted as u64;
+
+            if deleted < batch_size {
+                return Ok(total);
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{MessageFilter, SmsMessage};
+    use std::collections::HashMap;
+
+    const IMSI: &str = "310260123456789";
+    const OTHER_IMSI: &str = "310260999999999";
+
+    fn policy() -> RetentionPolicy {
+        RetentionPolicy {
+            max_age_days: 0,
+            imsi_max_age_days: HashMap::new(),
+            max_messages: 0,
+            interval: 3600,
+            batch_size: 2,
+            vacuum: VacuumMode::Off,
+        }
+    }
+
+    fn task(db: &Arc<Mutex<Database>>, policy: RetentionPolicy) -> RetentionTask {
+        RetentionTask::new(db.clone(), policy, Arc::new(Metrics::new()))
+    }
+
+    async fn insert_message(db: &Arc<Mutex<Database>>, imsi: &str, text: &str, days_ago: i64) {
+        let msg = SmsMessage {
+            id: None,
+            imei: "123456789012345".to_string(),
+            imsi: imsi.to_string(),
+            iccid: format!("89{}", imsi),
+            operator_id: "310260".to_string(),
+            operator_name: "Mock Mobile".to_string(),
+            own_numbers: Vec::new(),
+            sender: "+1234567890".to_string(),
+            text: text.to_string(),
+            timestamp: Utc::now() - ChronoDuration::days(days_ago),
+            partial: false,
+        };
+        db.lock().await.insert_message(&msg).unwrap();
+    }
+
+    async fn remaining(db: &Arc<Mutex<Database>>) -> Vec<String> {
+        db.lock()
+            .await
+            .get_messages(&MessageFilter::default())
+            .unwrap()
+            .messages
+            .into_iter()
+            .map(|m| m.text)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn prunes_by_age_with_imsi_overrides() {
+        let db = Arc::new(Mutex::new(Database::new(":memory:").unwrap()));
+        for days_ago in [40, 35, 31, 10] {
+            insert_message(&db, IMSI, &format!("imsi {}", days_ago), days_ago).await;
+            insert_message(&db, OTHER_IMSI, &format!("other {}", days_ago), days_ago).await;
+        }
+
+        let task = task(
+            &db,
+            RetentionPolicy {
+                max_age_days: 30,
+                imsi_max_age_days: HashMap::from([(OTHER_IMSI.to_string(), 7)]),
+                ..policy()
+            },
+        );
+
+        assert_eq!(task.run_once().await.unwrap(), 7);
+        assert_eq!(remaining(&db).await, vec!["imsi 10"]);
+        let pruned = &task.metrics.messages_pruned;
+        assert_eq!(pruned.with_label_values(&["age"]).get(), 7);
+        assert!(task.metrics.retention_last_run.get() > 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_overridden_imsi_forever() {
+        let db = Arc::new(Mutex::new(Database::new(":memory:").unwrap()));
+        insert_message(&db, IMSI, "old", 400).await;
+        insert_message(&db, OTHER_IMSI, "old other", 400).await;
+        assert!(!db.lock().await.incremental_vacuum_enabled().unwrap());
+        assert!(db.lock().await.enable_incremental_vacuum().unwrap());
+        assert!(!db.lock().await.enable_incremental_vacuum().unwrap());
+        assert!(db.lock().await.incremental_vacuum_enabled().unwrap());
+
+        let task = task(
+            &db,
+            RetentionPolicy {
+                max_age_days: 30,
+                imsi_max_age_days: HashMap::from([(OTHER_IMSI.to_string(), 0)]),
+                vacuum: VacuumMode::Incremental,
+                ..policy()
+            },
+        );
+
+        task.run_once().await.unwrap();
+        assert_eq!(remaining(&db).await, vec!["old other"]);
+    }
+
+    #[tokio::test]
+    async fn prunes_oldest_messages_beyond_max_count() {
+        let db = Arc::new(Mutex::new(Database::new(":memory:").unwrap()));
+        for i in 0..5 {
+            insert_message(&db, IMSI, &format!("message {}", i), 0).await;
+        }
+
+        let task = task(
+            &db,
+            RetentionPolicy {
+                max_messages: 2,
+                vacuum: VacuumMode::Full,
+                ..policy()
+            },
+        );
+
+        assert_eq!(task.run_once().await.unwrap(), 3);
+        assert_eq!(remaining(&db).await, vec!["message 3", "message 4"]);
+        let pruned = &task.metrics.messages_pruned;
+        assert_eq!(pruned.with_label_values(&["count"]).get(), 3);
+
+        assert_eq!(task.run_once().await.unwrap(), 0);
+    }
+}
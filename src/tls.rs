@@ -0,0 +1,337 @@
+use crate::config::TlsConfig;
+use anyhow::{Context, Result};
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::RootCertStore;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ServerConfig, WebPkiClientVerifier};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+/// A TLS listener's settings together with the live rustls config, so the
+/// certificates can be swapped without restarting the listener
+#[derive(Clone)]
+pub struct ReloadableTls {
+    name: &'static str,
+    settings: TlsConfig,
+    rustls: RustlsConfig,
+}
+
+impl ReloadableTls {
+    pub fn load(name: &'static str, settings: &TlsConfig) -> Result<Self> {
+        let config = server_config(settings).with_context(|| {
+            format!("Failed to load TLS configuration for the {} listener", name)
+        })?;
+
+        Ok(Self {
+            name,
+            settings: settings.clone(),
+            rustls: RustlsConfig::from_config(config),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Re-reads the certificate, key and client CA from disk. On failure the
+    /// listener keeps serving with the previous configuration.
+    pub fn reload(&self) -> Result<()> {
+        let config = server_config(&self.settings)?;
+        self.rustls.reload_from_config(config);
+        Ok(())
+    }
+}
+
+/// Serves `app` on `listener`, over TLS if `tls` is set
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    tls: Option<ReloadableTls>,
+) -> std::io::Result<()> {
+    match tls {
+        None => axum::serve(listener, app).await,
+        Some(tls) => {
+            axum_server::from_tcp_rustls(listener.into_std()?, tls.rustls)
+                .serve(app.into_make_service())
+                .await
+        }
+    }
+}
+
+fn server_config(settings: &TlsConfig) -> Result<Arc<ServerConfig>> {
+    let certs = load_certs(&settings.cert_path)?;
+    let key = load_key(&settings.key_path)?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match &settings.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("Invalid CA certificate in {}", path))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .context("Failed to build client certificate verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .context("Certificate does not match private key")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificates in {}", path))?;
+
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", path);
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse private key in {}", path))?
+        .with_context(|| format!("No private key found in {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, Connection, ServerConnection};
+    use std::path::PathBuf;
+
+    /// Directory for a test's PEM files, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("samson-tls-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn write(&self, name: &str, pem: &str) -> String {
+            let path = self.0.join(name);
+            std::fs::write(&path, pem).unwrap();
+            path.to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    struct Ca {
+        cert: Certificate,
+        key: KeyPair,
+    }
+
+    impl Ca {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            Self { cert, key }
+        }
+
+        /// Issues a certificate for `name` and returns it with its key
+        fn issue(&self, name: &str, usage: ExtendedKeyUsagePurpose) -> (Certificate, KeyPair) {
+            let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.extended_key_usages = vec![usage];
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            (cert, key)
+        }
+    }
+
+    /// Writes a server certificate issued by `ca` and its key
+    fn server_files(dir: &TempDir, ca: &Ca) -> TlsConfig {
+        let (cert, key) = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        TlsConfig {
+            cert_path: dir.write("cert.pem", &cert.pem()),
+            key_path: dir.write("key.pem", &key.serialize_pem()),
+            client_ca_path: None,
+        }
+    }
+
+    fn client_config(ca: &Ca, client: Option<(Certificate, KeyPair)>) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.cert.der().clone()).unwrap();
+        let builder =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+        match client {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(
+                    vec![cert.der().clone()],
+                    PrivateKeyDer::try_from(key.serialize_der()).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        }
+    }
+
+    /// Runs a TLS handshake in memory and returns the certificate the
+    /// server presented
+    fn handshake(
+        tls: &ReloadableTls,
+        client: ClientConfig,
+    ) -> Result<CertificateDer<'static>, rustls::Error> {
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut client = Connection::from(ClientConnection::new(Arc::new(client), server_name)?);
+        let mut server = Connection::from(ServerConnection::new(tls.rustls.get_inner())?);
+
+        for _ in 0..10 {
+            if !client.is_handshaking() && !server.is_handshaking() {
+                break;
+            }
+            transfer(&mut client, &mut server)?;
+            transfer(&mut server, &mut client)?;
+        }
+
+        let Connection::Client(client) = client else {
+            unreachable!()
+        };
+        Ok(client.peer_certificates().unwrap()[0].clone().into_owned())
+    }
+
+    fn transfer(from: &mut Connection, to: &mut Connection) -> Result<(), rustls::Error> {
+        let mut buffer = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut buffer).unwrap();
+        }
+        let mut pending = &buffer[..];
+        while !pending.is_empty() {
+            to.read_tls(&mut pending).unwrap();
+            to.process_new_packets()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn loads_matching_certificate_and_key() {
+        let dir = TempDir::new("load");
+        let ca = Ca::new();
+        let settings = server_files(&dir, &ca);
+
+        let tls = ReloadableTls::load("api", &settings).unwrap();
+
+        let presented = handshake(&tls, client_config(&ca, None)).unwrap();
+        assert_eq!(presented, load_certs(&settings.cert_path).unwrap()[0]);
+    }
+
+    #[test]
+    fn rejects_mismatched_or_missing_key() {
+        let dir = TempDir::new("mismatch");
+        let ca = Ca::new();
+        let settings = server_files(&dir, &ca);
+        let other_key = dir.write("other.pem", &KeyPair::generate().unwrap().serialize_pem());
+
+        let error = |settings: TlsConfig| {
+            format!("{:#}", ReloadableTls::load("api", &settings).err().unwrap())
+        };
+
+        let mismatched = error(TlsConfig {
+            key_path: other_key,
+            ..settings.clone()
+        });
+        assert!(
+            mismatched.contains("Certificate does not match private key"),
+            "{}",
+            mismatched
+        );
+
+        let missing_path = dir.0.join("missing.pem").to_string_lossy().into_owned();
+        let missing = error(TlsConfig {
+            key_path: missing_path.clone(),
+            ..settings.clone()
+        });
+        assert!(
+            missing.starts_with("Failed to load TLS configuration for the api listener")
+                && missing.contains(&format!("Failed to open {}", missing_path)),
+            "{}",
+            missing
+        );
+
+        // A certificate file holds no key
+        let no_key = error(TlsConfig {
+            key_path: settings.cert_path.clone(),
+            ..settings
+        });
+        assert!(no_key.contains("No private key found"), "{}", no_key);
+    }
+
+    #[test]
+    fn reload_picks_up_replaced_certificate() {
+        let dir = TempDir::new("reload");
+        let ca = Ca::new();
+        let settings = server_files(&dir, &ca);
+        let tls = ReloadableTls::load("api", &settings).unwrap();
+        let before = handshake(&tls, client_config(&ca, None)).unwrap();
+
+        // A broken replacement keeps the previous certificate in use
+        std::fs::write(&settings.key_path, "not a key").unwrap();
+        assert!(tls.reload().is_err());
+        assert_eq!(handshake(&tls, client_config(&ca, None)).unwrap(), before);
+
+        server_files(&dir, &ca);
+        tls.reload().unwrap();
+
+        let after = handshake(&tls, client_config(&ca, None)).unwrap();
+        assert_ne!(after, before);
+        assert_eq!(after, load_certs(&settings.cert_path).unwrap()[0]);
+    }
+
+    #[test]
+    fn client_ca_rejects_certificates_from_other_cas() {
+        let dir = TempDir::new("mtls");
+        let ca = Ca::new();
+        let client_ca = Ca::new();
+        let settings = TlsConfig {
+            client_ca_path: Some(dir.write("client-ca.pem", &client_ca.cert.pem())),
+            ..server_files(&dir, &ca)
+        };
+        let tls = ReloadableTls::load("api", &settings).unwrap();
+
+        let trusted = client_ca.issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+        assert!(handshake(&tls, client_config(&ca, Some(trusted))).is_ok());
+
+        let untrusted = Ca::new().issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+        let error = handshake(&tls, client_config(&ca, Some(untrusted))).unwrap_err();
+        assert!(
+            matches!(error, rustls::Error::InvalidCertificate(_)),
+            "{:?}",
+            error
+        );
+
+        // Clients must present a certificate at all
+        assert!(handshake(&tls, client_config(&ca, None)).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+use crate::config::UssdJob;
+use crate::db::{Database, UssdDirection, UssdSession, UssdSessionState};
+use crate::modem::{ModemBackend, ModemInfo, UssdReply};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+/// Time the network gets to answer a USSD code or response
+const USSD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A request that does not fit the state of the SIM's USSD session, such
+/// as a response while no session waits for one
+#[derive(Debug)]
+pub struct SessionConflict(pub String);
+
+impl fmt::Display for SessionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SessionConflict {}
+
+/// Runs USSD sessions and records their transcripts. A modem handles one
+/// session at a time, so requests for the same SIM are serialised.
+pub struct UssdService {
+    db: Arc<Mutex<Database>>,
+    modem_manager: Arc<dyn ModemBackend>,
+    /// One lock per IMSI, held for the duration of a USSD call
+    locks: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl UssdService {
+    pub fn new(db: Arc<Mutex<Database>>, modem_manager: Arc<dyn ModemBackend>) -> Self {
+        Self {
+            db,
+            modem_manager,
+            locks: Default::default(),
+        }
+    }
+
+    fn lock_for(&self, imsi: &str) -> Arc<Mutex<()>> {
+        self.locks
+            .lock()
+            .unwrap()
+            .entry(imsi.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Starts a session with `command` and records the network's reply. The
+    /// session stays active if the network waits for a response.
+    pub async fn initiate(
+        &self,
+        modem: &ModemInfo,
+        command: &str,
+        scheduled: bool,
+    ) -> Result<UssdSession> {
+        let lock = self.lock_for(&modem.imsi);
+        let _guard = lock.lock().await;
+
+        let id = {
+            let db = self.db.lock().await;
+            if let Some(active) = db.get_active_ussd_session(&modem.imsi)? {
+                return Err(SessionConflict(format!(
+                    "USSD session {} on SIM {} waits for a response, respond to or cancel it first",
+                    active.id, modem.imsi
+                ))
+                .into());
+            }
+            let id = db.insert_ussd_session(modem, command, scheduled)?;
+            db.add_ussd_message(id, UssdDirection::Sent, command)?;
+            id
+        };
+
+        info!(id, imsi = %modem.imsi, command, "Starting USSD session");
+        let reply = with_timeout(self.modem_manager.ussd_initiate(&modem.path, command)).await;
+        self.record_reply(id, reply).await
+    }
+
+    /// Answers the network in the active session of the modem's SIM
+    pub async fn respond(&self, modem: &ModemInfo, response: &str) -> Result<UssdSession> {
+        let lock = self.lock_for(&modem.imsi);
+        let _guard = lock.lock().await;
+
+        let id = {
+            let db = self.db.lock().await;
+            let Some(active) = db.get_active_ussd_session(&modem.imsi)? else {
+                return Err(SessionConflict(format!(
+                    "No USSD session on SIM {} waits for a response",
+                    modem.imsi
+                ))
+                .into());
+            };
+            db.add_ussd_message(active.id, UssdDirection::Sent, response)?;
+            active.id
+        };
+
+        let reply = with_timeout(self.modem_manager.ussd_respond(&modem.path, response)).await;
+        self.record_reply(id, reply).await
+    }
+
+    /// Cancels the modem's USSD session. Returns the recorded session that
+    /// was active, if any.
+    pub async fn cancel(&self, modem: &ModemInfo) -> Result<Option<UssdSession>> {
+        let lock = self.lock_for(&modem.imsi);
+        let _guard = lock.lock().await;
+
+        let result = with_timeout(self.modem_manager.ussd_cancel(&modem.path)).await;
+
+        let db = self.db.lock().await;
+        let Some(active) = db.get_active_ussd_session(&modem.imsi)? else {
+            // Nothing recorded, but a session started elsewhere may be gone now
+            result?;
+            return Ok(None);
+        };
+
+        // The session is over for us even if the modem had already dropped it
+        if let Err(e) = result {
+            warn!(id = active.id, error = %e, "Failed to cancel USSD session on the modem");
+        }
+        db.update_ussd_session(active.id, UssdSessionState::Cancelled, None)?;
+        info!(id = active.id, imsi = %modem.imsi, "Cancelled USSD session");
+        db.get_ussd_session(active.id)
+    }
+
+    /// Adds the network's reply to the transcript, or records the failure
+    async fn record_reply(&self, id: i64, reply: Result<UssdReply>) -> Result<UssdSession> {
+        let db = self.db.lock().await;
+        match reply {
+            Ok(reply) => {
+                db.add_ussd_message(id, UssdDirection::Received, &reply.text)?;
+                if !reply.awaiting_response {
+                    db.update_ussd_session(id, UssdSessionState::Completed, None)?;
+                }
+            }
+            Err(e) => {
+                warn!(id, error = %e, "USSD request failed");
+                db.update_ussd_session(id, UssdSessionState::Failed, Some(&format!("{:#}", e)))?;
+            }
+        }
+        db.get_ussd_session(id)?
+            .with_context(|| format!("USSD session {} disappeared", id))
+    }
+
+    /// Runs a scheduled job once and records the parsed reply as the
+    /// session's result
+    async fn run_job(&self, job: &UssdJob, pattern: Option<&Regex>) -> Result<()> {
+        let modem = self
+            .modem_manager
+            .get_modems()
+            .await?
+            .into_iter()
+            .find(|modem| modem.imsi == job.imsi)
+            .with_context(|| format!("SIM {} is not in any modem", job.imsi))?;
+
+        let session = self.initiate(&modem, &job.command, true).await?;
+        if session.state == UssdSessionState::Failed {
+            anyhow::bail!(session.error.unwrap_or_default());
+        }
+        let state = if session.state == UssdSessionState::Active {
+            // Jobs do not navigate menus
+            self.cancel(&modem).await?;
+            UssdSessionState::Cancelled
+        } else {
+            session.state
+        };
+
+        let reply = session
+            .transcript
+            .last()
+            .map(|message| message.text.as_str())
+            .unwrap_or_default();
+        let db = self.db.lock().await;
+        match parse_reply(pattern, reply) {
+            Some(result) => {
+                db.set_ussd_session_result(session.id, &result)?;
+                info!(imsi = %job.imsi, command = %job.command, result, "Recorded USSD job result");
+                Ok(())
+            }
+            None => {
+                db.update_ussd_session(
+                    session.id,
+                    state,
+                    Some("Reply did not match the job's pattern"),
+                )?;
+                anyhow::bail!("Reply '{}' did not match the job's pattern", reply)
+            }
+        }
+    }
+}
+
+/// Starts a task per scheduled USSD job. Each job runs `interval` seconds
+/// after its previous run, which is read from the database so restarts do
+/// not cause extra runs.
+pub fn start_jobs(service: Arc<UssdService>, jobs: Vec<UssdJob>) {
+    for job in jobs {
+        let service = service.clone();
+        tokio::spawn(async move { run_scheduled(service, job).await });
+    }
+}
+
+async fn run_scheduled(service: Arc<UssdService>, job: UssdJob) {
+    // Patterns were validated when the configuration was loaded
+    let pattern = match job.pattern.as_deref().map(Regex::new).transpose() {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            error!(imsi = %job.imsi, error = %e, "Invalid USSD job pattern, job disabled");
+            return;
+        }
+    };
+    let interval = Duration::from_secs(job.interval);
+
+    let last_run = service
+        .db
+        .lock()
+        .await
+        .last_scheduled_ussd_session(&job.imsi);
+    let mut delay = match last_run {
+        Ok(Some(last_run)) => (last_run + interval - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+        Ok(None) => Duration::ZERO,
+        Err(e) => {
+            error!(imsi = %job.imsi, error = %e, "Failed to read last USSD job run");
+            interval
+        }
+    };
+
+    info!(imsi = %job.imsi, command = %job.command, next_run_in = delay.as_secs(), "Scheduled USSD job");
+    loop {
+        tokio::time::sleep(delay).await;
+        if let Err(e) = service.run_job(&job, pattern.as_ref()).await {
+            warn!(imsi = %job.imsi, command = %job.command, "USSD job failed: {:#}", e);
+        }
+        delay = interval;
+    }
+}
+
+/// Extracts a job's result from a reply: the first capture group of the
+/// pattern, or its whole match, or without a pattern the trimmed reply
+fn parse_reply(pattern: Option<&Regex>, reply: &str) -> Option<String> {
+    let Some(pattern) = pattern else {
+        return Some(reply.trim().to_string());
+    };
+    let captures = pattern.captures(reply)?;
+    captures
+        .get(1)
+        .or_else(|| captures.get(0))
+        .map(|m| m.as_str().to_string())
+}
+
+async fn with_timeout<T>(call: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::time::timeout(USSD_TIMEOUT, call)
+        .await
+        .unwrap_or_else(|_| {
+            anyhow::bail!(
+                "No reply from the network within {}s",
+                USSD_TIMEOUT.as_secs()
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modem::mock::MockModemBackend;
+
+    const MODEM: &str = "/org/freedesktop/ModemManager1/Modem/0";
+    const IMSI: &str = "310260123456789";
+
+    fn setup() -> (Arc<MockModemBackend>, Arc<Mutex<Database>>, UssdService) {
+        let backend = Arc::new(MockModemBackend::new());
+        backend.add_modem(MODEM, "123456789012345", IMSI, "8901260123456789012");
+        let db = Arc::new(Mutex::new(Database::new(":memory:").unwrap()));
+        let service = UssdService::new(db.clone(), backend.clone());
+        (backend, db, service)
+    }
+
+    fn job(pattern: Option<&str>) -> UssdJob {
+        UssdJob {
+            imsi: IMSI.to_string(),
+            command: "*100#".to_string(),
+            interval: 3600,
+            pattern: pattern.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn parses_replies() {
+        let pattern = Regex::new(r"Balance: ([0-9.]+)").unwrap();
+        let reply = "Balance: 12.50 EUR, valid until 01/05";
+
+        assert_eq!(parse_reply(Some(&pattern), reply).as_deref(), Some("12.50"));
+        let whole = Regex::new(r"[0-9]+\.[0-9]+ EUR").unwrap();
+        assert_eq!(
+            parse_reply(Some(&whole), reply).as_deref(),
+            Some("12.50 EUR")
+        );
+        assert_eq!(parse_reply(None, " Hello \n").as_deref(), Some("Hello"));
+        assert_eq!(parse_reply(Some(&pattern), "Service unavailable"), None);
+    }
+
+    #[tokio::test]
+    async fn scheduled_job_records_result_and_leaves_menus() {
+        let (backend, db, service) = setup();
+        backend.set_ussd_reply("*100#", "Balance: 3.20 EUR\n1. Top up", true);
+        let pattern = Regex::new(r"Balance: ([0-9.]+)").unwrap();
+
+        let job = job(Some(r"Balance: ([0-9.]+)"));
+        service.run_job(&job, Some(&pattern)).await.unwrap();
+
+        let db = db.lock().await;
+        let session = db.get_ussd_sessions(IMSI, None, 10).unwrap().sessions;
+        assert_eq!(session.len(), 1);
+        assert!(session[0].scheduled);
+        assert_eq!(session[0].result.as_deref(), Some("3.20"));
+        assert_eq!(session[0].state, UssdSessionState::Cancelled);
+        assert!(db.last_scheduled_ussd_session(IMSI).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn scheduled_job_fails_on_unmatched_reply() {
+        let (backend, db, service) = setup();
+        backend.set_ussd_reply("*100#", "Service unavailable", false);
+        let pattern = Regex::new(r"Balance: ([0-9.]+)").unwrap();
+
+        let error = service
+            .run_job(&job(Some(r"Balance: ([0-9.]+)")), Some(&pattern))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("did not match"), "{}", error);
+
+        let db = db.lock().await;
+        let session = &db.get_ussd_sessions(IMSI, None, 10).unwrap().sessions[0];
+        assert_eq!(session.state, UssdSessionState::Completed);
+        assert_eq!(session.result, None);
+        assert!(session.error.is_some());
+    }
+}
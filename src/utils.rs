@@ -10,10 +10,10 @@ pub fn parse_rfc3339_timestamp(timestamp_str: &str) -> Result<DateTime<Utc>> {
     }
 
     // If that fails, try to fix incomplete timezone offset (e.g., +01 -> +01:00)
-    if let Some(fixed) = fix_incomplete_timezone(timestamp_str) {
-        if let Ok(dt) = DateTime::parse_from_rfc3339(&fixed) {
-            return Ok(dt.with_timezone(&Utc));
-        }
+    if let Some(fixed) = fix_incomplete_timezone(timestamp_str)
+        && let Ok(dt) = DateTime::parse_from_rfc3339(&fixed)
+    {
+        return Ok(dt.with_timezone(&Utc));
     }
 
     anyhow::bail!("Failed to parse RFC3339 timestamp: {}", timestamp_str)
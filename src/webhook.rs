@@ -0,0 +1,405 @@
+use crate::config::WebhookTarget;
+use crate::db::{Database, SmsMessage, WebhookDelivery};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, warn};
+
+/// How often the delivery queue is checked for due deliveries
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Maximum number of deliveries attempted per dispatch round
+const DISPATCH_BATCH_SIZE: usize = 50;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay before the first retry, doubled after every failed attempt
+const RETRY_BASE_DELAY_SECS: i64 = 5;
+const RETRY_MAX_DELAY_SECS: i64 = 3600;
+
+pub const SIGNATURE_HEADER: &str = "X-Samson-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Samson-Timestamp";
+pub const DELIVERY_HEADER: &str = "X-Samson-Delivery";
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    id: i64,
+    imei: &'a str,
+    imsi: &'a str,
+    iccid: &'a str,
+    operator_id: &'a str,
+    operator_name: &'a str,
+    own_numbers: &'a [String],
+    sender: &'a str,
+    text: &'a str,
+    timestamp: DateTime<Utc>,
+    partial: bool,
+}
+
+pub struct WebhookDispatcher {
+    db: Arc<Mutex<Database>>,
+    client: reqwest::Client,
+    /// Replaced on configuration reload
+    targets: RwLock<Vec<WebhookTarget>>,
+    secret: Option<String>,
+    max_attempts: u32,
+}
+
+impl WebhookDispatcher {
+    pub fn new(
+        db: Arc<Mutex<Database>>,
+        targets: Vec<WebhookTarget>,
+        secret: Option<String>,
+        max_attempts: u32,
+    ) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("Failed to create webhook HTTP client")?;
+
+        Ok(Self {
+            db,
+            client,
+            targets: RwLock::new(targets),
+            secret,
+            max_attempts,
+        })
+    }
+
+    /// Replaces the targets for messages stored from now on. Queued
+    /// deliveries still go to the URLs they were queued for.
+    pub fn set_targets(&self, targets: Vec<WebhookTarget>) {
+        *self.targets.write().unwrap() = targets;
+    }
+
+    /// Queues a stored message for delivery to every matching target.
+    /// Takes the already locked database so the caller can enqueue
+    /// right after inserting the message.
+    pub fn enqueue(&self, db: &Database, id: i64, msg: &SmsMessage) -> Result<()> {
+        let targets = self.targets.read().unwrap();
+        let urls: Vec<&str> = targets
+            .iter()
+            .filter(|t| t.sim.as_deref().is_none_or(|sim| msg.matches_sim(sim)))
+            .map(|t| t.url.as_str())
+            .collect();
+
+        if urls.is_empty() {
+            return Ok(());
+        }
+
+        let payload = serde_json::to_string(&WebhookPayload {
+            id,
+            imei: &msg.imei,
+            imsi: &msg.imsi,
+            iccid: &msg.iccid,
+            operator_id: &msg.operator_id,
+            operator_name: &msg.operator_name,
+            own_numbers: &msg.own_numbers,
+            sender: &msg.sender,
+            text: &msg.text,
+            timestamp: msg.timestamp,
+            partial: msg.partial,
+        })?;
+
+        for url in urls {
+            db.enqueue_webhook_delivery(id, url, &payload)?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn start(self: Arc<Self>) {
+        info!(
+            targets = self.targets.read().unwrap().len(),
+            "Starting webhook dispatcher"
+        );
+
+        loop {
+            if let Err(e) = self.dispatch_due().await {
+                error!("Error dispatching webhooks: {}", e);
+            }
+
+            tokio::time::sleep(DISPATCH_INTERVAL).await;
+        }
+    }
+
+    async fn dispatch_due(&self) -> Result<()> {
+        let deliveries = {
+            let db = self.db.lock().await;
+            db.get_due_webhook_deliveries(Utc::now(), DISPATCH_BATCH_SIZE)?
+        };
+
+        for delivery in deliveries {
+            let attempts = delivery.attempts + 1;
+            let result = self.deliver(&delivery).await;
+
+            let db = self.db.lock().await;
+            match result {
+                Ok(()) => {
+                    debug!(
+                        id = delivery.id,
+                        message_id = delivery.message_id,
+                        url = %delivery.url,
+                        "Webhook delivered"
+                    );
+                    db.mark_webhook_delivered(delivery.id, attempts)?;
+                }
+                Err(e) if attempts >= self.max_attempts => {
+                    error!(
+                        id = delivery.id,
+                        message_id = delivery.message_id,
+                        url = %delivery.url,
+                        attempts,
+                        error = %e,
+                        "Webhook delivery failed permanently, moving to dead-letter state"
+                    );
+                    db.mark_webhook_dead(delivery.id, attempts, &e.to_string())?;
+                }
+                Err(e) => {
+                    let next_attempt_at = Utc::now() + retry_delay(attempts);
+                    warn!(
+                        id = delivery.id,
+                        url = %delivery.url,
+                        attempts,
+                        error = %e,
+                        "Webhook delivery failed, retrying at {}",
+                        next_attempt_at
+                    );
+                    db.schedule_webhook_retry(
+                        delivery.id,
+                        attempts,
+                        next_attempt_at,
+                        &e.to_string(),
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn deliver(&self, delivery: &WebhookDelivery) -> Result<()> {
+        let timestamp = Utc::now().timestamp();
+        let mut request = self
+            .client
+            .post(&delivery.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(DELIVERY_HEADER, delivery.id.to_string())
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .body(delivery.payload.clone());
+
+        if let Some(secret) = &self.secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, timestamp, &delivery.payload));
+        }
+
+        let response = request.send().await.context("Request failed")?;
+
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("Receiver responded with {}", status);
+        }
+
+        Ok(())
+    }
+}
+
+/// Computes the signature header value: `sha256=` followed by the
+/// hex-encoded HMAC-SHA256 of `<timestamp>.<body>`. Signing the timestamp
+/// lets receivers reject captured deliveries that are replayed later.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn retry_delay(attempts: u32) -> chrono::Duration {
+    let exponent = attempts.saturating_sub(1).min(20);
+    let secs = (RETRY_BASE_DELAY_SECS << exponent).min(RETRY_MAX_DELAY_SECS);
+    chrono::Duration::seconds(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Router,
+        extract::State,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+    };
+
+    const IMSI: &str = "310260123456789";
+    const ICCID: &str = "8901260123456789012";
+
+    /// Requests received by a test receiver
+    type Received = Arc<std::sync::Mutex<Vec<(HeaderMap, String)>>>;
+
+    /// Starts a webhook receiver answering every request with `status`
+    async fn receiver(status: StatusCode) -> (String, Received) {
+        let received = Received::default();
+        let app =
+            Router::new()
+                .route(
+                    "/hook",
+                    post(
+                        move |State(received): State<Received>,
+                              headers: HeaderMap,
+                              body: String| async move {
+                            received.lock().unwrap().push((headers, body));
+                            status
+                        },
+                    ),
+                )
+                .with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, received)
+    }
+
+    fn target(url: &str, sim: Option<&str>) -> WebhookTarget {
+        WebhookTarget {
+            url: url.to_string(),
+            sim: sim.map(str::to_string),
+        }
+    }
+
+    fn message() -> SmsMessage {
+        SmsMessage {
+            id: None,
+            imei: "123456789012345".to_string(),
+            imsi: IMSI.to_string(),
+            iccid: ICCID.to_string(),
+            operator_id: "310260".to_string(),
+            operator_name: "Mock Mobile".to_string(),
+            own_numbers: vec!["+15551234567".to_string()],
+            sender: "+1234567890".to_string(),
+            text: "Your code is 4821".to_string(),
+            timestamp: Utc::now(),
+            partial: false,
+        }
+    }
+
+    /// Stores a message and queues it for the dispatcher's targets
+    async fn enqueue(dispatcher: &WebhookDispatcher) -> i64 {
+        let db = dispatcher.db.lock().await;
+        let id = db.insert_message(&message()).unwrap();
+        dispatcher.enqueue(&db, id, &message()).unwrap();
+        id
+    }
+
+    fn dispatcher(targets: Vec<WebhookTarget>, max_attempts: u32) -> WebhookDispatcher {
+        let db = Arc::new(Mutex::new(Database::new(":memory:").unwrap()));
+        WebhookDispatcher::new(db, targets, Some("secret".to_string()), max_attempts).unwrap()
+    }
+
+    async fn due(dispatcher: &WebhookDispatcher, at: DateTime<Utc>) -> Vec<WebhookDelivery> {
+        let db = dispatcher.db.lock().await;
+        db.get_due_webhook_deliveries(at, DISPATCH_BATCH_SIZE)
+            .unwrap()
+    }
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        // printf '1767946813.{"id":1}' | openssl dgst -sha256 -hmac secret
+        assert_eq!(
+            sign("secret", 1767946813, r#"{"id":1}"#),
+            "sha256=2078d2ea46a9585bd2e94e044557f4aba0a022ada880db1f6fade10d4cbd9f5c"
+        );
+        assert_ne!(
+            sign("secret", 1767946814, r#"{"id":1}"#),
+            sign("secret", 1767946813, r#"{"id":1}"#)
+        );
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        let delays: Vec<i64> = (1..=6).map(|n| retry_delay(n).num_seconds()).collect();
+        assert_eq!(delays, vec![5, 10, 20, 40, 80, 160]);
+        assert_eq!(retry_delay(10).num_seconds(), 2560);
+        assert_eq!(retry_delay(11).num_seconds(), RETRY_MAX_DELAY_SECS);
+        assert_eq!(retry_delay(u32::MAX).num_seconds(), RETRY_MAX_DELAY_SECS);
+    }
+
+    #[tokio::test]
+    async fn enqueues_for_targets_matching_the_sim() {
+        let dispatcher = dispatcher(
+            vec![
+                target("https://example.com/all", None),
+                target("https://example.com/imsi", Some(IMSI)),
+                target("https://example.com/iccid", Some(ICCID)),
+                target("https://example.com/number", Some("+15551234567")),
+                target("https://example.com/other", Some("310260999999999")),
+            ],
+            3,
+        );
+        enqueue(&dispatcher).await;
+
+        let urls: Vec<String> = due(&dispatcher, Utc::now())
+            .await
+            .into_iter()
+            .map(|d| d.url)
+            .collect();
+        assert_eq!(urls.len(), 4);
+        for url in ["all", "imsi", "iccid", "number"] {
+            assert!(urls.contains(&format!("https://example.com/{}", url)));
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_payload_with_timestamp() {
+        let (url, received) = receiver(StatusCode::OK).await;
+        let dispatcher = dispatcher(vec![target(&url, None)], 3);
+        let id = enqueue(&dispatcher).await;
+
+        dispatcher.dispatch_due().await.unwrap();
+
+        let (headers, body) = received.lock().unwrap().remove(0);
+        let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["id"], id);
+        let timestamp: i64 = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+        assert!((Utc::now().timestamp() - timestamp).abs() < 60);
+        assert_eq!(
+            headers[SIGNATURE_HEADER].to_str().unwrap(),
+            sign("secret", timestamp, &body)
+        );
+        assert!(
+            due(&dispatcher, Utc::now() + chrono::Duration::days(1))
+                .await
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn moves_delivery_to_dead_after_the_last_attempt() {
+        let (url, received) = receiver(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let dispatcher = dispatcher(vec![target(&url, None)], 2);
+        enqueue(&dispatcher).await;
+
+        dispatcher.dispatch_due().await.unwrap();
+        let retry = due(&dispatcher, Utc::now() + retry_delay(1)).await;
+        assert_eq!(retry[0].attempts, 1);
+        assert!(due(&dispatcher, Utc::now()).await.is_empty());
+
+        // Make the retry due now instead of waiting for the backoff
+        {
+            let db = dispatcher.db.lock().await;
+            db.schedule_webhook_retry(retry[0].id, 1, Utc::now(), "")
+                .unwrap();
+        }
+        dispatcher.dispatch_due().await.unwrap();
+
+        assert_eq!(received.lock().unwrap().len(), 2);
+        let db = dispatcher.db.lock().await;
+        assert_eq!(
+            db.get_webhook_delivery_status(retry[0].id).unwrap(),
+            ("dead".to_string(), 2)
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! End-to-end test of samson against samson-fakemm on a private D-Bus bus.
+//!
+//! Needs `dbus-daemon` on the PATH and is skipped without it.
+
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+const IMSI: &str = "310260123456789";
+
+/// Child processes, killed when the test ends however it ends
+struct Processes(Vec<Child>);
+
+impl Drop for Processes {
+    fn drop(&mut self) {
+        // Stop the daemons before the bus they are connected to
+        for child in self.0.iter_mut().rev() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Starts a bus of its own and returns its address
+fn start_bus(processes: &mut Processes) -> Option<String> {
+    let mut bus = Command::new("dbus-daemon")
+        .args(["--session", "--nofork", "--print-address"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .ok()?;
+    let mut address = String::new();
+    BufReader::new(bus.stdout.take().unwrap())
+        .read_line(&mut address)
+        .unwrap();
+    processes.0.push(bus);
+    Some(address.trim().to_string())
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn samson(db_path: &PathBuf, bus: &str) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_samson"));
+    command
+        .env_remove("SAMSON_CONFIG")
+        .env("LOG_LEVEL", "warn")
+        .env("DATABASE_PATH", db_path)
+        .env("DBUS_ADDRESS", bus);
+    command
+}
+
+/// Retries `check` until it returns a value or 20 seconds have passed
+async fn eventually<T, F: Future<Output = Option<T>>>(what: &str, check: impl Fn() -> F) -> T {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    loop {
+        if let Some(value) = check().await {
+            return value;
+        }
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+async fn get_json(client: &reqwest::Client, url: &str, key: Option<&str>) -> Option<Value> {
+    let mut request = client.get(url);
+    if let Some(key) = key {
+        request = request.bearer_auth(key);
+    }
+    let response = request.send().await.ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    response.json().await.ok()
+}
+
+#[tokio::test]
+async fn sms_round_trip_through_fake_modem_manager() {
+    let mut processes = Processes(Vec::new());
+    let Some(bus) = start_bus(&mut processes) else {
+        eprintln!("dbus-daemon not found, skipping end-to-end test");
+        return;
+    };
+    let client = reqwest::Client::new();
+
+    let control_port = free_port();
+    let control = format!("http://127.0.0.1:{}", control_port);
+    processes.0.push(
+        Command::new(env!("CARGO_BIN_EXE_samson-fakemm"))
+            .env("DBUS_ADDRESS", &bus)
+            .env("CONTROL_PORT", control_port.to_string())
+            .env("RUST_LOG", "warn")
+            .spawn()
+            .unwrap(),
+    );
+    let sent_url = format!("{}/sent", control);
+    eventually("samson-fakemm", || get_json(&client, &sent_url, None)).await;
+    let response = client
+        .post(format!("{}/modems", control))
+        .json(&json!({
+            "imei": "123456789012345",
+            "imsi": IMSI,
+            "iccid": "8901260123456789012",
+            "own_numbers": ["+15551234567"],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+
+    let db_path = std::env::temp_dir().join(format!("samson-e2e-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&db_path);
+    let status = samson(&db_path, &bus)
+        .args(["db", "migrate"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let output = samson(&db_path, &bus)
+        .args(["keys", "create", "--name", "e2e", "--permissions", "admin"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let key = String::from_utf8(output.stdout).unwrap().trim().to_string();
+
+    let api_port = free_port();
+    let api = format!("http://127.0.0.1:{}", api_port);
+    processes.0.push(
+        samson(&db_path, &bus)
+            .env("API_HOST", "127.0.0.1")
+            .env("API_PORT", api_port.to_string())
+            .env("METRICS_HOST", "127.0.0.1")
+            .env("METRICS_PORT", free_port().to_string())
+            .spawn()
+            .unwrap(),
+    );
+    let messages_url = format!("{}/messages/{}", api, IMSI);
+    eventually("samson", || get_json(&client, &messages_url, Some(&key))).await;
+
+    // Received: injected into the fake modem, announced over D-Bus and
+    // stored by the poller
+    let response = client
+        .post(format!("{}/modems/0/sms", control))
+        .json(&json!({ "number": "+1234567890", "text": "Your code is 4821" }))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let message = eventually("the injected SMS to be stored", || async {
+        let messages = get_json(&client, &messages_url, Some(&key)).await?;
+        messages["data"].as_array()?.first().cloned()
+    })
+    .await;
+    assert_eq!(message["sender"], "+1234567890");
+    assert_eq!(message["text"], "Your code is 4821");
+
+    // Sent: queued through the API and handed to the fake modem
+    let response = client
+        .post(&messages_url)
+        .bearer_auth(&key)
+        .json(&json!({ "number": "+1987654321", "text": "Hello from samson" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+    let sent = eventually("the SMS to be sent", || async {
+        let sent = get_json(&client, &sent_url, None).await?;
+        sent.as_array()?.first().cloned()
+    })
+    .await;
+    assert_eq!(sent["number"], "+1987654321");
+    assert_eq!(sent["text"], "Hello from samson");
+
+    drop(processes);
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", db_path.display(), suffix));
+    }
+}